@@ -0,0 +1,65 @@
+//! Reads voice messages with ffmpeg, see [crate::config::Config::ffmpeg].
+
+use std::process::{Command, Stdio};
+
+use anyhow::anyhow;
+use async_std::task;
+
+use crate::config::{data_path, CONFIG};
+
+/// How many peaks a waveform has, whatever the length.
+pub const PEAKS: usize = 64;
+/// Plenty to see loudness by, and a minute of it is under a megabyte.
+const SAMPLE_RATE: u64 = 8000;
+
+pub struct Probed {
+    pub duration_ms: u64,
+    /// The loudest sample of each of [PEAKS] stretches, 0 to 255.
+    pub peaks: Vec<u8>,
+}
+
+/// Decodes at most `max_seconds` of the file at `path`, so a longer one comes back as just
+/// over that. `None` without ffmpeg.
+pub async fn probe(path: &str, max_seconds: u64) -> anyhow::Result<Option<Probed>> {
+    let Some(ref ffmpeg) = CONFIG.ffmpeg else {
+        return Ok(None);
+    };
+    let (ffmpeg, path) = (ffmpeg.clone(), data_path(path));
+    task::spawn_blocking(move || {
+        let output = Command::new(ffmpeg)
+            .args(["-v", "error", "-nostdin", "-i"])
+            .arg(path)
+            .args(["-vn", "-ac", "1", "-ar", &SAMPLE_RATE.to_string()])
+            .args(["-t", &(max_seconds + 1).to_string(), "-f", "s16le", "-"])
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ffmpeg exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(Some(summarize(&output.stdout)))
+    })
+    .await
+}
+
+/// `pcm` is mono signed 16 bit little endian at [SAMPLE_RATE].
+fn summarize(pcm: &[u8]) -> Probed {
+    let samples: Vec<u16> = pcm
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs())
+        .collect();
+    let stretch = samples.len().div_ceil(PEAKS).max(1);
+    Probed {
+        duration_ms: samples.len() as u64 * 1000 / SAMPLE_RATE,
+        peaks: samples
+            .chunks(stretch)
+            .map(|stretch| {
+                let loudest = stretch.iter().copied().max().unwrap_or_default();
+                (u32::from(loudest) * 255 / 32768) as u8
+            })
+            .collect(),
+    }
+}
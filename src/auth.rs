@@ -1,13 +1,35 @@
+use std::ops::RangeInclusive;
+
 use anyhow::anyhow;
 use async_std::future::timeout;
 use chrono::{DateTime, Duration, Utc};
 use jsonwebtoken::{decode, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use surrealdb::sql::{Datetime, Thing};
-use tide::{http::mime::JSON, log::info, Body, Request, Response, StatusCode};
+use tide::{
+    http::mime::JSON,
+    log::{info, warn},
+    Body, Request, Response, StatusCode,
+};
 use crate::jwt::JwtAuthenticationDecoder;
 
-use crate::{http::HttpState as State, model::user::User, util::{RecordId, BooleanWhy}};
+use crate::{
+    captcha,
+    http::HttpState,
+    mail::{self, Email},
+    model::{
+        instance,
+        login::{Device, Login},
+        name_rule::NameRule,
+        security_event::{SecurityEvent, SecurityEventKind},
+        terms::Terms,
+        user::User,
+    },
+    security::{AttemptKind, THROTTLES},
+    tenant::{self, Tenant},
+    util::{random_string, BooleanWhy, RecordId, Ref, ReferrableExt},
+};
 
 #[derive(Serialize)]
 struct Tokens {
@@ -21,20 +43,28 @@ struct Cred {
     password: String,
 }
 
-pub async fn http_login(mut request: Request<State>) -> tide::Result<impl Into<Response>> {
+pub async fn http_login(mut request: Request<HttpState>) -> tide::Result<impl Into<Response>> {
+    THROTTLES.check(&request, AttemptKind::Login)?;
+    captcha::check(&request).await?;
+    let device = Device::of(&request);
     let credentials = request.body_json().await?;
-    if let Some(tokens) = login(request.state(), credentials).await? {
+    if let Some(tokens) = login(tenant::of(&request)?, credentials, device).await? {
         Ok(Response::builder(StatusCode::Ok)
             .body(Body::from_json(&tokens)?)
             .content_type(JSON))
     } else {
+        THROTTLES.record(&request, AttemptKind::Login);
         Ok(Response::builder(StatusCode::BadRequest))
     }
 }
 
-pub async fn http_register(mut request: Request<State>) -> tide::Result<impl Into<Response>> {
+pub async fn http_register(mut request: Request<HttpState>) -> tide::Result<impl Into<Response>> {
+    THROTTLES.check(&request, AttemptKind::Register)?;
+    captcha::check(&request).await?;
+    THROTTLES.record(&request, AttemptKind::Register);
+    let device = Device::of(&request);
     let data = request.body_json().await?;
-    if let Some(tokens) = register(request.state(), data).await? {
+    if let Some(tokens) = register(tenant::of(&request)?, data, device).await? {
         Ok(Response::builder(StatusCode::Ok)
             .body(Body::from_json(&tokens)?)
             .content_type(JSON))
@@ -43,9 +73,54 @@ pub async fn http_register(mut request: Request<State>) -> tide::Result<impl Int
     }
 }
 
-pub async fn http_refresh(mut request: Request<State>) -> tide::Result {
+#[derive(Deserialize)]
+struct Forgot {
+    email: String,
+}
+
+/// Mails a reset link if `email` has an account. Answers the same either way, so it can't be
+/// used to find out who has one.
+pub async fn http_forgot(mut request: Request<HttpState>) -> tide::Result {
+    THROTTLES.check(&request, AttemptKind::Login)?;
+    captcha::check(&request).await?;
+    // each one sends a mail
+    THROTTLES.record(&request, AttemptKind::Login);
+    let Forgot { email } = request.body_json().await?;
+    let tenant = tenant::of(&request)?;
+    let host = request.state().tenants.host(tenant);
+    forgot(tenant.surreal(), host, &email).await?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+#[derive(Deserialize)]
+struct Reset {
+    token: String,
+    password: String,
+}
+
+/// Sets a new password with a token [http_forgot] mailed, signing the user out everywhere.
+pub async fn http_reset(mut request: Request<HttpState>) -> tide::Result {
+    THROTTLES.check(&request, AttemptKind::Login)?;
+    let device = Device::of(&request);
+    let Reset { token, password } = request.body_json().await?;
+    check_password(&password)?;
+    if reset(tenant::of(&request)?, &token, &password, device).await? {
+        Ok(Response::new(StatusCode::NoContent))
+    } else {
+        THROTTLES.record(&request, AttemptKind::Login);
+        Ok(Response::new(StatusCode::BadRequest))
+    }
+}
+
+pub async fn http_refresh(mut request: Request<HttpState>) -> tide::Result {
+    let device = Device::of(&request);
     let refresh_token = request.body_string().await?;
-    if let Some(tokens) = refresh(request.state(), &refresh_token).await? {
+    let claims = JwtKind::Refresh.demake(&refresh_token).ok();
+    let tenant = request
+        .state()
+        .tenants
+        .resolve(request.host(), claims.as_ref())?;
+    if let Some(tokens) = refresh(tenant, &refresh_token, device).await? {
         Ok(Response::builder(StatusCode::Ok)
             .body(Body::from_json(&tokens)?)
             .content_type(JSON)
@@ -55,13 +130,17 @@ pub async fn http_refresh(mut request: Request<State>) -> tide::Result {
     }
 }
 
-pub async fn http_isactive(request: Request<State>) -> tide::Result {
+pub async fn http_isactive(request: Request<HttpState>) -> tide::Result {
     #[derive(Deserialize)]
     struct Q {
         token: String,
     }
     let Q { token } = request.query()?;
-    let activeness = is_active(request.state().surreal(), &token).await;
+    let tenant = request
+        .state()
+        .tenants
+        .resolve(request.host(), access_claims(&token).as_ref())?;
+    let activeness = is_active(tenant.surreal(), &token).await;
     let status = activeness.is_ok().why(StatusCode::Ok, StatusCode::BadRequest);
     let mut response = Response::builder(status);
 
@@ -95,6 +174,8 @@ pub struct JwtToken {
 pub enum JwtKind {
     Access,
     Refresh,
+    /// OpenID Connect ID tokens, signed with the requesting app's secret (see [crate::oidc]).
+    Id,
 }
 
 lazy_static::lazy_static! {
@@ -112,9 +193,18 @@ pub struct Claims_ {
     pub claims: Claims,
     pub jti: RecordId,
     pub sub: JwtKind,
+    /// Which tenant issued the token, see [tenant]. Not there for the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
 }
 impl JwtKind {
-    async fn make(&self, state: &State, claims: Claims) -> Result<String, anyhow::Error> {
+    /// Stores the record backing a token of this kind, returning its id and issue time.
+    pub(crate) async fn record(
+        &self,
+        state: &Tenant,
+        uid: RecordId,
+        family: Option<String>,
+    ) -> Result<(RecordId, DateTime<Utc>), anyhow::Error> {
         let iat = Utc::now();
         let jw: Jwt = state
             .surreal()
@@ -122,19 +212,33 @@ impl JwtKind {
             .content(Jwt {
                 id: None,
                 kind: *self,
-                uid: claims.uid.clone(),
+                uid,
                 issued_at: Datetime(iat),
                 active: true,
+                family,
+                rotated: false,
             })
             .await?;
-        let jti = jw.id.unwrap();
+        Ok((RecordId(jw.id.unwrap()), iat))
+    }
+
+    async fn make(
+        &self,
+        state: &Tenant,
+        claims: Claims,
+        family: &str,
+    ) -> Result<String, anyhow::Error> {
+        let (jti, iat) = self
+            .record(state, claims.uid.clone(), Some(family.to_owned()))
+            .await?;
 
         let claims_real = Claims_ {
             exp: iat.checked_add_signed(self.expiry()).unwrap(),
             iat,
-            jti: RecordId(jti),
+            jti,
             claims,
             sub: *self,
+            tenant: state.claim(),
         };
 
         let key = self.key_enc();
@@ -161,7 +265,7 @@ impl JwtKind {
 
     fn key(&self) -> &[u8] {
         match self {
-            Self::Access => &*ACCESS,
+            Self::Access | Self::Id => &*ACCESS,
             Self::Refresh => &*REFRESH,
         }
         .as_bytes()
@@ -175,9 +279,9 @@ impl JwtKind {
         DecodingKey::from_secret(self.key())
     }
 
-    fn expiry(&self) -> Duration {
+    pub(crate) fn expiry(&self) -> Duration {
         match self {
-            Self::Access => Duration::minutes(10),
+            Self::Access | Self::Id => Duration::minutes(10),
             Self::Refresh => Duration::minutes(60),
         }
     }
@@ -191,6 +295,14 @@ pub struct Jwt {
     uid: RecordId,
     issued_at: Datetime,
     active: bool,
+    /// Every token descending from one login shares a family, so a leak can be contained by
+    /// revoking all of them.
+    #[serde(default)]
+    family: Option<String>,
+    /// Set on refresh tokens that were exchanged for new ones. Seeing one of those again
+    /// means someone else has a copy.
+    #[serde(default)]
+    rotated: bool,
 }
 
 impl Jwt {
@@ -199,12 +311,15 @@ impl Jwt {
     }
 
     pub fn expired(&self) -> bool {
+        self.lapsed() || !self.active
+    }
+
+    fn lapsed(&self) -> bool {
         Utc::now()
             > self
                 .issued_at
                 .checked_add_signed(self.kind.expiry())
                 .unwrap()
-            || !self.active
     }
 }
 
@@ -234,17 +349,26 @@ mod jwt_numeric_date {
     }
 }
 
-async fn make_jwts(state: &State, uid: RecordId) -> Result<Tokens, anyhow::Error> {
+/// Starts a new token family without `family`, as on login.
+async fn make_jwts(
+    state: &Tenant,
+    uid: RecordId,
+    family: Option<String>,
+) -> Result<Tokens, anyhow::Error> {
+    let family = family.unwrap_or_else(|| random_string(16));
     let access = JwtKind::Access
-        .make(state, Claims { uid: uid.clone() })
+        .make(state, Claims { uid: uid.clone() }, &family)
+        .await?;
+    let refresh = JwtKind::Refresh
+        .make(state, Claims { uid }, &family)
         .await?;
-    let refresh = JwtKind::Refresh.make(state, Claims { uid }).await?;
     Ok(Tokens { access, refresh })
 }
 
 async fn login(
-    state: &State,
+    state: &Tenant,
     Cred { email, password }: Cred,
+    device: Device,
 ) -> Result<Option<Tokens>, tide::Error> {
     #[derive(Deserialize)]
     struct PasswordHash {
@@ -253,9 +377,9 @@ async fn login(
     }
     let real_hash: Option<PasswordHash> = state
         .surreal()
-        .query(format!(
-            "select password_hash, id from user where email == \"{email}\";"
-        ))
+        // remote users live elsewhere and can't log in here, see User::remote
+        .query("SELECT password_hash, id FROM user WHERE email == $email AND domain = NONE")
+        .bind(("email", &email))
         .await?
         .take(0)?;
     if real_hash.is_none() {
@@ -268,11 +392,23 @@ async fn login(
     } = real_hash.unwrap();
     let is_real = bcrypt::verify(password, &real_hash)?;
 
+    let user = Ref::new_owned(RecordId(uid.clone()).id());
     if is_real {
-        return Ok(Some(make_jwts(state, RecordId(uid)).await?));
+        let family = random_string(16);
+        let tokens = make_jwts(state, RecordId(uid), Some(family.clone())).await?;
+        SecurityEvent::new(user.clone(), SecurityEventKind::LoginSucceeded)
+            .from_device(device.clone())
+            .log(state.surreal())
+            .await;
+        Login::record(state.surreal(), &state.relay, &user, family, device).await;
+        return Ok(Some(tokens));
     }
 
     info!("Password does not match for {email}");
+    SecurityEvent::new(user, SecurityEventKind::LoginFailed)
+        .from_device(device)
+        .log(state.surreal())
+        .await;
 
     Ok(None)
 }
@@ -283,16 +419,21 @@ struct RegisterData {
     credentials: Cred,
     tag: String,
     display_name: String,
+    /// An instance invite code, needed while registration is invite only.
+    #[serde(default)]
+    invite: Option<String>,
+    /// The terms of service version the user agreed to, needed once there are terms.
+    #[serde(default)]
+    accept_terms: Option<u32>,
 }
 
-pub async fn make_tag(state: &State, tag: &str) -> Result<[u8; 4], surrealdb::Error> {
+pub async fn make_tag(surreal: &crate::Surreal, tag: &str) -> Result<[u8; 4], surrealdb::Error> {
     #[derive(serde::Deserialize)]
     struct TagTag {
         tag: [i32; 4],
     }
     use rand::Rng;
-    let reals: Vec<TagTag> = state
-        .surreal()
+    let reals: Vec<TagTag> = surreal
         .query("select tag[1] from user where tag[0] == $real_tag;")
         .bind(("real_tag", tag))
         .await?
@@ -313,65 +454,277 @@ pub async fn make_tag(state: &State, tag: &str) -> Result<[u8; 4], surrealdb::Er
     }
 }
 
-const SALT_ROUNDS: u32 = 10;
+pub const SALT_ROUNDS: u32 = 10;
+/// In bytes. bcrypt ignores whatever comes after the 72nd.
+pub const PASSWORD_LENGTH: RangeInclusive<usize> = 8..=72;
+
+/// `400 Bad Request` unless `password` is a length [PASSWORD_LENGTH] allows.
+fn check_password(password: &str) -> tide::Result<()> {
+    if !PASSWORD_LENGTH.contains(&password.len()) {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!(
+                "passwords are {} to {} bytes long",
+                PASSWORD_LENGTH.start(),
+                PASSWORD_LENGTH.end()
+            ),
+        ));
+    }
+    Ok(())
+}
 
 async fn register(
-    state: &State,
+    state: &Tenant,
     RegisterData {
         credentials: Cred { email, password },
         tag,
         display_name,
+        invite,
+        accept_terms,
     }: RegisterData,
+    device: Device,
 ) -> Result<Option<Tokens>, tide::Error> {
-    let password_hash = bcrypt::hash(password.as_bytes(), SALT_ROUNDS)?;
     if !state
         .surreal()
-        .query("SELECT * FROM user WHERE email == $real_email;")
+        .query("SELECT VALUE id FROM user WHERE email == $real_email;")
         .bind(("real_email", &email))
         .await?
-        .take::<Vec<User>>(0)?
+        .take::<Vec<Thing>>(0)?
         .is_empty()
     {
         info!("user with {email} tried to register, already exists.");
         return Ok(None);
     }
-    let [x, y, z, w] = timeout(Duration::seconds(10).to_std()?, make_tag(state, &tag)).await??;
-    let query = format!(
-        r#"
-            CREATE user SET
-                email = {email},
-                password_hash = {password_hash},
-                tag = ['{tag}', [{x}, {y}, {z}, {w}]],
-                display_name = {display_name};
-        "#
-    );
-    let query = unindent::unindent(&query);
-    info!("creating user {tag}#{x:x}{y:x}{z:x}{w:x} with email {email}: \n{query}");
-    let user: Option<User> = state.surreal().query(query).await?.check()?.take(0)?;
-    let user = user.ok_or_else(|| anyhow!("user no makey???"))?;
+    check_password(&password)?;
+    NameRule::check(state.surreal(), &tag).await?;
+    NameRule::check(state.surreal(), &display_name).await?;
+    let accepted_terms = Terms::check_accepted(state.surreal(), accept_terms).await?;
+    instance::check_registration(state.surreal(), invite.as_deref()).await?;
+    let user = timeout(
+        Duration::seconds(10).to_std()?,
+        User::with_password(state.surreal(), &email, &password, &tag, &display_name),
+    )
+    .await??;
+    info!("created user {} with email {email}", user.tag_fmt());
+    if let Some(version) = accepted_terms {
+        Terms::record(state.surreal(), &user.refer(), version).await?;
+    }
 
-    Ok(Some(make_jwts(state, RecordId(user.id)).await?))
+    let family = random_string(16);
+    let tokens = make_jwts(state, user.record_id(), Some(family.clone())).await?;
+    SecurityEvent::new(user.refer(), SecurityEventKind::Registered)
+        .from_device(device.clone())
+        .log(state.surreal())
+        .await;
+    Login::record(state.surreal(), &state.relay, &user.refer(), family, device).await;
+    Ok(Some(tokens))
 }
 
-async fn refresh(state: &State, token: &str) -> Result<Option<Tokens>, tide::Error> {
+async fn refresh(
+    state: &Tenant,
+    token: &str,
+    device: Device,
+) -> Result<Option<Tokens>, tide::Error> {
     let claims = JwtKind::Refresh.demake(token)?;
     let jwt: Option<Jwt> = state.surreal().select(("jwt", &claims.jti.id())).await?;
     let jwt = jwt.ok_or_else(|| anyhow!("token no exist"))?;
+    if jwt.rotated && !jwt.lapsed() {
+        revoke_family(state, &jwt, device).await?;
+        return Ok(None);
+    }
     if let Some(mut jwt) = jwt.check() {
         if let JwtKind::Refresh = &jwt.kind {
             jwt.active = false;
+            jwt.rotated = true;
             let uid = jwt.uid.clone();
+            let family = jwt.family.clone();
+            // clients refresh every few minutes, only the first one of a session is worth a
+            // security event
+            let refreshed_before = match &family {
+                Some(family) => !state
+                    .surreal()
+                    .query("SELECT VALUE id FROM jwt WHERE family = $family AND rotated = true LIMIT 1")
+                    .bind(("family", family))
+                    .await?
+                    .take::<Vec<Thing>>(0)?
+                    .is_empty(),
+                None => false,
+            };
             state
                 .surreal()
                 .update::<Option<Jwt>>(jwt.id.as_ref().unwrap().clone())
-                .content(jwt).await?;
-            return Ok(Some(make_jwts(state, uid).await?));
+                .content(jwt)
+                .await?;
+            if !refreshed_before {
+                SecurityEvent::new(Ref::new_owned(uid.id()), SecurityEventKind::TokenRefreshed)
+                    .from_device(device)
+                    .log(state.surreal())
+                    .await;
+            }
+            return Ok(Some(make_jwts(state, uid, family).await?));
         }
     };
 
     Ok(None)
 }
 
+/// A mailed password reset link, keyed by the hash of its token so the table alone can't
+/// take over accounts.
+#[derive(Deserialize, Serialize)]
+struct PasswordReset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Thing>,
+    user: Ref<User>,
+    issued_at: Datetime,
+}
+
+/// How long a reset link works, see also `password_reset_cleanup`.
+pub const RESET_MINUTES: i64 = 30;
+
+fn reset_key(token: &str) -> (&'static str, String) {
+    (
+        "password_reset",
+        format!("{:x}", Sha1::digest(token.as_bytes())),
+    )
+}
+
+async fn forgot(surreal: &crate::Surreal, host: &str, email: &str) -> tide::Result<()> {
+    let uid: Option<Thing> = surreal
+        .query("SELECT VALUE id FROM user WHERE email == $email AND domain = NONE")
+        .bind(("email", email))
+        .await?
+        .take(0)?;
+    let Some(uid) = uid else {
+        info!("password reset for an email with no account");
+        return Ok(());
+    };
+    let user: Ref<User> = Ref::new_owned(RecordId(uid).id());
+
+    // only the newest link works
+    surreal
+        .query("DELETE password_reset WHERE user = $user")
+        .bind(("user", &user))
+        .await?
+        .check()?;
+    let token = random_string(32);
+    let _: PasswordReset = surreal
+        .create(reset_key(&token))
+        .content(PasswordReset {
+            id: None,
+            user,
+            issued_at: Datetime(Utc::now()),
+        })
+        .await?;
+    mail::queue(
+        surreal,
+        Email {
+            to: email.to_owned(),
+            subject: String::from("Reset your password"),
+            body: format!(
+                "To choose a new password, open https://{host}/reset-password?token={token} \
+                within {RESET_MINUTES} minutes. If you didn't ask for this, ignore this mail; \
+                your password stays as it is."
+            ),
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether `token` was good. It can't be used again either way.
+async fn reset(state: &Tenant, token: &str, password: &str, device: Device) -> tide::Result<bool> {
+    let reset: Option<PasswordReset> = state.surreal().delete(reset_key(token)).await?;
+    let Some(reset) =
+        reset.filter(|reset| Utc::now() < reset.issued_at.0 + Duration::minutes(RESET_MINUTES))
+    else {
+        return Ok(false);
+    };
+    let password_hash = bcrypt::hash(password.as_bytes(), SALT_ROUNDS)?;
+    let revoked: Vec<Jwt> = state
+        .surreal()
+        .query("UPDATE $user SET password_hash = $password_hash")
+        .query("UPDATE jwt SET active = false WHERE uid = $user AND active = true")
+        .bind(("user", &reset.user))
+        .bind(("password_hash", password_hash))
+        .await?
+        .check()?
+        .take(1)?;
+
+    info!("password reset for {}", reset.user.id());
+    SecurityEvent::new(reset.user.clone(), SecurityEventKind::PasswordReset)
+        .from_device(device)
+        .with_detail(format!("{} tokens", revoked.len()))
+        .log(state.surreal())
+        .await;
+    User::send_system_message(
+        state.surreal(),
+        &state.relay,
+        &reset.user,
+        String::from(
+            "Your password was reset with a link sent to your email, and every session was \
+            signed out. If this wasn't you, reset it again and secure your email account.",
+        ),
+    )
+    .await?;
+    Ok(true)
+}
+
+/// A rotated refresh token came back: either the user or whoever stole it already used it,
+/// and we can't tell which, so every token of the family goes.
+async fn revoke_family(state: &Tenant, jwt: &Jwt, device: Device) -> tide::Result<()> {
+    let Some(ref family) = jwt.family else {
+        return Ok(());
+    };
+    let revoked: Vec<Jwt> = state
+        .surreal()
+        .query("UPDATE jwt SET active = false WHERE family = $family AND active = true")
+        .bind(("family", family))
+        .await?
+        .take(0)?;
+    // only tell them the first time the stolen token shows up
+    if revoked.is_empty() {
+        return Ok(());
+    }
+
+    warn!(
+        "refresh token reuse for {}, revoked family {family}",
+        jwt.uid
+    );
+    let user = Ref::new_owned(jwt.uid.id());
+    SecurityEvent::new(user.clone(), SecurityEventKind::TokenReuseRevoked)
+        .from_device(device)
+        .with_detail(format!("{} tokens", revoked.len()))
+        .log(state.surreal())
+        .await;
+    User::send_system_message(
+        state.surreal(),
+        &state.relay,
+        &user,
+        String::from(
+            "A sign-in token of yours was used after it had been replaced, which can mean \
+            someone copied it. That session has been signed out everywhere; if you don't \
+            recognize this, change your password.",
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The claims of a valid access token, without checking it wasn't revoked.
+pub fn access_claims(token: &str) -> Option<Claims_> {
+    JwtKind::Access.demake(token).ok()
+}
+
+/// What a request with a fresh access token for `uid` would carry, for tests.
+#[cfg(test)]
+pub async fn test_token(state: &Tenant, uid: RecordId) -> tide::Result<JwtToken> {
+    let access = JwtKind::Access
+        .make(state, Claims { uid }, &random_string(16))
+        .await?;
+    let claims = access_claims(&access).ok_or_else(|| anyhow!("couldn't read {access}"))?;
+    crate::http::make_jwt_token(&claims, state.surreal()).await
+}
+
 pub fn make_tide_authware() -> JwtAuthenticationDecoder<Claims_> {
     JwtAuthenticationDecoder::new(Validation::new(Algorithm::HS256), JwtKind::Access.key_dec())
 }
@@ -0,0 +1,339 @@
+//! Backups: a SurrealDB export and a manifest of the storage directory, in one tarball under
+//! `NETHERITE_CHAT_BACKUP_DIR`, per [tenant](crate::tenant). Uploaded files themselves are left to the file system's own
+//! backups, the manifest tells what a restore is missing.
+//!
+//! Restores are meant for an empty database, the export overwrites records but never removes
+//! any.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use async_std::task;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surf::http::Method;
+use surrealdb::sql::{Datetime, Thing};
+use tide::{
+    log::{info, warn},
+    StatusCode,
+};
+
+use crate::{
+    config::{data_path, CONFIG},
+    model::{erasure::Erasure, user::User},
+    tenant::Tenancy,
+    util::{query::Q, referrable, RecordId, Ref, Referrable},
+};
+
+pub const JOB: &str = "backup";
+
+const DATABASE: &str = "database.surql";
+const MANIFEST: &str = "storage-manifest.json";
+/// Regenerated on demand, not worth backing up, and the other tenants' files.
+const SKIPPED: &[&str] = &["storage/media-cache", "storage/tenants"];
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Backup {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    /// Path of the tarball, relative to `NETHERITE_CHAT_DATA_DIR`.
+    pub file: String,
+    pub size: u64,
+    pub database_size: u64,
+    /// Files listed in the storage manifest.
+    pub files: u64,
+    pub created_at: Datetime,
+}
+
+referrable!(Backup = "backup" .id: Option<Thing>);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha1: String,
+}
+
+/// What a restore did, or would do on a dry run.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub database_size: u64,
+    /// Files the backup knew about that aren't in storage anymore.
+    pub missing_files: Vec<String>,
+    /// Files that are there but differ from when the backup was taken.
+    pub changed_files: Vec<String>,
+    /// Users erased since the backup, erased again after restoring.
+    pub erasures_replayed: u64,
+}
+
+/// The HTTP side of the SurrealDB server, which is where exports and imports happen.
+fn surreal_http(method: Method, path: &str, namespace: &str) -> tide::Result<surf::RequestBuilder> {
+    let base = std::env::var("NETHERITE_CHAT_SURREALDB_URL")?;
+    let base = base
+        .trim_start_matches("ws://")
+        .trim_start_matches("wss://")
+        .trim_end_matches("/rpc");
+    // the same credentials the server signs in with
+    let auth = base64::engine::general_purpose::STANDARD.encode("root:root");
+    let url = match CONFIG.surrealdb_http {
+        Some(ref url) => format!("{url}{path}"),
+        None => format!("http://{base}{path}"),
+    };
+    Ok(surf::RequestBuilder::new(method, url.parse()?)
+        .header("Authorization", format!("Basic {auth}"))
+        .header("NS", namespace)
+        .header("DB", "chat"))
+}
+
+fn sha1_file(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hash = Sha1::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        hash.update(&chunk[..read]);
+    }
+    Ok(format!("{:x}", hash.finalize()))
+}
+
+/// Lists files under `dir` by their path relative to `root`, which is how uploads refer to them.
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<ManifestEntry>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        if SKIPPED.contains(&name.as_str()) {
+            continue;
+        }
+        if path.is_dir() {
+            walk(root, &path, entries)?;
+        } else {
+            entries.push(ManifestEntry {
+                size: path.metadata()?.len(),
+                sha1: sha1_file(&path)?,
+                path: name,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn append(tar: &mut tar::Builder<GzEncoder<File>>, name: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)
+}
+
+/// Takes a backup and drops the ones past `NETHERITE_CHAT_BACKUP_KEEP`.
+pub async fn run(surreal: &crate::Surreal, tenancy: Tenancy) -> anyhow::Result<Backup> {
+    let mut response = surreal_http(Method::Get, "/export", tenancy.namespace)
+        .map_err(|e| e.into_inner())?
+        .header("Accept", "application/octet-stream")
+        .await
+        .map_err(|e| e.into_inner())?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "SurrealDB refused the export ({})",
+            response.status()
+        ));
+    }
+    let database = response.body_bytes().await.map_err(|e| e.into_inner())?;
+
+    let created_at = Utc::now();
+    let file = format!(
+        "{}/netherite-{}.tar.gz",
+        tenancy.backup_dir,
+        created_at.format("%Y%m%d-%H%M%S")
+    );
+    let (size, files) = task::spawn_blocking({
+        let file = file.clone();
+        let database = database.clone();
+        move || -> anyhow::Result<(u64, u64)> {
+            let mut manifest = vec![];
+            let root = Path::new(&CONFIG.data_dir);
+            walk(root, &root.join(tenancy.storage_root), &mut manifest)?;
+
+            std::fs::create_dir_all(data_path(tenancy.backup_dir))?;
+            let path = data_path(&file);
+            let mut tar =
+                tar::Builder::new(GzEncoder::new(File::create(&path)?, Compression::default()));
+            append(&mut tar, DATABASE, &database)?;
+            append(&mut tar, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+            tar.into_inner()?.finish()?;
+            Ok((std::fs::metadata(&path)?.len(), manifest.len() as u64))
+        }
+    })
+    .await?;
+
+    let backup: Backup = surreal
+        .create(Backup::TABLE)
+        .content(Backup {
+            id: None,
+            file,
+            size,
+            database_size: database.len() as u64,
+            files,
+            created_at: Datetime(created_at),
+        })
+        .await?;
+    info!("backed up to {}", backup.file);
+
+    let old = Q::select::<Backup>()
+        .order_desc("created_at")
+        .start(CONFIG.backup_keep as i64)
+        .all(surreal)
+        .await?;
+    for old in old {
+        if let Err(e) = async_std::fs::remove_file(data_path(&old.file)).await {
+            warn!("couldn't remove old backup {}: {e}", old.file);
+        }
+        let _: Option<Backup> = surreal.delete(old.id.unwrap()).await?;
+    }
+    Ok(backup)
+}
+
+/// Newest first.
+pub async fn all(surreal: &crate::Surreal) -> surrealdb::Result<Vec<Backup>> {
+    Q::select::<Backup>()
+        .order_desc("created_at")
+        .all(surreal)
+        .await
+}
+
+/// Reads back what [run] wrote.
+fn unpack(file: &str) -> anyhow::Result<(String, Vec<ManifestEntry>)> {
+    let mut database = None;
+    let mut manifest = None;
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(data_path(file))?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mut contents = String::new();
+        let path = entry.path()?.to_string_lossy().into_owned();
+        match path.as_str() {
+            DATABASE => {
+                entry.read_to_string(&mut contents)?;
+                database = Some(contents);
+            }
+            MANIFEST => {
+                entry.read_to_string(&mut contents)?;
+                manifest = Some(serde_json::from_str(&contents)?);
+            }
+            _ => {}
+        }
+    }
+    Ok((
+        database.ok_or_else(|| anyhow!("the backup has no {DATABASE}"))?,
+        manifest.ok_or_else(|| anyhow!("the backup has no {MANIFEST}"))?,
+    ))
+}
+
+/// Users erased after `since`, from the erasure manifest.
+async fn erased_since(since: DateTime<Utc>) -> anyhow::Result<Vec<Ref<User>>> {
+    #[derive(Deserialize)]
+    struct Erased {
+        erased: String,
+        at: DateTime<Utc>,
+    }
+
+    let Some(ref path) = CONFIG.backup_manifest else {
+        return Ok(vec![]);
+    };
+    let lines = match async_std::fs::read_to_string(data_path(path)).await {
+        Ok(lines) => lines,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(lines
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Erased>(line).ok())
+        .filter(|erased| erased.at > since)
+        .filter_map(|erased| erased.erased.parse::<RecordId>().ok())
+        .filter(|user| user.0.tb == User::TABLE)
+        .map(|user| Ref::new_owned(user.id()))
+        .collect())
+}
+
+/// Checks the backup can be restored: the tarball reads, the export parses, and which
+/// uploaded files are gone or changed since. Without `dry_run` it then imports the export
+/// and erases again whoever was erased after the backup was taken.
+pub async fn restore(
+    surreal: &crate::Surreal,
+    tenancy: Tenancy,
+    admin: &Ref<User>,
+    backup: &Backup,
+    dry_run: bool,
+) -> tide::Result<RestoreReport> {
+    let file = backup.file.clone();
+    let (database, manifest) = task::spawn_blocking(move || unpack(&file))
+        .await
+        .map_err(|e| tide::Error::new(StatusCode::UnprocessableEntity, e))?;
+    surrealdb::sql::parse(&database).map_err(|e| {
+        tide::Error::new(
+            StatusCode::UnprocessableEntity,
+            anyhow!("the database export doesn't parse: {e}"),
+        )
+    })?;
+
+    let (missing_files, changed_files) = task::spawn_blocking(move || {
+        let mut missing = vec![];
+        let mut changed = vec![];
+        for entry in manifest {
+            let path = data_path(&entry.path);
+            let path = Path::new(&path);
+            if !path.exists() {
+                missing.push(entry.path);
+            } else if sha1_file(path).ok().as_ref() != Some(&entry.sha1) {
+                changed.push(entry.path);
+            }
+        }
+        (missing, changed)
+    })
+    .await;
+
+    let mut report = RestoreReport {
+        dry_run,
+        database_size: database.len() as u64,
+        missing_files,
+        changed_files,
+        erasures_replayed: 0,
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    let response = surreal_http(Method::Post, "/import", tenancy.namespace)?
+        .header("Accept", "application/json")
+        .body_string(database)
+        .await?;
+    if !response.status().is_success() {
+        return Err(tide::Error::new(
+            StatusCode::BadGateway,
+            anyhow!("SurrealDB refused the import ({})", response.status()),
+        ));
+    }
+    info!("restored the backup {}", backup.file);
+
+    for user in erased_since(backup.created_at.0).await? {
+        Erasure::request(surreal, admin, &user, false).await?;
+        report.erasures_replayed += 1;
+    }
+    Ok(report)
+}
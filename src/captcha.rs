@@ -0,0 +1,181 @@
+//! Bot checks for `/auth/register` and `/auth/login`. Deployments pick one with
+//! `NETHERITE_CHAT_CAPTCHA`: a hosted captcha, or a proof-of-work puzzle for self-hosters
+//! who'd rather not send their users to a third party.
+//!
+//! Clients ask `GET /auth/challenge` what to solve and send the solution in the
+//! `X-Captcha` header.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::Datetime;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::{config::CONFIG, http::HttpState, model::login::Device, tenant, util::random_string};
+
+pub const HEADER: &str = "x-captcha";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Captcha {
+    None,
+    HCaptcha,
+    Turnstile,
+    /// Find a nonce so that `sha1("<challenge>:<nonce>")` starts with `difficulty` zero bits.
+    Pow,
+}
+
+impl FromStr for Captcha {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "hcaptcha" => Ok(Self::HCaptcha),
+            "turnstile" => Ok(Self::Turnstile),
+            "pow" => Ok(Self::Pow),
+            _ => Err(anyhow!("unknown captcha {s}")),
+        }
+    }
+}
+
+impl Captcha {
+    fn verify_url(&self) -> Option<&'static str> {
+        match self {
+            Self::HCaptcha => Some("https://api.hcaptcha.com/siteverify"),
+            Self::Turnstile => Some("https://challenges.cloudflare.com/turnstile/v0/siteverify"),
+            Self::None | Self::Pow => None,
+        }
+    }
+}
+
+/// What `/auth/challenge` tells the client to solve.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Challenge {
+    kind: Captcha,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    difficulty: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PowChallenge {
+    issued_at: Datetime,
+}
+
+pub async fn http_challenge(request: Request<HttpState>) -> tide::Result {
+    let mut challenge = Challenge {
+        kind: CONFIG.captcha,
+        site_key: None,
+        challenge: None,
+        difficulty: None,
+    };
+    match CONFIG.captcha {
+        Captcha::None => {}
+        Captcha::HCaptcha | Captcha::Turnstile => {
+            challenge.site_key = CONFIG.captcha_site_key.clone();
+        }
+        Captcha::Pow => {
+            let id = random_string(24);
+            let _: PowChallenge = tenant::of(&request)?
+                .surreal()
+                .create(("pow_challenge", id.as_str()))
+                .content(PowChallenge {
+                    issued_at: Datetime(Utc::now()),
+                })
+                .await?;
+            challenge.challenge = Some(id);
+            challenge.difficulty = Some(CONFIG.pow_difficulty);
+        }
+    }
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&challenge)?)
+        .into())
+}
+
+/// Refuses the request unless it carries a valid solution for the configured captcha.
+pub async fn check(request: &Request<HttpState>) -> tide::Result<()> {
+    if CONFIG.captcha == Captcha::None {
+        return Ok(());
+    }
+    let solution = request
+        .header(HEADER)
+        .map(|solution| solution.as_str().to_owned())
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("captcha required")))?;
+
+    let solved = match CONFIG.captcha.verify_url() {
+        Some(url) => verify_hosted(url, &solution, Device::of(request).ip).await?,
+        None => verify_pow(tenant::of(request)?.surreal(), &solution).await?,
+    };
+    if !solved {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("captcha failed"),
+        ));
+    }
+    Ok(())
+}
+
+/// hCaptcha and Turnstile share the same verification API.
+async fn verify_hosted(url: &str, response: &str, ip: Option<String>) -> tide::Result<bool> {
+    #[derive(Serialize)]
+    struct Verify<'a> {
+        secret: &'a str,
+        response: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        remoteip: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Verified {
+        success: bool,
+    }
+
+    let secret = CONFIG
+        .captcha_secret
+        .as_deref()
+        .ok_or_else(|| anyhow!("NETHERITE_CHAT_CAPTCHA_SECRET is not set"))?;
+    let verified: Verified = surf::post(url)
+        .body_form(&Verify {
+            secret,
+            response,
+            remoteip: ip,
+        })?
+        .recv_json()
+        .await?;
+    Ok(verified.success)
+}
+
+/// Solutions are `<challenge>:<nonce>`. Each challenge can be used once.
+async fn verify_pow(surreal: &crate::Surreal, solution: &str) -> tide::Result<bool> {
+    let Some((challenge, _)) = solution.split_once(':') else {
+        return Ok(false);
+    };
+    if leading_zero_bits(&Sha1::digest(solution.as_bytes())) < CONFIG.pow_difficulty {
+        return Ok(false);
+    }
+    // deleting it is what makes it single use, expired ones are left to the cleanup job
+    let issued: Option<PowChallenge> = surreal
+        .query("DELETE type::thing('pow_challenge', $challenge) WHERE issued_at > time::now() - 10m RETURN BEFORE")
+        .bind(("challenge", challenge))
+        .await?
+        .take(0)?;
+    Ok(issued.is_some())
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
@@ -0,0 +1,471 @@
+use std::{env, path::Path, str::FromStr};
+
+use async_graphql::SimpleObject;
+use serde::Serialize;
+
+use crate::{
+    captcha::Captcha,
+    gif::{GifProvider, GifRating, GIFS},
+    model::{
+        instance::{InstanceSettings, RegistrationMode},
+        terms::Terms,
+    },
+    phone::{PhoneVerification, VERIFIER},
+    translate::{Translation, TRANSLATOR},
+    util::random_string,
+};
+
+/// Instance-wide settings, read once from `NETHERITE_CHAT_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The public domain of this instance, used for federated addresses (`tag@domain`).
+    pub domain: String,
+    pub federation: bool,
+    /// Path to the Ed25519 private key (PEM) used to sign outgoing deliveries.
+    pub federation_key: Option<String>,
+    /// Path to the matching public key (PEM), served to other instances.
+    pub federation_pubkey: Option<String>,
+    /// Domains of the instances deliveries are sent to and accepted from. Nobody else's keys
+    /// are fetched, so a delivery can't make the server request arbitrary hosts.
+    pub federation_peers: Vec<String>,
+    /// Disables introspection and GraphiQL, and hides internal error details from clients.
+    pub production: bool,
+    /// Uploads live under `storage/` in here, and the other relative paths below are
+    /// relative to it.
+    pub data_dir: String,
+    /// Requests to `/graphql` with a larger body are refused outright.
+    pub max_body_size: u64,
+    pub avatar_max_size: u64,
+    pub attachment_max_size: u64,
+    /// MIME types (as sniffed from the content, not as claimed) accepted for avatars.
+    pub avatar_types: Vec<String>,
+    /// Same for attachments, `*` allows anything.
+    pub attachment_types: Vec<String>,
+    /// `host:port` of a clamd to scan uploads with, uploads aren't scanned without one.
+    pub clamav_addr: Option<String>,
+    /// Path to an `ffmpeg` to time voice messages and draw their waveforms with, they go
+    /// without either when unset.
+    pub ffmpeg: Option<String>,
+    /// Signs media proxy URLs. A random one is used if unset, which breaks links on restart.
+    pub media_proxy_key: String,
+    /// External images larger than this aren't proxied.
+    pub media_proxy_max_size: u64,
+    /// How many background jobs run at once.
+    pub job_workers: usize,
+    /// Path to a `sendmail` to send mail with, mail is only logged without one.
+    pub sendmail: Option<String>,
+    pub mail_from: String,
+    /// Addresses or CIDR ranges of the reverse proxies in front of the server. Only requests
+    /// coming from them are believed about the client's IP, country and ASN, see
+    /// [client_ip](crate::model::login::client_ip).
+    pub trusted_proxies: Vec<String>,
+    /// A header a reverse proxy puts the client's country in, like `CF-IPCountry`.
+    pub geo_header: Option<String>,
+    /// What `/auth/register` and `/auth/login` make clients solve first.
+    pub captcha: Captcha,
+    pub captcha_site_key: Option<String>,
+    pub captcha_secret: Option<String>,
+    /// Leading zero bits a proof-of-work solution needs, every one doubles the work.
+    pub pow_difficulty: u32,
+    /// Who translates messages for `Message.translated`, nobody by default.
+    pub translation: Translation,
+    /// The LibreTranslate instance, or a DeepL API other than the one the key goes with.
+    pub translation_url: Option<String>,
+    pub translation_key: Option<String>,
+    /// Where `searchGifs` searches, nowhere by default.
+    pub gif_provider: GifProvider,
+    pub gif_api_key: Option<String>,
+    /// The most GIFs found may show.
+    pub gif_rating: GifRating,
+    /// Who texts codes for `startPhoneVerification`, nobody by default.
+    pub phone_verification: PhoneVerification,
+    pub twilio_account_sid: Option<String>,
+    pub twilio_auth_token: Option<String>,
+    /// The SID of the Twilio Verify service codes are sent through.
+    pub twilio_verify_service: Option<String>,
+    /// Verified numbers are kept as an HMAC with this. Changing it forgets who verified
+    /// which number, so it has no random default.
+    pub phone_hash_key: Option<String>,
+    /// How old accounts have to be to join guilds with
+    /// [JoinConstraint::JustRegistered](crate::model::guild::JoinConstraint::JustRegistered).
+    pub just_registered_minutes: i64,
+    /// Names nobody may take, more can be added at runtime through the admin API.
+    pub reserved_names: Vec<String>,
+    /// Words names may not contain, same.
+    pub profanity: Vec<String>,
+    /// In grapheme clusters, what users see as characters, so every script gets the same room.
+    pub max_message_length: usize,
+    /// Who may register until an admin changes it at runtime.
+    pub registration: RegistrationMode,
+    /// How long messages removed by the retention policy are kept as tombstones.
+    pub retention_grace_days: u32,
+    /// Signs erasure reports. A random one is used if unset, which makes older reports
+    /// unverifiable after a restart.
+    pub erasure_key: String,
+    /// A file erasures are appended to, one JSON object per line, so restoring a backup can
+    /// erase those users again.
+    pub backup_manifest: Option<String>,
+    /// Where backups are written, see [crate::backup].
+    pub backup_dir: String,
+    /// When backups are taken, as a cron expression with seconds.
+    pub backup_schedule: String,
+    /// How many backups are kept before the oldest are removed.
+    pub backup_keep: usize,
+    /// The HTTP address of SurrealDB, for exports and imports. Guessed from
+    /// `NETHERITE_CHAT_SURREALDB_URL` if unset.
+    pub surrealdb_http: Option<String>,
+    /// Bearer token `/metrics` wants. The endpoint is off while unset.
+    pub metrics_token: Option<String>,
+    /// Path to the output of the `allowlist` command. In production only those documents are
+    /// executed.
+    pub allowlist: Option<String>,
+    /// Open websockets one account may have, the oldest are closed past it. 0 for no cap.
+    pub ws_max_per_user: usize,
+    /// Same, per IP.
+    pub ws_max_per_ip: usize,
+    /// The GitHub OAuth app linked GitHub accounts are verified with. Without it they stay
+    /// unverified.
+    pub github_client_id: Option<String>,
+    pub github_client_secret: Option<String>,
+    /// Spam scores past which senders are slowed down, have to solve a captcha, or are
+    /// flagged for review, see [crate::model::spam]. 0 turns one off.
+    pub spam_throttle_score: u32,
+    pub spam_captcha_score: u32,
+    pub spam_flag_score: u32,
+    /// How long slowed down senders wait on every message.
+    pub spam_throttle_seconds: u64,
+    /// Identical messages within a minute that count as a burst.
+    pub spam_duplicate_burst: usize,
+    /// Accounts younger than this posting only links look like spam.
+    pub spam_new_account_hours: i64,
+    /// Distinct users messaged directly within ten minutes that count as mass DMs.
+    pub spam_mass_direct: usize,
+    /// A header a reverse proxy puts the client's ASN in, see [crate::security].
+    pub asn_header: Option<String>,
+    /// Registrations allowed per hour and failed logins per ten minutes, from one IP, subnet
+    /// or ASN. 0 for no limit.
+    pub throttle_register_per_ip: usize,
+    pub throttle_register_per_subnet: usize,
+    pub throttle_register_per_asn: usize,
+    pub throttle_login_per_ip: usize,
+    pub throttle_login_per_subnet: usize,
+    pub throttle_login_per_asn: usize,
+    /// How long the first block lasts, doubling every time after.
+    pub throttle_backoff_seconds: u64,
+    /// `name=host` pairs of the tenants besides the default one, see [crate::tenant].
+    pub tenants: Vec<String>,
+    /// Whether ids sent without their table, like `abc` for `user:abc`, are still taken.
+    /// Clients older than the `RecordId` scalar send those, this goes once they're gone.
+    pub legacy_ids: bool,
+    /// How many guilds a user can be in, members a guild can have and channels it can have,
+    /// see [crate::model::limits]. 0 for no limit.
+    pub max_guilds_per_user: usize,
+    pub max_members_per_guild: usize,
+    pub max_channels_per_guild: usize,
+}
+
+fn var<T: FromStr>(name: &str, default: T) -> T {
+    var_opt(name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn list(name: &str, default: &[&str]) -> Vec<String> {
+    var_opt(name)
+        .map(|value| value.split(',').map(|s| s.trim().to_owned()).collect())
+        .unwrap_or_else(|| default.iter().map(|s| s.to_string()).collect())
+}
+
+fn var_opt(name: &str) -> Option<String> {
+    env::var(format!("NETHERITE_CHAT_{name}"))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Every setting with its default and what it does, as in `.env.example`. Printed by
+/// `--example-config`.
+pub const EXAMPLE: &str = include_str!("../.env.example");
+
+/// Where `path` is on disk, see [Config::data_dir]. Absolute paths are left alone.
+pub fn data_path(path: &str) -> String {
+    if Path::new(path).is_absolute() {
+        path.to_owned()
+    } else {
+        format!("{}/{path}", CONFIG.data_dir.trim_end_matches('/'))
+    }
+}
+
+/// What `--check-config` complains about: settings the server can't start without, files
+/// it can't read, and variables it doesn't know, which are usually typos.
+pub fn check() -> Vec<String> {
+    let mut problems = vec![];
+    for name in ["SURREALDB_URL", "HTTP_URL", "TIDY_ACCESS", "TIDY_REFRESH"] {
+        if var_opt(name).is_none() {
+            problems.push(format!("NETHERITE_CHAT_{name} is not set"));
+        }
+    }
+
+    if !Path::new(&CONFIG.data_dir).is_dir() {
+        problems.push(format!(
+            "NETHERITE_CHAT_DATA_DIR ({}) is not a directory",
+            CONFIG.data_dir
+        ));
+    }
+    let mut files = vec![];
+    if let Some(ref path) = CONFIG.allowlist {
+        files.push(("ALLOWLIST", path));
+    }
+    if CONFIG.federation {
+        match (&CONFIG.federation_key, &CONFIG.federation_pubkey) {
+            (Some(key), Some(pubkey)) => {
+                files.push(("FEDERATION_KEY", key));
+                files.push(("FEDERATION_PUBKEY", pubkey));
+            }
+            _ => problems.push(String::from(
+                "federation needs NETHERITE_CHAT_FEDERATION_KEY and NETHERITE_CHAT_FEDERATION_PUBKEY",
+            )),
+        }
+    }
+    for (name, path) in files {
+        if let Err(e) = std::fs::metadata(data_path(path)) {
+            problems.push(format!("NETHERITE_CHAT_{name} ({path}): {e}"));
+        }
+    }
+    if matches!(CONFIG.captcha, Captcha::HCaptcha | Captcha::Turnstile)
+        && (CONFIG.captcha_site_key.is_none() || CONFIG.captcha_secret.is_none())
+    {
+        problems.push(String::from(
+            "the captcha needs NETHERITE_CHAT_CAPTCHA_SITE_KEY and NETHERITE_CHAT_CAPTCHA_SECRET",
+        ));
+    }
+    match CONFIG.translation {
+        Translation::DeepL if CONFIG.translation_key.is_none() => problems.push(String::from(
+            "translation with DeepL needs NETHERITE_CHAT_TRANSLATION_KEY",
+        )),
+        Translation::LibreTranslate if CONFIG.translation_url.is_none() => problems.push(
+            String::from("translation with LibreTranslate needs NETHERITE_CHAT_TRANSLATION_URL"),
+        ),
+        _ => {}
+    }
+    if CONFIG.gif_provider != GifProvider::None && CONFIG.gif_api_key.is_none() {
+        problems.push(String::from(
+            "the GIF provider needs NETHERITE_CHAT_GIF_API_KEY",
+        ));
+    }
+    if CONFIG.phone_verification == PhoneVerification::Twilio
+        && (CONFIG.twilio_account_sid.is_none()
+            || CONFIG.twilio_auth_token.is_none()
+            || CONFIG.twilio_verify_service.is_none())
+    {
+        problems.push(String::from(
+            "phone verification with Twilio needs NETHERITE_CHAT_TWILIO_ACCOUNT_SID, \
+             NETHERITE_CHAT_TWILIO_AUTH_TOKEN and NETHERITE_CHAT_TWILIO_VERIFY_SERVICE",
+        ));
+    }
+    if CONFIG.phone_verification != PhoneVerification::None && CONFIG.phone_hash_key.is_none() {
+        problems.push(String::from(
+            "phone verification needs NETHERITE_CHAT_PHONE_HASH_KEY",
+        ));
+    }
+
+    let known: Vec<&str> = EXAMPLE
+        .lines()
+        .filter_map(|line| line.split_once('=').map(|(name, _)| name.trim()))
+        .filter(|name| !name.starts_with('#'))
+        .collect();
+    for (name, _) in env::vars() {
+        if name == "NETHERITE_CHAT_CD" {
+            problems.push(String::from(
+                "NETHERITE_CHAT_CD is deprecated, set NETHERITE_CHAT_DATA_DIR instead",
+            ));
+        } else if name.starts_with("NETHERITE_CHAT_") && !known.contains(&name.as_str()) {
+            problems.push(format!("{name} is not a known setting"));
+        }
+    }
+    problems
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            domain: var("DOMAIN", String::from("localhost")),
+            federation: var("FEDERATION", false),
+            federation_key: var_opt("FEDERATION_KEY"),
+            federation_pubkey: var_opt("FEDERATION_PUBKEY"),
+            federation_peers: list("FEDERATION_PEERS", &[])
+                .into_iter()
+                .map(|peer| peer.to_lowercase())
+                .collect(),
+            production: var("PRODUCTION", false),
+            data_dir: var_opt("DATA_DIR")
+                // what it used to be called, when the server changed into it on startup
+                .or_else(|| var_opt("CD"))
+                .unwrap_or_else(|| String::from(".")),
+            max_body_size: var("MAX_BODY_SIZE", 64 * 1024 * 1024),
+            avatar_max_size: var("AVATAR_MAX_SIZE", 8 * 1024 * 1024),
+            attachment_max_size: var("ATTACHMENT_MAX_SIZE", 50 * 1024 * 1024),
+            avatar_types: list(
+                "AVATAR_TYPES",
+                &["image/png", "image/jpeg", "image/gif", "image/webp"],
+            ),
+            attachment_types: list(
+                "ATTACHMENT_TYPES",
+                &[
+                    "image/png",
+                    "image/jpeg",
+                    "image/gif",
+                    "image/webp",
+                    "video/mp4",
+                    "video/webm",
+                    "audio/mpeg",
+                    "audio/ogg",
+                    "audio/wav",
+                    "application/pdf",
+                    "application/zip",
+                    "text/plain",
+                ],
+            ),
+            clamav_addr: var_opt("CLAMAV_ADDR"),
+            ffmpeg: var_opt("FFMPEG"),
+            media_proxy_key: var_opt("MEDIA_PROXY_KEY").unwrap_or_else(|| random_string(64)),
+            media_proxy_max_size: var("MEDIA_PROXY_MAX_SIZE", 10 * 1024 * 1024),
+            job_workers: var("JOB_WORKERS", 4),
+            sendmail: var_opt("SENDMAIL"),
+            mail_from: var_opt("MAIL_FROM").unwrap_or_else(|| {
+                format!(
+                    "netherite <noreply@{}>",
+                    var("DOMAIN", String::from("localhost"))
+                )
+            }),
+            trusted_proxies: list("TRUSTED_PROXIES", &[]),
+            geo_header: var_opt("GEO_HEADER"),
+            captcha: var("CAPTCHA", Captcha::None),
+            captcha_site_key: var_opt("CAPTCHA_SITE_KEY"),
+            captcha_secret: var_opt("CAPTCHA_SECRET"),
+            pow_difficulty: var("POW_DIFFICULTY", 18),
+            translation: var("TRANSLATION", Translation::None),
+            translation_url: var_opt("TRANSLATION_URL"),
+            translation_key: var_opt("TRANSLATION_KEY"),
+            gif_provider: var("GIF_PROVIDER", GifProvider::None),
+            gif_api_key: var_opt("GIF_API_KEY"),
+            gif_rating: var("GIF_RATING", GifRating::Pg),
+            phone_verification: var("PHONE_VERIFICATION", PhoneVerification::None),
+            twilio_account_sid: var_opt("TWILIO_ACCOUNT_SID"),
+            twilio_auth_token: var_opt("TWILIO_AUTH_TOKEN"),
+            twilio_verify_service: var_opt("TWILIO_VERIFY_SERVICE"),
+            phone_hash_key: var_opt("PHONE_HASH_KEY"),
+            just_registered_minutes: var("JUST_REGISTERED_MINUTES", 10),
+            reserved_names: list(
+                "RESERVED_NAMES",
+                &[
+                    "admin",
+                    "administrator",
+                    "system",
+                    "moderator",
+                    "support",
+                    "staff",
+                    "netherite",
+                    "everyone",
+                    "here",
+                ],
+            ),
+            profanity: list("PROFANITY", &[]),
+            max_message_length: var("MAX_MESSAGE_LENGTH", 4000),
+            registration: var("REGISTRATION", RegistrationMode::Open),
+            retention_grace_days: var("RETENTION_GRACE_DAYS", 30),
+            erasure_key: var_opt("ERASURE_KEY").unwrap_or_else(|| random_string(64)),
+            backup_manifest: var_opt("BACKUP_MANIFEST"),
+            backup_dir: var("BACKUP_DIR", String::from("backups")),
+            backup_schedule: var("BACKUP_SCHEDULE", String::from("0 0 2 * * *")),
+            backup_keep: var("BACKUP_KEEP", 7),
+            surrealdb_http: var_opt("SURREALDB_HTTP"),
+            metrics_token: var_opt("METRICS_TOKEN"),
+            allowlist: var_opt("ALLOWLIST"),
+            ws_max_per_user: var("WS_MAX_PER_USER", 10),
+            ws_max_per_ip: var("WS_MAX_PER_IP", 50),
+            github_client_id: var_opt("GITHUB_CLIENT_ID"),
+            github_client_secret: var_opt("GITHUB_CLIENT_SECRET"),
+            spam_throttle_score: var("SPAM_THROTTLE_SCORE", 30),
+            spam_captcha_score: var("SPAM_CAPTCHA_SCORE", 70),
+            spam_flag_score: var("SPAM_FLAG_SCORE", 50),
+            spam_throttle_seconds: var("SPAM_THROTTLE_SECONDS", 5),
+            spam_duplicate_burst: var("SPAM_DUPLICATE_BURST", 4),
+            spam_new_account_hours: var("SPAM_NEW_ACCOUNT_HOURS", 24),
+            spam_mass_direct: var("SPAM_MASS_DIRECT", 10),
+            asn_header: var_opt("ASN_HEADER"),
+            throttle_register_per_ip: var("THROTTLE_REGISTER_PER_IP", 5),
+            throttle_register_per_subnet: var("THROTTLE_REGISTER_PER_SUBNET", 20),
+            throttle_register_per_asn: var("THROTTLE_REGISTER_PER_ASN", 100),
+            throttle_login_per_ip: var("THROTTLE_LOGIN_PER_IP", 20),
+            throttle_login_per_subnet: var("THROTTLE_LOGIN_PER_SUBNET", 60),
+            throttle_login_per_asn: var("THROTTLE_LOGIN_PER_ASN", 300),
+            throttle_backoff_seconds: var("THROTTLE_BACKOFF_SECONDS", 60),
+            tenants: list("TENANTS", &[]),
+            legacy_ids: var("LEGACY_IDS", true),
+            max_guilds_per_user: var("MAX_GUILDS_PER_USER", 100),
+            max_members_per_guild: var("MAX_MEMBERS_PER_GUILD", 0),
+            max_channels_per_guild: var("MAX_CHANNELS_PER_GUILD", 500),
+        }
+    }
+}
+
+/// The limits clients need to know about, so they don't have to hard-code them. Served as
+/// the `serverConfiguration` query and at `/api/v1/config`.
+#[derive(Debug, Clone, Serialize, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerConfiguration {
+    pub domain: String,
+    pub max_message_length: usize,
+    pub avatar_max_size: u64,
+    pub attachment_max_size: u64,
+    pub avatar_types: Vec<String>,
+    /// `*` means anything goes.
+    pub attachment_types: Vec<String>,
+    pub registration: RegistrationMode,
+    /// Whether anyone can register without an invite code.
+    pub registration_open: bool,
+    /// What `acceptTerms` in `/auth/register` has to be, `null` while there are no terms.
+    pub terms_version: Option<u32>,
+    pub federation: bool,
+    /// Whether `Message.translated` works here.
+    pub translation: bool,
+    /// Whether `searchGifs` works here.
+    pub gifs: bool,
+    /// Whether `startPhoneVerification` works here.
+    pub phone_verification: bool,
+    /// Whether ids may still be sent without their table, like `abc` for `user:abc`.
+    pub legacy_ids: bool,
+    /// Going past these fails with `LIMIT_REACHED`. 0 for no limit.
+    pub max_guilds_per_user: usize,
+    pub max_members_per_guild: usize,
+    pub max_channels_per_guild: usize,
+}
+
+impl ServerConfiguration {
+    pub async fn current(surreal: &crate::Surreal) -> surrealdb::Result<Self> {
+        let registration = InstanceSettings::get(surreal).await?.registration;
+        Ok(Self {
+            domain: CONFIG.domain.clone(),
+            max_message_length: CONFIG.max_message_length,
+            avatar_max_size: CONFIG.avatar_max_size,
+            attachment_max_size: CONFIG.attachment_max_size,
+            avatar_types: CONFIG.avatar_types.clone(),
+            attachment_types: CONFIG.attachment_types.clone(),
+            registration,
+            registration_open: registration == RegistrationMode::Open,
+            terms_version: Terms::current(surreal).await?.map(|terms| terms.version),
+            federation: CONFIG.federation,
+            translation: TRANSLATOR.is_some(),
+            gifs: GIFS.is_some(),
+            phone_verification: VERIFIER.is_some(),
+            legacy_ids: CONFIG.legacy_ids,
+            max_guilds_per_user: CONFIG.max_guilds_per_user,
+            max_members_per_guild: CONFIG.max_members_per_guild,
+            max_channels_per_guild: CONFIG.max_channels_per_guild,
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref CONFIG: Config = Config::from_env();
+}
@@ -0,0 +1,134 @@
+//! Caps on open `/graphql-subscription` websockets, per user and per IP. Past a cap the
+//! oldest connection is closed with [TOO_MANY], so reconnecting clients don't lock
+//! themselves out with their own stale sockets.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use async_std::channel::{self, Receiver, Sender};
+
+use crate::{config::CONFIG, util::RecordId};
+
+/// Close code of evicted connections, a private use twin of 1008 (policy violation).
+pub const TOO_MANY: u16 = 4008;
+
+/// Why a connection is being closed.
+#[derive(Debug, Clone)]
+pub struct Eviction {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+struct Connection {
+    id: u64,
+    ip: Option<String>,
+    user: Option<RecordId>,
+    opened_at: Instant,
+    evict: Sender<Eviction>,
+}
+
+#[derive(Default)]
+pub struct Connections {
+    open: Mutex<Vec<Connection>>,
+    next: AtomicU64,
+}
+
+/// Unregisters the connection once dropped.
+pub struct Registration {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Registration {
+    /// Counts the connection against `user`'s cap too.
+    pub fn identify(&self, user: RecordId) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(connection) = open.iter_mut().find(|c| c.id == self.id) {
+            connection.user = Some(user.clone());
+        }
+        evict_oldest(
+            &mut open,
+            |c| c.user.as_ref() == Some(&user),
+            CONFIG.ws_max_per_user,
+            "too many connections for this account",
+        );
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections
+            .open
+            .lock()
+            .unwrap()
+            .retain(|c| c.id != self.id);
+    }
+}
+
+/// Closes the oldest connections matching `filter` until at most `max` are left. A `max`
+/// of 0 means no cap.
+fn evict_oldest(
+    open: &mut Vec<Connection>,
+    filter: impl Fn(&Connection) -> bool,
+    max: usize,
+    reason: &'static str,
+) {
+    if max == 0 {
+        return;
+    }
+    let mut matching: Vec<_> = open.iter().filter(|c| filter(c)).collect();
+    if matching.len() <= max {
+        return;
+    }
+    matching.sort_by_key(|c| c.opened_at);
+    let evicted: Vec<u64> = matching[..matching.len() - max]
+        .iter()
+        .map(|c| {
+            let _ = c.evict.try_send(Eviction {
+                code: TOO_MANY,
+                reason,
+            });
+            c.id
+        })
+        .collect();
+    open.retain(|c| !evicted.contains(&c.id));
+}
+
+impl Connections {
+    /// Registers a new connection from `ip`, closing the oldest from there past the cap. The
+    /// receiver gets an [Eviction] when the connection should close.
+    pub fn open(self: &Arc<Self>, ip: Option<String>) -> (Registration, Receiver<Eviction>) {
+        let (evict, evicted) = channel::bounded(1);
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut open = self.open.lock().unwrap();
+        open.push(Connection {
+            id,
+            ip: ip.clone(),
+            user: None,
+            opened_at: Instant::now(),
+            evict,
+        });
+        if ip.is_some() {
+            evict_oldest(
+                &mut open,
+                |c| c.ip == ip,
+                CONFIG.ws_max_per_ip,
+                "too many connections from this address",
+            );
+        }
+        let registration = Registration {
+            id,
+            connections: self.clone(),
+        };
+        (registration, evicted)
+    }
+
+    pub fn count(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,205 @@
+//! Emails summarizing the DMs and mentions users missed while away. Opt-in through
+//! [`NotificationSettings`](crate::model::user::NotificationSettings).
+
+use std::fmt::Write;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Datetime;
+use tide::log::warn;
+
+use crate::{
+    mail::{self, Email},
+    model::{
+        guild::TextableChannel,
+        message::{Message, MessageRecipient},
+        read::ReadMarker,
+        user::User,
+    },
+    util::{
+        query::{field, Q},
+        RecordId, ReferrableExt,
+    },
+};
+
+/// Users are gone through this many at a time.
+const PAGE: i64 = 100;
+/// Messages beyond this are only counted as "and more".
+const MAX_MESSAGES: i64 = 500;
+const PREVIEW_LENGTH: usize = 100;
+
+/// When the last digest went out to a user, `digest:<user id>`.
+#[derive(Deserialize, Serialize)]
+struct Digested {
+    at: Datetime,
+}
+
+/// Sends everyone who is due a digest theirs.
+pub async fn run(surreal: &crate::Surreal) -> anyhow::Result<()> {
+    let mut start = 0;
+    loop {
+        let users = Q::select::<User>()
+            .filter(field("notifications.digest").eq(true))
+            .filter(field("domain").is_none())
+            .filter(field("email").ne(""))
+            .order("id")
+            .start(start)
+            .limit(PAGE)
+            .all(surreal)
+            .await?;
+        for user in &users {
+            // one broken user shouldn't hold up everyone else's
+            if let Err(e) = digest(surreal, user).await {
+                warn!("couldn't put together a digest for {}: {e}", user.id);
+            }
+        }
+        if (users.len() as i64) < PAGE {
+            return Ok(());
+        }
+        start += PAGE;
+    }
+}
+
+/// DMs are read in the conversation with their author, mentions in their channel.
+fn conversation(message: &Message) -> RecordId {
+    match message.recipient {
+        MessageRecipient::User(_) => message.author.record_id(),
+        MessageRecipient::Channel(ref channel) => channel.record_id(),
+    }
+}
+
+/// Clients refresh their access token every few minutes while open, so the newest token is
+/// when the user was last around.
+async fn last_active(
+    surreal: &crate::Surreal,
+    user: &User,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    #[derive(Deserialize)]
+    struct Issued {
+        issued_at: Datetime,
+    }
+
+    let issued: Option<Issued> = surreal
+        .query("SELECT issued_at FROM jwt WHERE uid = $user ORDER BY issued_at DESC LIMIT 1")
+        .bind(("user", user.record_id()))
+        .await?
+        .take(0)?;
+    Ok(issued.map(|issued| issued.issued_at.0))
+}
+
+async fn digest(surreal: &crate::Surreal, user: &User) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let me = user.refer();
+    let away_for = Duration::hours(user.notifications.digest_after_hours.into());
+    let last_active = last_active(surreal, user).await?;
+    if last_active.is_some_and(|at| now - at < away_for) {
+        return Ok(());
+    }
+    let Some(email) = User::email(surreal, &me).await? else {
+        return Ok(());
+    };
+    let previous: Option<Digested> = surreal.select(("digest", me.id())).await?;
+    let since = [last_active, previous.map(|digested| digested.at.0)]
+        .into_iter()
+        .flatten()
+        .max();
+
+    let mut missed = Q::select::<Message>()
+        .filter(
+            field("recipient.id")
+                .eq(&me)
+                .or(field("mentions").contains(&me)),
+        )
+        .filter(field("author").ne(&me))
+        .order("created_at")
+        .limit(MAX_MESSAGES);
+    if let Some(since) = since {
+        missed = missed.filter(field("created_at").gt(Datetime(since)));
+    }
+    let read = ReadMarker::of_user(surreal, &me).await?;
+    let mut unread = vec![];
+    for message in missed.all(surreal).await? {
+        if read
+            .get(&conversation(&message))
+            .is_some_and(|until| message.created_at.0 <= *until)
+        {
+            continue;
+        }
+        // a mention stays in the message after the user left the guild or lost the channel
+        if !message
+            .readable_by(surreal, &me)
+            .await
+            .map_err(|e| e.into_inner())?
+        {
+            continue;
+        }
+        unread.push(message);
+    }
+    let missed = unread;
+    if missed.is_empty() {
+        return Ok(());
+    }
+
+    let body = summarize(surreal, user, &missed).await?;
+    mail::queue(
+        surreal,
+        Email {
+            to: email,
+            subject: format!("You have {} unread messages", missed.len()),
+            body,
+        },
+    )
+    .await?;
+    let _: Option<Digested> = surreal
+        .update(("digest", me.id()))
+        .content(Digested { at: Datetime(now) })
+        .await?;
+    Ok(())
+}
+
+async fn summarize(
+    surreal: &crate::Surreal,
+    user: &User,
+    missed: &[Message],
+) -> anyhow::Result<String> {
+    // grouped by who sent them for DMs and by channel for mentions, in order of the oldest
+    let mut groups: Vec<(RecordId, Vec<&Message>)> = vec![];
+    for message in missed {
+        let key = conversation(message);
+        match groups.iter_mut().find(|(from, _)| *from == key) {
+            Some((_, messages)) => messages.push(message),
+            None => groups.push((key, vec![message])),
+        }
+    }
+
+    let mut body = format!("Hi {},\n\nWhile you were away:\n\n", user.display_name);
+    for (from, messages) in groups {
+        let count = messages.len();
+        let plural = if count == 1 { "" } else { "s" };
+        let previews = match messages[0].recipient {
+            MessageRecipient::User(_) => {
+                let author: User = from.fetch(surreal).await?;
+                writeln!(
+                    body,
+                    "- {count} message{plural} from {}",
+                    author.display_name
+                )?;
+                true
+            }
+            MessageRecipient::Channel(_) => {
+                let channel: TextableChannel = from.fetch(surreal).await?;
+                writeln!(body, "- {count} mention{plural} in #{}", channel.name())?;
+                // NSFW content has no business in an inbox
+                !channel.nsfw()
+            }
+        };
+        if previews && user.notifications.digest_previews {
+            for message in messages.iter().take(3) {
+                let preview: String = message.content.chars().take(PREVIEW_LENGTH).collect();
+                writeln!(body, "    {preview}")?;
+            }
+        }
+    }
+    body += "\nYou can turn these emails off in your notification settings.\n";
+    Ok(body)
+}
@@ -0,0 +1,380 @@
+use std::fmt::Display;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use tide::{
+    http::mime::JSON,
+    log::{error, info},
+    Body, Request, Response, StatusCode,
+};
+
+use crate::{
+    config::{data_path, CONFIG},
+    http::HttpState,
+    model::{
+        message::{Message, MessageInit, MessageRecipientIn, MessageRecipientInKind},
+        spam,
+        user::{parse_tag, Tag, User},
+    },
+    tenant,
+    util::{fetch, random_string, ReferrableExt},
+};
+
+pub const WELL_KNOWN: &str = "/.well-known/netherite";
+pub const INBOX: &str = "/federation/inbox";
+
+/// What an instance publishes about itself at [WELL_KNOWN].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstanceInfo {
+    pub domain: String,
+    pub public_key: String,
+}
+
+/// The identity of this instance: its domain and the key it signs deliveries with.
+pub struct Instance {
+    pub domain: String,
+    key: EncodingKey,
+    public_key: String,
+}
+
+impl Instance {
+    fn from_config() -> Option<Self> {
+        if !CONFIG.federation {
+            return None;
+        }
+
+        let load = || -> anyhow::Result<Self> {
+            let private = std::fs::read(data_path(
+                CONFIG
+                    .federation_key
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("NETHERITE_CHAT_FEDERATION_KEY is not set"))?,
+            ))?;
+            let public_key = std::fs::read_to_string(data_path(
+                CONFIG
+                    .federation_pubkey
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("NETHERITE_CHAT_FEDERATION_PUBKEY is not set"))?,
+            ))?;
+            Ok(Self {
+                domain: CONFIG.domain.clone(),
+                key: EncodingKey::from_ed_pem(&private)?,
+                public_key,
+            })
+        };
+
+        load()
+            .inspect_err(|e| error!("federation is enabled but the instance key is unusable: {e}"))
+            .ok()
+    }
+
+    pub fn info(&self) -> InstanceInfo {
+        InstanceInfo {
+            domain: self.domain.clone(),
+            public_key: self.public_key.clone(),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref INSTANCE: Option<Instance> = Instance::from_config();
+}
+
+/// A user address, `name#abcd` for local users or `name#abcd@domain` for anyone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub tag: Tag,
+    pub domain: Option<String>,
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Option<Self> {
+        let (tag, domain) = match address.rsplit_once('@') {
+            Some((tag, domain)) => (tag, Some(domain.to_lowercase())),
+            None => (address, None),
+        };
+        Some(Self {
+            tag: parse_tag(tag)?,
+            domain,
+        })
+    }
+
+    pub fn of(user: &User) -> Self {
+        Self {
+            tag: user.tag.clone(),
+            domain: Some(user.domain.clone().unwrap_or_else(|| CONFIG.domain.clone())),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.domain
+            .as_ref()
+            .map_or(true, |domain| domain == &CONFIG.domain)
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, [x, y, z, w]) = &self.tag;
+        write!(f, "{name}#{x:x}{y:x}{z:x}{w:x}")?;
+        if let Some(ref domain) = self.domain {
+            write!(f, "@{domain}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A direct message travelling between instances.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub from: String,
+    pub to: String,
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DeliveryClaims {
+    iss: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+    /// Unique per delivery, so a captured one can't be delivered again, see [record_jti].
+    jti: String,
+    #[serde(flatten)]
+    delivery: Delivery,
+}
+
+/// Whether `domain` is one of the [peers](crate::config::Config::federation_peers).
+fn is_peer(domain: &str) -> bool {
+    CONFIG
+        .federation_peers
+        .iter()
+        .any(|peer| peer.eq_ignore_ascii_case(domain))
+}
+
+fn require_peer(domain: &str) -> tide::Result<()> {
+    if !is_peer(domain) {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("{domain} is not an instance this one federates with"),
+        ));
+    }
+    Ok(())
+}
+
+/// Signs `delivery` with the instance key and POSTs it to the recipient instance's inbox.
+pub async fn deliver(instance: &Instance, domain: &str, delivery: Delivery) -> tide::Result<()> {
+    require_peer(domain)?;
+    let now = Utc::now();
+    let claims = DeliveryClaims {
+        iss: instance.domain.clone(),
+        aud: domain.to_owned(),
+        iat: now.timestamp(),
+        exp: (now + Duration::minutes(5)).timestamp(),
+        jti: random_string(24),
+        delivery,
+    };
+    let token = jsonwebtoken::encode(&Header::new(Algorithm::EdDSA), &claims, &instance.key)?;
+
+    info!(
+        "delivering a message from {} to {}",
+        claims.delivery.from, claims.delivery.to
+    );
+    let response = surf::post(format!("https://{domain}{INBOX}"))
+        .body_string(token)
+        .await?;
+    if !response.status().is_success() {
+        return Err(tide::Error::new(
+            StatusCode::BadGateway,
+            anyhow!("{domain} refused the delivery ({})", response.status()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Instance info is tiny, anything bigger isn't one.
+const MAX_INFO_SIZE: u64 = 64 * 1024;
+
+/// The key `domain` signs with. Only peers are asked, as `domain` comes from a delivery
+/// nobody verified yet.
+async fn remote_key(domain: &str) -> tide::Result<DecodingKey> {
+    require_peer(domain)?;
+    let mut response = fetch::get_public(&format!("https://{domain}{WELL_KNOWN}")).await?;
+    let info: InstanceInfo =
+        serde_json::from_slice(&fetch::read_limited(&mut response, MAX_INFO_SIZE).await?)?;
+    if info.domain != domain {
+        return Err(tide::Error::new(
+            StatusCode::Unauthorized,
+            anyhow!("{domain} claims to be {}", info.domain),
+        ));
+    }
+    Ok(DecodingKey::from_ed_pem(info.public_key.as_bytes())?)
+}
+
+/// Remembers a delivery's `jti` until it expires, failing if it was already seen.
+async fn record_jti(surreal: &crate::Surreal, claims: &DeliveryClaims) -> tide::Result<()> {
+    // CREATE fails on an existing id, so two copies racing in can't both get through
+    let created = surreal
+        .query("CREATE type::thing('federation_jti', [$iss, $jti]) SET expires_at = $expires_at")
+        .bind(("iss", &claims.iss))
+        .bind(("jti", &claims.jti))
+        .bind((
+            "expires_at",
+            surrealdb::sql::Datetime(
+                NaiveDateTime::from_timestamp_opt(claims.exp, 0)
+                    .map_or_else(Utc::now, |at| DateTime::from_utc(at, Utc)),
+            ),
+        ))
+        .await
+        .and_then(|response| response.check());
+    if created.is_err() {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow!("delivery {} from {} was already received", claims.jti, claims.iss),
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies an inbound delivery: the issuing instance must be a peer, the signature must match
+/// the key it publishes, the delivery must not have been seen before, the sender must live on
+/// that instance and the recipient must live here.
+async fn verify(
+    surreal: &crate::Surreal,
+    instance: &Instance,
+    token: &str,
+) -> tide::Result<(Address, Address, Delivery)> {
+    let unverified = {
+        let mut val = Validation::new(Algorithm::EdDSA);
+        val.insecure_disable_signature_validation();
+        val.validate_exp = false;
+        val.set_audience(&[&instance.domain]);
+        decode::<DeliveryClaims>(token, &DecodingKey::from_secret(&[]), &val)
+            .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?
+            .claims
+    };
+
+    let key = remote_key(&unverified.iss).await?;
+    let mut val = Validation::new(Algorithm::EdDSA);
+    val.set_audience(&[&instance.domain]);
+    val.set_issuer(&[&unverified.iss]);
+    let claims = decode::<DeliveryClaims>(token, &key, &val)
+        .map_err(|e| tide::Error::new(StatusCode::Unauthorized, e))?
+        .claims;
+    record_jti(surreal, &claims).await?;
+
+    let from = Address::parse(&claims.delivery.from)
+        .filter(|from| from.domain.as_ref() == Some(&claims.iss))
+        .ok_or_else(|| {
+            tide::Error::new(
+                StatusCode::Unauthorized,
+                anyhow!("sender does not belong to {}", claims.iss),
+            )
+        })?;
+    let to = Address::parse(&claims.delivery.to)
+        .filter(Address::is_local)
+        .ok_or_else(|| tide::Error::new(StatusCode::NotFound, anyhow!("recipient is not local")))?;
+
+    Ok((from, to, claims.delivery))
+}
+
+pub async fn http_well_known(_: Request<HttpState>) -> tide::Result {
+    let Some(ref instance) = *INSTANCE else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&instance.info())?)
+        .content_type(JSON)
+        .build())
+}
+
+pub async fn http_inbox(mut request: Request<HttpState>) -> tide::Result {
+    let Some(ref instance) = *INSTANCE else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+    let token = request.body_string().await?;
+    let surreal = tenant::of(&request)?.surreal();
+    let (from, to, delivery) = verify(surreal, instance, &token).await?;
+
+    let Some(recipient) = User::find_tag(surreal, &to.tag).await? else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+    let author = User::remote(surreal, &from).await?;
+    info!("received a federated message from {from} to {to}");
+    let init = MessageInit {
+        recipient: MessageRecipientIn {
+            kind: MessageRecipientInKind::User,
+            id: recipient.record_id(),
+        },
+        content: delivery.content,
+        reference: None,
+        tts: false,
+        gif: None,
+    };
+    Message::check_length(&init.content)?;
+    // a remote sender can't solve our captchas, so a hold just refuses their deliveries
+    spam::check(surreal, &author, &init)
+        .await
+        .map_err(|e| tide::Error::from_str(StatusCode::TooManyRequests, e.message))?;
+    author
+        .send_message(surreal, &tenant::of(&request)?.relay, init)
+        .await?;
+
+    Ok(Response::new(StatusCode::Accepted))
+}
+
+/// Sends a direct message to `to`, delivering it to the remote instance when it is not local.
+/// The local copy is addressed to the remote user's shadow record. Runs the same length and
+/// [spam](spam::check) checks as local messages before anything reaches the other instance.
+pub async fn send_direct(
+    surreal: &crate::Surreal,
+    relay: &crate::pubsub::Relay,
+    user: &User,
+    to: &Address,
+    content: String,
+) -> async_graphql::Result<Message> {
+    let instance = if to.is_local() {
+        None
+    } else {
+        Some(INSTANCE.as_ref().ok_or_else(|| {
+            tide::Error::new(StatusCode::Forbidden, anyhow!("federation is disabled"))
+        })?)
+    };
+    let recipient = match instance {
+        None => User::find_tag(surreal, &to.tag)
+            .await?
+            .ok_or_else(|| tide::Error::new(StatusCode::NotFound, anyhow!("no such user {to}")))?,
+        Some(_) => User::remote(surreal, to).await?,
+    };
+    let init = MessageInit {
+        recipient: MessageRecipientIn {
+            kind: MessageRecipientInKind::User,
+            id: recipient.record_id(),
+        },
+        content,
+        reference: None,
+        tts: false,
+        gif: None,
+    };
+    Message::check_length(&init.content)?;
+    spam::check(surreal, user, &init).await?;
+
+    if let Some(instance) = instance {
+        deliver(
+            instance,
+            to.domain.as_deref().unwrap(),
+            Delivery {
+                from: Address::of(user).to_string(),
+                to: to.to_string(),
+                content: init.content.clone(),
+                sent_at: Utc::now(),
+            },
+        )
+        .await?;
+    }
+    Ok(user.send_message(surreal, relay, init).await?)
+}
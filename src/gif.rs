@@ -0,0 +1,382 @@
+//! GIF search for the picker, through the instance's account with a GIF provider so clients
+//! never see the API key, and the provider never sees clients. Deployments pick one with
+//! `NETHERITE_CHAT_GIF_PROVIDER`, there is none by default.
+//!
+//! Clients send the `id` of a result with a message, which is looked up again here and
+//! attached as an embed, so what ends up in the message is what the provider says it is.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tide::StatusCode;
+
+use crate::{config::CONFIG, model::message::Embed};
+
+/// Most results one search returns.
+pub const MAX_RESULTS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GifProvider {
+    None,
+    Giphy,
+    Tenor,
+}
+
+impl FromStr for GifProvider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "giphy" => Ok(Self::Giphy),
+            "tenor" => Ok(Self::Tenor),
+            _ => Err(anyhow!("unknown gif provider {s}")),
+        }
+    }
+}
+
+/// The most a GIF may show, in GIPHY's terms. Tenor's content filters map onto these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GifRating {
+    G,
+    Pg,
+    Pg13,
+    R,
+}
+
+impl FromStr for GifRating {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "g" => Ok(Self::G),
+            "pg" => Ok(Self::Pg),
+            "pg-13" => Ok(Self::Pg13),
+            "r" => Ok(Self::R),
+            _ => Err(anyhow!("unknown gif rating {s}")),
+        }
+    }
+}
+
+/// A search result, the same whichever provider it came from.
+#[derive(Debug, Clone)]
+pub struct Gif {
+    pub id: String,
+    pub title: String,
+    /// The GIF's page on the provider's site.
+    pub url: String,
+    pub media_url: String,
+    /// A smaller version, for the picker.
+    pub preview_url: String,
+    pub width: u32,
+    pub height: u32,
+    pub provider: &'static str,
+}
+
+impl Gif {
+    /// What's attached to messages sent with it.
+    pub fn embed(self) -> Embed {
+        Embed {
+            title: (!self.title.is_empty()).then_some(self.title),
+            url: Some(self.url),
+            image_url: Some(self.media_url),
+            footer: Some(format!("via {}", self.provider)),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+pub trait GifSearch: Send + Sync {
+    async fn search(&self, query: &str, limit: u32) -> tide::Result<Vec<Gif>>;
+    /// `None` if there is no such GIF, or not anymore.
+    async fn get(&self, id: &str) -> tide::Result<Option<Gif>>;
+}
+
+/// The GIPHY API.
+pub struct Giphy {
+    pub key: String,
+    pub rating: GifRating,
+}
+
+#[derive(Deserialize)]
+struct GiphyGif {
+    id: String,
+    #[serde(default)]
+    title: String,
+    url: String,
+    images: GiphyImages,
+}
+
+#[derive(Deserialize)]
+struct GiphyImages {
+    original: GiphyImage,
+    fixed_width_small: GiphyImage,
+}
+
+/// Sizes come as strings.
+#[derive(Deserialize)]
+struct GiphyImage {
+    url: String,
+    #[serde(default)]
+    width: String,
+    #[serde(default)]
+    height: String,
+}
+
+impl From<GiphyGif> for Gif {
+    fn from(gif: GiphyGif) -> Self {
+        Self {
+            id: gif.id,
+            title: gif.title,
+            url: gif.url,
+            width: gif.images.original.width.parse().unwrap_or(0),
+            height: gif.images.original.height.parse().unwrap_or(0),
+            media_url: gif.images.original.url,
+            preview_url: gif.images.fixed_width_small.url,
+            provider: "GIPHY",
+        }
+    }
+}
+
+impl Giphy {
+    const API: &'static str = "https://api.giphy.com/v1/gifs";
+
+    fn rating(&self) -> &'static str {
+        match self.rating {
+            GifRating::G => "g",
+            GifRating::Pg => "pg",
+            GifRating::Pg13 => "pg-13",
+            GifRating::R => "r",
+        }
+    }
+}
+
+#[async_trait]
+impl GifSearch for Giphy {
+    async fn search(&self, query: &str, limit: u32) -> tide::Result<Vec<Gif>> {
+        #[derive(Serialize)]
+        struct Search<'a> {
+            api_key: &'a str,
+            q: &'a str,
+            limit: u32,
+            rating: &'static str,
+        }
+        #[derive(Deserialize)]
+        struct Found {
+            data: Vec<GiphyGif>,
+        }
+
+        let mut response = surf::get(format!("{}/search", Self::API))
+            .query(&Search {
+                api_key: &self.key,
+                q: query,
+                limit,
+                rating: self.rating(),
+            })?
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("GIPHY answered {}", response.status()).into());
+        }
+        let found: Found = response.body_json().await?;
+        Ok(found.data.into_iter().map(Gif::from).collect())
+    }
+
+    async fn get(&self, id: &str) -> tide::Result<Option<Gif>> {
+        #[derive(Serialize)]
+        struct Get<'a> {
+            api_key: &'a str,
+        }
+        #[derive(Deserialize)]
+        struct Found {
+            data: GiphyGif,
+        }
+
+        // ids go in the path
+        if !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Ok(None);
+        }
+        let mut response = surf::get(format!("{}/{id}", Self::API))
+            .query(&Get { api_key: &self.key })?
+            .await?;
+        match response.status() {
+            StatusCode::NotFound => Ok(None),
+            status if status.is_success() => {
+                let found: Found = response.body_json().await?;
+                Ok(Some(found.data.into()))
+            }
+            status => Err(anyhow!("GIPHY answered {status}").into()),
+        }
+    }
+}
+
+/// The Tenor API, v2.
+pub struct Tenor {
+    pub key: String,
+    pub rating: GifRating,
+}
+
+#[derive(Deserialize)]
+struct TenorGif {
+    id: String,
+    #[serde(default)]
+    content_description: String,
+    itemurl: String,
+    media_formats: TenorFormats,
+}
+
+#[derive(Deserialize)]
+struct TenorFormats {
+    gif: TenorMedia,
+    tinygif: TenorMedia,
+}
+
+#[derive(Deserialize)]
+struct TenorMedia {
+    url: String,
+    #[serde(default)]
+    dims: Vec<u32>,
+}
+
+impl From<TenorGif> for Gif {
+    fn from(gif: TenorGif) -> Self {
+        Self {
+            id: gif.id,
+            title: gif.content_description,
+            url: gif.itemurl,
+            width: gif.media_formats.gif.dims.first().copied().unwrap_or(0),
+            height: gif.media_formats.gif.dims.get(1).copied().unwrap_or(0),
+            media_url: gif.media_formats.gif.url,
+            preview_url: gif.media_formats.tinygif.url,
+            provider: "Tenor",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TenorResults {
+    results: Vec<TenorGif>,
+}
+
+impl Tenor {
+    const API: &'static str = "https://tenor.googleapis.com/v2";
+
+    fn content_filter(&self) -> &'static str {
+        match self.rating {
+            GifRating::G => "high",
+            GifRating::Pg => "medium",
+            GifRating::Pg13 => "low",
+            GifRating::R => "off",
+        }
+    }
+
+    async fn results(&self, path: &str, query: &impl Serialize) -> tide::Result<Vec<Gif>> {
+        let mut response = surf::get(format!("{}/{path}", Self::API))
+            .query(query)?
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Tenor answered {}", response.status()).into());
+        }
+        let found: TenorResults = response.body_json().await?;
+        Ok(found.results.into_iter().map(Gif::from).collect())
+    }
+}
+
+#[async_trait]
+impl GifSearch for Tenor {
+    async fn search(&self, query: &str, limit: u32) -> tide::Result<Vec<Gif>> {
+        #[derive(Serialize)]
+        struct Search<'a> {
+            key: &'a str,
+            client_key: &'static str,
+            q: &'a str,
+            limit: u32,
+            contentfilter: &'static str,
+            media_filter: &'static str,
+        }
+
+        self.results(
+            "search",
+            &Search {
+                key: &self.key,
+                client_key: "netherite",
+                q: query,
+                limit,
+                contentfilter: self.content_filter(),
+                media_filter: "gif,tinygif",
+            },
+        )
+        .await
+    }
+
+    async fn get(&self, id: &str) -> tide::Result<Option<Gif>> {
+        #[derive(Serialize)]
+        struct Get<'a> {
+            key: &'a str,
+            client_key: &'static str,
+            ids: &'a str,
+            media_filter: &'static str,
+        }
+
+        // several ids are comma separated
+        if id.contains(',') {
+            return Ok(None);
+        }
+        let found = self
+            .results(
+                "posts",
+                &Get {
+                    key: &self.key,
+                    client_key: "netherite",
+                    ids: id,
+                    media_filter: "gif,tinygif",
+                },
+            )
+            .await?;
+        Ok(found.into_iter().next())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// `None` unless a provider is configured, with a key.
+    pub static ref GIFS: Option<Box<dyn GifSearch>> = match CONFIG.gif_provider {
+        GifProvider::None => None,
+        GifProvider::Giphy => CONFIG.gif_api_key.clone().map(|key| {
+            let rating = CONFIG.gif_rating;
+            Box::new(Giphy { key, rating }) as Box<dyn GifSearch>
+        }),
+        GifProvider::Tenor => CONFIG.gif_api_key.clone().map(|key| {
+            let rating = CONFIG.gif_rating;
+            Box::new(Tenor { key, rating }) as Box<dyn GifSearch>
+        }),
+    };
+}
+
+fn provider() -> tide::Result<&'static dyn GifSearch> {
+    GIFS.as_deref().ok_or_else(|| {
+        tide::Error::new(
+            StatusCode::NotImplemented,
+            anyhow!("GIF search isn't set up on this instance"),
+        )
+    })
+}
+
+/// At most `limit` GIFs for `query`, within the instance's rating.
+pub async fn search(query: &str, limit: u32) -> tide::Result<Vec<Gif>> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+    provider()?.search(query, limit.clamp(1, MAX_RESULTS)).await
+}
+
+/// The embed for the GIF `id` from [search].
+pub async fn embed(id: &str) -> tide::Result<Embed> {
+    let gif = provider()?
+        .get(id)
+        .await?
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("there is no GIF {id}")))?;
+    Ok(gif.embed())
+}
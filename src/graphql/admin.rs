@@ -0,0 +1,215 @@
+use async_graphql::*;
+
+use crate::{
+    backup::Backup,
+    model::{
+        admin_audit::{AdminAction, AdminActionKind},
+        erasure::{Erasure, ErasureReport, ErasureState},
+        instance::InstanceInvite,
+        name_rule::{NameRule, NameRuleKind},
+        spam::{SpamFlag, SpamSignal},
+        stats::{DailyStats, InstanceStats, StatsTotals},
+    },
+    util::{RecordId, ReferrableExt, Timestamp},
+};
+
+#[Object]
+impl AdminAction {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn admin(&self) -> RecordId {
+        self.admin.gql_id()
+    }
+    async fn kind(&self) -> AdminActionKind {
+        self.kind
+    }
+    async fn subject(&self) -> RecordId {
+        self.subject.clone()
+    }
+    async fn reason(&self) -> &str {
+        &self.reason
+    }
+    async fn at(&self) -> Timestamp {
+        Timestamp(self.at.0)
+    }
+}
+
+#[Object]
+impl NameRule {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn kind(&self) -> NameRuleKind {
+        self.kind
+    }
+    /// Normalized: lowercase letters and digits only.
+    async fn word(&self) -> &str {
+        &self.word
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl SpamFlag {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn user(&self) -> RecordId {
+        self.user.gql_id()
+    }
+    async fn score(&self) -> u32 {
+        self.score
+    }
+    async fn signals(&self) -> &[SpamSignal] {
+        &self.signals
+    }
+    /// The start of the message that got them flagged.
+    async fn excerpt(&self) -> &str {
+        &self.excerpt
+    }
+    async fn resolved(&self) -> bool {
+        self.resolved
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl InstanceInvite {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    /// What goes in the `invite` field of `/auth/register`.
+    async fn code(&self) -> &str {
+        &self.code
+    }
+    async fn created_by(&self) -> RecordId {
+        self.created_by.gql_id()
+    }
+    /// Unlimited if null.
+    async fn max_uses(&self) -> Option<u32> {
+        self.max_uses
+    }
+    async fn uses(&self) -> u32 {
+        self.uses
+    }
+    async fn expires_at(&self) -> Option<Timestamp> {
+        self.expires_at.as_ref().map(|at| Timestamp(at.0))
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl Erasure {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn user(&self) -> RecordId {
+        self.user.gql_id()
+    }
+    async fn requested_by(&self) -> RecordId {
+        self.requested_by.gql_id()
+    }
+    async fn anonymize_content(&self) -> bool {
+        self.anonymize_content
+    }
+    async fn state(&self) -> ErasureState {
+        self.state
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+    async fn report(&self) -> Option<&ErasureReport> {
+        self.report.as_ref()
+    }
+    /// The report as a JWT, signed with the instance's erasure key.
+    async fn signed_report(&self) -> Option<&str> {
+        self.signed_report.as_deref()
+    }
+    /// Why it failed, request it again to retry.
+    async fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+#[Object]
+impl Backup {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    /// Where the tarball is on the server.
+    async fn file(&self) -> &str {
+        &self.file
+    }
+    async fn size(&self) -> u64 {
+        self.size
+    }
+    async fn database_size(&self) -> u64 {
+        self.database_size
+    }
+    /// Uploaded files listed in the manifest, which aren't in the backup themselves.
+    async fn files(&self) -> u64 {
+        self.files
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl InstanceStats {
+    async fn totals(&self) -> Option<&StatsTotals> {
+        self.totals.as_ref()
+    }
+    /// Oldest first.
+    async fn days(&self) -> &[DailyStats] {
+        &self.days
+    }
+}
+
+#[Object]
+impl StatsTotals {
+    /// Local accounts, bots not included.
+    async fn users(&self) -> u64 {
+        self.users
+    }
+    async fn guilds(&self) -> u64 {
+        self.guilds
+    }
+    /// Not counting the ones retention removed.
+    async fn messages(&self) -> u64 {
+        self.messages
+    }
+    /// The size of all uploaded attachments.
+    async fn storage_bytes(&self) -> u64 {
+        self.storage_bytes
+    }
+    /// When they were counted.
+    async fn at(&self) -> Timestamp {
+        Timestamp(self.at.0)
+    }
+}
+
+#[Object]
+impl DailyStats {
+    /// `YYYY-MM-DD`, in UTC.
+    async fn day(&self) -> String {
+        self.day.to_string()
+    }
+    /// Users who logged in or sent a message that day.
+    async fn active_users(&self) -> u64 {
+        self.active_users
+    }
+    async fn registrations(&self) -> u64 {
+        self.registrations
+    }
+    async fn messages(&self) -> u64 {
+        self.messages
+    }
+}
@@ -0,0 +1,120 @@
+//! In production, only the GraphQL documents the official client ships with are executed.
+//! They're compiled into an allow-list at deploy time:
+//!
+//! ```sh
+//! netherite-chat-backend allowlist client/src/**/*.graphql > allowlist.json
+//! ```
+//!
+//! and `NETHERITE_CHAT_ALLOWLIST` points at the result. Documents are matched by the sha1 of
+//! their text, so the client has to send them exactly as they are in the files.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::anyhow;
+use async_graphql::{
+    async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery},
+    parser::{parse_query, types::ExecutableDocument},
+    ServerError, ServerResult, Variables,
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tide::log::{info, warn};
+
+use crate::config::{data_path, CONFIG};
+
+static ALLOWED: OnceLock<HashSet<String>> = OnceLock::new();
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    file: String,
+    operations: Vec<String>,
+}
+
+fn hash(document: &str) -> String {
+    format!("{:x}", Sha1::digest(document.trim().as_bytes()))
+}
+
+/// Reads `NETHERITE_CHAT_ALLOWLIST`, if set. Without it every document goes, production or
+/// not.
+pub fn load() -> anyhow::Result<()> {
+    let Some(ref path) = CONFIG.allowlist else {
+        if CONFIG.production {
+            warn!("no NETHERITE_CHAT_ALLOWLIST, any GraphQL document will be executed");
+        }
+        return Ok(());
+    };
+    let entries: BTreeMap<String, Entry> =
+        serde_json::from_str(&std::fs::read_to_string(data_path(path))?)?;
+    info!("{} documents on the allow-list", entries.len());
+    ALLOWED
+        .set(entries.into_keys().collect())
+        .map_err(|_| anyhow!("the allow-list was already loaded"))
+}
+
+/// Whether the [AllowList] extension has anything to enforce.
+pub fn enforced() -> bool {
+    CONFIG.production && ALLOWED.get().is_some()
+}
+
+/// The `allowlist` command: parses every document in `files` and prints the allow-list.
+/// Errors on the first one that doesn't parse, or has an anonymous operation.
+pub fn compile(files: &[String]) -> anyhow::Result<()> {
+    let mut entries = BTreeMap::new();
+    for file in files {
+        let document = std::fs::read_to_string(file)?;
+        let parsed = parse_query(&document).map_err(|e| anyhow!("{file}: {e}"))?;
+        let operations = parsed
+            .operations
+            .iter()
+            .map(|(name, _)| {
+                name.map(|name| name.to_string())
+                    .ok_or_else(|| anyhow!("{file}: operations have to be named"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        entries.insert(
+            hash(&document),
+            Entry {
+                file: file.clone(),
+                operations,
+            },
+        );
+    }
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
+}
+
+pub struct AllowList;
+
+impl ExtensionFactory for AllowList {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(AllowList)
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for AllowList {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let allowed = ALLOWED
+            .get()
+            .map_or(true, |allowed| allowed.contains(&hash(query)));
+        if !allowed {
+            let mut error = ServerError::new("this document isn't on the allow-list", None);
+            error
+                .extensions
+                .get_or_insert_with(Default::default)
+                .set("code", "DOCUMENT_NOT_ALLOWED");
+            return Err(error);
+        }
+        next.run(ctx, query, variables).await
+    }
+}
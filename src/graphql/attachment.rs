@@ -0,0 +1,41 @@
+use async_graphql::*;
+
+use crate::{
+    model::attachment::{Attachment, VoiceNote},
+    util::{RecordId, ReferrableExt},
+};
+
+#[Object]
+impl Attachment {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn filename(&self) -> &str {
+        &self.filename
+    }
+    async fn mime(&self) -> &str {
+        &self.mime
+    }
+    async fn size(&self) -> u64 {
+        self.size
+    }
+    /// Blocked and held attachments are quarantined and can't be downloaded.
+    async fn url(&self) -> Option<String> {
+        (!self.blocked && !self.held).then(|| self.url())
+    }
+    async fn blocked(&self) -> bool {
+        self.blocked
+    }
+    /// Waiting to be scanned, it gets a `url` once it's found clean.
+    async fn held(&self) -> bool {
+        self.held
+    }
+    /// A description for screen readers, `null` if the uploader didn't give one.
+    async fn alt(&self) -> Option<&str> {
+        self.alt.as_deref()
+    }
+    /// `null` unless it was sent as a voice message.
+    async fn voice_note(&self) -> Option<&VoiceNote> {
+        self.voice_note.as_ref()
+    }
+}
@@ -0,0 +1,29 @@
+use async_graphql::*;
+
+use crate::{
+    model::{
+        event::{Event, EventKind},
+        message::Message,
+    },
+    util::{Cx, RecordId, Timestamp},
+};
+
+#[Object]
+impl Event {
+    async fn kind(&self) -> EventKind {
+        self.kind
+    }
+    async fn subject(&self) -> RecordId {
+        self.subject.clone()
+    }
+    async fn at(&self) -> Timestamp {
+        Timestamp(self.at.0)
+    }
+    /// The created message, unless it has been deleted since.
+    async fn message(&self, cx: &Context<'_>) -> Result<Option<Message>> {
+        if self.kind != EventKind::MessageCreated {
+            return Ok(None);
+        }
+        Ok(cx.cx().surreal().select(self.subject.0.clone()).await?)
+    }
+}
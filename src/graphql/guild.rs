@@ -1,7 +1,19 @@
+use crate::model::audit::{AuditLogEntry, AuditLogEntryType, AuditLogKind, Ban, Kick, Timeout};
+use crate::model::automod::AutomodSettings;
+use crate::model::emoji::GuildEmoji;
+use crate::model::event::Changes;
+use crate::model::feed::Feed;
 use crate::model::guild::*;
+use crate::model::guild_application::{ApplicationAnswer, ApplicationStatus, GuildApplication};
+use crate::model::guild_invite::GuildInvite;
 use crate::model::message::{Conversation, MessageRecipient};
+use crate::model::retention;
+use crate::model::token::Scope;
+use crate::model::trust::{TrustLevel, TrustSettings};
 use crate::model::user::User;
-use crate::util::{unwrap_id_str, Cx, ReferrableExt, Ref, ReferrableWithId};
+use crate::model::webhook::Webhook;
+use crate::util::query::Wanted;
+use crate::util::{Cx, RecordId, ReferrableExt, Ref, ReferrableWithId, Timestamp};
 use async_graphql::*;
 use async_graphql::connection::{Connection, EmptyFields};
 use serde::Deserialize;
@@ -24,6 +36,16 @@ impl Member {
     async fn nickname(&self) -> Option<&str> {
         self.nickname.as_deref()
     }
+    async fn age_verified(&self) -> bool {
+        self.age_verified
+    }
+    /// Can't send messages in the guild until then.
+    async fn timed_out_until(&self) -> Option<Timestamp> {
+        self.timed_out_until
+            .as_ref()
+            .filter(|until| until.0 > chrono::Utc::now())
+            .map(|until| Timestamp(until.0))
+    }
     async fn roles(&self, cx: &Context<'_>) -> FieldResult<Vec<Role>> {
         #[derive(Deserialize)]
         struct Roles {
@@ -39,15 +61,36 @@ impl Member {
 
 #[Object]
 impl Guild {
-    async fn id(&self) -> ID {
-        self.gql_id_just()
+    pub async fn id(&self) -> RecordId {
+        self.gql_id()
     }
     async fn name(&self) -> &str {
         &self.name
     }
+    async fn icon_url(&self) -> Option<String> {
+        self.icon.as_ref().map(|path| format!("/{path}"))
+    }
+    /// Shown behind the join screen, see `invitePreview`.
+    async fn splash_url(&self) -> Option<String> {
+        self.splash.as_ref().map(|path| format!("/{path}"))
+    }
+    /// Days messages are kept, `null` to go by the instance's retention.
+    async fn retention_days(&self) -> Option<u32> {
+        self.retention_days
+    }
     async fn roles(&self, cx: &Context<'_>) -> Result<Vec<Role>> {
         Ok(self.fetch_roles(cx.cx().surreal()).await?)
     }
+    /// What the viewer may do here, with `Administrator` expanded to everything.
+    async fn my_permissions(&self, cx: &Context<'_>) -> Result<Vec<Permission>> {
+        let user = cx.cx().ref_user()?;
+        Ok(Member::effective_permissions(cx.cx().surreal(), &self.refer(), &user).await?)
+    }
+    /// Changes along with the settings, channels, roles or emoji. Pass it as `ifNoneMatch` to
+    /// `byId.guild` to skip refetching all that when nothing changed.
+    async fn state_hash(&self, cx: &Context<'_>) -> Result<String> {
+        Ok(self.hash_state(cx.cx().surreal()).await?)
+    }
     async fn members(
         &self,
         cx: &Context<'_>,
@@ -56,55 +99,272 @@ impl Guild {
         first: Option<i32>,
         last: Option<i32>,
     ) -> Result<Connection<i64, Member, EmptyFields, EmptyFields>> {
-        self.members_paginate(cx.cx().surreal(), after, before, first, last).await
+        let wanted = Wanted::in_connection::<Member>(cx);
+        self.members_paginate(cx.cx().surreal(), &wanted, after, before, first, last)
+            .await
     }
+    /// Only the ones you can see, none unless you're a member.
     async fn channels(&self, cx: &Context<'_>) -> Result<Vec<Channel>> {
-        let gid = unwrap_id_str(&self.id.id).unwrap();
-        let query = format!(
-            r#"
-            SELECT * FROM channel WHERE guild = guild:{gid}
-        "#
-        );
+        let user = cx.cx().ref_user()?;
+        Ok(self.channels_visible_to(cx.cx().surreal(), &user).await?)
+    }
 
-        Ok(cx.cx().surreal().query(query).await?.take(0)?)
+    async fn emojis(&self, cx: &Context<'_>) -> Result<Vec<GuildEmoji>> {
+        Ok(GuildEmoji::of_guild(cx.cx().surreal(), &self.refer()).await?)
     }
 
+    /// Fails with `INVALID_NAME` if the name is too short or long, has other characters than
+    /// letters, digits, spaces, `-` and `_`, or has the same `slug` as another channel here.
+    /// Fails with `LIMIT_REACHED` once the guild has as many channels as it can.
     async fn create_channel(&self, cx: &Context<'_>, init: ChannelInit) -> Result<Channel> {
-        let ChannelInit { name, kind } = init;
-        let gid = unwrap_id_str(&self.id.id).unwrap();
-        let query = format!(
-            r#"
-            CREATE channel CONTENT {{
-                guild: {gid},
-                name: $name,
-                kind: '{kind}'
-            }}
-        "#
-        );
-        Ok(Option::unwrap(
-            cx.cx()
-                .surreal()
-                .query(query)
-                .bind(("name", name.as_str()))
-                .await?
-                .take(0)?,
-        ))
+        cx.cx().require_scope(Scope::GuildsManage)?;
+        cx.services().guilds.create_channel(self, init).await
+    }
+
+    /// Confirms you're old enough to read this guild's NSFW channels.
+    async fn verify_age(&self, cx: &Context<'_>) -> Result<Member> {
+        let user = cx.cx().ref_user()?;
+        Ok(cx
+            .services()
+            .guilds
+            .verify_age(&self.refer(), &user)
+            .await?)
     }
 
     async fn join_constraint(&self) -> JoinConstraint {
         self.join_constraint
     }
+    /// What joining asks with the `APPLICATION` constraint.
+    async fn application_questions(&self) -> &[String] {
+        &self.application_questions
+    }
+    /// How new members are held back.
+    async fn trust(&self) -> &TrustSettings {
+        &self.trust
+    }
+    /// Limits on mentions.
+    async fn automod(&self) -> &AutomodSettings {
+        &self.automod
+    }
+    /// Your trust level here, always `MEMBER` unless the guild holds new members back.
+    async fn my_trust_level(&self, cx: &Context<'_>) -> Result<TrustLevel> {
+        let user = cx.cx().user().await?;
+        Ok(TrustLevel::of(cx.cx().surreal(), self, &user).await?)
+    }
+    /// Your last application to join, `null` if you never applied.
+    async fn my_application(&self, cx: &Context<'_>) -> Result<Option<GuildApplication>> {
+        let user = cx.cx().ref_user()?;
+        Ok(GuildApplication::latest(cx.cx().surreal(), &self.refer(), &user).await?)
+    }
+
+    /// Channels created and members joined since `cursor`. Only for members.
+    async fn changes_since(&self, cx: &Context<'_>, cursor: Option<String>) -> Result<Changes> {
+        cx.cx().require_scope(Scope::Guilds)?;
+        let user = cx.cx().ref_user()?;
+        Ok(cx
+            .services()
+            .guilds
+            .changes_since(&user, &self.refer(), cursor.as_deref())
+            .await?)
+    }
 }
 
 #[ComplexObject]
 impl TextChannel {
-    pub async fn identifier(&self) -> ID {
-        <Self as ReferrableExt>::gql_id_just(self)
+    pub async fn id(&self) -> RecordId {
+        <Self as ReferrableExt>::gql_id(self)
+    }
+    /// Same as `id`.
+    pub async fn identifier(&self) -> RecordId {
+        <Self as ReferrableExt>::gql_id(self)
     }
-    async fn guild(&self) -> ID {
+    async fn guild(&self) -> RecordId {
         self.guild.gql_id()
     }
+    /// Days messages are kept here, `null` to go by the guild's retention.
+    async fn retention_days(&self) -> Option<u32> {
+        self.retention_days
+    }
+    /// Days messages are kept here in effect: the shortest of the channel's, guild's and
+    /// instance's retention. `null` if they're kept forever.
+    async fn retention(&self, cx: &Context<'_>) -> Result<Option<u32>> {
+        Ok(retention::of_channel(cx.cx().surreal(), self).await?)
+    }
+    /// Roles that may see the channel while it's private.
+    async fn allowed_roles(&self) -> Vec<RecordId> {
+        self.allowed_roles
+            .iter()
+            .map(|role| role.gql_id())
+            .collect()
+    }
+    /// Members that may see the channel while it's private, whatever their roles.
+    async fn allowed_users(&self) -> Vec<RecordId> {
+        self.allowed_users
+            .iter()
+            .map(|user| user.gql_id())
+            .collect()
+    }
+    /// What the viewer may do in this channel, with `Administrator` expanded to everything.
+    async fn my_permissions(&self, cx: &Context<'_>) -> Result<Vec<Permission>> {
+        let user = cx.cx().ref_user()?;
+        Ok(self.effective_permissions(cx.cx().surreal(), &user).await?)
+    }
+    /// Needs `ManageWebhooks`.
+    async fn webhooks(&self, cx: &Context<'_>) -> Result<Vec<Webhook>> {
+        let user = cx.cx().ref_user()?;
+        Ok(cx.services().guilds.webhooks(&user, self).await?)
+    }
+    /// Needs `ManageWebhooks`.
+    async fn feeds(&self, cx: &Context<'_>) -> Result<Vec<Feed>> {
+        let user = cx.cx().ref_user()?;
+        Ok(cx.services().guilds.feeds(&user, self).await?)
+    }
     async fn talk(&self, cx: &Context<'_>) -> Result<Conversation> {
         Ok(Conversation(cx.cx().ref_user()?, MessageRecipient::Channel(Ref::new(<Self as ReferrableWithId>::id(self).as_ref()))))
     }
 }
+
+#[Object]
+impl GuildEmoji {
+    pub async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    async fn url(&self, context: &Context<'_>) -> String {
+        format!("/{}", self.path(context.storage().read().await.root()))
+    }
+    async fn animated(&self) -> bool {
+        self.animated
+    }
+}
+
+#[Object]
+impl AuditLogEntry {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn kind(&self) -> AuditLogKind {
+        self.entry_type.kind()
+    }
+    /// The moderator.
+    async fn by(&self) -> RecordId {
+        RecordId(self.by.clone())
+    }
+    async fn timestamp(&self) -> Timestamp {
+        Timestamp(self.timestamp.0)
+    }
+    /// Who was timed out, kicked or banned.
+    async fn user(&self) -> Option<RecordId> {
+        match &self.entry_type {
+            AuditLogEntryType::Timeout(Timeout { user, .. })
+            | AuditLogEntryType::Kick(Kick { user, .. })
+            | AuditLogEntryType::Ban(Ban { user, .. }) => Some(RecordId(user.clone())),
+            AuditLogEntryType::Transcript(_) | AuditLogEntryType::Prune(_) => None,
+        }
+    }
+    async fn reason(&self) -> Option<&str> {
+        match &self.entry_type {
+            AuditLogEntryType::Timeout(Timeout { reason, .. })
+            | AuditLogEntryType::Kick(Kick { reason, .. })
+            | AuditLogEntryType::Ban(Ban { reason, .. }) => Some(reason),
+            AuditLogEntryType::Transcript(_) | AuditLogEntryType::Prune(_) => None,
+        }
+    }
+    /// The channel a transcript is of.
+    async fn channel(&self) -> Option<RecordId> {
+        match &self.entry_type {
+            AuditLogEntryType::Transcript(transcript) => Some(RecordId(transcript.channel.clone())),
+            _ => None,
+        }
+    }
+    /// Where the transcript's HTML is.
+    async fn transcript_url(&self) -> Option<String> {
+        match &self.entry_type {
+            AuditLogEntryType::Transcript(transcript) => Some(format!("/{}", transcript.path)),
+            _ => None,
+        }
+    }
+    async fn message_count(&self) -> Option<u64> {
+        match &self.entry_type {
+            AuditLogEntryType::Transcript(transcript) => Some(transcript.message_count),
+            _ => None,
+        }
+    }
+    /// Who a prune removed.
+    async fn users(&self) -> Vec<RecordId> {
+        match &self.entry_type {
+            AuditLogEntryType::Prune(prune) => prune.users.iter().cloned().map(RecordId).collect(),
+            _ => vec![],
+        }
+    }
+    /// What a prune went by.
+    async fn inactive_days(&self) -> Option<u32> {
+        match &self.entry_type {
+            AuditLogEntryType::Prune(prune) => Some(prune.inactive_days),
+            _ => None,
+        }
+    }
+}
+
+#[Object]
+impl GuildInvite {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    /// What goes in `joinGuild` and `invitePreview`.
+    async fn code(&self) -> &str {
+        &self.code
+    }
+    async fn guild(&self) -> RecordId {
+        self.guild.gql_id()
+    }
+    async fn created_by(&self) -> RecordId {
+        self.created_by.gql_id()
+    }
+    /// Unlimited if null.
+    async fn max_uses(&self) -> Option<u32> {
+        self.max_uses
+    }
+    async fn uses(&self) -> u32 {
+        self.uses
+    }
+    async fn expires_at(&self) -> Option<Timestamp> {
+        self.expires_at.as_ref().map(|at| Timestamp(at.0))
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl GuildApplication {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn guild(&self) -> RecordId {
+        self.guild.gql_id()
+    }
+    async fn user(&self, cx: &Context<'_>) -> FieldResult<User> {
+        Ok(self.user.fetch(cx.cx().surreal()).await?)
+    }
+    async fn answers(&self) -> &[ApplicationAnswer] {
+        &self.answers
+    }
+    async fn status(&self) -> ApplicationStatus {
+        self.status
+    }
+    async fn reviewed_by(&self) -> Option<RecordId> {
+        self.reviewed_by.as_ref().map(|user| user.gql_id())
+    }
+    async fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+    async fn reviewed_at(&self) -> Option<Timestamp> {
+        self.reviewed_at.as_ref().map(|at| Timestamp(at.0))
+    }
+}
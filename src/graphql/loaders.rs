@@ -1,50 +1,114 @@
+use serde::de::DeserializeOwned;
+
 use crate::{
     model::{
-        guild::{Channel, Guild},
+        emoji::GuildEmoji,
+        guild::{Channel, Guild, TextChannel},
         message::Message,
         user::User,
     },
-    util::{Cx, Referrable},
+    util::{Cx, RecordId, Ref, Referrable},
 };
 use async_graphql::*;
 
+/// Anything with a global `id` that `node` can fetch again, so client caches can normalize by
+/// `id` alone.
+#[derive(Interface)]
+#[graphql(field(name = "id", type = "RecordId"))]
+pub enum Node {
+    User(User),
+    Guild(Guild),
+    TextChannel(TextChannel),
+    Message(Message),
+    GuildEmoji(GuildEmoji),
+}
+
+async fn select<T: DeserializeOwned>(
+    surreal: &crate::Surreal,
+    id: &RecordId,
+) -> surrealdb::Result<Option<T>> {
+    surreal.select(id.0.clone()).await
+}
+
+impl Node {
+    /// `None` for ids of other tables, and for what `user` can't see: channels as in
+    /// `Guild.channels`, and messages they couldn't read.
+    pub async fn load(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        id: &RecordId,
+    ) -> tide::Result<Option<Self>> {
+        Ok(match id.0.tb.as_str() {
+            User::TABLE => select(surreal, id).await?.map(Self::User),
+            Guild::TABLE => select(surreal, id).await?.map(Self::Guild),
+            GuildEmoji::TABLE => select(surreal, id).await?.map(Self::GuildEmoji),
+            Channel::TABLE => match select::<Channel>(surreal, id).await? {
+                Some(channel) if channel.visible_to(surreal, user).await? => {
+                    let Channel::Text(text) = channel;
+                    Some(Self::TextChannel(text))
+                }
+                _ => None,
+            },
+            Message::TABLE => match select::<Message>(surreal, id).await? {
+                Some(message) if message.readable_by(surreal, user).await? => {
+                    Some(Self::Message(message))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+}
+
 pub struct ById;
 
+/// What `node` does, typed.
 #[Object]
 impl ById {
-    async fn user(&self, cx: &Context<'_>, id: ID) -> Result<Option<User>> {
-        let user: Option<User> = cx
-            .cx()
-            .surreal()
-            .select((User::TABLE, id.0.as_str()))
-            .await?;
-        Ok(user)
+    async fn user(&self, cx: &Context<'_>, id: Ref<User>) -> Result<Option<User>> {
+        let user = cx.cx().ref_user()?;
+        match Node::load(cx.cx().surreal(), &user, &id.record_id()).await? {
+            Some(Node::User(user)) => Ok(Some(user)),
+            _ => Ok(None),
+        }
     }
 
-    async fn message(&self, cx: &Context<'_>, id: ID) -> Result<Option<Message>> {
-        let message: Option<Message> = cx
-            .cx()
-            .surreal()
-            .select((Message::TABLE, id.0.as_str()))
-            .await?;
-        Ok(message)
+    /// `null` unless you can read it.
+    async fn message(&self, cx: &Context<'_>, id: Ref<Message>) -> Result<Option<Message>> {
+        let user = cx.cx().ref_user()?;
+        match Node::load(cx.cx().surreal(), &user, &id.record_id()).await? {
+            Some(Node::Message(message)) => Ok(Some(message)),
+            _ => Ok(None),
+        }
     }
 
-    async fn channel(&self, cx: &Context<'_>, id: ID) -> Result<Option<Channel>> {
-        let channel: Option<Channel> = cx
-            .cx()
-            .surreal()
-            .select((Channel::TABLE, id.0.as_str()))
-            .await?;
-        Ok(channel)
+    /// `null` unless you can see it, see `Guild.channels`.
+    async fn channel(&self, cx: &Context<'_>, id: Ref<Channel>) -> Result<Option<Channel>> {
+        let user = cx.cx().ref_user()?;
+        match Node::load(cx.cx().surreal(), &user, &id.record_id()).await? {
+            Some(Node::TextChannel(text)) => Ok(Some(Channel::Text(text))),
+            _ => Ok(None),
+        }
     }
 
-    async fn guild(&self, cx: &Context<'_>, id: ID) -> Result<Option<Guild>> {
-        let guild: Option<Guild> = cx
-            .cx()
-            .surreal()
-            .select((Guild::TABLE, id.0.as_str()))
-            .await?;
-        Ok(guild)
+    /// Also `null` while the guild's `stateHash` is still `ifNoneMatch`, nothing to refetch.
+    async fn guild(
+        &self,
+        cx: &Context<'_>,
+        id: Ref<Guild>,
+        if_none_match: Option<String>,
+    ) -> Result<Option<Guild>> {
+        let user = cx.cx().ref_user()?;
+        let Some(Node::Guild(guild)) =
+            Node::load(cx.cx().surreal(), &user, &id.record_id()).await?
+        else {
+            return Ok(None);
+        };
+        if let Some(hash) = if_none_match {
+            if guild.hash_state(cx.cx().surreal()).await? == hash {
+                return Ok(None);
+            }
+        }
+        Ok(Some(guild))
     }
 }
@@ -0,0 +1,65 @@
+use async_graphql::*;
+
+use crate::{
+    model::{
+        login::{Device, Login},
+        security_event::{SecurityEvent, SecurityEventKind},
+        user::User,
+    },
+    util::{Cx, RecordId, Ref, ReferrableExt, Timestamp},
+};
+
+#[Object]
+impl Login {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn device(&self) -> &Device {
+        &self.device
+    }
+    async fn at(&self) -> Timestamp {
+        Timestamp(self.at.0)
+    }
+    async fn new_device(&self) -> bool {
+        self.new_device
+    }
+}
+
+#[Object]
+impl SecurityEvent {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn kind(&self) -> SecurityEventKind {
+        self.kind
+    }
+    async fn device(&self) -> Option<&Device> {
+        self.device.as_ref()
+    }
+    async fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+    async fn at(&self) -> Timestamp {
+        Timestamp(self.at.0)
+    }
+}
+
+/// Where the user is, and recently was, logged in.
+pub struct Sessions(pub Ref<User>);
+
+#[Object]
+impl Sessions {
+    /// Logins that haven't expired or been revoked yet, newest first.
+    async fn active(&self, cx: &Context<'_>) -> Result<Vec<Login>> {
+        Ok(cx.services().users.active_logins(&self.0).await?)
+    }
+
+    /// Newest first, including expired ones.
+    async fn recent_logins(
+        &self,
+        cx: &Context<'_>,
+        #[graphql(default = 20)] limit: i64,
+    ) -> Result<Vec<Login>> {
+        Ok(cx.services().users.recent_logins(&self.0, limit).await?)
+    }
+}
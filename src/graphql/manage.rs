@@ -3,8 +3,26 @@ use std::convert::identity;
 use async_graphql::*;
 
 use crate::{
-    model::{message::Message, user::User},
-    util::{ReferrableWithId, ReferrableExt},
+    model::{
+        audit::AuditLogEntry,
+        automod::AutomodSettings,
+        emoji::GuildEmoji,
+        event::{Event, EventKind},
+        feed::Feed,
+        guild::{
+            Channel, ChannelUpdate, Guild, GuildImage, JoinConstraint, Member, Permission, Role,
+            TextChannel,
+        },
+        guild_application::GuildApplication,
+        guild_invite::GuildInvite,
+        legal_hold,
+        message::Message,
+        trust::TrustSettings,
+        user::User,
+        webhook::{CreatedWebhook, Webhook},
+    },
+    storage,
+    util::{Cx, Ref, ReferrableExt, ReferrableWithId, Timestamp},
 };
 
 pub struct ManageMessage {
@@ -22,8 +40,19 @@ impl ManageMessage {
         }
     }
 
-    pub async fn _delete(&self) -> surrealdb::Result<Message> {
-        Ok(crate::SURREAL.delete(self.message.record_id().0).await?)
+    /// Only tombstones messages under a [legal hold](legal_hold), which keeps their content.
+    pub async fn _delete(&self, surreal: &crate::Surreal) -> tide::Result<Message> {
+        let message: Message = if legal_hold::covers(surreal, &self.message).await? {
+            let mut held = self.message.clone();
+            held.deleted_at = Some(surrealdb::sql::Datetime(chrono::Utc::now()));
+            held.save(surreal).await?
+        } else {
+            surreal.delete(self.message.record_id().0).await?
+        };
+        Event::message(EventKind::MessageDeleted, &self.message)
+            .log(surreal)
+            .await?;
+        Ok(message)
     }
 }
 
@@ -32,8 +61,8 @@ impl ManageMessage {
     async fn capabilities(&self) -> &[Capability] {
         &self.capabilities
     }
-    async fn delete(&self) -> Result<Message> {
-        Ok(self._delete().await?)
+    async fn delete(&self, context: &Context<'_>) -> Result<Message> {
+        Ok(self._delete(context.cx().surreal()).await?)
     }
 }
 
@@ -56,3 +85,385 @@ impl Capability {
         .collect()
     }
 }
+
+/// A guild as the caller may manage it. `permissions` says which of the mutations here they
+/// can use, the rest fail with `MISSING_PERMISSION`.
+pub struct ManageGuild {
+    permissions: Vec<Permission>,
+    user: Ref<User>,
+    guild: Guild,
+}
+
+impl ManageGuild {
+    /// `None` unless `user` is a member of `guild`.
+    pub async fn new(
+        surreal: &crate::Surreal,
+        user: Ref<User>,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Option<Self>> {
+        if Member::find(surreal, guild, &user).await?.is_none() {
+            return Ok(None);
+        }
+        let Some(guild) = surreal.select::<Option<Guild>>(guild.record_id().0).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            permissions: Member::effective_permissions(surreal, &guild.refer(), &user).await?,
+            user,
+            guild,
+        }))
+    }
+}
+
+#[Object]
+impl ManageGuild {
+    async fn guild(&self) -> &Guild {
+        &self.guild
+    }
+    /// With `Administrator` expanded to everything.
+    async fn permissions(&self) -> &[Permission] {
+        &self.permissions
+    }
+    /// Needs `ManageServer`. Fails with `INVALID_NAME` like `createGuild`.
+    async fn rename(&self, cx: &Context<'_>, name: String) -> Result<Guild> {
+        cx.services()
+            .guilds
+            .rename(&self.user, &self.guild.refer(), name)
+            .await
+    }
+    /// Needs `ManageServer`. `null` goes by the instance's retention.
+    async fn set_retention(&self, cx: &Context<'_>, days: Option<u32>) -> Result<Guild> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_retention(&self.user, &self.guild.refer(), days)
+            .await?)
+    }
+    /// Needs `Kick`. Removes members without roles who weren't around or posted here in the
+    /// last `inactiveDays` days, and says how many. `dryRun` only counts them.
+    async fn prune_members(
+        &self,
+        cx: &Context<'_>,
+        inactive_days: u32,
+        #[graphql(default)] dry_run: bool,
+    ) -> Result<usize> {
+        Ok(cx
+            .services()
+            .guilds
+            .prune_members(&self.user, &self.guild.refer(), inactive_days, dry_run)
+            .await?)
+    }
+    /// The latest 100 entries, newest first. Needs `ManageMessages`.
+    async fn audit_log(&self, cx: &Context<'_>) -> Result<Vec<AuditLogEntry>> {
+        Ok(cx
+            .services()
+            .guilds
+            .audit_log(&self.user, &self.guild.refer())
+            .await?)
+    }
+    /// Needs `ManageEmojis`.
+    async fn create_emoji(
+        &self,
+        cx: &Context<'_>,
+        name: String,
+        image: Upload,
+    ) -> Result<GuildEmoji> {
+        let image = storage::process_avatar_upload(image.value(cx)?)
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(cx
+            .services()
+            .guilds
+            .create_emoji(cx.storage(), &self.user, &self.guild.refer(), name, image)
+            .await?)
+    }
+    /// Needs `ManageEmojis`.
+    async fn delete_emoji(&self, cx: &Context<'_>, emoji: Ref<GuildEmoji>) -> Result<bool> {
+        Ok(cx
+            .services()
+            .guilds
+            .delete_emoji(&self.user, &emoji)
+            .await?)
+    }
+    /// Needs `ManageServer`. Leaving out `file` removes the image. Splashes are scaled to fit
+    /// 1920x1080, and never animated.
+    async fn set_image(
+        &self,
+        cx: &Context<'_>,
+        kind: GuildImage,
+        file: Option<Upload>,
+    ) -> Result<Guild> {
+        let image = match file {
+            Some(file) => Some(
+                match kind {
+                    GuildImage::Icon => storage::process_avatar_upload(file.value(cx)?).await,
+                    GuildImage::Splash => storage::process_splash_upload(file.value(cx)?).await,
+                }
+                .map_err(|e| e.extend())?,
+            ),
+            None => None,
+        };
+        Ok(cx
+            .services()
+            .guilds
+            .set_image(cx.storage(), &self.user, &self.guild.refer(), kind, image)
+            .await?)
+    }
+    /// Needs `Invite`. Both limits are optional.
+    async fn create_invite(
+        &self,
+        cx: &Context<'_>,
+        max_uses: Option<u32>,
+        expires_at: Option<Timestamp>,
+    ) -> Result<GuildInvite> {
+        Ok(cx
+            .services()
+            .guilds
+            .create_invite(
+                &self.user,
+                &self.guild.refer(),
+                max_uses,
+                expires_at.map(|at| at.0),
+            )
+            .await?)
+    }
+    /// Needs `Invite`. Newest first, used up and expired ones included.
+    async fn invites(&self, cx: &Context<'_>) -> Result<Vec<GuildInvite>> {
+        Ok(cx
+            .services()
+            .guilds
+            .invites(&self.user, &self.guild.refer())
+            .await?)
+    }
+    /// Needs `Invite`.
+    async fn revoke_invite(&self, cx: &Context<'_>, invite: Ref<GuildInvite>) -> Result<bool> {
+        Ok(cx
+            .services()
+            .guilds
+            .revoke_invite(&self.user, &invite)
+            .await?)
+    }
+    /// Needs `ManageServer`. Members who already joined stay either way.
+    async fn set_join_constraint(
+        &self,
+        cx: &Context<'_>,
+        constraint: JoinConstraint,
+    ) -> Result<Guild> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_join_constraint(&self.user, &self.guild.refer(), constraint)
+            .await?)
+    }
+    /// Needs `ManageServer`. What applicants answer with the `APPLICATION` constraint.
+    async fn set_application_questions(
+        &self,
+        cx: &Context<'_>,
+        questions: Vec<String>,
+    ) -> Result<Guild> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_application_questions(&self.user, &self.guild.refer(), questions)
+            .await?)
+    }
+    /// Needs `ManageServer`.
+    async fn set_trust(&self, cx: &Context<'_>, trust: TrustSettings) -> Result<Guild> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_trust(&self.user, &self.guild.refer(), trust)
+            .await?)
+    }
+    /// Needs `ManageServer`.
+    async fn set_automod(&self, cx: &Context<'_>, automod: AutomodSettings) -> Result<Guild> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_automod(&self.user, &self.guild.refer(), automod)
+            .await?)
+    }
+    /// Needs `ReviewApplications`. The pending ones, oldest first.
+    async fn applications(&self, cx: &Context<'_>) -> Result<Vec<GuildApplication>> {
+        Ok(cx
+            .services()
+            .guilds
+            .applications(&self.user, &self.guild.refer())
+            .await?)
+    }
+    /// Needs `ReviewApplications`. Lets the applicant in, and tells them.
+    async fn approve_application(
+        &self,
+        cx: &Context<'_>,
+        application: Ref<GuildApplication>,
+        reason: Option<String>,
+    ) -> Result<GuildApplication> {
+        Ok(cx
+            .services()
+            .guilds
+            .review_application(&self.user, &application, true, reason)
+            .await?)
+    }
+    /// Needs `ReviewApplications`. Tells the applicant, with `reason` if given.
+    async fn deny_application(
+        &self,
+        cx: &Context<'_>,
+        application: Ref<GuildApplication>,
+        reason: Option<String>,
+    ) -> Result<GuildApplication> {
+        Ok(cx
+            .services()
+            .guilds
+            .review_application(&self.user, &application, false, reason)
+            .await?)
+    }
+}
+
+/// A channel as the caller may manage it, see [ManageGuild].
+pub struct ManageChannel {
+    permissions: Vec<Permission>,
+    user: Ref<User>,
+    channel: TextChannel,
+}
+
+impl ManageChannel {
+    /// `None` unless `user` is a member of the channel's guild who may see it.
+    pub async fn new(
+        surreal: &crate::Surreal,
+        user: Ref<User>,
+        channel: &Ref<Channel>,
+    ) -> surrealdb::Result<Option<Self>> {
+        let Some(Channel::Text(channel)) = surreal
+            .select::<Option<Channel>>(channel.record_id().0)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let Some(member) = Member::find(surreal, &channel.guild, &user).await? else {
+            return Ok(None);
+        };
+        let permissions = Member::effective_permissions(surreal, &channel.guild, &user).await?;
+        if !channel.grants_access(&member, &permissions) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            permissions: channel.effective_permissions(surreal, &user).await?,
+            user,
+            channel,
+        }))
+    }
+
+    fn refer_channel(&self) -> Ref<Channel> {
+        Ref::new_owned(<TextChannel as ReferrableWithId>::id(&self.channel).clone())
+    }
+}
+
+#[Object]
+impl ManageChannel {
+    async fn channel(&self) -> &TextChannel {
+        &self.channel
+    }
+    /// With `Administrator` expanded to everything.
+    async fn permissions(&self) -> &[Permission] {
+        &self.permissions
+    }
+    /// Needs `ManageChannels`.
+    async fn update(&self, cx: &Context<'_>, update: ChannelUpdate) -> Result<Channel> {
+        Ok(cx
+            .services()
+            .guilds
+            .update_channel(&self.user, &self.refer_channel(), update)
+            .await?)
+    }
+    /// Keeps messages here for fewer days than the guild does. Needs `ManageChannels`. `null`
+    /// goes by the guild's retention.
+    async fn set_retention(&self, cx: &Context<'_>, days: Option<u32>) -> Result<Channel> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_channel_retention(&self.user, &self.refer_channel(), days)
+            .await?)
+    }
+    /// Writes the messages sent here between `from` and `to` to a static HTML page, and links it
+    /// from the guild's audit log. Needs `ManageMessages`.
+    async fn export_transcript(
+        &self,
+        cx: &Context<'_>,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> Result<AuditLogEntry> {
+        Ok(cx
+            .services()
+            .guilds
+            .export_transcript(cx.storage(), &self.user, &self.refer_channel(), from.0, to.0)
+            .await?)
+    }
+    /// Lets `roles` and `users` see the channel while it's private. Needs `ManageChannels`.
+    async fn grant_access(
+        &self,
+        cx: &Context<'_>,
+        #[graphql(default)] roles: Vec<Ref<Role>>,
+        #[graphql(default)] users: Vec<Ref<User>>,
+    ) -> Result<Channel> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_channel_access(&self.user, &self.refer_channel(), roles, users, true)
+            .await?)
+    }
+    /// Needs `ManageChannels`. Subscriptions following the channel complete, whoever may still
+    /// see it resubscribes.
+    async fn revoke_access(
+        &self,
+        cx: &Context<'_>,
+        #[graphql(default)] roles: Vec<Ref<Role>>,
+        #[graphql(default)] users: Vec<Ref<User>>,
+    ) -> Result<Channel> {
+        Ok(cx
+            .services()
+            .guilds
+            .set_channel_access(&self.user, &self.refer_channel(), roles, users, false)
+            .await?)
+    }
+    /// Needs `ManageChannels`.
+    async fn delete(&self, cx: &Context<'_>) -> Result<bool> {
+        Ok(cx
+            .services()
+            .guilds
+            .delete_channel(&self.user, &self.refer_channel())
+            .await?)
+    }
+    /// Needs `ManageWebhooks`. The URL to post to is only shown here.
+    async fn create_webhook(
+        &self,
+        cx: &Context<'_>,
+        name: String,
+        avatar_url: Option<String>,
+    ) -> Result<CreatedWebhook> {
+        Ok(cx
+            .services()
+            .guilds
+            .create_webhook(&self.user, &self.refer_channel(), name, avatar_url)
+            .await?)
+    }
+    /// Needs `ManageWebhooks`.
+    async fn delete_webhook(&self, cx: &Context<'_>, webhook: Ref<Webhook>) -> Result<bool> {
+        Ok(cx
+            .services()
+            .guilds
+            .delete_webhook(&self.user, &webhook)
+            .await?)
+    }
+    /// Needs `ManageWebhooks`.
+    async fn add_feed(&self, cx: &Context<'_>, url: String) -> Result<Feed> {
+        Ok(cx
+            .services()
+            .guilds
+            .add_feed(&self.user, &self.refer_channel(), url)
+            .await?)
+    }
+    /// Needs `ManageWebhooks`.
+    async fn remove_feed(&self, cx: &Context<'_>, feed: Ref<Feed>) -> Result<bool> {
+        Ok(cx.services().guilds.remove_feed(&self.user, &feed).await?)
+    }
+}
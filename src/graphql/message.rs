@@ -4,41 +4,183 @@ use async_graphql::connection::{Connection, EmptyFields};
 use async_graphql::*;
 use futures_util::Future;
 
-use crate::http::SURREAL;
+use crate::gif::Gif;
+use crate::media_proxy;
+use crate::model::event::{Changes, Event};
 use crate::model::guild::TextableChannel;
-use crate::model::message::{Conversation, Message, MessageRecipient};
+use crate::model::message::{
+    AuthorOverride, Conversation, Embed, EmbedField, Message, MessageRecipient, ParticipantActivity,
+};
+use crate::model::reminder::Reminder;
+use crate::model::token::Scope;
 use crate::model::user::User;
-use crate::util::{Cx, ReferrableExt};
+use crate::translate::{self, Locale};
+use crate::util::{query::Wanted, Cx, RecordId, Ref, ReferrableExt, Timestamp};
 
 #[Object]
 impl Message {
-    async fn id(&self) -> ID {
-        self.id.to_raw().into()
+    pub async fn id(&self) -> RecordId {
+        self.gql_id()
     }
     async fn author(&self, context: &Context<'_>) -> Result<User> {
         Ok(self.author.fetch(context.cx().surreal()).await?)
     }
+    /// Empty once deleted, even if a legal hold keeps it in the database.
     async fn content(&self) -> &str {
+        if self.deleted_at.is_some() {
+            return "";
+        }
         &self.content
     }
+    /// The content machine translated to `to`. Errors unless `ServerConfiguration.translation`.
+    async fn translated(&self, context: &Context<'_>, to: Locale) -> Result<String> {
+        if self.deleted_at.is_some() {
+            return Ok(String::new());
+        }
+        Ok(translate::translate(context.cx().surreal(), self, &to).await?)
+    }
+    /// Whether clients should read it out loud as it comes in.
+    async fn tts(&self) -> bool {
+        self.tts
+    }
+    /// The name and avatar to show instead of the author's, set on webhook posts.
+    async fn author_override(&self) -> Option<&AuthorOverride> {
+        self.author_override.as_ref()
+    }
+    async fn embeds(&self) -> &[Embed] {
+        if self.deleted_at.is_some() {
+            return &[];
+        }
+        &self.embeds
+    }
     async fn recipient(&self) -> Result<MessageRecipient> {
         Ok(self.recipient.clone())
     }
-    async fn created_at(&self) -> String {
-        self.created_at.0.to_rfc3339()
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+    async fn pinned(&self) -> bool {
+        self.pinned
+    }
+    /// Set on messages removed by the retention policy, or deleted under a legal hold.
+    async fn deleted_at(&self) -> Option<Timestamp> {
+        self.deleted_at.as_ref().map(|at| Timestamp(at.0))
     }
 
     async fn can_delete(&self, context: &Context<'_>) -> Result<bool> {
         Ok(context.cx().ref_user()? == self.author)
     }
 
-    async fn reference(&self) -> Result<Option<Message>> {
+    async fn reference(&self, context: &Context<'_>) -> Result<Option<Message>> {
         if let Some(ref reply) = self.reference {
-            return Ok(Some(reply.fetch(&SURREAL).await?));
+            return Ok(Some(reply.fetch(context.cx().surreal()).await?));
         }
 
         Ok(None)
     }
+
+    /// How many messages reply to this one.
+    async fn reply_count(&self, context: &Context<'_>) -> Result<i64> {
+        Ok(self.count_replies(context.cx().surreal()).await?)
+    }
+
+    /// Messages replying to this one, oldest first.
+    async fn replies(
+        &self,
+        context: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+    ) -> Result<Connection<i64, Message, EmptyFields, EmptyFields>> {
+        context.cx().require_scope(Scope::MessagesRead)?;
+        let wanted = Wanted::in_connection::<Message>(context);
+        self.replies_paginate(context.cx().surreal(), &wanted, after, before, first, last)
+            .await
+    }
+}
+
+#[Object]
+impl Gif {
+    /// What goes in `MessageInit.gif`.
+    async fn id(&self) -> &str {
+        &self.id
+    }
+    async fn title(&self) -> &str {
+        &self.title
+    }
+    /// Through the media proxy.
+    async fn media_url(&self) -> String {
+        media_proxy::proxied(&self.media_url)
+    }
+    /// Smaller, for the picker. Through the media proxy.
+    async fn preview_url(&self) -> String {
+        media_proxy::proxied(&self.preview_url)
+    }
+    async fn width(&self) -> u32 {
+        self.width
+    }
+    async fn height(&self) -> u32 {
+        self.height
+    }
+    /// Who to credit, like `GIPHY`.
+    async fn provider(&self) -> &str {
+        self.provider
+    }
+}
+
+#[Object]
+impl Embed {
+    async fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    async fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+    async fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+    async fn color(&self) -> Option<i32> {
+        self.color
+    }
+    async fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+    /// Through the media proxy.
+    async fn image_url(&self) -> Option<String> {
+        self.image_url.as_deref().map(media_proxy::proxied)
+    }
+    async fn fields(&self) -> &[EmbedField] {
+        &self.fields
+    }
+    async fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+            .as_ref()
+            .map(|timestamp| Timestamp(timestamp.0))
+    }
+    async fn footer(&self) -> Option<&str> {
+        self.footer.as_deref()
+    }
+}
+
+#[Object]
+impl Reminder {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    /// Errors if the message was deleted since.
+    async fn message(&self, context: &Context<'_>) -> Result<Message> {
+        Ok(self.message.fetch(context.cx().surreal()).await?)
+    }
+    async fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+    async fn remind_at(&self) -> Timestamp {
+        Timestamp(self.remind_at.0)
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
 }
 
 #[derive(Enum, Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -79,11 +221,42 @@ impl Conversation {
         last: Option<i32>,
         before: Option<String>,
     ) -> Result<Connection<i64, Message, EmptyFields, EmptyFields>> {
-        self.messages_paginate(context.cx().surreal(), after, before, first, last)
+        context.cx().require_scope(Scope::MessagesRead)?;
+        self.require_viewable(context.cx().surreal()).await?;
+        let wanted = Wanted::in_connection::<Message>(context);
+        self.messages_paginate(context.cx().surreal(), &wanted, after, before, first, last)
             .await
     }
 
+    /// A window of messages centered on `message`, for jumping to it without paginating.
+    async fn messages_around(
+        &self,
+        context: &Context<'_>,
+        message: Ref<Message>,
+        #[graphql(default = 25, validator(maximum = 100))] before: u32,
+        #[graphql(default = 25, validator(maximum = 100))] after: u32,
+    ) -> Result<Vec<Message>> {
+        context.cx().require_scope(Scope::MessagesRead)?;
+        self.require_viewable(context.cx().surreal()).await?;
+        Ok(self
+            .messages_window(context.cx().surreal(), &message, before, after)
+            .await?)
+    }
+
+    /// Messages sent and deleted since `cursor`, for catching up after reconnecting.
+    async fn messages_since(
+        &self,
+        context: &Context<'_>,
+        cursor: Option<String>,
+    ) -> Result<Changes> {
+        context.cx().require_scope(Scope::MessagesRead)?;
+        self.require_viewable(context.cx().surreal()).await?;
+        Ok(Event::since_in_conversation(context.cx().surreal(), self, cursor.as_deref()).await?)
+    }
+
     async fn get_all_messages(&self, context: &Context<'_>) -> Result<Vec<Message>> {
+        context.cx().require_scope(Scope::MessagesRead)?;
+        self.require_viewable(context.cx().surreal()).await?;
         Ok(self.all_messages(context.cx().surreal()).await?)
     }
 
@@ -95,7 +268,29 @@ impl Conversation {
         &self.1
     }
 
-    async fn id(&self) -> String {
-        self.1.gql_id().to_string()
+    /// Who has been talking here lately, most recently active first. In channels that's
+    /// everyone who posted in the last 30 days.
+    async fn participants(
+        &self,
+        context: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+    ) -> Result<Connection<i64, User, EmptyFields, ParticipantActivity>> {
+        context.cx().require_scope(Scope::MessagesRead)?;
+        self.require_viewable(context.cx().surreal()).await?;
+        self.participants_paginate(context.cx().surreal(), after, before, first, last)
+            .await
+    }
+
+    /// The name set with `renameConversation`, or else the other user's display name, or the
+    /// channel's name.
+    async fn display_name(&self, context: &Context<'_>) -> Result<String> {
+        Ok(self.resolve_name(context.cx().surreal()).await?)
+    }
+
+    async fn id(&self) -> RecordId {
+        self.1.gql_id()
     }
 }
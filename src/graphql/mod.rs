@@ -1,26 +1,76 @@
 #![allow(unused_variables)]
+pub mod admin;
+pub mod allowlist;
+pub mod attachment;
+pub mod event;
 pub mod guild;
 mod loaders;
+pub mod login;
 pub mod manage;
 pub mod message;
+mod permission;
+pub mod scope;
+mod terms;
+#[cfg(test)]
+mod tests;
+pub mod token;
+pub mod trace;
 pub mod user;
+pub mod viewer;
+pub mod voice;
+pub mod webhook;
 
-use async_graphql::{Result as FieldResult, *};
+use async_graphql::{
+    connection::{Connection, EmptyFields},
+    Result as FieldResult, *,
+};
 use async_std::future;
 use futures_util::{Stream, StreamExt};
-use serde::Deserialize;
 
 use crate::{
-    http::SURREAL,
+    backup::{Backup, RestoreReport},
+    config::{ServerConfiguration, CONFIG},
+    gif::{self, Gif},
     model::{
-        guild::{Guild, GuildInit},
-        message::{Conversation, Message, MessageInit, MessageRecipient},
-        user::{parse_tag, Status, User, Theme},
+        admin_audit::AdminAction,
+        attachment::{Attachment, AttachmentInit},
+        emoji::{EmojiMatch, GuildEmoji},
+        erasure::Erasure,
+        feed::Feed,
+        guild::{Channel, ChannelUpdate, Guild, GuildInit, TextableChannel},
+        guild_invite::InvitePreview,
+        guild_layout::GuildFolder,
+        instance::{InstanceInvite, RegistrationMode},
+        linked_account::{LinkedAccount, LinkedAccountKind, LinkedAccountVisibility},
+        member_list::MemberChunk,
+        message::{
+            Conversation, ConversationActivity, Message, MessageInit, MessageRecipient,
+            MessageRecipientIn,
+        },
+        name_rule::{NameRule, NameRuleKind},
+        oauth::{OAuthApp, OAuthAppCredentials},
+        quick_search::QuickSearchHit,
+        read::{Inbox, ReadState},
+        reminder::Reminder,
+        security_event::{SecurityEvent, SecurityEventKind},
+        spam::SpamFlag,
+        stats::InstanceStats,
+        terms::Terms,
+        token::{ApiToken, CreatedApiToken, Scope},
+        user::{Status, User},
+        voice::{VoiceFlags, VoiceState},
+        webhook::{CreatedWebhook, Webhook},
     },
-    util::{Cx, RecordId, Ref, ReferrableExt},
+    security::Throttle,
+    storage,
+    util::{Cx, RecordId, Ref, Timestamp},
 };
 
-use self::{loaders::ById, manage::ManageMessage};
+use self::{
+    loaders::{ById, Node},
+    manage::{ManageChannel, ManageGuild, ManageMessage},
+    viewer::{ManageViewer, Viewer},
+};
 
 pub struct QueryRoot;
 
@@ -30,39 +80,214 @@ impl QueryRoot {
         ById
     }
 
-    async fn me(&self, context: &Context<'_>) -> FieldResult<User> {
-        Ok(context.cx().user().await?)
+    /// Whatever `id` is of, `null` if you can't see it.
+    async fn node(&self, context: &Context<'_>, id: RecordId) -> FieldResult<Option<Node>> {
+        let user = context.cx().ref_user()?;
+        Ok(Node::load(context.cx().surreal(), &user, &id).await?)
+    }
+
+    /// Limits of this instance. Needs no authentication.
+    async fn server_configuration(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<ServerConfiguration> {
+        Ok(ServerConfiguration::current(context.cx().surreal()).await?)
+    }
+
+    /// The current terms of service, `null` if there are none. Needs no authentication.
+    async fn terms(&self, context: &Context<'_>) -> FieldResult<Option<Terms>> {
+        Ok(context.services().users.terms().await?)
+    }
+
+    /// What joining with an invite `code` leads to, for the join screen. `null` if the code
+    /// is invalid, used up or expired. Needs no authentication.
+    async fn invite_preview(
+        &self,
+        context: &Context<'_>,
+        code: String,
+    ) -> FieldResult<Option<InvitePreview>> {
+        Ok(context.services().guilds.invite_preview(&code).await?)
     }
 
-    async fn conversations(&self, context: &Context<'_>) -> FieldResult<Vec<Conversation>> {
-        Ok(Conversation::all(context.cx().surreal(), &context.cx().user().await?).await?)
+    async fn me(&self, context: &Context<'_>) -> FieldResult<Viewer> {
+        Ok(Viewer(context.cx().user().await?))
+    }
+
+    /// Direct conversations, most recently active first.
+    async fn conversations(
+        &self,
+        context: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+        last: Option<i32>,
+        before: Option<String>,
+    ) -> FieldResult<Connection<i64, Conversation, EmptyFields, ConversationActivity>> {
+        let user = context.cx().user().await?;
+        context
+            .services()
+            .messages
+            .conversations(&user, after, before, first, last)
+            .await
+    }
+
+    /// Unread DMs by sender and unread mentions by guild, for badge counts.
+    async fn inbox(&self, context: &Context<'_>) -> FieldResult<Inbox> {
+        let user = context.cx().ref_user()?;
+        Ok(context.services().messages.inbox(&user).await?)
+    }
+
+    /// For the Ctrl-K switcher: guilds, channels, DMs and friends named like `query`, the
+    /// ones you were last active in first.
+    async fn quick_search(
+        &self,
+        context: &Context<'_>,
+        query: String,
+    ) -> FieldResult<Vec<QuickSearchHit>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().users.quick_search(&user, &query).await?)
+    }
+
+    /// For the emoji picker: unicode emoji and the custom ones of `guild`, or of all your
+    /// guilds, whose name contains `query`.
+    async fn search_emoji(
+        &self,
+        context: &Context<'_>,
+        query: String,
+        guild: Option<Ref<Guild>>,
+    ) -> FieldResult<Vec<EmojiMatch>> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .search_emoji(&user, &query, guild)
+            .await?)
+    }
+
+    /// For the GIF picker, through the instance's GIF provider. Send one with
+    /// `MessageInit.gif`. Errors unless `ServerConfiguration.gifs`.
+    async fn search_gifs(
+        &self,
+        context: &Context<'_>,
+        query: String,
+        #[graphql(default = 20)] limit: u32,
+    ) -> FieldResult<Vec<Gif>> {
+        // it's the instance's quota, not for anyone who isn't logged in
+        context.cx().ref_user()?;
+        Ok(gif::search(&query, limit).await?)
+    }
+
+    /// Pending "remind me later"s, soonest first.
+    async fn reminders(&self, context: &Context<'_>) -> FieldResult<Vec<Reminder>> {
+        let user = context.cx().ref_user()?;
+        Ok(context.services().messages.reminders(&user).await?)
     }
 
     async fn conversation_direct(
         &self,
         context: &Context<'_>,
-        recipient: ID,
+        recipient: Ref<User>,
     ) -> FieldResult<Conversation> {
         Ok(Conversation(
             context.cx().ref_user()?,
-            MessageRecipient::User(Ref::new(&recipient)),
+            MessageRecipient::User(recipient),
         ))
     }
 
     async fn guilds(&self, context: &Context<'_>) -> FieldResult<Vec<Guild>> {
-        #[derive(Deserialize)]
-        struct Memer {
-            guild: Guild,
-        }
-        let uid_id = context.cx().ref_user()?;
-        let uid = uid_id.id();
-        let query = format!(
-            r#"
-                SELECT guild FROM member WHERE user = user:{uid} FETCH guild.*
-            "#
-        );
-        let memers: Vec<Memer> = context.cx().surreal().query(query).await?.take(0)?;
-        Ok(memers.into_iter().map(|memer| memer.guild).collect())
+        let user = context.cx().ref_user()?;
+        Ok(context.services().guilds.of_user(&user).await?)
+    }
+
+    /// How the sidebar groups `guilds`, which already come in this order.
+    async fn guild_folders(&self, context: &Context<'_>) -> FieldResult<Vec<GuildFolder>> {
+        let user = context.cx().ref_user()?;
+        Ok(context.services().guilds.folders(&user).await?)
+    }
+
+    /// Admin only.
+    async fn name_rules(&self, context: &Context<'_>) -> FieldResult<Vec<NameRule>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.name_rules(&user).await?)
+    }
+
+    /// Admin only. Newest first, only the ones still kept.
+    async fn backups(&self, context: &Context<'_>) -> FieldResult<Vec<Backup>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.backups(&user).await?)
+    }
+
+    /// Admin only. Newest first.
+    async fn erasures(&self, context: &Context<'_>) -> FieldResult<Vec<Erasure>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.erasures(&user).await?)
+    }
+
+    /// Admin only. What admins did, like placing legal holds, newest first. Only what was done
+    /// to `subject` if given.
+    async fn admin_actions(
+        &self,
+        context: &Context<'_>,
+        subject: Option<RecordId>,
+    ) -> FieldResult<Vec<AdminAction>> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .admin_actions(&user, subject)
+            .await?)
+    }
+
+    /// Admin only. Registration and login throttles of this node, blocked ones first.
+    async fn throttles(&self, context: &Context<'_>) -> FieldResult<Vec<Throttle>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.throttles(&user)?)
+    }
+
+    /// Admin only. Senders the spam checks flagged and nobody looked at yet, newest first.
+    async fn spam_flags(&self, context: &Context<'_>) -> FieldResult<Vec<SpamFlag>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.spam_flags(&user).await?)
+    }
+
+    /// Admin only. Totals and the last `days` days of activity, from nightly rollups: the
+    /// totals are `null` until the first one, and today isn't in yet.
+    async fn stats(
+        &self,
+        context: &Context<'_>,
+        #[graphql(default = 30)] days: u32,
+    ) -> FieldResult<InstanceStats> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.stats(&user, days).await?)
+    }
+
+    /// Admin only. Days messages are kept, `null` for forever.
+    async fn retention(&self, context: &Context<'_>) -> FieldResult<Option<u32>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.retention(&user).await?)
+    }
+
+    /// Admin only.
+    async fn registration_mode(&self, context: &Context<'_>) -> FieldResult<RegistrationMode> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.registration_mode(&user).await?)
+    }
+
+    /// Admin only. Newest first, used up and expired ones included.
+    async fn instance_invites(&self, context: &Context<'_>) -> FieldResult<Vec<InstanceInvite>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.instance_invites(&user).await?)
+    }
+
+    async fn api_tokens(&self, context: &Context<'_>) -> FieldResult<Vec<ApiToken>> {
+        context.cx().require_session()?;
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .cx()
+            .surreal()
+            .query("SELECT * FROM api_token WHERE owner = $owner AND revoked = false")
+            .bind(("owner", user))
+            .await?
+            .take(0)?)
     }
 }
 
@@ -70,116 +295,877 @@ pub struct MutationRoot;
 
 #[Object]
 impl MutationRoot {
+    /// Has to be the current version, in case it changed while the user was reading.
+    async fn accept_terms(&self, context: &Context<'_>, version: u32) -> FieldResult<bool> {
+        context.cx().require_session()?;
+        let user = context.cx().ref_user()?;
+        context
+            .services()
+            .users
+            .accept_terms(&user, version)
+            .await?;
+        Ok(true)
+    }
+
     async fn add_friend(&self, context: &Context<'_>, other: String) -> FieldResult<Option<User>> {
-        let tag = parse_tag(&other).ok_or_else(|| anyhow::anyhow!("invalid friend tag"))?;
-        let user = User::find_tag(context.cx().surreal(), &tag).await?;
-        if user.is_none() {
-            return Ok(None);
-        }
-        Ok(Some(
-            context
-                .cx()
-                .user()
-                .await?
-                .add_friend(context.cx().surreal(), user.unwrap())
-                .await?,
-        ))
+        let user = context.cx().user().await?;
+        Ok(context.services().users.add_friend(&user, &other).await?)
     }
 
-    async fn set_theme(&self, context: &Context<'_>, theme: Theme) -> FieldResult<User> {
-        let mut user = context.cx().user().await?;
-        user.theme = theme;
-        Ok(user.save(&SURREAL).await?)
+    /// Changes your own account.
+    async fn me(&self, context: &Context<'_>) -> FieldResult<ManageViewer> {
+        Ok(ManageViewer(context.cx().user().await?))
     }
 
-    async fn set_avatar(&self, context: &Context<'_>, avatar: Upload) -> FieldResult<User> {
-        let f = avatar.value(context)?;
+    /// Texts a code to `phone`, in international format like `+14155550123`, for
+    /// `confirmPhoneVerification`. Errors unless `ServerConfiguration.phoneVerification`.
+    async fn start_phone_verification(
+        &self,
+        context: &Context<'_>,
+        phone: String,
+    ) -> FieldResult<bool> {
+        context.cx().require_session()?;
+        let user = context.cx().ref_user()?;
+        context
+            .services()
+            .users
+            .start_phone_verification(&user, &phone)
+            .await?;
+        Ok(true)
+    }
 
+    /// Verifies `phone` with the code texted to it. Only a hash of the number is kept, and
+    /// one number verifies one account.
+    async fn confirm_phone_verification(
+        &self,
+        context: &Context<'_>,
+        phone: String,
+        code: String,
+    ) -> FieldResult<User> {
+        context.cx().require_session()?;
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .users
+            .confirm_phone_verification(&user, &phone, &code)
+            .await?)
+    }
+
+    /// A note on `user` only the caller can see, as `User.note`. Blank notes remove it.
+    async fn set_user_note(
+        &self,
+        context: &Context<'_>,
+        user: Ref<User>,
+        note: Option<String>,
+    ) -> FieldResult<Option<String>> {
+        let owner = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .users
+            .set_note(&owner, &user, note)
+            .await?)
+    }
+
+    /// Links an account elsewhere to the profile, unverified until its `verifyUrl` is
+    /// followed, or for websites, `verifyConnection`.
+    async fn add_connection(
+        &self,
+        context: &Context<'_>,
+        kind: LinkedAccountKind,
+        name: String,
+        #[graphql(default)] visibility: LinkedAccountVisibility,
+    ) -> FieldResult<LinkedAccount> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .users
+            .add_connection(&user, kind, name, visibility)
+            .await?)
+    }
+
+    async fn remove_connection(
+        &self,
+        context: &Context<'_>,
+        connection: Ref<LinkedAccount>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .users
+            .remove_connection(&user, &connection)
+            .await?)
+    }
+
+    /// Websites only: checks the page for `<meta name="netherite-verification">` with the
+    /// connection's `code`.
+    async fn verify_connection(
+        &self,
+        context: &Context<'_>,
+        connection: Ref<LinkedAccount>,
+    ) -> FieldResult<LinkedAccount> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .users
+            .verify_connection(&user, &connection)
+            .await?)
+    }
+
+    /// Admin only. Names equal to a reserved word, or containing a profane one, are refused
+    /// from now on; existing names are left alone.
+    async fn add_name_rule(
+        &self,
+        context: &Context<'_>,
+        kind: NameRuleKind,
+        word: String,
+    ) -> FieldResult<NameRule> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .add_name_rule(&user, kind, &word)
+            .await?)
+    }
+
+    /// Admin only. Rules from the config can't be removed here.
+    async fn remove_name_rule(
+        &self,
+        context: &Context<'_>,
+        rule: Ref<NameRule>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .remove_name_rule(&user, rule.record_id())
+            .await?)
+    }
+
+    /// Admin only. Publishes the next version of the terms of service, which everyone has to
+    /// accept again.
+    async fn publish_terms(&self, context: &Context<'_>, content: String) -> FieldResult<Terms> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .publish_terms(&user, content)
+            .await?)
+    }
+
+    /// Admin only. Takes a backup in the background, it shows up in `backups` once done.
+    async fn create_backup(&self, context: &Context<'_>) -> FieldResult<bool> {
+        let user = context.cx().user().await?;
+        context.services().admin.create_backup(&user).await?;
+        Ok(true)
+    }
+
+    /// Admin only. Restores `backup` into the database, which should be empty. Only checks it
+    /// can be restored unless `dryRun` is false, run it that way first.
+    async fn restore_backup(
+        &self,
+        context: &Context<'_>,
+        backup: Ref<Backup>,
+        #[graphql(default = true)] dry_run: bool,
+    ) -> FieldResult<RestoreReport> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .restore_backup(&user, &backup, dry_run)
+            .await?)
+    }
+
+    /// Admin only. Unblocks an IP, subnet or ASN as listed by `throttles`. `false` if it
+    /// wasn't throttled.
+    async fn clear_throttle(&self, context: &Context<'_>, key: String) -> FieldResult<bool> {
+        let admin = context.cx().user().await?;
+        Ok(context.services().admin.clear_throttle(&admin, &key)?)
+    }
+
+    /// Admin only. Takes the flag off the list; erase the user to get rid of them.
+    async fn resolve_spam_flag(
+        &self,
+        context: &Context<'_>,
+        flag: Ref<SpamFlag>,
+    ) -> FieldResult<SpamFlag> {
+        let admin = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .resolve_spam_flag(&admin, &flag)
+            .await?)
+    }
+
+    /// Admin only. Erases everything that identifies `user`, in the background; the returned
+    /// erasure gets a signed report once done. `anonymizeContent` also empties their messages.
+    async fn erase_user(
+        &self,
+        context: &Context<'_>,
+        user: Ref<User>,
+        #[graphql(default = false)] anonymize_content: bool,
+    ) -> FieldResult<Erasure> {
+        let admin = context.cx().user().await?;
+        let erasure = context
+            .services()
+            .admin
+            .erase_user(&admin, &user, anonymize_content)
+            .await?;
         context
             .storage()
             .write()
             .await
-            .put_avatar_graphql(
-                context.cx().ref_user()?.id().to_owned(),
-                crate::storage::AvatarKind::U,
-                crate::storage::AvatarFiletype::Static,
-                f,
-            )
-            .await?;
+            .forget_avatar(user.id().to_owned(), storage::AvatarKind::U);
+        Ok(erasure)
+    }
+
+    /// Admin only. Keeps every message of `subject`, a user or channel, out of retention and
+    /// deletion while `held`. Users under a hold can't be erased either.
+    async fn set_legal_hold(
+        &self,
+        context: &Context<'_>,
+        subject: RecordId,
+        held: bool,
+        reason: String,
+    ) -> FieldResult<AdminAction> {
+        let admin = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .set_legal_hold(&admin, subject, held, reason)
+            .await?)
+    }
+
+    /// Admin only. Messages older than `days` are removed, `null` keeps them forever. Guilds can
+    /// only go shorter.
+    async fn set_retention(
+        &self,
+        context: &Context<'_>,
+        days: Option<u32>,
+    ) -> FieldResult<Option<u32>> {
+        let user = context.cx().user().await?;
+        Ok(context.services().admin.set_retention(&user, days).await?)
+    }
+
+    /// Admin only. Takes effect right away, until changed again.
+    async fn set_registration_mode(
+        &self,
+        context: &Context<'_>,
+        mode: RegistrationMode,
+    ) -> FieldResult<RegistrationMode> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .set_registration_mode(&user, mode)
+            .await?)
+    }
+
+    /// Admin only. An invite to register on this instance, not to a guild. Both limits are
+    /// optional.
+    async fn create_instance_invite(
+        &self,
+        context: &Context<'_>,
+        max_uses: Option<u32>,
+        expires_at: Option<Timestamp>,
+    ) -> FieldResult<InstanceInvite> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .admin
+            .create_instance_invite(&user, max_uses, expires_at.map(|at| at.0))
+            .await?)
+    }
+
+    /// Admin only.
+    async fn revoke_instance_invite(
+        &self,
+        context: &Context<'_>,
+        invite: Ref<InstanceInvite>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().user().await?;
+        let invite = invite.record_id();
+        Ok(context
+            .services()
+            .admin
+            .revoke_instance_invite(&user, invite)
+            .await?)
+    }
+
+    /// Registers an app that can sign users in via OpenID Connect.
+    /// The secret is only ever shown here.
+    async fn create_oauth_app(
+        &self,
+        context: &Context<'_>,
+        name: String,
+        redirect_uris: Vec<String>,
+    ) -> FieldResult<OAuthAppCredentials> {
+        let user = context.cx().user().await?;
+        let app = OAuthApp::create(context.cx().surreal(), &user, name, redirect_uris)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("app no makey"))?;
+        Ok(app.credentials())
+    }
+
+    async fn create_api_token(
+        &self,
+        context: &Context<'_>,
+        name: String,
+        scopes: Vec<Scope>,
+    ) -> FieldResult<CreatedApiToken> {
+        context.cx().require_session()?;
+        let user = context.cx().user().await?;
+        let (token, secret) =
+            ApiToken::create(context.cx().surreal(), &user, name.clone(), scopes, None).await?;
+        SecurityEvent::new(user.refer(), SecurityEventKind::ApiTokenCreated)
+            .with_detail(name)
+            .log(context.cx().surreal())
+            .await;
+        Ok(CreatedApiToken { token, secret })
+    }
+
+    async fn revoke_api_token(
+        &self,
+        context: &Context<'_>,
+        token: Ref<ApiToken>,
+    ) -> FieldResult<bool> {
+        context.cx().require_session()?;
+        let user = context.cx().ref_user()?;
+        let token = token.record_id();
+        let revoked: Vec<ApiToken> = context
+            .cx()
+            .surreal()
+            .query("UPDATE api_token SET revoked = true WHERE id = $token AND owner = $owner")
+            .bind(("token", token.0))
+            .bind(("owner", user.clone()))
+            .await?
+            .take(0)?;
+        if let Some(token) = revoked.first() {
+            SecurityEvent::new(user, SecurityEventKind::ApiTokenRevoked)
+                .with_detail(token.name.clone())
+                .log(context.cx().surreal())
+                .await;
+        }
+        Ok(!revoked.is_empty())
+    }
+
+    async fn set_avatar(&self, context: &Context<'_>, avatar: Upload) -> FieldResult<User> {
+        let user = context.cx().ref_user()?;
+        // decoding takes a while, and every avatar lookup waits on the lock
+        let avatar = storage::process_avatar_upload(avatar.value(context)?)
+            .await
+            .map_err(|e| e.extend())?;
+        context
+            .storage()
+            .write()
+            .await
+            .put_avatar(user.id().to_owned(), crate::storage::AvatarKind::U, avatar)
+            .await
+            .map_err(|e| e.extend())?;
 
         Ok(context.cx().user().await?)
     }
 
+    /// Uploads a file to attach to messages. It is scanned once stored, and comes back
+    /// `blocked` if it was flagged, or `held` until the scanner can be asked. `alt` describes
+    /// it for screen readers. `voice` sends audio as a voice message, with its `voiceNote` for
+    /// an inline player. Large files can be sent in resumable chunks over tus at
+    /// `/api/v1/uploads` instead.
+    async fn upload_attachment(
+        &self,
+        context: &Context<'_>,
+        file: Upload,
+        channel: Option<Ref<TextableChannel>>,
+        alt: Option<String>,
+        #[graphql(default)] voice: bool,
+    ) -> FieldResult<Attachment> {
+        let file = file.value(context)?;
+        Attachment::upload(
+            context.cx().surreal(),
+            context.relay(),
+            &*context.storage().read().await,
+            &context.cx().user().await?,
+            file,
+            AttachmentInit {
+                channel,
+                alt,
+                voice,
+            },
+        )
+        .await
+    }
+
+    /// Only for the uploader. A blank `alt` removes it.
+    async fn set_attachment_alt(
+        &self,
+        context: &Context<'_>,
+        attachment: Ref<Attachment>,
+        alt: Option<String>,
+    ) -> FieldResult<Attachment> {
+        let user = context.cx().ref_user()?;
+        Ok(Attachment::set_alt(context.cx().surreal(), &user, &attachment, alt).await?)
+    }
+
     async fn send_message(
         &self,
         context: &Context<'_>,
         message: MessageInit,
     ) -> FieldResult<Message> {
+        let user = context.cx().user().await?;
+        context.services().messages.send(&user, message).await
+    }
+
+    /// Sends a system DM about `message` at `at`, or in a few hours.
+    async fn remind_me(
+        &self,
+        context: &Context<'_>,
+        message: Ref<Message>,
+        at: Option<Timestamp>,
+        note: Option<String>,
+    ) -> FieldResult<Reminder> {
+        let user = context.cx().ref_user()?;
         Ok(context
-            .cx()
-            .user()
-            .await?
-            .send_message(context.cx().surreal(), context.relay(), message)
+            .services()
+            .messages
+            .remind(&user, &message, at.map(|at| at.0), note)
+            .await?)
+    }
+
+    async fn cancel_reminder(
+        &self,
+        context: &Context<'_>,
+        reminder: Ref<Reminder>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .messages
+            .cancel_reminder(&user, &reminder)
+            .await?)
+    }
+
+    /// Pinned messages are kept past the retention period. Needs `ManageMessages` in channels.
+    async fn pin_message(
+        &self,
+        context: &Context<'_>,
+        message: Ref<Message>,
+        #[graphql(default = true)] pinned: bool,
+    ) -> FieldResult<Message> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .messages
+            .set_pinned(&user, &message, pinned)
+            .await?)
+    }
+
+    /// Marks the conversation with `with` read up to `until`, or up to now.
+    async fn mark_read(
+        &self,
+        context: &Context<'_>,
+        with: MessageRecipientIn,
+        until: Option<Ref<Message>>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        context
+            .services()
+            .messages
+            .mark_read(&user, with, until)
+            .await?;
+        Ok(true)
+    }
+
+    /// Saves the sidebar order and folders, as `guildFolders`. Guilds left out go last.
+    async fn update_guild_positions(
+        &self,
+        context: &Context<'_>,
+        input: Vec<GuildFolder>,
+    ) -> FieldResult<Vec<GuildFolder>> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .update_positions(&user, input)
+            .await?)
+    }
+
+    /// Names the direct conversation with `with` just for the caller, as
+    /// `Conversation.displayName`. Blank names remove it.
+    async fn rename_conversation(
+        &self,
+        context: &Context<'_>,
+        with: MessageRecipientIn,
+        name: Option<String>,
+    ) -> FieldResult<Option<String>> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .messages
+            .rename_conversation(&user, with, name)
+            .await?)
+    }
+
+    /// Marks every channel of `guild` read.
+    async fn mark_guild_read(&self, context: &Context<'_>, guild: Ref<Guild>) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        context
+            .services()
+            .messages
+            .mark_guild_read(&user, &guild)
+            .await?;
+        Ok(true)
+    }
+
+    /// Marks all guilds and direct messages read.
+    async fn mark_all_read(&self, context: &Context<'_>) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        context.services().messages.mark_all_read(&user).await?;
+        Ok(true)
+    }
+
+    /// Sends a direct message to a `name#abcd` or `name#abcd@domain` address,
+    /// delivering it to the remote instance if needed.
+    async fn send_direct_message(
+        &self,
+        context: &Context<'_>,
+        to: String,
+        content: String,
+    ) -> FieldResult<Message> {
+        let user = context.cx().user().await?;
+        Ok(context
+            .services()
+            .messages
+            .send_direct(&user, &to, content)
             .await?)
     }
 
+    /// Fails with `INVALID_NAME` if the name is too short or long, or you're already in a guild
+    /// called that, and with `LIMIT_REACHED` if you're in as many guilds as you can be.
     async fn create_guild(&self, context: &Context<'_>, guild: GuildInit) -> FieldResult<Guild> {
         let user = context.cx().user().await?;
+        context.services().guilds.create(&user, guild).await
+    }
 
-        Guild::create(context.cx().surreal(), &user, guild).await
+    /// Joins the guild an invite `code` is for, see `invitePreview`. Already being a member
+    /// doesn't use the invite up. Guilds with the `APPLICATION` constraint need `answers` to
+    /// their `applicationQuestions`, in order, and only let you in once a moderator approves,
+    /// see `Guild.myApplication`. Accounts too new for `JUST_REGISTERED` fail with
+    /// `ACCOUNT_TOO_NEW`, and `retryAfter` in seconds. Full guilds, or being in too many
+    /// already, fail with `LIMIT_REACHED`.
+    async fn join_guild(
+        &self,
+        context: &Context<'_>,
+        code: String,
+        #[graphql(default)] answers: Vec<String>,
+    ) -> FieldResult<Guild> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .join(&user, &code, answers)
+            .await?)
     }
 
     async fn set_status(&self, context: &Context<'_>, status: Status) -> FieldResult<User> {
-        let mut user = context.cx().user().await?;
-        user.status = status;
-        Ok(user.save(context.cx().surreal()).await?)
+        let user = context.cx().user().await?;
+        Ok(context.services().users.set_status(user, status).await?)
     }
 
     async fn manage_message(
         &self,
         cx: &Context<'_>,
-        message: ID,
+        message: Ref<Message>,
     ) -> FieldResult<Option<ManageMessage>> {
-        let m: Option<_> = SURREAL
-            .select(message.as_str().parse::<RecordId>()?.0)
-            .await?;
+        let m = cx.services().messages.get(&message.record_id()).await?;
         Ok(if let Some(m) = m {
             Some(ManageMessage::new(cx.cx().user().await?, m))
         } else {
             None
         })
     }
+
+    /// `null` unless you're a member. Says what you may do to the guild, and does it.
+    async fn manage_guild(
+        &self,
+        cx: &Context<'_>,
+        guild: Ref<Guild>,
+    ) -> FieldResult<Option<ManageGuild>> {
+        let user = cx.cx().ref_user()?;
+        Ok(ManageGuild::new(cx.cx().surreal(), user, &guild).await?)
+    }
+
+    /// `null` unless you're a member of its guild who can see it. Says what you may do to the
+    /// channel, and does it.
+    async fn manage_channel(
+        &self,
+        cx: &Context<'_>,
+        channel: Ref<Channel>,
+    ) -> FieldResult<Option<ManageChannel>> {
+        let user = cx.cx().ref_user()?;
+        Ok(ManageChannel::new(cx.cx().surreal(), user, &channel).await?)
+    }
+
+    /// Connects to voice in `channel`, or updates what you're doing there.
+    async fn update_voice_state(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<Channel>,
+        flags: VoiceFlags,
+    ) -> FieldResult<VoiceState> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .join_voice(&user, &channel, flags)
+            .await?)
+    }
+
+    async fn leave_voice(&self, context: &Context<'_>) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context.services().guilds.leave_voice(&user).await?)
+    }
+
+    async fn server_mute(
+        &self,
+        context: &Context<'_>,
+        user: Ref<User>,
+        muted: bool,
+    ) -> FieldResult<VoiceState> {
+        let moderator = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .server_mute(&moderator, &user, muted)
+            .await?)
+    }
+
+    /// Renames the channel or changes its topic, NSFW or private flag. Needs `ManageChannels`.
+    /// Names are checked like in `createChannel`.
+    async fn update_channel(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<Channel>,
+        update: ChannelUpdate,
+    ) -> FieldResult<Channel> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .update_channel(&user, &channel, update)
+            .await?)
+    }
+
+    /// Needs `ManageChannels`. Subscriptions following the channel complete.
+    async fn delete_channel(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<Channel>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .delete_channel(&user, &channel)
+            .await?)
+    }
+
+    /// Needs `ManageWebhooks`. The URL to post to is only shown here.
+    async fn create_webhook(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<Channel>,
+        name: String,
+        avatar_url: Option<String>,
+    ) -> FieldResult<CreatedWebhook> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .create_webhook(&user, &channel, name, avatar_url)
+            .await?)
+    }
+
+    /// Needs `ManageWebhooks`. Messages it posted stay.
+    async fn delete_webhook(
+        &self,
+        context: &Context<'_>,
+        webhook: Ref<Webhook>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .delete_webhook(&user, &webhook)
+            .await?)
+    }
+
+    /// Needs `ManageEmojis`. Takes the same images as avatars, animated ones included.
+    async fn create_emoji(
+        &self,
+        context: &Context<'_>,
+        guild: Ref<Guild>,
+        name: String,
+        image: Upload,
+    ) -> FieldResult<GuildEmoji> {
+        let user = context.cx().ref_user()?;
+        let image = storage::process_avatar_upload(image.value(context)?)
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(context
+            .services()
+            .guilds
+            .create_emoji(context.storage(), &user, &guild, name, image)
+            .await?)
+    }
+
+    /// Needs `ManageEmojis`.
+    async fn delete_emoji(
+        &self,
+        context: &Context<'_>,
+        emoji: Ref<GuildEmoji>,
+    ) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .delete_emoji(&user, &emoji)
+            .await?)
+    }
+
+    /// Needs `ManageServer`. Messages older than `days` are removed, `null` goes by the
+    /// instance's retention.
+    async fn set_guild_retention(
+        &self,
+        context: &Context<'_>,
+        guild: Ref<Guild>,
+        days: Option<u32>,
+    ) -> FieldResult<Guild> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .set_retention(&user, &guild, days)
+            .await?)
+    }
+
+    /// Needs `ManageWebhooks`. Only entries published after this are posted.
+    async fn add_feed(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<Channel>,
+        url: String,
+    ) -> FieldResult<Feed> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .add_feed(&user, &channel, url)
+            .await?)
+    }
+
+    /// Needs `ManageWebhooks`.
+    async fn remove_feed(&self, context: &Context<'_>, feed: Ref<Feed>) -> FieldResult<bool> {
+        let user = context.cx().ref_user()?;
+        Ok(context.services().guilds.remove_feed(&user, &feed).await?)
+    }
+
+    /// Moves someone already in voice to another channel of the same guild.
+    async fn move_to_channel(
+        &self,
+        context: &Context<'_>,
+        user: Ref<User>,
+        channel: Ref<Channel>,
+    ) -> FieldResult<VoiceState> {
+        let moderator = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .move_to_channel(&moderator, &user, channel)
+            .await?)
+    }
 }
 
 pub struct SubscriptionRoot;
 
 #[Subscription]
 impl SubscriptionRoot {
+    /// Direct messages to the user.
     async fn messages(&self, context: &Context<'_>) -> Result<impl Stream<Item = Message>> {
         let user = context.cx().ref_user()?;
 
-        let messages_stream = context.relay().stream_sent_messages().await;
+        Ok(context.relay().stream_messages(vec![user.record_id().0]))
+    }
 
-        Ok(messages_stream.filter(move |message| {
-            future::ready(matches!(
-                &message.recipient,
-                MessageRecipient::User(ref recipient) if recipient.id() == user.id()
-            ))
-        }))
+    /// Messages sent to `channels`, at most 200 of them. Resubscribe to follow others.
+    async fn channel_messages(
+        &self,
+        context: &Context<'_>,
+        channels: Vec<Ref<TextableChannel>>,
+    ) -> Result<impl Stream<Item = Message>> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .messages
+            .watch_channels(&user, channels)
+            .await?)
     }
-}
 
-pub type Schema = async_graphql::Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+    /// Lazily loads `channel`'s member list: a chunk per range of `ranges`, like `[[0, 99]]`,
+    /// then again whenever one changes. At most 5 ranges of 100 members.
+    async fn member_list(
+        &self,
+        context: &Context<'_>,
+        channel: Ref<TextableChannel>,
+        ranges: Vec<Vec<i32>>,
+    ) -> Result<impl Stream<Item = MemberChunk>> {
+        let user = context.cx().ref_user()?;
+        Ok(context
+            .services()
+            .guilds
+            .watch_member_list(&user, &channel, ranges)
+            .await?)
+    }
 
-pub fn schema() -> Schema {
-    schema_builder().finish()
+    /// What the user marks read on their other devices, to clear the same badges here.
+    async fn read_states(&self, context: &Context<'_>) -> Result<impl Stream<Item = ReadState>> {
+        let user = context.cx().ref_user()?;
+        Ok(context.relay().stream_read_states(&user))
+    }
+
+    /// Joins, leaves and flag changes of everyone in voice in `guild`.
+    async fn voice_states(
+        &self,
+        context: &Context<'_>,
+        guild: Ref<Guild>,
+    ) -> Result<impl Stream<Item = VoiceState>> {
+        let user = context.cx().ref_user()?;
+        let states = context.services().guilds.watch_voice(&user, &guild).await?;
+
+        Ok(states.filter(move |state| future::ready(state.guild == guild)))
+    }
 }
 
-pub fn schema_builder() -> SchemaBuilder<QueryRoot, MutationRoot, SubscriptionRoot> {
-    async_graphql::Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+pub type Schema = async_graphql::Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+fn schema_builder() -> SchemaBuilder<QueryRoot, MutationRoot, SubscriptionRoot> {
+    let builder = async_graphql::Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
         .extension(async_graphql::extensions::Logger)
+        .extension(terms::TermsGate)
+        .extension(permission::PermissionErrors)
+        .extension(scope::ScopeGate)
+        .extension(trace::Tracing);
+    let builder = if allowlist::enforced() {
+        builder.extension(allowlist::AllowList)
+    } else {
+        builder
+    };
+    if CONFIG.production {
+        builder.disable_introspection()
+    } else {
+        builder
+    }
 }
 
 lazy_static::lazy_static! {
-    pub static ref SCHEMA: Schema = schema();
+    /// The one schema, serving requests as well as `sdl` and its snapshot test.
+    pub static ref SCHEMA: Schema = schema_builder().finish();
 }
@@ -0,0 +1,50 @@
+//! Turns a failed `Member::require_permission` into something clients can act on:
+//! `extensions: { code: "MISSING_PERMISSION", permission: "ManageChannels", guild: "guild:x" }`.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextResolve, ResolveInfo},
+    ServerError, ServerResult, Value,
+};
+
+use crate::model::guild::MissingPermission;
+
+pub struct PermissionErrors;
+
+impl ExtensionFactory for PermissionErrors {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(PermissionErrors)
+    }
+}
+
+/// Resolvers hand back permission errors either as they are or still wrapped in the
+/// [tide::Error] the services return.
+fn missing_permission(error: &ServerError) -> Option<&MissingPermission> {
+    error.source::<MissingPermission>().or_else(|| {
+        error
+            .source::<tide::Error>()
+            .and_then(|e| e.downcast_ref::<MissingPermission>())
+    })
+}
+
+#[async_trait::async_trait]
+impl Extension for PermissionErrors {
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        next.run(ctx, info).await.map_err(|mut error| {
+            if let Some(missing) = missing_permission(&error).cloned() {
+                let extensions = error.extensions.get_or_insert_with(Default::default);
+                extensions.set("code", "MISSING_PERMISSION");
+                extensions.set("permission", format!("{:?}", missing.permission));
+                extensions.set("guild", missing.guild.record_id().to_string());
+            }
+            error
+        })
+    }
+}
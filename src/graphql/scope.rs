@@ -0,0 +1,160 @@
+//! What an API token may reach. Every root field declares the [Scope]s it needs here, and
+//! tokens are refused any field that doesn't, so new fields are session-only until someone
+//! decides otherwise. Sessions aren't restricted.
+//!
+//! Nested fields that need more than their root field, like a guild's messages, still call
+//! [State::require_scope](crate::http::State::require_scope) themselves.
+
+use std::{collections::HashSet, sync::Arc};
+
+use async_graphql::{
+    async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery},
+    parser::types::{ExecutableDocument, OperationType, Selection, SelectionSet},
+    ServerError, ServerResult, Variables,
+};
+
+use crate::{
+    http::State,
+    model::token::{ApiTokenAuth, Scope},
+};
+
+use Scope::*;
+
+/// The scopes a token needs for `field` of the `operation` root, `None` if tokens may not use
+/// it at all.
+fn required(operation: OperationType, field: &str) -> Option<&'static [Scope]> {
+    let scopes: &'static [Scope] = match (operation, field) {
+        (_, "__typename" | "__schema" | "__type") => &[],
+
+        (OperationType::Query, "serverConfiguration" | "terms" | "invitePreview") => &[],
+        (OperationType::Query, "me") => &[Identify],
+        // these load whatever the id is of: users, guilds, channels or messages
+        (OperationType::Query, "node" | "byId") => &[Identify, Guilds, MessagesRead],
+        (
+            OperationType::Query,
+            "conversations" | "conversationDirect" | "inbox" | "quickSearch" | "reminders",
+        ) => &[MessagesRead],
+        (OperationType::Query, "searchEmoji" | "searchGifs") => &[MessagesSend],
+        (OperationType::Query, "guilds" | "guildFolders") => &[Guilds],
+
+        (
+            OperationType::Mutation,
+            "sendMessage" | "sendDirectMessage" | "manageMessage" | "pinMessage"
+            | "uploadAttachment" | "setAttachmentAlt",
+        ) => &[MessagesSend],
+        (
+            OperationType::Mutation,
+            "markRead" | "markGuildRead" | "markAllRead" | "remindMe" | "cancelReminder",
+        ) => &[MessagesRead],
+        (OperationType::Mutation, "updateGuildPositions") => &[Guilds],
+        (
+            OperationType::Mutation,
+            "createGuild" | "joinGuild" | "manageGuild" | "manageChannel" | "updateChannel"
+            | "deleteChannel" | "createWebhook" | "deleteWebhook" | "createEmoji"
+            | "deleteEmoji" | "setGuildRetention" | "addFeed" | "removeFeed",
+        ) => &[GuildsManage],
+
+        (OperationType::Subscription, "messages" | "channelMessages" | "readStates") => {
+            &[MessagesRead]
+        }
+        (OperationType::Subscription, "memberList" | "voiceStates") => &[Guilds],
+
+        _ => return None,
+    };
+    Some(scopes)
+}
+
+/// The root fields `selection_set` selects, through fragments too.
+fn root_fields<'a>(
+    document: &'a ExecutableDocument,
+    selection_set: &'a SelectionSet,
+    seen: &mut HashSet<&'a str>,
+    fields: &mut Vec<&'a str>,
+) {
+    for selection in &selection_set.items {
+        match &selection.node {
+            Selection::Field(field) => fields.push(field.node.name.node.as_str()),
+            Selection::InlineFragment(fragment) => {
+                root_fields(document, &fragment.node.selection_set.node, seen, fields)
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.as_str();
+                // cycles are refused by validation, which comes after this
+                if !seen.insert(name) {
+                    continue;
+                }
+                if let Some(fragment) = document.fragments.get(&spread.node.fragment_name.node) {
+                    root_fields(document, &fragment.node.selection_set.node, seen, fields);
+                }
+            }
+        }
+    }
+}
+
+/// The first root field of `document` that `token` may not use, with the scope it's missing,
+/// `None` for fields no token may use.
+fn denied<'a>(
+    document: &'a ExecutableDocument,
+    token: &ApiTokenAuth,
+) -> Option<(&'a str, Option<Scope>)> {
+    document.operations.iter().find_map(|(_, operation)| {
+        let mut fields = vec![];
+        root_fields(
+            document,
+            &operation.node.selection_set.node,
+            &mut HashSet::new(),
+            &mut fields,
+        );
+        fields.into_iter().find_map(|field| {
+            match required(operation.node.ty, field) {
+                None => Some((field, None)),
+                Some(scopes) => scopes
+                    .iter()
+                    .find(|scope| !token.scopes.contains(scope))
+                    .map(|scope| (field, Some(*scope))),
+            }
+        })
+    })
+}
+
+pub struct ScopeGate;
+
+impl ExtensionFactory for ScopeGate {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ScopeGate)
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for ScopeGate {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        let Some(token) = ctx
+            .data_opt::<State>()
+            .and_then(|state| state.api_token.as_ref())
+        else {
+            return Ok(document);
+        };
+        if let Some((field, missing)) = denied(&document, token) {
+            let message = match missing {
+                Some(scope) => format!("{field} needs the {} scope", scope.name()),
+                None => format!("{field} needs a session, not an api token"),
+            };
+            let mut error = ServerError::new(message, None);
+            let extensions = error.extensions.get_or_insert_with(Default::default);
+            extensions.set("code", "MISSING_SCOPE");
+            if let Some(scope) = missing {
+                extensions.set("scope", scope.name());
+            }
+            return Err(error);
+        }
+        Ok(document)
+    }
+}
@@ -0,0 +1,56 @@
+//! Holds back every mutation but `acceptTerms` while the user hasn't accepted the current
+//! terms of service. Queries still work, so clients can show the terms.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextResolve, ResolveInfo},
+    ServerError, ServerResult, Value,
+};
+
+use crate::{http::State, model::terms::Terms};
+
+pub struct TermsGate;
+
+impl ExtensionFactory for TermsGate {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(TermsGate)
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for TermsGate {
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        if info.parent_type == "MutationRoot" && info.name != "acceptTerms" {
+            if let Some(state) = ctx
+                .data_opt::<State>()
+                .filter(|state| state.uid().is_some())
+            {
+                let must_accept = async {
+                    let user = state.user().await?;
+                    tide::Result::Ok(Terms::must_accept(state.surreal(), &user).await?)
+                }
+                .await
+                .map_err(|e| ServerError::new(e.to_string(), None))?;
+                if must_accept {
+                    let mut error = ServerError::new(
+                        "the terms of service changed, acceptTerms before doing anything else",
+                        None,
+                    );
+                    error
+                        .extensions
+                        .get_or_insert_with(Default::default)
+                        .set("code", "TERMS_NOT_ACCEPTED");
+                    return Err(error);
+                }
+            }
+        }
+        next.run(ctx, info).await
+    }
+}
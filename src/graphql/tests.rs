@@ -0,0 +1,126 @@
+//! The schema against its `schema.graphql` snapshot, and a few queries against the
+//! [seed](crate::seed) data. Those need SurrealDB at `NETHERITE_CHAT_SURREALDB_URL` and are
+//! ignored unless run with `just test-db`; they seed a `test` tenant, never the default one.
+
+use async_graphql::{Request, Response, Value};
+
+use crate::{
+    auth,
+    config::CONFIG,
+    http::State,
+    seed,
+    tenant::{self, Tenant},
+    util::RecordId,
+};
+
+use super::SCHEMA;
+
+const SNAPSHOT: &str = "schema.graphql";
+
+/// Renames and nullability changes break clients, so they have to show up in the snapshot.
+/// `just schema` updates it after deliberate changes.
+#[test]
+fn schema_matches_snapshot() {
+    let sdl = SCHEMA.sdl();
+    match std::fs::read_to_string(SNAPSHOT) {
+        Ok(snapshot) => assert!(
+            snapshot == sdl,
+            "the schema changed, run `just schema` and commit {SNAPSHOT} if that was on purpose"
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            panic!("{SNAPSHOT} is missing, run `just schema` and commit it")
+        }
+        Err(e) => panic!("couldn't read {SNAPSHOT}: {e}"),
+    }
+}
+
+async fn seeded() -> &'static Tenant {
+    let tenant = tenant::connect_test().await.unwrap();
+    seed::run(tenant).await.unwrap();
+    tenant
+}
+
+/// Runs `query` as `user`, the email of a seeded one, or without a token.
+async fn execute(tenant: &'static Tenant, user: Option<&str>, query: &str) -> Response {
+    let token = match user {
+        Some(email) => {
+            let uid: Option<RecordId> = tenant
+                .surreal()
+                .query("SELECT VALUE id FROM user WHERE email = $email")
+                .bind(("email", email))
+                .await
+                .unwrap()
+                .take(0)
+                .unwrap();
+            Some(auth::test_token(tenant, uid.unwrap()).await.unwrap())
+        }
+        None => None,
+    };
+    let mut request = Request::new(query).data(State {
+        token,
+        api_token: None,
+        tenant,
+    });
+    tenant.insert_data(&mut request.data);
+    SCHEMA.execute(request).await
+}
+
+fn expect_data(response: Response) -> serde_json::Value {
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    response.data.into_json().unwrap()
+}
+
+#[async_std::test]
+#[ignore = "needs SurrealDB, see `just test-db`"]
+async fn server_configuration_needs_no_token() {
+    let tenant = seeded().await;
+    let data = expect_data(execute(tenant, None, "{ serverConfiguration { domain } }").await);
+    assert_eq!(
+        data["serverConfiguration"]["domain"],
+        CONFIG.domain.as_str()
+    );
+}
+
+#[async_std::test]
+#[ignore = "needs SurrealDB, see `just test-db`"]
+async fn me_needs_a_token() {
+    let tenant = seeded().await;
+    let response = execute(tenant, None, "{ me { user { displayName } } }").await;
+    assert!(!response.errors.is_empty());
+    assert_eq!(response.data, Value::Null);
+}
+
+#[async_std::test]
+#[ignore = "needs SurrealDB, see `just test-db`"]
+async fn me_is_who_the_token_is_for() {
+    let tenant = seeded().await;
+    let data = expect_data(
+        execute(
+            tenant,
+            Some("alice@seed.local"),
+            "{ me { user { displayName } } }",
+        )
+        .await,
+    );
+    assert_eq!(data["me"]["user"]["displayName"], "Alice");
+}
+
+#[async_std::test]
+#[ignore = "needs SurrealDB, see `just test-db`"]
+async fn guilds_lists_the_seeded_guild() {
+    let tenant = seeded().await;
+    let data = expect_data(
+        execute(
+            tenant,
+            Some("bob@seed.local"),
+            "{ guilds { name channels { __typename } } }",
+        )
+        .await,
+    );
+    let guilds = data["guilds"].as_array().unwrap();
+    let demo = guilds
+        .iter()
+        .find(|guild| guild["name"] == "Netherite Demo")
+        .expect("Bob is a member of the demo guild");
+    assert!(demo["channels"].as_array().unwrap().len() >= 4);
+}
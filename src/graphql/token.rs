@@ -0,0 +1,32 @@
+use async_graphql::*;
+
+use crate::{
+    model::token::{ApiToken, Scope},
+    util::{Cx, RecordId, ReferrableExt, Timestamp},
+};
+
+#[Object]
+impl ApiToken {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    async fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+    async fn revoked(&self) -> bool {
+        self.revoked
+    }
+    /// The name of the app this token was granted to, if any.
+    async fn app(&self, cx: &Context<'_>) -> Result<Option<String>> {
+        Ok(match self.app {
+            Some(ref app) => Some(app.fetch(cx.cx().surreal()).await?.name),
+            None => None,
+        })
+    }
+}
@@ -0,0 +1,157 @@
+//! Apollo-style tracing for hunting N+1s in production: with the [HEADER] on the request,
+//! admins get how long every field took and how many queries it made, in the `tracing`
+//! response extension. Everyone else gets the usual response.
+//!
+//! Queries are counted as described at [count_query](crate::util::query::count_query).
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use async_graphql::{
+    async_trait,
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextResolve, ResolveInfo,
+    },
+    Response, ServerResult, Value,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{http::State, model::user::Badge, util::query::Counted};
+
+pub const HEADER: &str = "x-netherite-trace";
+
+/// Put in the request data when the request has the [HEADER].
+pub struct TraceRequested;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResolverTrace {
+    path: Vec<String>,
+    parent_type: String,
+    field_name: String,
+    return_type: String,
+    /// Nanoseconds since the operation started.
+    start_offset: u64,
+    duration: u64,
+    /// Made by this field itself, not by the fields below it.
+    queries: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace {
+    version: u8,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    duration: u64,
+    queries: u64,
+    execution: Execution,
+}
+
+#[derive(Serialize)]
+struct Execution {
+    resolvers: Vec<ResolverTrace>,
+}
+
+pub struct Tracing;
+
+impl ExtensionFactory for Tracing {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(TracingExtension {
+            enabled: AtomicBool::new(false),
+            started: Instant::now(),
+            resolvers: Mutex::new(vec![]),
+        })
+    }
+}
+
+struct TracingExtension {
+    enabled: AtomicBool,
+    started: Instant,
+    resolvers: Mutex<Vec<ResolverTrace>>,
+}
+
+async fn requested_by_admin(ctx: &ExtensionContext<'_>) -> bool {
+    if ctx.data_opt::<TraceRequested>().is_none() {
+        return false;
+    }
+    let Some(state) = ctx
+        .data_opt::<State>()
+        .filter(|state| state.uid().is_some())
+    else {
+        return false;
+    };
+    state
+        .user()
+        .await
+        .is_ok_and(|user| user.badges.contains(&Badge::Admin))
+}
+
+#[async_trait::async_trait]
+impl Extension for TracingExtension {
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        if !requested_by_admin(ctx).await {
+            return next.run(ctx, operation_name).await;
+        }
+        self.enabled.store(true, Ordering::Relaxed);
+
+        let start_time = Utc::now();
+        let started = Instant::now();
+        let queries = Arc::new(AtomicU64::new(0));
+        let response = Counted::new(next.run(ctx, operation_name), queries.clone()).await;
+        let resolvers = std::mem::take(&mut *self.resolvers.lock().unwrap());
+        let trace = Trace {
+            version: 1,
+            start_time,
+            end_time: Utc::now(),
+            duration: started.elapsed().as_nanos() as u64,
+            // the resolvers counted theirs apart
+            queries: queries.load(Ordering::Relaxed)
+                + resolvers.iter().map(|trace| trace.queries).sum::<u64>(),
+            execution: Execution { resolvers },
+        };
+        match async_graphql::to_value(trace) {
+            Ok(trace) => response.extension("tracing", trace),
+            Err(_) => response,
+        }
+    }
+
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        if !self.enabled.load(Ordering::Relaxed) || info.is_for_introspection {
+            return next.run(ctx, info).await;
+        }
+
+        let mut trace = ResolverTrace {
+            path: info.path_node.to_string_vec(),
+            parent_type: info.parent_type.to_owned(),
+            field_name: info.name.to_owned(),
+            return_type: info.return_type.to_owned(),
+            start_offset: self.started.elapsed().as_nanos() as u64,
+            duration: 0,
+            queries: 0,
+        };
+        let started = Instant::now();
+        let queries = Arc::new(AtomicU64::new(0));
+        let result = Counted::new(next.run(ctx, info), queries.clone()).await;
+        trace.duration = started.elapsed().as_nanos() as u64;
+        trace.queries = queries.load(Ordering::Relaxed);
+        self.resolvers.lock().unwrap().push(trace);
+        result
+    }
+}
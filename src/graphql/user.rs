@@ -1,14 +1,21 @@
 use async_graphql::*;
 
 use crate::{
-    model::user::{Badge, Status, User, Theme},
-    util::{Cx, ReferrableWithId},
+    federation::Address,
+    model::{
+        linked_account::{LinkedAccount, LinkedAccountKind, LinkedAccountVisibility},
+        security_event::SecurityEvent,
+        terms::Terms,
+        user::{Badge, NotificationSettings, PrivacySettings, Status, User, Theme},
+    },
+    storage::{AvatarKind, AvatarUrls},
+    util::{Cx, RecordId, ReferrableExt, ReferrableWithId, Timestamp},
 };
 
 #[Object]
 impl User {
-    async fn id(&self) -> ID {
-        ID(self.id.id.to_string())
+    pub async fn id(&self) -> RecordId {
+        self.gql_id()
     }
     async fn tag_name(&self) -> &str {
         &self.tag.0
@@ -22,12 +29,27 @@ impl User {
     async fn display_name(&self) -> &str {
         &self.display_name
     }
+    async fn bot(&self) -> bool {
+        self.bot
+    }
+    /// `null` until the user uploads one.
+    async fn avatar(&self, context: &Context<'_>) -> Option<AvatarUrls> {
+        context
+            .storage()
+            .read()
+            .await
+            .get_avatar_urls(<Self as ReferrableWithId>::id(self).clone(), AvatarKind::U)
+    }
+    /// The federated address of this user, `name#abcd@domain`.
+    async fn address(&self) -> String {
+        Address::of(self).to_string()
+    }
 
     async fn friends(&self, context: &Context<'_>) -> FieldResult<Vec<User>> {
         if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
             return Ok(vec![]);
         }
-        Ok(self.get_friends(context.cx().surreal()).await?)
+        Ok(context.services().users.friends(self).await?)
     }
 
     async fn badges(&self) -> &[Badge] {
@@ -40,4 +62,145 @@ impl User {
     async fn theme(&self) -> Theme {
         self.theme
     }
+
+    async fn bio(&self) -> Option<&str> {
+        self.bio.as_deref()
+    }
+
+    /// The viewer's private note on this user, `null` on themselves or without one.
+    async fn note(&self, context: &Context<'_>) -> FieldResult<Option<String>> {
+        let viewer = context.cx().ref_user()?;
+        if viewer == self.refer() {
+            return Ok(None);
+        }
+        Ok(context
+            .services()
+            .users
+            .note(&viewer, &self.refer())
+            .await?)
+    }
+
+    /// Accounts elsewhere, as far as their visibility lets the viewer see them.
+    async fn connections(&self, context: &Context<'_>) -> FieldResult<Vec<LinkedAccount>> {
+        let viewer = context.cx().ref_user()?;
+        Ok(context.services().users.connections(self, &viewer).await?)
+    }
+
+    /// To within a few minutes. `null` if they never logged in since this was
+    /// recorded, or hide it from others in their privacy settings.
+    async fn last_seen_at(&self, context: &Context<'_>) -> FieldResult<Option<Timestamp>> {
+        let viewer = context.cx().ref_user()?;
+        if viewer != self.refer() && !self.privacy.show_last_seen {
+            return Ok(None);
+        }
+        Ok(self.last_seen_at.as_ref().map(|at| Timestamp(at.0)))
+    }
+
+    /// Only visible to the user themselves.
+    async fn privacy(&self, context: &Context<'_>) -> FieldResult<Option<&PrivacySettings>> {
+        if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
+            return Ok(None);
+        }
+        Ok(Some(&self.privacy))
+    }
+
+    /// Logins, refreshes and revocations on this account, newest first. Only visible to the
+    /// user themselves.
+    async fn security_events(
+        &self,
+        context: &Context<'_>,
+        #[graphql(default = 50)] limit: i64,
+    ) -> FieldResult<Option<Vec<SecurityEvent>>> {
+        if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
+            return Ok(None);
+        }
+        Ok(Some(
+            context
+                .services()
+                .users
+                .security_events(&self.refer(), limit)
+                .await?,
+        ))
+    }
+
+    /// Only visible to the user themselves.
+    async fn notifications(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<&NotificationSettings>> {
+        if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
+            return Ok(None);
+        }
+        Ok(Some(&self.notifications))
+    }
+
+    /// Only visible to the user themselves. Guilds with the `PHONE` constraint want it.
+    async fn phone_verified(&self, context: &Context<'_>) -> FieldResult<Option<bool>> {
+        if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
+            return Ok(None);
+        }
+        Ok(Some(self.phone_hash.is_some()))
+    }
+
+    /// Only visible to the user themselves. While set, every mutation but `acceptTerms`
+    /// fails with `TERMS_NOT_ACCEPTED`.
+    async fn must_accept_terms(&self, context: &Context<'_>) -> FieldResult<Option<bool>> {
+        if context.cx().ref_user()?.id() != <Self as ReferrableWithId>::id(self) {
+            return Ok(None);
+        }
+        let must_accept = context.services().users.must_accept_terms(self).await?;
+        Ok(Some(must_accept))
+    }
+}
+
+#[Object]
+impl LinkedAccount {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn kind(&self) -> LinkedAccountKind {
+        self.kind
+    }
+    /// Username, SteamID64 or URL.
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    async fn verified(&self) -> bool {
+        self.verified
+    }
+    async fn visibility(&self) -> LinkedAccountVisibility {
+        self.visibility
+    }
+    /// Only visible to the owner. What the website's `netherite-verification` meta tag has to
+    /// contain.
+    async fn code(&self, context: &Context<'_>) -> FieldResult<Option<&str>> {
+        if context.cx().ref_user()? != self.user {
+            return Ok(None);
+        }
+        Ok(Some(&self.code))
+    }
+    /// Only visible to the owner. Where to log in to verify a GitHub or Steam account.
+    async fn verify_url(&self, context: &Context<'_>) -> FieldResult<Option<String>> {
+        if context.cx().ref_user()? != self.user || self.verified {
+            return Ok(None);
+        }
+        Ok(self.verification_url())
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl Terms {
+    async fn version(&self) -> u32 {
+        self.version
+    }
+    /// Markdown.
+    async fn content(&self) -> &str {
+        &self.content
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
 }
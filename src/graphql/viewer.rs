@@ -0,0 +1,150 @@
+use async_graphql::*;
+
+use crate::{
+    model::{
+        security_event::SecurityEvent,
+        user::{Friend, NotificationSettings, PrivacySettings, RelationshipKind, Theme, User},
+    },
+    util::{Cx, ReferrableExt, Timestamp},
+};
+
+use super::login::Sessions;
+
+/// The logged in user's own account, with what only they may see. What others see of them
+/// is `user`.
+pub struct Viewer(pub User);
+
+#[Object]
+impl Viewer {
+    async fn user(&self) -> &User {
+        &self.0
+    }
+
+    async fn friends(&self, cx: &Context<'_>) -> Result<Vec<User>> {
+        Ok(cx.services().users.friends(&self.0).await?)
+    }
+
+    /// Everyone you're related to and how, for the friends page in one go. Earliest first.
+    async fn relationships(&self, cx: &Context<'_>) -> Result<Vec<Friend>> {
+        Ok(cx.services().users.relationships(&self.0).await?)
+    }
+
+    async fn notification_settings(&self) -> &NotificationSettings {
+        &self.0.notifications
+    }
+
+    async fn privacy_settings(&self) -> &PrivacySettings {
+        &self.0.privacy
+    }
+
+    /// Not for API tokens.
+    async fn sessions(&self, cx: &Context<'_>) -> Result<Sessions> {
+        cx.cx().require_session()?;
+        Ok(Sessions(self.0.refer()))
+    }
+
+    /// Logins, refreshes and revocations on this account, newest first. Not for API tokens.
+    async fn security_events(
+        &self,
+        cx: &Context<'_>,
+        #[graphql(default = 50)] limit: i64,
+    ) -> Result<Vec<SecurityEvent>> {
+        cx.cx().require_session()?;
+        Ok(cx
+            .services()
+            .users
+            .security_events(&self.0.refer(), limit)
+            .await?)
+    }
+
+    /// Guilds with the `PHONE` constraint want it.
+    async fn phone_verified(&self) -> bool {
+        self.0.phone_hash.is_some()
+    }
+
+    /// While set, every mutation but `acceptTerms` fails with `TERMS_NOT_ACCEPTED`.
+    async fn must_accept_terms(&self, cx: &Context<'_>) -> Result<bool> {
+        Ok(cx.services().users.must_accept_terms(&self.0).await?)
+    }
+}
+
+#[Object(name = "Relationship")]
+impl Friend {
+    async fn user(&self) -> &User {
+        &self.user
+    }
+
+    async fn kind(&self) -> RelationshipKind {
+        RelationshipKind::Friend
+    }
+
+    /// `null` for friendships older than when this was recorded.
+    async fn since(&self) -> Option<Timestamp> {
+        self.friended_at.as_ref().map(|at| Timestamp(at.0))
+    }
+
+    /// Whoever added the other, `user` or you.
+    async fn requester(&self, cx: &Context<'_>) -> Result<User> {
+        if self.requester == self.user.refer() {
+            return Ok(self.user.clone());
+        }
+        Ok(self.requester.fetch(cx.cx().surreal()).await?)
+    }
+}
+
+/// Changes to the logged in user's own account.
+pub struct ManageViewer(pub User);
+
+#[Object]
+impl ManageViewer {
+    async fn set_theme(&self, cx: &Context<'_>, theme: Theme) -> Result<Viewer> {
+        let user = cx.services().users.set_theme(self.0.clone(), theme).await?;
+        Ok(Viewer(user))
+    }
+
+    async fn set_display_name(&self, cx: &Context<'_>, name: String) -> Result<Viewer> {
+        let user = cx
+            .services()
+            .users
+            .set_display_name(self.0.clone(), name)
+            .await?;
+        Ok(Viewer(user))
+    }
+
+    /// Changes the part of the tag before the `#`.
+    async fn set_tag_name(&self, cx: &Context<'_>, name: String) -> Result<Viewer> {
+        let user = cx
+            .services()
+            .users
+            .set_tag_name(self.0.clone(), name)
+            .await?;
+        Ok(Viewer(user))
+    }
+
+    async fn set_bio(&self, cx: &Context<'_>, bio: Option<String>) -> Result<Viewer> {
+        let user = cx.services().users.set_bio(self.0.clone(), bio).await?;
+        Ok(Viewer(user))
+    }
+
+    async fn set_privacy(&self, cx: &Context<'_>, privacy: PrivacySettings) -> Result<Viewer> {
+        let user = cx
+            .services()
+            .users
+            .set_privacy(self.0.clone(), privacy)
+            .await?;
+        Ok(Viewer(user))
+    }
+
+    async fn set_notifications(
+        &self,
+        cx: &Context<'_>,
+        notifications: NotificationSettings,
+    ) -> Result<Viewer> {
+        let user = cx
+            .services()
+            .users
+            .set_notifications(self.0.clone(), notifications)
+            .await?;
+        Ok(Viewer(user))
+    }
+}
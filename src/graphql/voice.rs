@@ -0,0 +1,19 @@
+use async_graphql::*;
+
+use crate::{
+    model::{user::User, voice::VoiceState},
+    util::{Cx, RecordId},
+};
+
+#[ComplexObject]
+impl VoiceState {
+    async fn user(&self, cx: &Context<'_>) -> Result<User> {
+        Ok(self.user.fetch(cx.cx().surreal()).await?)
+    }
+    async fn guild(&self) -> RecordId {
+        self.guild.gql_id()
+    }
+    async fn channel(&self) -> RecordId {
+        self.channel.gql_id()
+    }
+}
@@ -0,0 +1,64 @@
+use async_graphql::*;
+
+use crate::{
+    media_proxy,
+    model::{feed::Feed, message::AuthorOverride, webhook::Webhook},
+    util::{RecordId, ReferrableExt, Timestamp},
+};
+
+#[Object]
+impl Webhook {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    /// Through the media proxy.
+    async fn avatar_url(&self) -> Option<String> {
+        self.avatar_url.as_deref().map(media_proxy::proxied)
+    }
+    async fn channel(&self) -> RecordId {
+        self.channel.gql_id()
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+}
+
+#[Object]
+impl AuthorOverride {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+    /// Through the media proxy.
+    async fn avatar_url(&self) -> Option<String> {
+        self.avatar_url.as_deref().map(media_proxy::proxied)
+    }
+}
+
+#[Object]
+impl Feed {
+    async fn id(&self) -> RecordId {
+        self.gql_id()
+    }
+    async fn url(&self) -> &str {
+        &self.url
+    }
+    async fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    async fn channel(&self) -> RecordId {
+        self.channel.gql_id()
+    }
+    async fn created_at(&self) -> Timestamp {
+        Timestamp(self.created_at.0)
+    }
+    async fn last_polled(&self) -> Option<Timestamp> {
+        self.last_polled.as_ref().map(|at| Timestamp(at.0))
+    }
+    /// Why the feed couldn't be fetched last time, if it couldn't.
+    async fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
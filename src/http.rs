@@ -1,50 +1,111 @@
-use crate::{pubsub::Relay, storage::Storage};
 use anyhow::anyhow;
-use async_graphql::{http::GraphiQLSource, Data};
+use async_graphql::{
+    http::{GraphiQLSource, MultipartOptions, WebSocketProtocols, WsMessage},
+    Data, ServerError,
+};
 use async_graphql_tide::*;
-use async_std::sync::RwLock;
+use futures_util::{future, stream, StreamExt};
 use serde::Deserialize;
-use std::{env, sync::Arc};
+use std::{env, str::FromStr, sync::Arc};
 use tide::{
     http::{headers::HeaderValue, mime},
-    log::{error, info, LogMiddleware},
+    log::{error, LogMiddleware},
     security::{CorsMiddleware, Origin},
     Body, Request, Response, StatusCode,
 };
+use tide_websockets::{tungstenite::protocol::CloseFrame, Message, WebSocket, WebSocketConnection};
 
 use crate::{
     auth::{self, Claims_, JwtKind},
-    graphql::schema_builder,
-    model::user::User,
-    util::Ref,
+    captcha,
+    config::CONFIG,
+    connections::{self, Connections, Registration},
+    federation,
+    graphql::{trace, Schema, SCHEMA},
+    linked, media_proxy, metrics,
+    model::{
+        login,
+        token::{ApiToken, ApiTokenAuth, Scope},
+        user::User,
+    },
+    oidc, rest,
+    tenant::{self, Tenant, Tenants},
+    tus,
+    util::{random_string, RecordId, Ref},
+    webhooks,
 };
 
 #[derive(Clone)]
 pub struct HttpState {
-    pub relay: Arc<Relay>,
-    pub storage: Arc<RwLock<Storage>>,
-}
-
-impl HttpState {
-    pub fn surreal(&self) -> &super::Surreal {
-        &crate::SURREAL
-    }
+    pub tenants: &'static Tenants,
+    pub connections: Arc<Connections>,
+    /// Built once and shared by every tenant. Who's asking, and the tenant's relay, storage
+    /// and services, go in with each request.
+    pub schema: Schema,
 }
 
 #[derive(Clone, Debug)]
 pub struct State {
     pub token: Option<auth::JwtToken>,
+    pub api_token: Option<ApiTokenAuth>,
+    pub tenant: &'static Tenant,
 }
 
 impl State {
+    /// Who made a request that went through [auth::make_tide_authware].
+    pub async fn of(request: &Request<HttpState>) -> tide::Result<Self> {
+        let tenant = tenant::of(request)?;
+        let token = match request.ext::<Claims_>() {
+            Some(claims) if !matches!(claims.sub, JwtKind::Refresh) => {
+                Some(make_jwt_token(claims, tenant.surreal()).await?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            token,
+            api_token: request.ext::<ApiTokenAuth>().cloned(),
+            tenant,
+        })
+    }
+
     pub fn surreal(&self) -> &super::Surreal {
-        &crate::http::SURREAL
+        self.tenant.surreal()
     }
-    pub async fn user(&self) -> tide::Result<User> {
-        let uid = self
-            .token
+
+    /// The authenticated user, whether they came with a session or an API token.
+    pub fn uid(&self) -> Option<RecordId> {
+        self.token
             .as_ref()
-            .map(|token| token.claims.claims.uid.clone());
+            .map(|token| token.claims.claims.uid.clone())
+            .or_else(|| self.api_token.as_ref().map(|api| api.uid.clone()))
+    }
+
+    /// Sessions may do anything, API tokens only what they were scoped for. Root GraphQL fields
+    /// are already gated by [ScopeGate](crate::graphql::scope::ScopeGate), this is for REST
+    /// endpoints and nested fields that need more than their root.
+    pub fn require_scope(&self, scope: Scope) -> tide::Result<()> {
+        match self.api_token {
+            Some(ref api) if !api.scopes.contains(&scope) => Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("this token lacks the {scope:?} scope"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// For things a restricted token must never do, like minting more tokens.
+    pub fn require_session(&self) -> tide::Result<()> {
+        if self.api_token.is_some() {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("this requires a session, not an api token"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn user(&self) -> tide::Result<User> {
+        let uid = self.uid();
         if let Some(uid) = uid {
             let user: Option<User> = self.surreal().select(uid.0).await?;
             return user.ok_or_else(|| {
@@ -63,16 +124,12 @@ impl State {
     }
 
     pub fn ref_user(&self) -> tide::Result<Ref<User>> {
-        let uid = self
-            .token
-            .as_ref()
-            .map(|token| token.claims.claims.uid.id())
-            .ok_or_else(|| {
-                tide::Error::new(
-                    StatusCode::Unauthorized,
-                    anyhow::anyhow!("not authenticated"),
-                )
-            })?;
+        let uid = self.uid().map(|uid| uid.id()).ok_or_else(|| {
+            tide::Error::new(
+                StatusCode::Unauthorized,
+                anyhow::anyhow!("not authenticated"),
+            )
+        })?;
         Ok(Ref::new_owned(uid))
     }
 }
@@ -88,77 +145,148 @@ async fn graphiql(_: Request<HttpState>) -> tide::Result<impl Into<Response>> {
         .content_type(mime::HTML))
 }
 
+/// The GraphQL over websocket endpoint, `graphql-transport-ws` or the older `graphql-ws`.
 async fn gql_subscrimb(request: Request<HttpState>) -> tide::Result {
-    let endpoint = GraphQLSubscription::on_connection_init(
-        async_graphql_tide::GraphQLSubscription::new(
-            crate::graphql::schema_builder()
-                .data(request.state().relay.clone())
-                .data(request.state().storage.clone())
-                .finish(),
-        ),
-        move |val| async move {
-            #[derive(Deserialize)]
-            #[serde(rename_all = "camelCase")]
-            struct I {
-                access_token: String,
+    let endpoint =
+        WebSocket::new(gql_socket).with_protocols(&["graphql-transport-ws", "graphql-ws"]);
+    tide::Endpoint::call(&endpoint, request).await
+}
+
+/// Serves one websocket, until the client leaves or [connections] evicts it.
+async fn gql_socket(
+    request: Request<HttpState>,
+    connection: WebSocketConnection,
+) -> tide::Result<()> {
+    let Some(protocol) = request
+        .header("sec-websocket-protocol")
+        .and_then(|protocols| {
+            protocols
+                .as_str()
+                .split(',')
+                .find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok())
+        })
+    else {
+        return Ok(());
+    };
+    let (registration, evicted) = request.state().connections.open(login::client_ip(&request));
+    let registration = Arc::new(registration);
+    let tenants = request.state().tenants;
+    let host = request.host().map(str::to_owned);
+
+    let schema = request.state().schema.clone();
+    let incoming = connection
+        .clone()
+        .take_while(|message| future::ready(message.is_ok()))
+        .map(|message| message.unwrap().into_data());
+    let outgoing = async_graphql::http::WebSocket::new(schema, incoming, protocol)
+        .on_connection_init({
+            let registration = registration.clone();
+            move |val| connection_init(val, registration, tenants, host)
+        });
+    let evicted =
+        evicted.map(|eviction| WsMessage::Close(eviction.code, eviction.reason.to_owned()));
+    let mut outgoing = Box::pin(stream::select(outgoing, evicted));
+    while let Some(message) = outgoing.next().await {
+        match message {
+            WsMessage::Text(text) => {
+                if connection.send_string(text).await.is_err() {
+                    break;
+                }
+            }
+            WsMessage::Close(code, reason) => {
+                let _ = connection
+                    .send(Message::Close(Some(CloseFrame {
+                        code: code.into(),
+                        reason: reason.into(),
+                    })))
+                    .await;
+                break;
             }
+        }
+    }
+    Ok(())
+}
 
-            let result: Result<_, async_graphql::Error> = async move {
-                let token = if val.is_object() {
-                    Some(serde_json::from_value::<I>(val)?).map(|i| i.access_token)
-                } else {
-                    None
-                };
+/// Authenticates a websocket with the `accessToken` of its `connection_init` payload, if any,
+/// which also tells which tenant it's for when the host doesn't.
+async fn connection_init(
+    val: serde_json::Value,
+    registration: Arc<Registration>,
+    tenants: &'static Tenants,
+    host: Option<String>,
+) -> async_graphql::Result<Data> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct I {
+        access_token: String,
+    }
 
-                let claims = if let Some(token) = token {
-                    info!("oh boy, found authorization token: {token}");
-                    let y = crate::auth::make_tide_authware();
-                    if crate::auth::is_active(&SURREAL, &token).await? {
-                        let data = match jsonwebtoken::decode::<crate::auth::Claims_>(
-                            &token,
-                            &y.key,
-                            &y.validation,
-                        ) {
-                            Ok(c) => c,
-                            Err(_) => {
-                                return Err(async_graphql::Error::new("invalid token"));
-                            }
-                        };
-
-                        Some(data.claims)
-                    } else {
-                        return Err(async_graphql::Error::new("inactive token"));
-                    }
-                } else {
-                    None
-                };
-                let token = if let Some(c) = claims {
-                    if let JwtKind::Refresh = c.sub {
-                        None
-                    } else {
-                        Some(make_jwt_token(&c, &SURREAL).await?)
+    let result: Result<_, async_graphql::Error> = async move {
+        let token = if val.is_object() {
+            Some(serde_json::from_value::<I>(val)?).map(|i| i.access_token)
+        } else {
+            None
+        };
+
+        let claims = token.as_deref().and_then(auth::access_claims);
+        let tenant = tenants.resolve(host.as_deref(), claims.as_ref())?;
+        let api_token = match token {
+            Some(ref token) => ApiToken::authenticate(tenant.surreal(), token).await?,
+            None => None,
+        };
+
+        let claims = if let Some(token) = token.filter(|_| api_token.is_none()) {
+            let y = crate::auth::make_tide_authware();
+            if crate::auth::is_active(tenant.surreal(), &token).await? {
+                let data = match jsonwebtoken::decode::<crate::auth::Claims_>(
+                    &token,
+                    &y.key,
+                    &y.validation,
+                ) {
+                    Ok(c) => c,
+                    Err(_) => {
+                        return Err(async_graphql::Error::new("invalid token"));
                     }
-                } else {
-                    None
                 };
-                let state = State { token };
-                let mut d = Data::default();
-                d.insert(state);
-                Ok(d)
+
+                Some(data.claims)
+            } else {
+                return Err(async_graphql::Error::new("inactive token"));
             }
-            .await;
-            match result {
-                Err(ref e) => {
-                    error!("error: {e:?}");
-                    result
-                }
-                _ => result,
+        } else {
+            None
+        };
+        let token = if let Some(c) = claims {
+            if let JwtKind::Refresh = c.sub {
+                None
+            } else {
+                Some(make_jwt_token(&c, tenant.surreal()).await?)
             }
-        },
-    )
-    .build::<HttpState>();
-
-    tide::Endpoint::call(&endpoint, request).await
+        } else {
+            None
+        };
+        let state = State {
+            token,
+            api_token,
+            tenant,
+        };
+        if let Some(uid) = state.uid() {
+            tenant.services.users.seen(&Ref::new_owned(uid.id())).await;
+            registration.identify(uid);
+        }
+        let mut d = Data::default();
+        d.insert(state);
+        tenant.insert_data(&mut d);
+        Ok(d)
+    }
+    .await;
+    match result {
+        Err(ref e) => {
+            error!("error: {e:?}");
+            result
+        }
+        _ => result,
+    }
 }
 
 pub async fn make_jwt_token(
@@ -179,50 +307,87 @@ pub async fn make_jwt_token(
     })
 }
 
+/// Errors that carry extensions were built to be shown to clients, anything else could leak
+/// internals. Every error is logged with the request id, and in production the unexpected
+/// ones only reach the client as that id.
+fn scrub_errors(request_id: &str, errors: &mut [ServerError]) {
+    for error in errors {
+        error!("[{request_id}] {error:?}");
+        if CONFIG.production && error.extensions.is_none() {
+            error.message = String::from("internal server error");
+            error.source = None;
+        }
+        error
+            .extensions
+            .get_or_insert_with(Default::default)
+            .set("requestId", request_id.to_owned());
+    }
+}
+
 async fn handle_gql(request: Request<HttpState>) -> tide::Result {
-    let surreal = &SURREAL;
-    let claims = request.ext::<Claims_>();
-    let token: tide::Result<_> = async move {
-        if let Some(c) = claims {
-            if let JwtKind::Refresh = c.sub {
-                return Ok(None);
-            }
-            Ok(Some(make_jwt_token(c, surreal).await?))
-        } else {
-            Ok(None)
+    match request.len() {
+        None => return Ok(Response::new(StatusCode::LengthRequired)),
+        Some(len) if len as u64 > CONFIG.max_body_size => {
+            return Ok(Response::new(StatusCode::PayloadTooLarge))
         }
+        _ => {}
     }
-    .await;
-    let state = State { token: token? };
-    let schema = schema_builder()
-        .data(state)
-        .data(request.state().relay.clone())
-        .data(request.state().storage.clone())
-        .finish();
-    let req = receive_request(request).await?;
-    let response = schema.execute(req).await;
-    let result = async_graphql_tide::respond(response);
-    result.inspect_err(|e| error!("{e}"))
+
+    let request_id = random_string(12);
+    let state = State::of(&request).await?;
+    let tenant = state.tenant;
+    if let Ok(user) = state.ref_user() {
+        tenant.services.users.seen(&user).await;
+    }
+    let traced = request.header(trace::HEADER).is_some();
+    let schema = request.state().schema.clone();
+    let largest_upload = CONFIG.avatar_max_size.max(CONFIG.attachment_max_size);
+    let mut req = receive_request_opts(
+        request,
+        MultipartOptions::default().max_file_size(largest_upload as usize),
+    )
+    .await?
+    .data(state);
+    tenant.insert_data(&mut req.data);
+    if traced {
+        req = req.data(trace::TraceRequested);
+    }
+    let mut response = schema.execute(req).await;
+    scrub_errors(&request_id, &mut response.errors);
+    let result = async_graphql_tide::respond(response).map(|mut response| {
+        response.insert_header("x-request-id", &request_id);
+        response
+    });
+    result.inspect_err(|e| error!("[{request_id}] {e}"))
 }
 
 pub static SURREAL: crate::Surreal = crate::Surreal::init();
 
-pub(super) async fn run() -> tide::Result<()> {
-    let relay = Arc::new(Relay::new());
-    let storage = Arc::new(RwLock::new(Storage::new()));
+pub(super) async fn run(tenants: &'static Tenants) -> tide::Result<()> {
     let mut tide = tide::with_state(HttpState {
-        relay,
-        storage: storage.clone(),
+        tenants,
+        connections: Default::default(),
+        schema: SCHEMA.clone(),
     });
     tide.with(LogMiddleware::new());
 
-    let s = storage.read().await;
-    s.init_fs().await?;
-    s.tide(&mut tide)?;
-    drop(s);
+    for tenant in tenants.all() {
+        tenant.storage.read().await.tide(&mut tide)?;
+    }
 
     let cors = CorsMiddleware::new()
-        .allow_methods("GET, POST, OPTIONS".parse::<HeaderValue>().unwrap())
+        .allow_methods(
+            "GET, POST, OPTIONS, HEAD, PATCH, DELETE"
+                .parse::<HeaderValue>()
+                .unwrap(),
+        )
+        // what tus clients read back
+        .expose_headers(
+            "Location, Tus-Resumable, Tus-Version, Tus-Max-Size, Upload-Offset, Upload-Length, \
+             Attachment-Id"
+                .parse::<HeaderValue>()
+                .unwrap(),
+        )
         .allow_origin(Origin::from("*"))
         .allow_credentials(true);
 
@@ -231,9 +396,11 @@ pub(super) async fn run() -> tide::Result<()> {
     tide.at("/graphql")
         .with(auth::make_tide_authware())
         .post(handle_gql);
-    tide.at("/graphiql")
-        .with(auth::make_tide_authware())
-        .get(graphiql);
+    if !CONFIG.production {
+        tide.at("/graphiql")
+            .with(auth::make_tide_authware())
+            .get(graphiql);
+    }
     tide.at("/graphql-subscription")
         .with(auth::make_tide_authware())
         .get(gql_subscrimb);
@@ -241,7 +408,22 @@ pub(super) async fn run() -> tide::Result<()> {
     tide.at("/auth/login").post(auth::http_login);
     tide.at("/auth/register").post(auth::http_register);
     tide.at("/auth/refresh").post(auth::http_refresh);
+    tide.at("/auth/forgot").post(auth::http_forgot);
+    tide.at("/auth/reset").post(auth::http_reset);
     tide.at("/auth/isactive").get(auth::http_isactive);
+    tide.at("/auth/challenge").get(captcha::http_challenge);
+
+    rest::routes(&mut tide);
+    oidc::routes(&mut tide);
+    linked::routes(&mut tide);
+    media_proxy::routes(&mut tide);
+    metrics::routes(&mut tide);
+    webhooks::routes(&mut tide);
+    tus::routes(&mut tide);
+
+    tide.at(federation::WELL_KNOWN)
+        .get(federation::http_well_known);
+    tide.at(federation::INBOX).post(federation::http_inbox);
 
     tide.listen(env::var("NETHERITE_CHAT_HTTP_URL")?).await?;
 
@@ -0,0 +1,387 @@
+//! Deferred and recurring work. Jobs are stored in the `job` table, so they survive restarts
+//! and are shared between instances; any worker may pick up any due job.
+
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration as StdDuration};
+
+use anyhow::anyhow;
+use async_std::task;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use futures_util::Future;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::{error, info, warn};
+
+use crate::{
+    auth, backup,
+    config::CONFIG,
+    digest,
+    mail::{Email, MAILER},
+    model::{
+        attachment::Attachment, erasure::Erasure, feed::Feed, reminder::Reminder, retention, stats,
+        upload::UploadSession,
+    },
+    pubsub::Relay,
+    tenant::Tenancy,
+    util::{
+        query::{field, Q},
+        referrable, Ref, Referrable, ReferrableExt,
+    },
+};
+
+/// How long workers sleep when there is nothing to do.
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+/// Running jobs older than this are assumed to belong to a dead worker and are retried.
+const STALE_AFTER: i64 = 10;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    /// Gave up after `max_attempts`. Kept around for inspection.
+    Failed,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Job {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub kind: String,
+    pub payload: Value,
+    pub state: JobState,
+    pub run_at: Datetime,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub locked_at: Option<Datetime>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+referrable!(Job = "job" .id: Option<Thing>);
+
+impl Job {
+    pub const MAX_ATTEMPTS: u32 = 5;
+
+    fn new(kind: &str, payload: Value, run_at: DateTime<Utc>) -> Self {
+        Self {
+            id: None,
+            kind: kind.to_owned(),
+            payload,
+            state: JobState::Pending,
+            run_at: Datetime(run_at),
+            attempts: 0,
+            max_attempts: Self::MAX_ATTEMPTS,
+            locked_at: None,
+            last_error: None,
+        }
+    }
+
+    /// Waits twice as long after every failed attempt, up to an hour.
+    fn backoff(attempts: u32) -> Duration {
+        Duration::seconds((10i64 << attempts.min(9)).min(60 * 60))
+    }
+}
+
+/// Queues a job to run as soon as a worker is free.
+pub async fn enqueue(
+    surreal: &crate::Surreal,
+    kind: &str,
+    payload: impl Serialize,
+) -> anyhow::Result<Job> {
+    enqueue_at(surreal, kind, payload, Utc::now()).await
+}
+
+/// Queues a job to run once `run_at` has passed.
+pub async fn enqueue_at(
+    surreal: &crate::Surreal,
+    kind: &str,
+    payload: impl Serialize,
+    run_at: DateTime<Utc>,
+) -> anyhow::Result<Job> {
+    let job = Job::new(kind, serde_json::to_value(payload)?, run_at);
+    Ok(surreal.create(Job::TABLE).content(job).await?)
+}
+
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    async fn run(&self, payload: Value) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl<F, Fut> Handler for F
+where
+    F: Fn(Value) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    async fn run(&self, payload: Value) -> anyhow::Result<()> {
+        self(payload).await
+    }
+}
+
+/// The handlers for each job kind, and which kinds recur on a schedule.
+pub struct Jobs {
+    handlers: HashMap<&'static str, Box<dyn Handler>>,
+    recurring: Vec<(Schedule, &'static str)>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            recurring: vec![],
+        }
+    }
+
+    pub fn handle(mut self, kind: &'static str, handler: impl Handler) -> Self {
+        self.handlers.insert(kind, Box::new(handler));
+        self
+    }
+
+    /// Enqueues `kind` (with a null payload) on a cron schedule,
+    /// `sec min hour day-of-month month day-of-week`.
+    pub fn recurring(mut self, cron: &str, kind: &'static str) -> Self {
+        let schedule = Schedule::from_str(cron).expect("invalid cron expression");
+        self.recurring.push((schedule, kind));
+        self
+    }
+
+    /// Spawns the schedulers and `workers` workers, which run for as long as the process does.
+    pub fn start(self, surreal: &'static crate::Surreal, workers: usize) {
+        let jobs = Arc::new(self);
+        for (schedule, kind) in jobs.recurring.clone() {
+            task::spawn(schedule_loop(surreal, schedule, kind));
+        }
+        task::spawn(recover_loop(surreal));
+        for _ in 0..workers {
+            task::spawn(jobs.clone().work(surreal));
+        }
+        info!("started {workers} job workers");
+    }
+
+    async fn work(self: Arc<Self>, surreal: &'static crate::Surreal) {
+        loop {
+            match claim(surreal).await {
+                Ok(Some(job)) => {
+                    if let Err(e) = self.run(surreal, job).await {
+                        error!("couldn't record a job's outcome: {e}");
+                    }
+                }
+                Ok(None) => task::sleep(POLL_INTERVAL).await,
+                Err(e) => {
+                    error!("couldn't claim a job: {e}");
+                    task::sleep(POLL_INTERVAL).await
+                }
+            }
+        }
+    }
+
+    async fn run(&self, surreal: &crate::Surreal, mut job: Job) -> surrealdb::Result<()> {
+        let id = job.id.clone().unwrap();
+        let result = match self.handlers.get(job.kind.as_str()) {
+            Some(handler) => handler.run(job.payload.clone()).await,
+            None => Err(anyhow!("no handler for {} jobs", job.kind)),
+        };
+
+        match result {
+            Ok(()) => {
+                let _: Option<Job> = surreal.delete(id).await?;
+                return Ok(());
+            }
+            Err(e) if job.attempts >= job.max_attempts => {
+                error!("{} job {id} failed for good: {e}", job.kind);
+                job.state = JobState::Failed;
+                job.last_error = Some(e.to_string());
+            }
+            Err(e) => {
+                warn!("{} job {id} failed, retrying: {e}", job.kind);
+                job.state = JobState::Pending;
+                job.run_at = Datetime(Utc::now() + Job::backoff(job.attempts));
+                job.last_error = Some(e.to_string());
+            }
+        }
+        job.locked_at = None;
+        let _: Job = surreal.update(id).content(job).await?;
+        Ok(())
+    }
+}
+
+/// Takes the next due job, if another worker doesn't get to it first.
+async fn claim(surreal: &crate::Surreal) -> surrealdb::Result<Option<Job>> {
+    let due = Q::select::<Job>()
+        .filter(field("state").eq("pending"))
+        .filter(field("run_at").le(Datetime(Utc::now())))
+        .order("run_at")
+        .limit(10)
+        .all(surreal)
+        .await?;
+    for job in due {
+        // only one worker sees the state flip from pending
+        let claimed: Option<Job> = surreal
+            .query("UPDATE $job SET state = 'running', locked_at = time::now(), attempts += 1 WHERE state = 'pending'")
+            .bind(("job", job.id))
+            .await?
+            .take(0)?;
+        if claimed.is_some() {
+            return Ok(claimed);
+        }
+    }
+    Ok(None)
+}
+
+async fn recover_loop(surreal: &'static crate::Surreal) {
+    loop {
+        let stale = Datetime(Utc::now() - Duration::minutes(STALE_AFTER));
+        let recovered = surreal
+            .query("UPDATE job SET state = 'pending', locked_at = NONE WHERE state = 'running' AND locked_at < $stale")
+            .bind(("stale", stale))
+            .await;
+        if let Err(e) = recovered {
+            error!("couldn't recover stale jobs: {e}");
+        }
+        task::sleep(POLL_INTERVAL * 12).await;
+    }
+}
+
+/// Every instance runs the schedulers, the job id (kind and time) keeps a firing from being
+/// enqueued more than once.
+async fn schedule_loop(surreal: &'static crate::Surreal, schedule: Schedule, kind: &'static str) {
+    for fire_at in schedule.upcoming(Utc) {
+        if let Ok(wait) = (fire_at - Utc::now()).to_std() {
+            task::sleep(wait).await;
+        }
+        let id = format!("{kind}-{}", fire_at.timestamp());
+        let created: surrealdb::Result<Job> = surreal
+            .create((Job::TABLE, id.as_str()))
+            .content(Job::new(kind, Value::Null, fire_at))
+            .await;
+        if created.is_ok() {
+            info!("enqueued recurring {kind} job");
+        }
+    }
+}
+
+/// Every job kind this server knows how to run, on one tenant's namespace.
+pub fn registry(surreal: &'static crate::Surreal, relay: Arc<Relay>, tenancy: Tenancy) -> Jobs {
+    Jobs::new()
+        .handle("oauth_code_cleanup", move |_: Value| async move {
+            surreal
+                .query("DELETE oauth_code WHERE issued_at < time::now() - 5m")
+                .await?
+                .check()?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring("0 0 * * * *", "oauth_code_cleanup")
+        .handle("password_reset_cleanup", move |_: Value| async move {
+            let expired = Datetime(Utc::now() - Duration::minutes(auth::RESET_MINUTES));
+            surreal
+                .query("DELETE password_reset WHERE issued_at < $expired")
+                .bind(("expired", expired))
+                .await?
+                .check()?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring("0 5 * * * *", "password_reset_cleanup")
+        .handle("pow_challenge_cleanup", move |_: Value| async move {
+            surreal
+                .query("DELETE pow_challenge WHERE issued_at < time::now() - 10m")
+                .await?
+                .check()?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring("0 */10 * * * *", "pow_challenge_cleanup")
+        .handle("federation_jti_cleanup", move |_: Value| async move {
+            surreal
+                .query("DELETE federation_jti WHERE expires_at < time::now()")
+                .await?
+                .check()?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring("0 15 * * * *", "federation_jti_cleanup")
+        .handle("email_digest", move |_: Value| digest::run(surreal))
+        .recurring("0 30 * * * *", "email_digest")
+        .handle("email", |payload: Value| async move {
+            let email: Email = serde_json::from_value(payload)?;
+            MAILER.send(&email).await
+        })
+        .handle("feeds", move |_: Value| async move {
+            // one job per feed, so a slow one doesn't hold up the rest
+            for feed in Q::select::<Feed>().all(surreal).await? {
+                enqueue(surreal, "feed_poll", feed.refer()).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring("0 */15 * * * *", "feeds")
+        .handle(retention::JOB, move |_: Value| retention::apply(surreal))
+        .recurring("0 0 3 * * *", retention::JOB)
+        .handle(retention::PURGE_JOB, move |_: Value| {
+            retention::purge(surreal)
+        })
+        .recurring("0 0 4 * * *", retention::PURGE_JOB)
+        .handle(stats::JOB, move |_: Value| stats::rollup(surreal))
+        .recurring("0 10 0 * * *", stats::JOB)
+        .handle(UploadSession::JOB, move |_: Value| {
+            UploadSession::expire(surreal)
+        })
+        .recurring("0 20 * * * *", UploadSession::JOB)
+        .handle(backup::JOB, move |_: Value| async move {
+            backup::run(surreal, tenancy).await?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .recurring(&CONFIG.backup_schedule, backup::JOB)
+        .handle(Erasure::JOB, move |payload: Value| async move {
+            let erasure: Ref<Erasure> = serde_json::from_value(payload)?;
+            erasure
+                .fetch(surreal)
+                .await?
+                .run(surreal, tenancy.storage_root)
+                .await
+        })
+        .handle("attachment_rescan", {
+            let relay = relay.clone();
+            move |_: Value| {
+                let relay = relay.clone();
+                async move {
+                    Attachment::rescan_held(surreal, &relay)
+                        .await
+                        .map_err(|e| e.into_inner())
+                }
+            }
+        })
+        .recurring("0 */5 * * * *", "attachment_rescan")
+        .handle("feed_poll", {
+            let relay = relay.clone();
+            move |payload: Value| {
+                let relay = relay.clone();
+                async move {
+                    let feed: Ref<Feed> = serde_json::from_value(payload)?;
+                    // removed since it was queued
+                    let Some(feed) = surreal.select::<Option<Feed>>(feed.record_id().0).await?
+                    else {
+                        return Ok(());
+                    };
+                    feed.poll(surreal, &relay).await.map_err(|e| e.into_inner())
+                }
+            }
+        })
+        .handle(Reminder::JOB, move |payload: Value| {
+            let relay = relay.clone();
+            async move {
+                let reminder: Ref<Reminder> = serde_json::from_value(payload)?;
+                // cancelled
+                let Some(reminder) = surreal
+                    .select::<Option<Reminder>>(reminder.record_id().0)
+                    .await?
+                else {
+                    return Ok(());
+                };
+                reminder
+                    .fire(surreal, &relay)
+                    .await
+                    .map_err(|e| e.into_inner())
+            }
+        })
+}
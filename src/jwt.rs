@@ -6,7 +6,7 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::marker::PhantomData;
 use tide::{Middleware, Next, Request, Response, StatusCode};
 
-use crate::{auth::is_active, http::HttpState};
+use crate::{auth::is_active, http::HttpState, model::token::ApiToken, tenant};
 
 pub fn jwtsign<Claims: Serialize + DeserializeOwned + Send + Sync + 'static>(
     claims: &Claims,
@@ -75,6 +75,7 @@ where
             return Ok(Response::new(StatusCode::Unauthorized));
         }
 
+        let surreal = tenant::of(&req)?.surreal();
         for value in values {
             let value = value.as_str();
             if !value.starts_with("Bearer") {
@@ -82,8 +83,15 @@ where
             }
 
             let token = &value["Bearer ".len()..];
-            println!("found authorization token: {token}");
-            if is_active(req.state().surreal(), token).await? {
+            match ApiToken::authenticate(surreal, token).await {
+                Ok(Some(auth)) => {
+                    req.set_ext(auth);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => return Err(tide::Error::new(StatusCode::Unauthorized, e)),
+            }
+            if is_active(surreal, token).await? {
                 let data = match decode::<Claims>(token, &self.key, &self.validation) {
                     Ok(c) => c,
                     Err(_) => {
@@ -0,0 +1,193 @@
+//! Where GitHub and Steam send users back to after they verified a
+//! [linked account](crate::model::linked_account). Both carry the account's code as `state`.
+//!
+//! Verifying starts here too: `/linked/{provider}/start` puts the `state` in a cookie before
+//! redirecting to the provider, and the callbacks refuse a `state` this browser didn't start
+//! with, so nobody can get their pending account verified with someone else's login.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tide::{
+    http::{cookies::SameSite, Cookie},
+    Redirect, Request, Response, StatusCode,
+};
+
+use crate::{
+    config::CONFIG,
+    http::HttpState as State,
+    model::linked_account::{LinkedAccount, LinkedAccountKind},
+    tenant,
+};
+
+const STEAM_OPENID: &str = "https://steamcommunity.com/openid/login";
+const STEAM_ID_PREFIX: &str = "https://steamcommunity.com/openid/id/";
+const STATE_COOKIE: &str = "linked_state";
+
+pub fn routes(tide: &mut tide::Server<State>) {
+    tide.at("/linked/github/start").get(http_start);
+    tide.at("/linked/steam/start").get(http_start);
+    tide.at("/linked/github/callback").get(http_github_callback);
+    tide.at("/linked/steam/callback").get(http_steam_callback);
+}
+
+/// Back to the client's settings, where the account now shows as verified.
+fn done() -> tide::Result {
+    let mut response: Response =
+        Redirect::new(format!("https://{}/settings/connections", CONFIG.domain)).into();
+    response.remove_cookie(Cookie::build(STATE_COOKIE, "").path("/linked").finish());
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct Start {
+    state: String,
+}
+
+/// Remembers the `state` in this browser, then off to the provider.
+async fn http_start(request: Request<State>) -> tide::Result {
+    let Start { state } = request.query()?;
+    let account = LinkedAccount::by_code(tenant::of(&request)?.surreal(), &state)
+        .await?
+        .filter(|account| !account.verified)
+        .ok_or_else(expired)?;
+    let url = account.provider_url().ok_or_else(|| {
+        tide::Error::new(
+            StatusCode::NotImplemented,
+            anyhow!("this account can't be verified here"),
+        )
+    })?;
+    let mut response: Response = Redirect::new(url).into();
+    response.insert_cookie(
+        Cookie::build(STATE_COOKIE, state)
+            .path("/linked")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .finish(),
+    );
+    Ok(response)
+}
+
+fn expired() -> tide::Error {
+    tide::Error::new(
+        StatusCode::BadRequest,
+        anyhow!("this link expired, start verifying again"),
+    )
+}
+
+/// The account `state` is the code of, if this browser is the one that started verifying it.
+async fn pending(
+    request: &Request<State>,
+    state: &str,
+    kind: LinkedAccountKind,
+) -> tide::Result<LinkedAccount> {
+    if request
+        .cookie(STATE_COOKIE)
+        .map_or(true, |cookie| cookie.value() != state)
+    {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("verifying was started in another browser, start again here"),
+        ));
+    }
+    LinkedAccount::by_code(tenant::of(request)?.surreal(), state)
+        .await?
+        .filter(|account| account.kind == kind)
+        .ok_or_else(expired)
+}
+
+#[derive(Deserialize)]
+struct GithubCallback {
+    code: String,
+    state: String,
+}
+
+async fn http_github_callback(request: Request<State>) -> tide::Result {
+    #[derive(Serialize)]
+    struct Exchange<'a> {
+        client_id: &'a str,
+        client_secret: &'a str,
+        code: &'a str,
+    }
+    #[derive(Deserialize)]
+    struct Token {
+        access_token: String,
+    }
+    #[derive(Deserialize)]
+    struct GithubUser {
+        login: String,
+    }
+
+    let GithubCallback { code, state } = request.query()?;
+    let account = pending(&request, &state, LinkedAccountKind::Github).await?;
+    let (Some(client_id), Some(client_secret)) =
+        (&CONFIG.github_client_id, &CONFIG.github_client_secret)
+    else {
+        return Err(tide::Error::new(
+            StatusCode::NotImplemented,
+            anyhow!("GitHub accounts can't be verified here"),
+        ));
+    };
+
+    let token: Token = surf::post("https://github.com/login/oauth/access_token")
+        .header("Accept", "application/json")
+        .body_form(&Exchange {
+            client_id,
+            client_secret,
+            code: &code,
+        })?
+        .recv_json()
+        .await?;
+    let user: GithubUser = surf::get("https://api.github.com/user")
+        .header("Authorization", format!("Bearer {}", token.access_token))
+        .header("User-Agent", "netherite-chat")
+        .recv_json()
+        .await?;
+
+    account
+        .verify(tenant::of(&request)?.surreal(), user.login)
+        .await?;
+    done()
+}
+
+/// OpenID 2.0: Steam's assertion is only trusted after asking Steam whether it made it.
+async fn http_steam_callback(request: Request<State>) -> tide::Result {
+    let mut params: HashMap<String, String> = request.query()?;
+    let state = params
+        .remove("state")
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("no state")))?;
+    let account = pending(&request, &state, LinkedAccountKind::Steam).await?;
+    // an assertion made for another relying party, or another account, is no proof of this one
+    if params.get("openid.return_to") != Some(&LinkedAccount::steam_return_to(&state)) {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("Steam's answer wasn't meant for this link"),
+        ));
+    }
+
+    let steam_id = params
+        .get("openid.claimed_id")
+        .and_then(|id| id.strip_prefix(STEAM_ID_PREFIX))
+        .filter(|id| id.len() == 17 && id.chars().all(|c| c.is_ascii_digit()))
+        .map(str::to_owned)
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("not a SteamID")))?;
+
+    params.insert("openid.mode".into(), "check_authentication".into());
+    let answer = surf::post(STEAM_OPENID)
+        .body_form(&params)?
+        .recv_string()
+        .await?;
+    if !answer.lines().any(|line| line.trim() == "is_valid:true") {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("Steam didn't confirm this login"),
+        ));
+    }
+
+    account
+        .verify(tenant::of(&request)?.surreal(), steam_id)
+        .await?;
+    done()
+}
@@ -0,0 +1,99 @@
+//! Outgoing email. Mail is sent from the job runner (see [`queue`]), so a slow or unreachable
+//! mail server doesn't hold up requests and failed sends are retried.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::anyhow;
+use async_std::task;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tide::log::info;
+
+use crate::{config::CONFIG, jobs};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    /// Plain text.
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> anyhow::Result<()>;
+}
+
+/// Only logs mail, for instances (and development setups) that can't send any.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        info!(
+            "not sending mail to {}, no mailer configured: {}\n{}",
+            email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+/// Hands mail to a local `sendmail -t`, which postfix, msmtp and friends all provide.
+pub struct SendmailMailer {
+    pub path: String,
+    pub from: String,
+}
+
+#[async_trait]
+impl Mailer for SendmailMailer {
+    async fn send(&self, email: &Email) -> anyhow::Result<()> {
+        // header injection would let anyone with a say in the subject add recipients
+        if [&email.to, &email.subject]
+            .iter()
+            .any(|header| header.contains(['\r', '\n']))
+        {
+            return Err(anyhow!("line break in mail header"));
+        }
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            self.from, email.to, email.subject, email.body
+        );
+        let path = self.path.clone();
+        task::spawn_blocking(move || {
+            let mut sendmail = Command::new(path)
+                .args(["-t", "-i"])
+                .stdin(Stdio::piped())
+                .spawn()?;
+            sendmail
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(message.as_bytes())?;
+            let status = sendmail.wait()?;
+            if !status.success() {
+                return Err(anyhow!("sendmail exited with {status}"));
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref MAILER: Box<dyn Mailer> = match CONFIG.sendmail {
+        Some(ref path) => Box::new(SendmailMailer {
+            path: path.clone(),
+            from: CONFIG.mail_from.clone(),
+        }),
+        None => Box::new(LogMailer),
+    };
+}
+
+/// Sends `email` in the background.
+pub async fn queue(surreal: &crate::Surreal, email: Email) -> anyhow::Result<()> {
+    jobs::enqueue(surreal, "email", email).await?;
+    Ok(())
+}
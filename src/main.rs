@@ -8,22 +8,45 @@
 #![feature(negative_impls)]
 #![feature(auto_traits)]
 #![feature(specialization)]
+#![feature(ip)]
 use std::{env, str::FromStr};
 
 use chrono::{Datelike, Utc};
-use surrealdb::{engine::remote::ws, opt::auth::Root};
+use surrealdb::engine::remote::ws;
 use tide::log::{info, warn, LevelFilter};
 
-use crate::http::SURREAL;
-
+mod audio;
 mod auth;
+mod backup;
+mod captcha;
+mod config;
+mod connections;
+mod digest;
+mod federation;
+mod gif;
 mod graphql;
 mod http;
+mod jobs;
 mod jwt;
+mod linked;
+mod mail;
+mod media_proxy;
+mod metrics;
 mod model;
+mod oidc;
+mod phone;
 mod pubsub;
+mod rest;
+mod scan;
+mod security;
+mod seed;
+mod service;
 mod storage;
+mod tenant;
+mod translate;
+mod tus;
 mod util;
+mod webhooks;
 
 pub type Surreal = surrealdb::Surreal<ws::Client>;
 
@@ -36,11 +59,38 @@ static LOG_LEVEL_NAMES: [&str; 6] = ["OFF", "ERROR", "WARN", "INFO", "DEBUG", "T
 
 #[async_std::main]
 async fn main() -> tide::Result<()> {
-    dotenv::dotenv()?;
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("allowlist") {
+        graphql::allowlist::compile(&args[2..])?;
+        return Ok(());
+    }
+    if args.get(1).map(String::as_str) == Some("sdl") {
+        print!("{}", graphql::SCHEMA.sdl());
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("--example-config") {
+        print!("{}", config::EXAMPLE);
+        return Ok(());
+    }
 
-    let cd_dir = env::var("NETHERITE_CHAT_CD").ok();
-    if let Some(cd_dir) = cd_dir {
-        env::set_current_dir(cd_dir)?;
+    // containers usually pass the environment directly
+    if let Err(e) = dotenv::dotenv() {
+        if !e.not_found() {
+            return Err(e.into());
+        }
+    }
+
+    if args.get(1).map(String::as_str) == Some("--check-config") {
+        let problems = config::check();
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("config ok, data in {}", config::CONFIG.data_dir);
+        return Ok(());
     }
 
     let log_level_env = env::var("NETHERITE_CHAT_LOG_LEVEL");
@@ -69,17 +119,15 @@ async fn main() -> tide::Result<()> {
         info!("Happy birthday Remy_Clarke!");
     }
 
-    SURREAL
-        .connect::<ws::Ws>(env::var("NETHERITE_CHAT_SURREALDB_URL")?)
-        .await?;
-    SURREAL
-        .signin(Root {
-            username: "root",
-            password: "root",
-        })
-        .await?;
-    SURREAL.use_ns("netherite").use_db("chat").await?;
-    http::run().await?;
+    let tenants = tenant::Tenants::connect().await?;
+    if args.get(1).map(String::as_str) == Some("seed") {
+        return seed::run(tenants.default()).await;
+    }
+    graphql::allowlist::load()?;
+    for tenant in tenants.all() {
+        tenant.start_jobs();
+    }
+    http::run(tenants).await?;
 
     Ok(())
 }
@@ -0,0 +1,118 @@
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use async_std::{fs, path::Path};
+use hmac::{Hmac, Mac};
+use image::{DynamicImage, ImageFormat};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use tide::{
+    http::{mime, Url},
+    log::info,
+    Body, Request, Response, StatusCode,
+};
+
+use crate::{
+    config::{data_path, CONFIG},
+    http::HttpState,
+    util::{
+        fetch::{get_public, read_limited},
+        random_string, secure_eq,
+    },
+};
+
+pub const PATH: &str = "/media-proxy";
+/// Shared by every tenant, it only ever holds public images.
+pub(crate) const CACHE_DIR: &str = "storage/media-cache";
+/// Proxied images are scaled down to fit in a square this big.
+const MAX_DIMENSION: u32 = 1024;
+
+fn sign(url: &str) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(CONFIG.media_proxy_key.as_bytes())
+        .expect("hmac takes keys of any size");
+    mac.update(url.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+fn verify(url: &str, sig: &str) -> bool {
+    secure_eq(&sign(url), sig)
+}
+
+/// The path clients should load an external image through, so they never talk to its host.
+/// Only URLs the server handed out this way get proxied.
+pub fn proxied(url: &str) -> String {
+    let mut proxied = Url::parse("http://localhost").unwrap();
+    proxied
+        .query_pairs_mut()
+        .append_pair("url", url)
+        .append_pair("sig", &sign(url));
+    format!("{PATH}?{}", proxied.query().unwrap_or_default())
+}
+
+pub fn routes(tide: &mut tide::Server<HttpState>) {
+    tide.at(PATH).get(http_media_proxy);
+}
+
+#[derive(Deserialize)]
+struct ProxyQuery {
+    url: String,
+    sig: String,
+}
+
+async fn http_media_proxy(request: Request<HttpState>) -> tide::Result {
+    let ProxyQuery { url, sig } = request.query()?;
+    if !verify(&url, &sig) {
+        return Ok(Response::new(StatusCode::Forbidden));
+    }
+
+    let cached = data_path(&format!(
+        "{CACHE_DIR}/{:x}.png",
+        Sha1::digest(url.as_bytes())
+    ));
+    if !Path::new(&cached).exists().await {
+        info!("fetching {url} for the media proxy");
+        let fetched = fetch(&url).await?;
+        let shrunk = async_std::task::spawn_blocking(move || shrink(&fetched))
+            .await
+            .map_err(|e| tide::Error::new(StatusCode::UnprocessableEntity, e))?;
+        // written elsewhere first so concurrent requests never serve half a file
+        let partial = format!("{cached}.{}", random_string(8));
+        fs::write(&partial, shrunk).await?;
+        fs::rename(&partial, &cached).await?;
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_file(&cached).await?)
+        .content_type(mime::PNG)
+        .header("cache-control", "public, max-age=86400")
+        .build())
+}
+
+/// Refuses anything that doesn't resolve to public addresses only, so the proxy can't be
+/// pointed at the server's own network.
+async fn fetch(url: &str) -> tide::Result<Vec<u8>> {
+    let mut response = get_public(url).await?;
+    if !response
+        .content_type()
+        .is_some_and(|mime| mime.basetype() == "image")
+    {
+        return Err(tide::Error::new(
+            StatusCode::UnsupportedMediaType,
+            anyhow!("{url} is not an image"),
+        ));
+    }
+    read_limited(&mut response, CONFIG.media_proxy_max_size).await
+}
+
+/// Re-encodes to PNG, which also drops any metadata, scaling down if needed.
+fn shrink(fetched: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(fetched)?;
+    let image: DynamicImage = if image.width() > MAX_DIMENSION || image.height() > MAX_DIMENSION {
+        image.thumbnail(MAX_DIMENSION, MAX_DIMENSION)
+    } else {
+        image
+    };
+    let mut encoded = Cursor::new(vec![]);
+    image.write_to(&mut encoded, ImageFormat::Png)?;
+    Ok(encoded.into_inner())
+}
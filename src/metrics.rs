@@ -0,0 +1,199 @@
+//! `GET /metrics` in the Prometheus text format, for whoever has `NETHERITE_CHAT_METRICS_TOKEN`,
+//! and `GET /debug/relay` for admins: what the relay is publishing and who is falling behind.
+
+use std::{
+    fmt::Write,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use async_std::future::timeout;
+use serde::Deserialize;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::{
+    auth,
+    config::CONFIG,
+    http::{HttpState, State},
+    tenant, util,
+};
+
+/// Past this SurrealDB counts as down.
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn routes(tide: &mut tide::Server<HttpState>) {
+    tide.at("/metrics").get(http_metrics);
+    tide.at("/debug/relay")
+        .with(auth::make_tide_authware())
+        .get(http_debug_relay);
+}
+
+#[derive(Deserialize)]
+struct QueueDepth {
+    kind: String,
+    state: String,
+    depth: u64,
+}
+
+/// How long SurrealDB took to answer, `None` if it didn't.
+async fn ping(surreal: &crate::Surreal) -> Option<Duration> {
+    let started = Instant::now();
+    match timeout(PING_TIMEOUT, surreal.query("RETURN true")).await {
+        Ok(Ok(_)) => Some(started.elapsed()),
+        _ => None,
+    }
+}
+
+/// Writes the `# HELP` and `# TYPE` lines of a metric.
+fn describe(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP netherite_{name} {help}");
+    let _ = writeln!(out, "# TYPE netherite_{name} {kind}");
+}
+
+async fn http_metrics(request: Request<HttpState>) -> tide::Result {
+    // the endpoint doesn't exist unless configured
+    let Some(ref token) = CONFIG.metrics_token else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+    let authorized = request
+        .header("Authorization")
+        .and_then(|header| header.as_str().strip_prefix("Bearer "))
+        .is_some_and(|given| util::secure_eq(given, token));
+    if !authorized {
+        return Ok(Response::new(StatusCode::Unauthorized));
+    }
+
+    let state = tenant::of(&request)?;
+    let surreal = state.surreal();
+    let mut out = String::new();
+
+    let ping = ping(surreal).await;
+    describe(
+        &mut out,
+        "surrealdb_up",
+        "gauge",
+        "Whether SurrealDB answers queries.",
+    );
+    let _ = writeln!(out, "netherite_surrealdb_up {}", ping.is_some() as u8);
+    if let Some(ping) = ping {
+        describe(
+            &mut out,
+            "surrealdb_ping_seconds",
+            "gauge",
+            "How long a trivial query took.",
+        );
+        let _ = writeln!(
+            out,
+            "netherite_surrealdb_ping_seconds {}",
+            ping.as_secs_f64()
+        );
+    }
+
+    describe(
+        &mut out,
+        "websocket_connections",
+        "gauge",
+        "Open GraphQL websockets.",
+    );
+    let _ = writeln!(
+        out,
+        "netherite_websocket_connections {}",
+        state.connections.count()
+    );
+
+    let topics = state.relay.stats();
+    describe(
+        &mut out,
+        "relay_subscribers",
+        "gauge",
+        "Open subscriptions per topic.",
+    );
+    for topic in &topics {
+        let _ = writeln!(
+            out,
+            "netherite_relay_subscribers{{topic=\"{}\"}} {}",
+            topic.topic, topic.subscribers
+        );
+    }
+    describe(
+        &mut out,
+        "relay_lagging_subscribers",
+        "gauge",
+        "Subscribers at least half a buffer behind.",
+    );
+    for topic in &topics {
+        let _ = writeln!(
+            out,
+            "netherite_relay_lagging_subscribers{{topic=\"{}\"}} {}",
+            topic.topic,
+            topic.lagging.len()
+        );
+    }
+    describe(
+        &mut out,
+        "relay_publish_seconds",
+        "summary",
+        "Time spent publishing, waiting for slow subscribers included.",
+    );
+    for topic in &topics {
+        let _ = writeln!(
+            out,
+            "netherite_relay_publish_seconds_sum{{topic=\"{}\"}} {}",
+            topic.topic, topic.publish_seconds
+        );
+        let _ = writeln!(
+            out,
+            "netherite_relay_publish_seconds_count{{topic=\"{}\"}} {}",
+            topic.topic, topic.published
+        );
+    }
+
+    describe(
+        &mut out,
+        "legacy_ids_total",
+        "counter",
+        "Ids sent without their table, taken while NETHERITE_CHAT_LEGACY_IDS is on.",
+    );
+    let _ = writeln!(
+        out,
+        "netherite_legacy_ids_total {}",
+        util::LEGACY_IDS.load(Ordering::Relaxed)
+    );
+
+    // without SurrealDB there is no queue to look at
+    if ping.is_some() {
+        let depths: Vec<QueueDepth> = surreal
+            .query(
+                "SELECT kind, state, count() AS depth FROM job \
+                 WHERE state != 'running' GROUP BY kind, state",
+            )
+            .await?
+            .take(0)?;
+        describe(
+            &mut out,
+            "job_queue_depth",
+            "gauge",
+            "Jobs waiting to run, or given up on, per kind.",
+        );
+        for depth in depths {
+            let _ = writeln!(
+                out,
+                "netherite_job_queue_depth{{kind=\"{}\",state=\"{}\"}} {}",
+                depth.kind, depth.state, depth.depth
+            );
+        }
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(out)
+        .content_type("text/plain; version=0.0.4")
+        .build())
+}
+
+async fn http_debug_relay(request: Request<HttpState>) -> tide::Result {
+    let user = State::of(&request).await?.user().await?;
+    tenant::of(&request)?.services.admin.require_admin(&user)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&tenant::of(&request)?.relay.stats())?)
+        .build())
+}
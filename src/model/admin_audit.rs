@@ -0,0 +1,75 @@
+//! What instance admins did that others may have to account for later, like placing
+//! [legal holds](super::legal_hold). Guild moderation has its own [audit log](super::audit).
+
+use async_graphql::Enum;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::util::{
+    query::{field, Q},
+    referrable, RecordId, Ref, Referrable,
+};
+
+use super::user::User;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminActionKind {
+    LegalHoldPlaced,
+    LegalHoldLifted,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminAction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub admin: Ref<User>,
+    pub kind: AdminActionKind,
+    /// The user, channel or whatever else it was done to.
+    pub subject: RecordId,
+    pub reason: String,
+    pub at: Datetime,
+}
+
+referrable!(AdminAction = "admin_action" .id: Option<Thing>);
+
+impl AdminAction {
+    pub const PAGE: i64 = 100;
+
+    pub async fn log(
+        surreal: &crate::Surreal,
+        admin: &Ref<User>,
+        kind: AdminActionKind,
+        subject: RecordId,
+        reason: String,
+    ) -> surrealdb::Result<Self> {
+        surreal
+            .create(Self::TABLE)
+            .content(AdminAction {
+                id: None,
+                admin: admin.clone(),
+                kind,
+                subject,
+                reason,
+                at: Datetime(Utc::now()),
+            })
+            .await
+    }
+
+    /// The latest [PAGE](Self::PAGE), newest first, only those about `subject` if given.
+    pub async fn latest(
+        surreal: &crate::Surreal,
+        subject: Option<RecordId>,
+    ) -> surrealdb::Result<Vec<Self>> {
+        let mut actions = Q::select::<Self>();
+        if let Some(subject) = subject {
+            actions = actions.filter(field("subject").eq(subject));
+        }
+        actions
+            .order_desc("at")
+            .limit(Self::PAGE)
+            .all(surreal)
+            .await
+    }
+}
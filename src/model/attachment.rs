@@ -0,0 +1,318 @@
+use anyhow::anyhow;
+use async_graphql::{ErrorExtensions, SimpleObject, UploadValue};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::{
+    log::{error, warn},
+    StatusCode,
+};
+
+use crate::{
+    audio,
+    pubsub::Relay,
+    scan::{Verdict, SCANNER},
+    storage::{self, Storage},
+    util::{
+        query::{field, Q},
+        referrable, Ref, ReferrableExt, ReferrableWithId,
+    },
+};
+
+use super::{
+    guild::{Member, Permission, TextableChannel},
+    trust,
+    user::User,
+};
+
+/// A file uploaded to be shared in a conversation.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Attachment {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub owner: Ref<User>,
+    /// The channel it was uploaded for, if any; its moderators hear about flagged files.
+    #[serde(default)]
+    pub channel: Option<Ref<TextableChannel>>,
+    pub filename: String,
+    /// Sniffed from the content, see [crate::storage::sniff].
+    pub mime: String,
+    pub size: u64,
+    /// Relative to the storage root's parent, which is also the URL path it is served at.
+    pub path: String,
+    pub created_at: Datetime,
+    /// Set when the scanner flagged the file; the file itself is quarantined.
+    #[serde(default)]
+    pub blocked: bool,
+    /// Set while the scanner couldn't be asked. The file stays quarantined until
+    /// `attachment_rescan` finds it clean.
+    #[serde(default)]
+    pub held: bool,
+    /// What's in it, for screen readers and anyone who can't see it.
+    #[serde(default)]
+    pub alt: Option<String>,
+    /// Set when it was sent as a voice message.
+    #[serde(default)]
+    pub voice_note: Option<VoiceNote>,
+}
+
+referrable!(Attachment = "attachment" .id: Option<Thing>);
+
+/// What the uploader says about a file, besides the file.
+#[derive(Debug, Clone, Default)]
+pub struct AttachmentInit {
+    pub channel: Option<Ref<TextableChannel>>,
+    pub alt: Option<String>,
+    pub voice: bool,
+}
+
+/// What clients need to show a voice message as a player rather than a file.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject)]
+pub struct VoiceNote {
+    /// `null` if the server couldn't tell.
+    pub duration_ms: Option<u64>,
+    /// How loud it is across the clip, 0 to 255. Empty if the server couldn't tell.
+    pub waveform: Vec<u8>,
+}
+
+impl VoiceNote {
+    pub const MAX_SECONDS: u64 = 15 * 60;
+    /// What voice messages are recorded in. Browsers record to WebM or MP4, which sniff as
+    /// video.
+    const TYPES: [&'static str; 5] = [
+        "audio/ogg",
+        "audio/mpeg",
+        "audio/wav",
+        "video/webm",
+        "video/mp4",
+    ];
+
+    /// Times the stored file at `path` and takes its waveform.
+    async fn probe(path: &str, mime: &str) -> tide::Result<Self> {
+        if !Self::TYPES.contains(&mime) {
+            return Err(tide::Error::new(
+                StatusCode::UnsupportedMediaType,
+                anyhow!("voice messages are audio, not {mime}"),
+            ));
+        }
+        match audio::probe(path, Self::MAX_SECONDS).await {
+            Ok(Some(probed)) if probed.duration_ms > Self::MAX_SECONDS * 1000 => {
+                Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!(
+                        "voice messages are at most {} minutes",
+                        Self::MAX_SECONDS / 60
+                    ),
+                ))
+            }
+            Ok(Some(probed)) => Ok(Self {
+                duration_ms: Some(probed.duration_ms),
+                waveform: probed.peaks,
+            }),
+            Ok(None) => Ok(Self {
+                duration_ms: None,
+                waveform: vec![],
+            }),
+            Err(e) => {
+                warn!("couldn't probe the voice message {path}: {e}");
+                Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!("that voice message can't be played back"),
+                ))
+            }
+        }
+    }
+}
+
+impl Attachment {
+    pub const MAX_ALT_LENGTH: usize = 1500;
+
+    /// Blank alt text is none, too long is an error.
+    pub fn check_alt(alt: Option<String>) -> tide::Result<Option<String>> {
+        let alt = alt
+            .map(|alt| alt.trim().to_owned())
+            .filter(|alt| !alt.is_empty());
+        if alt
+            .as_ref()
+            .is_some_and(|alt| alt.chars().count() > Self::MAX_ALT_LENGTH)
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("alt text is at most {} characters", Self::MAX_ALT_LENGTH),
+            ));
+        }
+        Ok(alt)
+    }
+
+    /// Stores the upload, records it and runs it through the [SCANNER]. `voice` ones have to
+    /// be audio, see [VoiceNote].
+    pub async fn upload(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        storage: &Storage,
+        owner: &User,
+        upload: UploadValue,
+        init: AttachmentInit,
+    ) -> async_graphql::Result<Self> {
+        let AttachmentInit {
+            channel,
+            alt,
+            voice,
+        } = init;
+        let alt = Self::check_alt(alt)?;
+        if let Some(ref channel) = channel {
+            trust::check_upload(surreal, channel, owner, upload.size()?).await?;
+        }
+        let filename = upload.filename.clone();
+        let (path, mime, size) = storage
+            .put_attachment(upload)
+            .await
+            .map_err(|e| e.extend())?;
+
+        Ok(Self {
+            id: None,
+            owner: owner.refer(),
+            channel,
+            filename,
+            mime: mime.to_owned(),
+            size,
+            path,
+            created_at: Datetime(Utc::now()),
+            blocked: false,
+            held: false,
+            alt,
+            voice_note: None,
+        }
+        .record(surreal, relay, owner, voice)
+        .await?)
+    }
+
+    /// Records a file already in [Storage] and runs it through the [SCANNER]. `voice` ones are
+    /// removed again if they aren't usable as one.
+    pub async fn record(
+        mut self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        owner: &User,
+        voice: bool,
+    ) -> tide::Result<Self> {
+        if voice {
+            match VoiceNote::probe(&self.path, &self.mime).await {
+                Ok(note) => self.voice_note = Some(note),
+                Err(e) => {
+                    storage::remove_attachment_files(&self.path).await?;
+                    return Err(e);
+                }
+            }
+        }
+        // nobody gets to download it before it's scanned
+        storage::quarantine(&self.path).await?;
+        self.held = true;
+        let attachment: Self = surreal.create(Self::TABLE).content(self).await?;
+        attachment.scan(surreal, relay, owner).await
+    }
+
+    /// Releases a [held](Self::held) file if the [SCANNER] finds it clean and blocks it if it's
+    /// flagged. If the scanner can't be asked it stays held, never let through unscanned.
+    pub async fn scan(
+        mut self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        owner: &User,
+    ) -> tide::Result<Self> {
+        match SCANNER.scan(&storage::quarantined(&self.path)).await {
+            Ok(Verdict::Clean) => {
+                storage::release(&self.path).await?;
+                self.held = false;
+                self.save(surreal).await
+            }
+            Ok(Verdict::Flagged(signature)) => {
+                warn!(
+                    "attachment {} by {} flagged as {signature}",
+                    self.path,
+                    owner.tag_fmt()
+                );
+                self.held = false;
+                self.blocked = true;
+                let attachment = self.save(surreal).await?;
+                attachment
+                    .notify_moderators(surreal, relay, owner, &signature)
+                    .await?;
+                Ok(attachment)
+            }
+            Err(e) => {
+                error!(
+                    "couldn't scan {}, holding it until it can be: {e}",
+                    self.path
+                );
+                Ok(self)
+            }
+        }
+    }
+
+    /// Scans the [held](Self::held) attachments again, oldest first.
+    pub async fn rescan_held(surreal: &crate::Surreal, relay: &Relay) -> tide::Result<()> {
+        let held = Q::select::<Attachment>()
+            .filter(field("held").eq(true))
+            .order("created_at")
+            .all(surreal)
+            .await?;
+        for attachment in held {
+            let owner = attachment.owner.fetch(surreal).await?;
+            attachment.scan(surreal, relay, &owner).await?;
+        }
+        Ok(())
+    }
+
+    async fn notify_moderators(
+        &self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        owner: &User,
+        signature: &str,
+    ) -> tide::Result<()> {
+        let Some(ref channel) = self.channel else {
+            return Ok(());
+        };
+        let TextableChannel::Normal(channel) = channel.fetch(surreal).await?;
+        let moderators =
+            Member::with_permission(surreal, &channel.guild, Permission::ManageMessages).await?;
+        for moderator in moderators {
+            User::send_system_message(
+                surreal,
+                relay,
+                &moderator,
+                format!(
+                    "{} uploaded {} in #{}, which was flagged as {signature} and quarantined.",
+                    owner.tag_fmt(),
+                    self.filename,
+                    channel.name
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Only the uploader can describe it.
+    pub async fn set_alt(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        attachment: &Ref<Attachment>,
+        alt: Option<String>,
+    ) -> tide::Result<Self> {
+        let mut attachment = attachment.fetch(surreal).await?;
+        if attachment.owner != *user {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("only the uploader can change the alt text"),
+            ));
+        }
+        attachment.alt = Self::check_alt(alt)?;
+        Ok(attachment.save(surreal).await?)
+    }
+
+    pub fn url(&self) -> String {
+        format!("/{}", self.path)
+    }
+}
@@ -1,7 +1,14 @@
+use async_graphql::Enum;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use surrealdb::sql::Thing;
 
-use crate::util::{DurationSeconds, Datetime};
+use crate::util::{
+    query::{field, Q},
+    referrable, Datetime, DurationSeconds, Ref, Referrable,
+};
+
+use super::{guild::Guild, user::User};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Timeout {
@@ -22,17 +29,100 @@ pub struct Ban {
     pub reason: String,
 }
 
+/// A channel's messages written out as a static HTML page, see
+/// [transcript](super::transcript).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Transcript {
+    pub channel: Thing,
+    /// Served like [attachments](super::attachment::Attachment::path) are.
+    pub path: String,
+    pub from: Datetime,
+    pub to: Datetime,
+    pub message_count: u64,
+}
+
+/// Members removed for being inactive, see [Member::inactive](super::guild::Member::inactive).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Prune {
+    pub inactive_days: u32,
+    pub users: Vec<Thing>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum AuditLogEntryType {
     Timeout(Timeout),
     Kick(Kick),
-    Ban(Ban)
+    Ban(Ban),
+    Transcript(Transcript),
+    Prune(Prune),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum AuditLogKind {
+    Timeout,
+    Kick,
+    Ban,
+    Transcript,
+    Prune,
+}
+
+impl AuditLogEntryType {
+    pub fn kind(&self) -> AuditLogKind {
+        match self {
+            Self::Timeout(_) => AuditLogKind::Timeout,
+            Self::Kick(_) => AuditLogKind::Kick,
+            Self::Ban(_) => AuditLogKind::Ban,
+            Self::Transcript(_) => AuditLogKind::Transcript,
+            Self::Prune(_) => AuditLogKind::Prune,
+        }
+    }
 }
 
+/// What a guild's moderators did, `audit_log:<random>`.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AuditLogEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub guild: Ref<Guild>,
     pub entry_type: AuditLogEntryType,
     pub by: Thing,
     pub timestamp: Datetime,
 }
+
+referrable!(AuditLogEntry = "audit_log" .id: Option<Thing>);
+
+impl AuditLogEntry {
+    pub const PAGE: i64 = 100;
+
+    pub async fn log(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        by: &Ref<User>,
+        entry_type: AuditLogEntryType,
+    ) -> surrealdb::Result<Self> {
+        surreal
+            .create(Self::TABLE)
+            .content(AuditLogEntry {
+                id: None,
+                guild: guild.clone(),
+                entry_type,
+                by: by.record_id().0,
+                timestamp: Datetime(Utc::now()),
+            })
+            .await
+    }
+
+    /// The latest [PAGE](Self::PAGE) entries, newest first.
+    pub async fn of_guild(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Vec<Self>> {
+        Q::select::<Self>()
+            .filter(field("guild").eq(guild))
+            .order_desc("timestamp")
+            .limit(Self::PAGE)
+            .all(surreal)
+            .await
+    }
+}
@@ -0,0 +1,168 @@
+//! Limits on mentions that guilds set against mention spam and raids: how many different
+//! people one message may mention, and how many mentions a member may send per minute.
+//! Going over blocks the message, or also times the member out, which the guild's moderators
+//! hear about and which goes in its [audit log](super::audit).
+
+use async_graphql::{Enum, ErrorExtensions, InputObject, SimpleObject};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Datetime;
+
+use crate::{
+    pubsub::Relay,
+    util::{DurationSeconds, ReferrableExt},
+};
+
+use super::{
+    audit::{AuditLogEntry, AuditLogEntryType, Timeout},
+    guild::{Guild, Member, Permission, TextableChannel},
+    message::parse_mentions,
+    user::User,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum AutomodAction {
+    /// Only the message is refused.
+    Block,
+    /// The member can't send anything for `timeoutMinutes` either.
+    Timeout,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[serde(default)]
+#[graphql(input_name = "AutomodSettingsInput")]
+pub struct AutomodSettings {
+    /// Different users mentioned in one message, unlimited if unset.
+    pub max_mentions_per_message: Option<u32>,
+    /// Mentions a member sends in the guild within a minute, unlimited if unset.
+    pub max_mentions_per_minute: Option<u32>,
+    pub action: AutomodAction,
+    pub timeout_minutes: u32,
+}
+
+impl Default for AutomodSettings {
+    fn default() -> Self {
+        Self {
+            max_mentions_per_message: None,
+            max_mentions_per_minute: None,
+            action: AutomodAction::Block,
+            timeout_minutes: 10,
+        }
+    }
+}
+
+/// Fails with `TIMED_OUT`, and `retryAfter` in seconds.
+fn timed_out(until: &Datetime) -> async_graphql::Error {
+    // rounded up, so waiting that long is always enough
+    let seconds = (until.0 - Utc::now()).num_seconds() + 1;
+    async_graphql::Error::new("you're timed out in this guild").extend_with(|_, extensions| {
+        extensions.set("code", "TIMED_OUT");
+        extensions.set("retryAfter", seconds);
+    })
+}
+
+/// Mentions `author` sent in `guild` over the last minute.
+async fn recent_mentions(
+    surreal: &crate::Surreal,
+    guild: &Guild,
+    author: &User,
+) -> surrealdb::Result<u32> {
+    let sent: Option<u32> = surreal
+        .query(
+            "SELECT VALUE math::sum(array::len(mentions)) FROM message WHERE author = $user \
+             AND recipient.kind = 'Channel' AND recipient.id.guild = $guild \
+             AND created_at > time::now() - 1m GROUP ALL",
+        )
+        .bind(("user", author.record_id()))
+        .bind(("guild", guild.record_id()))
+        .await?
+        .take(0)?;
+    Ok(sent.unwrap_or(0))
+}
+
+/// Run before `author` sends `content` to `channel`. Errors with `TIMED_OUT` while they're
+/// timed out, and with `AUTOMOD_BLOCKED` when the message goes over the guild's limits.
+/// Whoever can [manage messages](Permission::ManageMessages) isn't limited.
+pub async fn check(
+    surreal: &crate::Surreal,
+    relay: &Relay,
+    channel: &TextableChannel,
+    author: &User,
+    content: &str,
+) -> async_graphql::Result<()> {
+    let Some(mut member) = Member::find(surreal, channel.guild(), &author.refer()).await? else {
+        return Ok(());
+    };
+    if let Some(ref until) = member.timed_out_until {
+        if until.0 > Utc::now() {
+            return Err(timed_out(until));
+        }
+    }
+
+    let guild = channel.guild().fetch(surreal).await?;
+    let settings = &guild.automod;
+    if settings.max_mentions_per_message.is_none() && settings.max_mentions_per_minute.is_none() {
+        return Ok(());
+    }
+    let mentions = parse_mentions(content).len() as u32;
+    if mentions == 0
+        || Member::effective_permissions(surreal, &guild.refer(), &author.refer())
+            .await?
+            .contains(&Permission::ManageMessages)
+    {
+        return Ok(());
+    }
+    let reason = if settings
+        .max_mentions_per_message
+        .is_some_and(|max| mentions > max)
+    {
+        format!("mentioned {mentions} people in one message")
+    } else if let Some(max) = settings.max_mentions_per_minute {
+        let sent = recent_mentions(surreal, &guild, author).await?;
+        if sent + mentions <= max {
+            return Ok(());
+        }
+        format!("sent {} mentions within a minute", sent + mentions)
+    } else {
+        return Ok(());
+    };
+
+    if settings.action == AutomodAction::Timeout {
+        let duration = Duration::minutes(settings.timeout_minutes.into());
+        member.timed_out_until = Some(Datetime(Utc::now() + duration));
+        member.save(surreal).await?;
+        let system = User::system(surreal).await?;
+        AuditLogEntry::log(
+            surreal,
+            &guild.refer(),
+            &system.refer(),
+            AuditLogEntryType::Timeout(Timeout {
+                user: author.id.clone(),
+                duration: DurationSeconds(duration),
+                reason: reason.clone(),
+            }),
+        )
+        .await?;
+        let moderators =
+            Member::with_permission(surreal, &guild.refer(), Permission::ManageMessages).await?;
+        for moderator in moderators {
+            User::send_system_message(
+                surreal,
+                relay,
+                &moderator,
+                format!(
+                    "{} {reason} in #{}, automod timed them out for {} minutes.",
+                    author.tag_fmt(),
+                    channel.name(),
+                    settings.timeout_minutes
+                ),
+            )
+            .await?;
+        }
+    }
+    Err(async_graphql::Error::new(format!(
+        "this guild's automod blocked your message: you {reason}"
+    ))
+    .extend_with(|_, extensions| extensions.set("code", "AUTOMOD_BLOCKED")))
+}
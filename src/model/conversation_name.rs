@@ -0,0 +1,75 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+use tide::StatusCode;
+
+use crate::util::{referrable, RecordId, Ref, Referrable};
+
+use super::user::User;
+
+/// A name only `user` sees a direct conversation under, like "Mum" for whoever's tag that is.
+/// Channels have one shared name already, changed with `updateChannel`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ConversationName {
+    pub id: Thing,
+    pub user: Ref<User>,
+    /// The other end of the conversation.
+    pub target: RecordId,
+    pub name: String,
+}
+
+referrable!(ConversationName = "conversation_name" .id: Thing);
+
+impl ConversationName {
+    pub const MAX_LENGTH: usize = 64;
+
+    /// One name per user and conversation.
+    fn key(user: &Ref<User>, target: &RecordId) -> Thing {
+        Thing::from((
+            Self::TABLE.to_owned(),
+            format!("{}-{}-{}", user.id(), target.0.tb, target.id()),
+        ))
+    }
+
+    pub async fn get(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        target: &RecordId,
+    ) -> surrealdb::Result<Option<String>> {
+        let name: Option<ConversationName> = surreal.select(Self::key(user, target)).await?;
+        Ok(name.map(|name| name.name))
+    }
+
+    /// Blank names remove it.
+    pub async fn set(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        target: RecordId,
+        name: Option<String>,
+    ) -> tide::Result<Option<String>> {
+        let key = Self::key(user, &target);
+        let Some(name) = name
+            .map(|name| name.trim().to_owned())
+            .filter(|name| !name.is_empty())
+        else {
+            let _: Option<ConversationName> = surreal.delete(key).await?;
+            return Ok(None);
+        };
+        if name.chars().count() > Self::MAX_LENGTH {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("name is too long"),
+            ));
+        }
+        let saved: ConversationName = surreal
+            .update(key.clone())
+            .content(ConversationName {
+                id: key,
+                user: user.clone(),
+                target,
+                name,
+            })
+            .await?;
+        Ok(Some(saved.name))
+    }
+}
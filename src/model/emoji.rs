@@ -0,0 +1,210 @@
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{
+    query::{field, Q},
+    referrable, RecordId, Ref, Referrable, ReferrableExt,
+};
+
+use super::{guild::Guild, user::User};
+
+/// A unicode emoji by its shortcode, `smile` or `:smile:`, or the emoji itself. For
+/// validating what users put in where only an emoji belongs.
+pub fn resolve(input: &str) -> Option<&'static emojis::Emoji> {
+    let input = input.trim();
+    let shortcode = input
+        .strip_prefix(':')
+        .and_then(|input| input.strip_suffix(':'))
+        .unwrap_or(input);
+    emojis::get_by_shortcode(shortcode).or_else(|| emojis::get(input))
+}
+
+/// Replaces the `:shortcode:`s of unicode emoji with the emoji, so every client shows the
+/// same thing. Unknown ones, like custom emoji, are left alone.
+pub fn normalize(content: &str) -> String {
+    let mut normalized = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(':') {
+        normalized.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let shortcode = after.find(':').map(|end| &after[..end]).filter(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+'))
+        });
+        match shortcode.and_then(|name| Some((name, resolve(name)?))) {
+            Some((name, emoji)) => {
+                normalized.push_str(emoji.as_str());
+                rest = &after[name.len() + 1..];
+            }
+            None => {
+                // the closing colon may open the next one
+                normalized.push(':');
+                rest = after;
+            }
+        }
+    }
+    normalized.push_str(rest);
+    normalized
+}
+
+/// An emoji a guild uploaded, usable as `:name:` in the picker.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildEmoji {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub guild: Ref<Guild>,
+    pub name: String,
+    pub animated: bool,
+    pub created_by: Ref<User>,
+    pub created_at: Datetime,
+}
+
+referrable!(GuildEmoji = "emoji" .id: Option<Thing>);
+
+/// What the emoji picker shows: a unicode emoji or a guild's own.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct EmojiMatch {
+    pub name: String,
+    /// Set for unicode emoji.
+    pub unicode: Option<String>,
+    /// Set for guild emoji, along with `url`.
+    pub id: Option<RecordId>,
+    pub url: Option<String>,
+    pub animated: bool,
+}
+
+impl GuildEmoji {
+    pub const MAX_PER_GUILD: i64 = 50;
+    pub const MAX_NAME_LENGTH: usize = 32;
+
+    /// Where the image is stored and served from under a tenant's storage `root`, without the
+    /// leading `/`.
+    pub fn path(&self, root: &str) -> String {
+        let ext = if self.animated { "gif" } else { "png" };
+        format!("{root}/emoji/{}.{ext}", self.refer().id())
+    }
+
+    pub fn check_name(name: &str) -> tide::Result<()> {
+        let valid = (2..=Self::MAX_NAME_LENGTH).contains(&name.len())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!(
+                    "emoji names are 2 to {} letters, digits or underscores",
+                    Self::MAX_NAME_LENGTH
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Only records the emoji, storing the image is up to the caller.
+    pub async fn create(
+        surreal: &crate::Surreal,
+        creator: &Ref<User>,
+        guild: &Ref<Guild>,
+        name: String,
+        animated: bool,
+    ) -> tide::Result<GuildEmoji> {
+        Self::check_name(&name)?;
+        let existing = Self::of_guild(surreal, guild).await?;
+        if existing.len() as i64 >= Self::MAX_PER_GUILD {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("guilds may have at most {} emoji", Self::MAX_PER_GUILD),
+            ));
+        }
+        if existing.iter().any(|emoji| emoji.name == name) {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("this guild already has an emoji called {name}"),
+            ));
+        }
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(GuildEmoji {
+                id: None,
+                guild: guild.clone(),
+                name,
+                animated,
+                created_by: creator.clone(),
+                created_at: Datetime(Utc::now()),
+            })
+            .await?)
+    }
+
+    pub async fn of_guild(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Vec<GuildEmoji>> {
+        Q::select::<GuildEmoji>()
+            .filter(field("guild").eq(guild))
+            .order("name")
+            .all(surreal)
+            .await
+    }
+
+    fn matched(self, root: &str) -> EmojiMatch {
+        EmojiMatch {
+            url: Some(format!("/{}", self.path(root))),
+            id: Some(self.gql_id()),
+            name: self.name,
+            unicode: None,
+            animated: self.animated,
+        }
+    }
+}
+
+/// Emoji whose name contains `query`, the ones starting with it first. `guilds` custom emoji
+/// come before unicode ones.
+pub async fn search(
+    surreal: &crate::Surreal,
+    root: &str,
+    query: &str,
+    guilds: &[Ref<Guild>],
+    limit: usize,
+) -> surrealdb::Result<Vec<EmojiMatch>> {
+    let query = query.trim().trim_matches(':').to_lowercase();
+    let rank = |name: &str| match name.find(&query) {
+        Some(0) => Some(0),
+        Some(_) => Some(1),
+        None => None,
+    };
+
+    let mut custom = vec![];
+    for guild in guilds {
+        custom.extend(
+            GuildEmoji::of_guild(surreal, guild)
+                .await?
+                .into_iter()
+                .filter_map(|emoji| Some((rank(&emoji.name.to_lowercase())?, emoji))),
+        );
+    }
+    custom.sort_by_key(|(rank, _)| *rank);
+
+    let mut unicode: Vec<_> = emojis::iter()
+        .flat_map(|emoji| emoji.shortcodes().map(move |shortcode| (shortcode, emoji)))
+        .filter_map(|(shortcode, emoji)| Some((rank(shortcode)?, shortcode, emoji)))
+        .collect();
+    unicode.sort_by_key(|(rank, shortcode, _)| (*rank, shortcode.len()));
+
+    Ok(custom
+        .into_iter()
+        .map(|(_, emoji)| emoji.matched(root))
+        .chain(unicode.into_iter().map(|(_, shortcode, emoji)| EmojiMatch {
+            name: shortcode.to_owned(),
+            unicode: Some(emoji.as_str().to_owned()),
+            id: None,
+            url: None,
+            animated: false,
+        }))
+        .take(limit)
+        .collect())
+}
@@ -0,0 +1,350 @@
+//! Erasing a user on request: everything that identifies them goes, what they took part in
+//! stays. The user record itself is kept, anonymized, so messages and events still have an
+//! author. Runs as a job, and leaves a signed report behind as proof.
+//!
+//! Guild audit logs and admin actions keep what was done, but whoever did it or had it done
+//! to them becomes `user:erased`, the same for every erased user.
+//!
+//! Messages and attachments under a [legal hold](super::legal_hold) are left as they are,
+//! and counted in the report as kept. A user who is held themselves isn't erased at all.
+
+use anyhow::anyhow;
+use async_graphql::{Enum, SimpleObject};
+use async_std::{fs::OpenOptions, io::WriteExt};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::{error, info};
+
+use crate::{
+    auth::make_tag,
+    config::{data_path, CONFIG},
+    jobs,
+    jwt::jwtsign_with,
+    storage::{self, AvatarKind},
+    util::{random_string, referrable, Ref, Referrable, ReferrableExt},
+};
+
+use super::{
+    attachment::Attachment,
+    legal_hold::{ATTACHMENT_NOT_HELD, NOT_HELD},
+    user::User,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureState {
+    Pending,
+    Done,
+    Failed,
+}
+
+/// An admin's request to erase a user, and how it went.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Erasure {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    pub requested_by: Ref<User>,
+    /// Also empties the content of every message the user wrote, not just their identity.
+    pub anonymize_content: bool,
+    pub state: ErasureState,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub report: Option<ErasureReport>,
+    /// The report as a JWT signed with `NETHERITE_CHAT_ERASURE_KEY` (HS256).
+    #[serde(default)]
+    pub signed_report: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+referrable!(Erasure = "erasure" .id: Option<Thing>);
+
+/// What was erased, by count.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject)]
+pub struct ErasureReport {
+    /// The record id, the only thing left that points at the user.
+    pub user: String,
+    pub anonymized_content: bool,
+    /// Messages by the user emptied, only with `anonymized_content`.
+    pub messages_emptied: u64,
+    /// Messages by others that mentioned the user.
+    pub mentions_removed: u64,
+    /// Messages by or mentioning the user left alone because of a legal hold.
+    #[serde(default)]
+    pub messages_kept: u64,
+    pub logins_removed: u64,
+    pub tokens_revoked: u64,
+    /// Reminders, read markers and friendships.
+    pub records_removed: u64,
+    /// Guild audit log entries and admin actions by or about the user.
+    #[serde(default)]
+    pub audit_entries_anonymized: u64,
+    pub attachments_removed: u64,
+    /// Attachments left alone because of a legal hold, files included.
+    #[serde(default)]
+    pub attachments_kept: u64,
+    pub files_removed: u64,
+    /// Whether the erasure was noted in `NETHERITE_CHAT_BACKUP_MANIFEST`, for restores to
+    /// replay it.
+    pub backup_manifest: bool,
+    pub erased_at: String,
+}
+
+/// Records touched by a statement, whatever they were.
+#[derive(Deserialize)]
+struct Touched {}
+
+/// Who erased users were in records about others.
+fn erased() -> Thing {
+    Thing::from((User::TABLE, "erased"))
+}
+
+impl Erasure {
+    pub const JOB: &'static str = "erasure";
+
+    pub async fn request(
+        surreal: &crate::Surreal,
+        admin: &Ref<User>,
+        user: &Ref<User>,
+        anonymize_content: bool,
+    ) -> tide::Result<Erasure> {
+        let target = user.fetch(surreal).await?;
+        if target.bot {
+            return Err(tide::Error::new(
+                tide::StatusCode::BadRequest,
+                anyhow!("bots belong to their guild, remove the webhook or feed instead"),
+            ));
+        }
+        let erasure: Erasure = surreal
+            .create(Self::TABLE)
+            .content(Erasure {
+                id: None,
+                user: user.clone(),
+                requested_by: admin.clone(),
+                anonymize_content,
+                state: ErasureState::Pending,
+                created_at: Datetime(Utc::now()),
+                report: None,
+                signed_report: None,
+                error: None,
+            })
+            .await?;
+        jobs::enqueue(surreal, Self::JOB, erasure.refer()).await?;
+        Ok(erasure)
+    }
+
+    /// Newest first.
+    pub async fn all(surreal: &crate::Surreal) -> surrealdb::Result<Vec<Erasure>> {
+        crate::util::query::Q::select::<Erasure>()
+            .order_desc("created_at")
+            .all(surreal)
+            .await
+    }
+
+    /// Runs the erasure, recording the report or why it failed. Every step can run again,
+    /// so a failed erasure can just be requested again.
+    pub async fn run(mut self, surreal: &crate::Surreal, storage_root: &str) -> anyhow::Result<()> {
+        let id = self.id.clone().unwrap();
+        match erase(surreal, storage_root, &self.user, self.anonymize_content).await {
+            Ok(report) => {
+                info!("erased {}", report.user);
+                self.signed_report = Some(jwtsign_with(
+                    &Header::new(Algorithm::HS256),
+                    &report,
+                    &EncodingKey::from_secret(CONFIG.erasure_key.as_bytes()),
+                )?);
+                self.report = Some(report);
+                self.state = ErasureState::Done;
+                self.error = None;
+            }
+            Err(e) => {
+                error!("couldn't erase {}: {e}", self.user.record_id());
+                self.state = ErasureState::Failed;
+                self.error = Some(e.to_string());
+            }
+        }
+        let _: Erasure = surreal.update(id).content(self).await?;
+        Ok(())
+    }
+}
+
+async fn count(
+    surreal: &crate::Surreal,
+    statement: &str,
+    user: &Ref<User>,
+) -> surrealdb::Result<u64> {
+    let touched: Vec<Touched> = surreal
+        .query(statement)
+        .bind(("user", user.record_id()))
+        .bind(("erased", erased()))
+        .await?
+        .take(0)?;
+    Ok(touched.len() as u64)
+}
+
+async fn erase(
+    surreal: &crate::Surreal,
+    storage_root: &str,
+    user: &Ref<User>,
+    anonymize_content: bool,
+) -> anyhow::Result<ErasureReport> {
+    // placed since the erasure was requested
+    if user.fetch(surreal).await?.legal_hold {
+        return Err(anyhow!("{} is under a legal hold", user.record_id()));
+    }
+
+    let mut messages_kept = 0;
+    let messages_emptied = if anonymize_content {
+        let written = count(
+            surreal,
+            "SELECT id FROM message WHERE author = $user AND deleted_at = NONE",
+            user,
+        )
+        .await?;
+        let emptied = count(
+            surreal,
+            &format!(
+                "UPDATE message SET content = '', embeds = [], mentions = [], \
+                 author_override = NONE, deleted_at = time::now() \
+                 WHERE author = $user AND deleted_at = NONE AND {NOT_HELD}"
+            ),
+            user,
+        )
+        .await?;
+        messages_kept += written.saturating_sub(emptied);
+        emptied
+    } else {
+        0
+    };
+    let mentioned = count(
+        surreal,
+        "SELECT id FROM message WHERE mentions CONTAINS $user",
+        user,
+    )
+    .await?;
+    let mentions_removed = count(
+        surreal,
+        &format!(
+            "UPDATE message SET mentions -= $user WHERE mentions CONTAINS $user AND {NOT_HELD}"
+        ),
+        user,
+    )
+    .await?;
+    messages_kept += mentioned.saturating_sub(mentions_removed);
+    let logins_removed = count(
+        surreal,
+        "DELETE login WHERE user = $user RETURN BEFORE",
+        user,
+    )
+    .await?;
+    let tokens_revoked = count(surreal, "DELETE jwt WHERE uid = $user RETURN BEFORE", user).await?
+        + count(
+            surreal,
+            "DELETE api_token WHERE owner = $user RETURN BEFORE",
+            user,
+        )
+        .await?;
+    let mut records_removed = 0;
+    for statement in [
+        "DELETE reminder WHERE user = $user RETURN BEFORE",
+        "DELETE read_marker WHERE user = $user RETURN BEFORE",
+        "DELETE friends WHERE in = $user OR out = $user RETURN BEFORE",
+        "DELETE conversation WHERE in = $user OR out = $user RETURN BEFORE",
+        "DELETE linked_account WHERE user = $user RETURN BEFORE",
+        "DELETE user_note WHERE owner = $user OR subject = $user RETURN BEFORE",
+        "DELETE conversation_name WHERE user = $user RETURN BEFORE",
+        "DELETE guild_layout WHERE user = $user RETURN BEFORE",
+        "DELETE spam_flag WHERE user = $user RETURN BEFORE",
+        "DELETE captcha_hold WHERE user = $user RETURN BEFORE",
+        "DELETE security_event WHERE user = $user RETURN BEFORE",
+        "DELETE translation WHERE message.author = $user RETURN BEFORE",
+        "DELETE guild_application WHERE user = $user RETURN BEFORE",
+        "DELETE phone_verification WHERE user = $user RETURN BEFORE",
+    ] {
+        records_removed += count(surreal, statement, user).await?;
+    }
+
+    let mut audit_entries_anonymized = 0;
+    for statement in [
+        "UPDATE audit_log SET by = $erased WHERE by = $user RETURN BEFORE",
+        "UPDATE audit_log SET entry_type.user = $erased WHERE entry_type.user = $user \
+         RETURN BEFORE",
+        "UPDATE audit_log SET entry_type.users -= $user, entry_type.users += $erased \
+         WHERE entry_type.users CONTAINS $user RETURN BEFORE",
+        "UPDATE admin_action SET admin = $erased WHERE admin = $user RETURN BEFORE",
+        "UPDATE admin_action SET subject = $erased WHERE subject = $user RETURN BEFORE",
+    ] {
+        audit_entries_anonymized += count(surreal, statement, user).await?;
+    }
+
+    let uploaded = count(surreal, "SELECT id FROM attachment WHERE owner = $user", user).await?;
+    let attachments: Vec<Attachment> = surreal
+        .query(format!(
+            "DELETE attachment WHERE owner = $user AND {ATTACHMENT_NOT_HELD} RETURN BEFORE"
+        ))
+        .bind(("user", user.record_id()))
+        .await?
+        .take(0)?;
+    let mut files_removed = 0;
+    for attachment in &attachments {
+        files_removed += storage::remove_attachment_files(&attachment.path).await?;
+    }
+    files_removed += storage::remove_avatar_files(storage_root, user.id(), AvatarKind::U).await?;
+
+    let [x, y, z, w] = make_tag(surreal, "deleted").await?;
+    surreal
+        .query(
+            "UPDATE $user SET tag = $tag, display_name = 'Deleted user', email = $email, \
+             password_hash = '', bio = NONE, badges = [], status = 'offline', phone_hash = NONE, \
+             last_seen_at = NONE, privacy = { public_profile: false, show_badges: false, \
+             show_bio: false, show_last_seen: false }",
+        )
+        .bind(("user", user.record_id()))
+        .bind(("tag", ("deleted", [x, y, z, w])))
+        // unique and unreachable, nobody can log in or register with it
+        .bind((
+            "email",
+            format!("erased-{}@invalid", random_string(16).to_lowercase()),
+        ))
+        .await?
+        .check()?;
+
+    let erased_at = Utc::now().to_rfc3339();
+    let backup_manifest = match CONFIG.backup_manifest {
+        Some(ref path) => {
+            let line = serde_json::json!({
+                "erased": user.record_id().to_string(),
+                "at": erased_at,
+            });
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(data_path(path))
+                .await?
+                .write_all(format!("{line}\n").as_bytes())
+                .await?;
+            true
+        }
+        None => false,
+    };
+
+    Ok(ErasureReport {
+        user: user.record_id().to_string(),
+        anonymized_content: anonymize_content,
+        messages_emptied,
+        mentions_removed,
+        messages_kept,
+        logins_removed,
+        tokens_revoked,
+        records_removed,
+        audit_entries_anonymized,
+        attachments_removed: attachments.len() as u64,
+        attachments_kept: uploaded.saturating_sub(attachments.len() as u64),
+        files_removed,
+        backup_manifest,
+        erased_at,
+    })
+}
@@ -0,0 +1,172 @@
+use std::time::UNIX_EPOCH;
+
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::util::{
+    query::{field, Cond, Q},
+    referrable, unwrap_id_str, RecordId, Ref, Referrable, ReferrableExt,
+};
+
+use super::{
+    guild::Guild,
+    message::{Conversation, Message},
+    user::User,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Enum, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    MessageCreated,
+    MessageDeleted,
+    ChannelCreated,
+    ChannelDeleted,
+    MemberJoined,
+}
+
+/// An entry of the event log, which reconnecting clients replay to catch up on what they
+/// missed instead of downloading everything again.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Event {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub kind: EventKind,
+    /// What changed: the message, channel or member.
+    pub subject: RecordId,
+    #[serde(default)]
+    pub guild: Option<Ref<Guild>>,
+    /// For message events, who sent it and to whom, which is what conversations filter on.
+    #[serde(default)]
+    pub author: Option<Ref<User>>,
+    #[serde(default)]
+    pub recipient: Option<RecordId>,
+    pub at: Datetime,
+}
+
+referrable!(Event = "event" .id: Option<Thing>);
+
+/// A page of the event log. Pass `cursor` back to get what happened after it. Cursors are the
+/// time of the last event and its id, so events logged at the same time are neither skipped
+/// nor repeated.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Changes {
+    pub events: Vec<Event>,
+    pub cursor: String,
+    /// More events are waiting past `cursor`, ask again.
+    pub has_more: bool,
+}
+
+impl Event {
+    const PAGE: usize = 500;
+
+    pub fn new(kind: EventKind, subject: RecordId) -> Self {
+        Self {
+            id: None,
+            kind,
+            subject,
+            guild: None,
+            author: None,
+            recipient: None,
+            at: Datetime(Utc::now()),
+        }
+    }
+
+    pub fn message(kind: EventKind, message: &Message) -> Self {
+        Self {
+            author: Some(message.author.clone()),
+            recipient: Some(message.recipient.record_id()),
+            ..Self::new(kind, message.record_id())
+        }
+    }
+
+    pub fn in_guild(self, guild: Ref<Guild>) -> Self {
+        Self {
+            guild: Some(guild),
+            ..self
+        }
+    }
+
+    pub async fn log(self, surreal: &crate::Surreal) -> surrealdb::Result<Self> {
+        surreal.create(Self::TABLE).content(self).await
+    }
+
+    /// `<time>/<event id>`, or only the time for cursors from before ids were in them.
+    fn parse_cursor(cursor: Option<&str>) -> tide::Result<(Datetime, Option<String>)> {
+        let Some(cursor) = cursor else {
+            return Ok((Datetime(DateTime::<Utc>::from(UNIX_EPOCH)), None));
+        };
+        let (at, id) = match cursor.split_once('/') {
+            Some((at, id)) => (at, Some(id.to_owned())),
+            None => (cursor, None),
+        };
+        let at = DateTime::parse_from_rfc3339(at)
+            .map_err(|e| tide::Error::new(tide::StatusCode::BadRequest, e))?
+            .with_timezone(&Utc);
+        Ok((Datetime(at), id))
+    }
+
+    async fn page(
+        surreal: &crate::Surreal,
+        filter: Cond,
+        cursor: Option<&str>,
+    ) -> tide::Result<Changes> {
+        let (since, last) = Self::parse_cursor(cursor)?;
+        let after = match last {
+            Some(ref last) => field("at").gt(since.clone()).or(field("at")
+                .eq(since.clone())
+                .and(field("id").gt(RecordId(Thing::from((Self::TABLE, last.as_str())))))),
+            None => field("at").gt(since.clone()),
+        };
+        let mut events = Q::select::<Self>()
+            .filter(filter)
+            .filter(after)
+            .order("at")
+            .order("id")
+            .limit(Self::PAGE as i64 + 1)
+            .all(surreal)
+            .await?;
+        let has_more = events.len() > Self::PAGE;
+        events.truncate(Self::PAGE);
+        let (at, id) = match events.last() {
+            Some(event) => (
+                &event.at,
+                event.id.as_ref().and_then(|id| unwrap_id_str(&id.id)),
+            ),
+            None => (&since, last.as_ref()),
+        };
+        let cursor = match id {
+            Some(id) => format!("{}/{id}", at.0.to_rfc3339()),
+            None => at.0.to_rfc3339(),
+        };
+
+        Ok(Changes {
+            events,
+            cursor,
+            has_more,
+        })
+    }
+
+    /// Message events of `conversation` after `cursor`, from the beginning without one.
+    pub async fn since_in_conversation(
+        surreal: &crate::Surreal,
+        Conversation(user, other): &Conversation,
+        cursor: Option<&str>,
+    ) -> tide::Result<Changes> {
+        let (user, other) = (user.record_id(), other.record_id());
+        let filter = field("author")
+            .eq(user.clone())
+            .and(field("recipient").eq(other.clone()))
+            .or(field("author").eq(other).and(field("recipient").eq(user)));
+        Self::page(surreal, filter, cursor).await
+    }
+
+    pub async fn since_in_guild(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        cursor: Option<&str>,
+    ) -> tide::Result<Changes> {
+        Self::page(surreal, field("guild").eq(guild), cursor).await
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use feed_rs::model as parsed;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::{log::warn, StatusCode};
+
+use crate::{
+    pubsub::Relay,
+    util::{
+        fetch::{get_public, read_limited},
+        query::{field, Q},
+        referrable, Ref, Referrable, ReferrableExt,
+    },
+};
+
+use super::{
+    guild::{Guild, TextChannel, TextableChannel},
+    message::{Embed, Message, MessageInit, MessageRecipientIn, MessageRecipientInKind},
+    name_rule::NameRule,
+    user::User,
+};
+
+/// An RSS or Atom feed whose new entries get posted into a channel.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Feed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub guild: Ref<Guild>,
+    pub channel: Ref<TextableChannel>,
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The bot account entries are posted as.
+    pub user: Ref<User>,
+    pub created_by: Ref<User>,
+    /// Ids of the entries already posted, newest first.
+    #[serde(default)]
+    pub seen: Vec<String>,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub last_polled: Option<Datetime>,
+    /// Why the last poll failed, cleared once one succeeds.
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+referrable!(Feed = "feed" .id: Option<Thing>);
+
+/// Feeds are documents, not media, so they get a lot less room than the media proxy.
+const MAX_FEED_SIZE: u64 = 2 * 1024 * 1024;
+
+async fn fetch(url: &str) -> tide::Result<parsed::Feed> {
+    let mut response = get_public(url).await?;
+    let body = read_limited(&mut response, MAX_FEED_SIZE).await?;
+    feed_rs::parser::parse(body.as_slice()).map_err(|e| {
+        tide::Error::new(
+            StatusCode::UnprocessableEntity,
+            anyhow!("{url} is not an RSS or Atom feed: {e}"),
+        )
+    })
+}
+
+/// Summaries are usually HTML, only the text is kept.
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn embed(feed: &parsed::Feed, entry: &parsed::Entry) -> Embed {
+    Embed {
+        title: entry.title.as_ref().map(|title| strip_html(&title.content)),
+        description: entry
+            .summary
+            .as_ref()
+            .map(|summary| strip_html(&summary.content))
+            .filter(|summary| !summary.is_empty()),
+        url: entry.links.first().map(|link| link.href.clone()),
+        color: None,
+        author: entry.authors.first().map(|author| author.name.clone()),
+        image_url: entry
+            .media
+            .iter()
+            .flat_map(|media| &media.thumbnails)
+            .map(|thumbnail| thumbnail.image.uri.clone())
+            .next(),
+        fields: vec![],
+        timestamp: entry.published.or(entry.updated).map(Datetime),
+        footer: feed.title.as_ref().map(|title| strip_html(&title.content)),
+    }
+    .truncated()
+}
+
+impl Feed {
+    /// Posting more than this at once would bury the channel, the rest are skipped.
+    pub const MAX_PER_POLL: usize = 5;
+    /// Entry ids remembered per feed. Has to be more than a feed ever lists at once.
+    pub const MAX_SEEN: usize = 500;
+
+    /// Fetches the feed once to check it and remembers everything already in it, so only
+    /// entries published from now on are posted.
+    pub async fn create(
+        surreal: &crate::Surreal,
+        creator: &Ref<User>,
+        channel: &TextChannel,
+        url: String,
+    ) -> tide::Result<Feed> {
+        let parsed = fetch(&url).await?;
+        let title = parsed
+            .title
+            .as_ref()
+            .map(|title| strip_html(&title.content));
+
+        let mut name: String = title
+            .as_deref()
+            .unwrap_or("Feed")
+            .chars()
+            .take(User::MAX_NAME_LENGTH)
+            .collect();
+        if name.trim().is_empty() || NameRule::check(surreal, &name).await.is_err() {
+            name = String::from("Feed");
+        }
+        let user = User::bot(surreal, &name).await?;
+
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(Feed {
+                id: None,
+                guild: channel.guild.clone(),
+                channel: Ref::new_owned(channel.id.id.to_raw()),
+                url,
+                title,
+                user: user.refer(),
+                created_by: creator.clone(),
+                seen: parsed
+                    .entries
+                    .into_iter()
+                    .map(|entry| entry.id)
+                    .take(Self::MAX_SEEN)
+                    .collect(),
+                created_at: Datetime(Utc::now()),
+                last_polled: None,
+                last_error: None,
+            })
+            .await?)
+    }
+
+    pub async fn of_channel(
+        surreal: &crate::Surreal,
+        channel: &TextChannel,
+    ) -> surrealdb::Result<Vec<Feed>> {
+        Q::select::<Feed>()
+            .filter(field("channel").eq(channel.record_id()))
+            .all(surreal)
+            .await
+    }
+
+    /// Posts the entries not seen before, oldest first. A feed that can't be fetched only
+    /// gets its error recorded, it's tried again on the next round anyway.
+    pub async fn poll(mut self, surreal: &crate::Surreal, relay: &Relay) -> tide::Result<()> {
+        let id = self.id.clone().unwrap();
+        self.last_polled = Some(Datetime(Utc::now()));
+        let parsed = match fetch(&self.url).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("couldn't poll feed {id}: {e}");
+                self.last_error = Some(e.to_string());
+                let _: Feed = surreal.update(id).content(self).await?;
+                return Ok(());
+            }
+        };
+        self.last_error = None;
+
+        let new: Vec<_> = parsed
+            .entries
+            .iter()
+            .filter(|entry| !self.seen.contains(&entry.id))
+            .collect();
+        if !new.is_empty() {
+            let author = self.user.fetch(surreal).await?;
+            for entry in new.iter().take(Self::MAX_PER_POLL).rev() {
+                let embed = embed(&parsed, entry);
+                let init = MessageInit {
+                    recipient: MessageRecipientIn {
+                        kind: MessageRecipientInKind::Channel,
+                        id: self.channel.record_id(),
+                    },
+                    content: embed
+                        .url
+                        .clone()
+                        .or_else(|| embed.title.clone())
+                        .unwrap_or_default(),
+                    reference: None,
+                    tts: false,
+                    gif: None,
+                };
+                let message =
+                    Message::create_with(surreal, &author, init, None, vec![embed]).await?;
+                relay.send_message(&message).await;
+            }
+        }
+
+        let mut seen: Vec<String> = new.into_iter().map(|entry| entry.id.clone()).collect();
+        seen.append(&mut self.seen);
+        seen.truncate(Self::MAX_SEEN);
+        self.seen = seen;
+        let _: Feed = surreal.update(id).content(self).await?;
+        Ok(())
+    }
+}
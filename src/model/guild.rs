@@ -3,13 +3,25 @@ use async_graphql::{
     connection::{query, Connection, Edge, EmptyFields},
     *,
 };
+use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
-use surrealdb::sql::Thing;
+use sha1::{Digest, Sha1};
+use surrealdb::sql::{Datetime, Thing};
 use tide::log::info;
 
-use crate::util::{referrable, unwrap_id_str, Ref, Referrable, ReferrableExt, ReferrableWithId};
+use crate::util::{
+    query::{field, Projection, Wanted, Q},
+    referrable, save_versioned, unwrap_id_str, Ref, Referrable, ReferrableExt, ReferrableWithId,
+    Versioned,
+};
 
-use super::user::User;
+use super::{
+    automod::AutomodSettings,
+    emoji::GuildEmoji,
+    event::{Event, EventKind},
+    trust::TrustSettings,
+    user::User,
+};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Guild {
@@ -17,6 +29,36 @@ pub struct Guild {
     pub name: String,
     #[serde(default)]
     pub join_constraint: JoinConstraint,
+    /// Days messages are kept in the guild's channels, never longer than the instance's.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    /// Paths like [attachments](super::attachment::Attachment::path), see [GuildImage].
+    #[serde(default)]
+    pub icon: Option<String>,
+    #[serde(default)]
+    pub splash: Option<String>,
+    /// What [JoinConstraint::Application] asks applicants.
+    #[serde(default)]
+    pub application_questions: Vec<String>,
+    /// How new members are held back, see [trust](super::trust).
+    #[serde(default)]
+    pub trust: TrustSettings,
+    #[serde(default)]
+    pub automod: AutomodSettings,
+    /// How often the guild was saved, see [Versioned].
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// The pictures a guild has besides its emoji, stored under `<storage root>/guild`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum, derive_more::Display)]
+pub enum GuildImage {
+    /// Square, and animated if uploaded that way.
+    #[display(fmt = "icon")]
+    Icon,
+    /// Shown behind invites, never animated.
+    #[display(fmt = "splash")]
+    Splash,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Copy, Enum, PartialEq, Eq, Default)]
@@ -24,9 +66,11 @@ pub enum JoinConstraint {
     #[default]
     None = 0,
     VerifiedEmail = 1,
-    // 10 minutes
+    /// Only accounts older than `NETHERITE_CHAT_JUST_REGISTERED_MINUTES`, 10 by default.
     JustRegistered = 2,
     Phone = 3,
+    /// Joining applies, see [GuildApplication](super::guild_application::GuildApplication).
+    Application = 4,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, InputObject)]
@@ -36,13 +80,68 @@ pub struct GuildInit {
 
 referrable!(Guild = "guild" .id: Thing);
 
+impl Versioned for Guild {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+}
+
+impl ReferrableExt for Guild {
+    async fn save(&self, surreal: &crate::Surreal) -> tide::Result<Self> {
+        save_versioned(surreal, self, false).await
+    }
+}
+
 impl Guild {
     pub async fn fetch_roles(&self, surreal: &crate::Surreal) -> surrealdb::Result<Vec<Role>> {
-        let id = &self.id;
-        surreal
-            .query(format!("SELECT * FROM role WHERE guild = {id}"))
+        Q::select::<Role>()
+            .filter(field("guild").eq(self.record_id()))
+            .all(surreal)
+            .await
+    }
+
+    /// The channels `user` can see, none unless they're a member, see [Channel::visible_to].
+    pub async fn channels_visible_to(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<Channel>> {
+        let Some(member) = Member::find(surreal, &self.refer(), user).await? else {
+            return Ok(vec![]);
+        };
+        let permissions = Member::effective_permissions(surreal, &self.refer(), user).await?;
+        Ok(Q::select::<Channel>()
+            .filter(field("guild").eq(self.record_id()))
+            .all(surreal)
             .await?
-            .take(0)
+            .into_iter()
+            .filter(|Channel::Text(text)| text.grants_access(&member, &permissions))
+            .collect())
+    }
+
+    /// Changes whenever the guild's settings, channels, roles or emoji do, so clients can
+    /// tell whether what they have is still current. Members don't count, the member list is
+    /// loaded lazily anyway.
+    pub async fn hash_state(&self, surreal: &crate::Surreal) -> tide::Result<String> {
+        let channels = Q::select::<Channel>()
+            .filter(field("guild").eq(self.record_id()))
+            .order("id")
+            .all(surreal)
+            .await?;
+        let roles = Q::select::<Role>()
+            .filter(field("guild").eq(self.record_id()))
+            .order("id")
+            .all(surreal)
+            .await?;
+        let emojis = GuildEmoji::of_guild(surreal, &self.refer()).await?;
+
+        let mut hash = Sha1::new();
+        hash.update(serde_json::to_vec(&(self, channels, roles, emojis))?);
+        Ok(format!("{:x}", hash.finalize()))
     }
 
     pub async fn create(
@@ -75,17 +174,17 @@ impl Guild {
     pub async fn members_paginate(
         &self,
         surreal: &crate::Surreal,
+        wanted: &Wanted,
         after: Option<String>,
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
     ) -> Result<Connection<i64, Member, EmptyFields, EmptyFields>> {
-        #[derive(Deserialize)]
-        struct Counted {
-            counted: i64,
-        }
-
-        let gid = &self.id;
+        let members = || {
+            Q::select::<Member>()
+                .filter(field("guild").eq(self.record_id()))
+                .filter(field("pending").ne(true))
+        };
 
         query(
             after,
@@ -94,15 +193,7 @@ impl Guild {
             last,
             |after, before, first, last| async move {
                 let mut start = after.map(|a| a + 1).unwrap_or(0);
-                let Counted { counted: count }: Counted = Option::unwrap_or(
-                    surreal
-                        .query(format!(
-                            r#"SELECT count() as counted FROM member WHERE guild = {gid} GROUP BY counted"#,
-                        ))
-                        .await?
-                        .take(0)?,
-                    Counted { counted: 0 },
-                );
+                let count = members().count(surreal).await?;
                 let mut end = before.unwrap_or(count);
                 if let Some(first) = first {
                     end = (start + first as i64).min(end)
@@ -114,15 +205,11 @@ impl Guild {
                         (end - last as i64).max(0)
                     };
                 }
-                let before_surreal = (end > 0).then(|| format!("LIMIT BY {end}")).unwrap_or_default();
-                let after_surreal = format!("START AT {start}");
-
-                let query = format!(r#"
-                SELECT * FROM member WHERE guild = {gid} {before_surreal} {after_surreal}
-                "#);
- 
-                let members: Vec<Member> =
-                    surreal.query(unindent::unindent(&query)).await?.take(0)?;
+                let mut page = members().project(wanted).start(start);
+                if end > 0 {
+                    page = page.limit(end);
+                }
+                let members = page.all(surreal).await?;
                 let mut members = members.into_iter().map(Some).collect::<Vec<_>>();
 
                 let mut connection = Connection::new(start > 0, end < count);
@@ -143,31 +230,233 @@ pub struct Member {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<Thing>,
     pub guild: Ref<Guild>,
+    #[serde(default)]
     pub nickname: Option<String>,
     pub user: Ref<User>,
     #[serde(default)]
     pub roles: Vec<Ref<Role>>,
+    /// Confirmed being old enough for NSFW channels.
+    #[serde(default)]
+    pub age_verified: bool,
+    /// Applied to join and waiting for a decision, see
+    /// [GuildApplication](super::guild_application::GuildApplication). [Member::find] and
+    /// everything else that goes by membership leave pending members out.
+    #[serde(default)]
+    pub pending: bool,
+    /// Can't send messages in the guild until then, see [automod](super::automod).
+    #[serde(default)]
+    pub timed_out_until: Option<Datetime>,
 }
 
 referrable!(Member = "member" .id: Option<Thing>);
 
+impl Projection for Member {
+    const ALWAYS: &'static [&'static str] = &["id", "guild", "user", "pending"];
+    const OPTIONAL: &'static [(&'static str, &'static [&'static str])] = &[
+        ("nickname", &["nickname"]),
+        ("age_verified", &["ageVerified"]),
+        ("timed_out_until", &["timedOutUntil"]),
+        // `Member.roles` looks them up itself
+        ("roles", &[]),
+    ];
+}
+
 impl Member {
     pub async fn create(
         surreal: &crate::Surreal,
         user: &User,
         guild: &Guild,
+    ) -> surrealdb::Result<Self> {
+        let member = Self::insert(surreal, user, guild, false).await?;
+        member.log_joined(surreal).await?;
+        Ok(member)
+    }
+
+    /// A member waiting for their application to be approved, who hasn't joined yet.
+    pub async fn create_pending(
+        surreal: &crate::Surreal,
+        user: &User,
+        guild: &Guild,
+    ) -> surrealdb::Result<Self> {
+        Self::insert(surreal, user, guild, true).await
+    }
+
+    async fn insert(
+        surreal: &crate::Surreal,
+        user: &User,
+        guild: &Guild,
+        pending: bool,
     ) -> surrealdb::Result<Self> {
         let init = Member {
             id: None,
             guild: guild.refer(),
             nickname: None,
             user: user.refer(),
-            roles: vec![]
+            roles: vec![],
+            age_verified: false,
+            pending,
+            timed_out_until: None,
         };
         surreal.create(Self::TABLE).content(init).await
     }
+
+    async fn log_joined(&self, surreal: &crate::Surreal) -> surrealdb::Result<()> {
+        Event::new(EventKind::MemberJoined, self.record_id())
+            .in_guild(self.guild.clone())
+            .log(surreal)
+            .await?;
+        Ok(())
+    }
+
+    /// Lets a [pending](Member::pending) member in.
+    pub async fn approve(mut self, surreal: &crate::Surreal) -> tide::Result<Self> {
+        self.pending = false;
+        let member = self.save(surreal).await?;
+        member.log_joined(surreal).await?;
+        Ok(member)
+    }
+
+    /// Members of `guild` who weren't seen in the last `days` days and didn't post in its
+    /// channels either. Those with roles, bots and `except` are never counted, and neither
+    /// are pending members. Users not seen since that was recorded count as unseen.
+    pub async fn inactive(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        days: u32,
+        except: &Ref<User>,
+    ) -> surrealdb::Result<Vec<Self>> {
+        let since = Datetime(Utc::now() - Duration::days(days.into()));
+        surreal
+            .query(
+                "LET $channels = (SELECT VALUE id FROM channel WHERE guild = $guild); \
+                 LET $posted = (SELECT VALUE author FROM message \
+                    WHERE recipient.id INSIDE $channels AND created_at > $since); \
+                 SELECT * FROM member WHERE guild = $guild AND pending != true \
+                    AND (roles = NONE OR roles = []) AND user != $except AND user.bot != true \
+                    AND (user.last_seen_at = NONE OR user.last_seen_at < $since) \
+                    AND user NOTINSIDE $posted",
+            )
+            .bind(("guild", guild.record_id()))
+            .bind(("since", since))
+            .bind(("except", except.record_id()))
+            .await?
+            .take(2)
+    }
+
+    /// Users of `guild` holding `permission`, or [Permission::Administrator], through any role.
+    pub async fn with_permission(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        permission: Permission,
+    ) -> surrealdb::Result<Vec<Ref<User>>> {
+        surreal
+            .query(
+                "LET $roles = (SELECT VALUE id FROM role WHERE guild = $guild \
+                 AND permissions CONTAINSANY $granting); \
+                 SELECT VALUE user FROM member WHERE guild = $guild AND pending != true \
+                 AND roles CONTAINSANY $roles",
+            )
+            .bind(("guild", guild.record_id()))
+            .bind(("granting", [permission, Permission::Administrator]))
+            .await?
+            .take(1)
+    }
+
+    pub async fn find(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Option<Self>> {
+        Q::select::<Self>()
+            .filter(field("guild").eq(guild).and(field("user").eq(user)))
+            .filter(field("pending").ne(true))
+            .one(surreal)
+            .await
+    }
+
+    /// `user`'s membership while their application to `guild` is pending.
+    pub async fn find_pending(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Option<Self>> {
+        Q::select::<Self>()
+            .filter(field("guild").eq(guild).and(field("user").eq(user)))
+            .filter(field("pending").eq(true))
+            .one(surreal)
+            .await
+    }
+
+    /// Everything `user` may do in `guild`: what their roles grant, or every permission with
+    /// [Permission::Administrator]. Empty if they aren't a member.
+    pub async fn effective_permissions(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<Permission>> {
+        let held: Option<Vec<Vec<Permission>>> = surreal
+            .query(
+                "SELECT VALUE roles.*.permissions FROM member \
+                 WHERE guild = $guild AND user = $user AND pending != true",
+            )
+            .bind(("guild", guild.record_id()))
+            .bind(("user", user.record_id()))
+            .await?
+            .take(0)?;
+        let held: Vec<Permission> = held.into_iter().flatten().flatten().collect();
+        if held.contains(&Permission::Administrator) {
+            return Ok(Permission::ALL.to_vec());
+        }
+        Ok(Permission::ALL
+            .into_iter()
+            .filter(|p| held.contains(p))
+            .collect())
+    }
+
+    /// Errors with `403 Forbidden` unless `user` holds `permission` in `guild`.
+    pub async fn require_permission(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+        permission: Permission,
+    ) -> tide::Result<()> {
+        if !Self::effective_permissions(surreal, guild, user)
+            .await?
+            .contains(&permission)
+        {
+            return Err(tide::Error::new(
+                tide::StatusCode::Forbidden,
+                MissingPermission {
+                    permission,
+                    guild: guild.clone(),
+                },
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What [Member::require_permission] fails with, so GraphQL can tell clients exactly what was
+/// missing, see `graphql::permission`.
+#[derive(Debug, Clone)]
+pub struct MissingPermission {
+    pub permission: Permission,
+    pub guild: Ref<Guild>,
 }
 
+impl std::fmt::Display for MissingPermission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "missing the {:?} permission in {}",
+            self.permission,
+            self.guild.record_id()
+        )
+    }
+}
+
+impl std::error::Error for MissingPermission {}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Role {
     pub id: Thing,
@@ -203,17 +492,46 @@ pub enum Permission {
     Timeout,
     /// A user with this permission may create invitation links to the guild.
     Invite,
+    /// A user with this permission may approve or deny applications to join the guild.
+    ReviewApplications,
+    /// A user with this permission may mute others in voice for everyone.
+    MuteMembers,
+    /// A user with this permission may move others between voice channels.
+    MoveMembers,
     ManageRoles,
     ManageChannels,
     ManageMessages,
     ManageWebhooks,
     ManageEmojis,
     SendMessages,
+    /// A user with this permission may send messages clients read out loud.
+    SendTtsMessages,
 
     ManageServer,
     Administrator,
 }
 
+impl Permission {
+    pub const ALL: [Self; 16] = [
+        Self::Kick,
+        Self::Ban,
+        Self::Timeout,
+        Self::Invite,
+        Self::ReviewApplications,
+        Self::MuteMembers,
+        Self::MoveMembers,
+        Self::ManageRoles,
+        Self::ManageChannels,
+        Self::ManageMessages,
+        Self::ManageWebhooks,
+        Self::ManageEmojis,
+        Self::SendMessages,
+        Self::SendTtsMessages,
+        Self::ManageServer,
+        Self::Administrator,
+    ];
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Union)]
 #[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Channel {
@@ -222,7 +540,10 @@ pub enum Channel {
 
 #[derive(Deserialize, Serialize, Debug, Clone, Interface)]
 #[serde(tag = "kind")]
-#[graphql(field(name = "identifier", type = "ID"), field(name = "name", type = "String"))]
+#[graphql(
+    field(name = "identifier", type = "crate::util::RecordId"),
+    field(name = "name", type = "String")
+)]
 pub enum TextableChannel {
     #[serde(rename = "text")]
     Normal(TextChannel),
@@ -238,6 +559,32 @@ impl Channel {
             Self::Text(ref t) => &t.id,
         }
     }
+
+    pub fn guild(&self) -> &Ref<Guild> {
+        match self {
+            Self::Text(ref t) => &t.guild,
+        }
+    }
+
+    /// Whether `user` may know the channel exists: members of its guild do, private channels
+    /// only to those [granted](TextChannel::grants_access) them. NSFW channels are shown to
+    /// members who haven't verified their age, so clients can ask them to, reading them still
+    /// needs [TextableChannel::require_viewable].
+    pub async fn visible_to(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<bool> {
+        let Some(member) = Member::find(surreal, self.guild(), user).await? else {
+            return Ok(false);
+        };
+        let Self::Text(text) = self;
+        if !text.private {
+            return Ok(true);
+        }
+        let permissions = Member::effective_permissions(surreal, self.guild(), user).await?;
+        Ok(text.grants_access(&member, &permissions))
+    }
 }
 
 impl TextableChannel {
@@ -246,6 +593,72 @@ impl TextableChannel {
             Self::Normal(ref t) => &t.id,
         }
     }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Normal(ref t) => &t.name,
+        }
+    }
+
+    pub fn nsfw(&self) -> bool {
+        match self {
+            Self::Normal(ref t) => t.nsfw,
+        }
+    }
+
+    pub fn guild(&self) -> &Ref<Guild> {
+        match self {
+            Self::Normal(ref t) => &t.guild,
+        }
+    }
+
+    /// Errors with `403 Forbidden` if the channel is private and `user` wasn't
+    /// [granted](TextChannel::grants_access) it, or it's NSFW and they haven't verified their
+    /// age in its guild.
+    pub async fn require_viewable(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> tide::Result<()> {
+        let Self::Normal(text) = self;
+        if !text.private && !text.nsfw {
+            return Ok(());
+        }
+        let member = Member::find(surreal, self.guild(), user).await?;
+        if text.private {
+            let permissions = Member::effective_permissions(surreal, self.guild(), user).await?;
+            if !member
+                .as_ref()
+                .is_some_and(|member| text.grants_access(member, &permissions))
+            {
+                return Err(tide::Error::new(
+                    tide::StatusCode::Forbidden,
+                    anyhow!("this channel is private"),
+                ));
+            }
+        }
+        if !text.nsfw {
+            return Ok(());
+        }
+        let verified = member.is_some_and(|member| member.age_verified);
+        if !verified {
+            return Err(tide::Error::new(
+                tide::StatusCode::Forbidden,
+                anyhow!("this channel is NSFW, verify your age to view it"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether `user` is a member of the guild who may [view](Self::require_viewable) it.
+    pub async fn readable_by(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> tide::Result<bool> {
+        Ok(Member::find(surreal, self.guild(), user).await?.is_some()
+            && self.require_viewable(surreal, user).await.is_ok())
+    }
 }
 
 impl ReferrableWithId for Channel {
@@ -266,8 +679,97 @@ pub struct TextChannel {
     #[graphql(skip)]
     pub id: Thing,
     pub name: String,
+    /// The name lowercased with dashes between words, unique within the guild.
+    #[serde(default)]
+    pub slug: String,
     #[graphql(skip)]
     pub guild: Ref<Guild>,
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Only members who are [age verified](Member::age_verified) can read these.
+    #[serde(default)]
+    pub nsfw: bool,
+    /// Hidden from everyone but the [allowed](TextChannel::grants_access) members.
+    #[serde(default)]
+    pub private: bool,
+    /// Who may see the channel while it's private.
+    #[graphql(skip)]
+    #[serde(default)]
+    pub allowed_roles: Vec<Ref<Role>>,
+    #[graphql(skip)]
+    #[serde(default)]
+    pub allowed_users: Vec<Ref<User>>,
+    /// Days messages are kept here, shorter than the guild's, see [retention](super::retention).
+    #[graphql(skip)]
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+    /// Keeps every message here, see [legal_hold](super::legal_hold).
+    #[graphql(skip)]
+    #[serde(default)]
+    pub legal_hold: bool,
+}
+
+impl TextChannel {
+    pub const MAX_TOPIC_LENGTH: usize = 1024;
+    pub const MAX_ALLOWED: usize = 100;
+
+    /// Whether `member`, holding `permissions` in the guild, may see the channel: anyone if
+    /// it isn't private, otherwise the allowed users and roles, and whoever can
+    /// [manage channels](Permission::ManageChannels).
+    pub fn grants_access(&self, member: &Member, permissions: &[Permission]) -> bool {
+        !self.private
+            || permissions.contains(&Permission::ManageChannels)
+            || permissions.contains(&Permission::Administrator)
+            || self.allowed_users.contains(&member.user)
+            || member
+                .roles
+                .iter()
+                .any(|role| self.allowed_roles.contains(role))
+    }
+
+    /// What `user` may do here. Channels don't override guild permissions, except that NSFW
+    /// ones leave nothing to members who haven't verified their age, and private ones nothing
+    /// to members who weren't [granted](Self::grants_access) them.
+    pub async fn effective_permissions(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<Permission>> {
+        let Some(member) = Member::find(surreal, &self.guild, user).await? else {
+            return Ok(vec![]);
+        };
+        if self.nsfw && !member.age_verified {
+            return Ok(vec![]);
+        }
+        let permissions = Member::effective_permissions(surreal, &self.guild, user).await?;
+        if !self.grants_access(&member, &permissions) {
+            return Ok(vec![]);
+        }
+        Ok(permissions)
+    }
+
+    /// Errors with `403 Forbidden` unless `user` holds `permission` here.
+    pub async fn require_permission(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        permission: Permission,
+    ) -> tide::Result<()> {
+        if !self
+            .effective_permissions(surreal, user)
+            .await?
+            .contains(&permission)
+        {
+            return Err(tide::Error::new(
+                tide::StatusCode::Forbidden,
+                MissingPermission {
+                    permission,
+                    guild: self.guild.clone(),
+                },
+            ));
+        }
+        Ok(())
+    }
 }
 
 
@@ -282,6 +784,17 @@ pub struct ChannelInit {
     pub name: String,
 }
 
+/// Fields left out stay as they are.
+#[derive(Deserialize, Serialize, Debug, Clone, InputObject)]
+pub struct ChannelUpdate {
+    pub name: Option<String>,
+    /// A blank topic clears it.
+    pub topic: Option<String>,
+    pub nsfw: Option<bool>,
+    /// Who may see a private channel is set with `grantAccess` and `revokeAccess`.
+    pub private: Option<bool>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Category {
     pub id: Thing,
@@ -0,0 +1,181 @@
+//! Applications to join guilds that only let people in with
+//! [JoinConstraint::Application](super::guild::JoinConstraint::Application). Applicants answer
+//! the guild's questions when joining, and stay [pending](super::guild::Member::pending)
+//! members until someone with [Permission::ReviewApplications](super::guild::Permission::ReviewApplications)
+//! approves or denies them.
+
+use anyhow::anyhow;
+use async_graphql::{Enum, SimpleObject};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{
+    query::{field, Q},
+    referrable, Ref, Referrable,
+};
+
+use super::{guild::Guild, user::User};
+
+pub const MAX_QUESTIONS: usize = 5;
+pub const MAX_QUESTION_LENGTH: usize = 300;
+pub const MAX_ANSWER_LENGTH: usize = 1000;
+pub const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplicationStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+/// The question as it was asked, so changing the questions later doesn't mix up answers.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject)]
+pub struct ApplicationAnswer {
+    pub question: String,
+    pub answer: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildApplication {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub guild: Ref<Guild>,
+    pub user: Ref<User>,
+    pub answers: Vec<ApplicationAnswer>,
+    pub status: ApplicationStatus,
+    #[serde(default)]
+    pub reviewed_by: Option<Ref<User>>,
+    /// Told to the applicant along with the decision.
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub reviewed_at: Option<Datetime>,
+}
+
+referrable!(GuildApplication = "guild_application" .id: Option<Thing>);
+
+impl GuildApplication {
+    /// Trimmed, without blank ones.
+    pub fn check_questions(questions: Vec<String>) -> tide::Result<Vec<String>> {
+        let questions: Vec<String> = questions
+            .into_iter()
+            .map(|question| question.trim().to_owned())
+            .filter(|question| !question.is_empty())
+            .collect();
+        if questions.len() > MAX_QUESTIONS {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("at most {MAX_QUESTIONS} questions"),
+            ));
+        }
+        if questions
+            .iter()
+            .any(|question| question.chars().count() > MAX_QUESTION_LENGTH)
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("questions can be at most {MAX_QUESTION_LENGTH} characters"),
+            ));
+        }
+        Ok(questions)
+    }
+
+    /// Pairs `answers` up with `questions`, in order. Every question needs an answer.
+    pub fn answer(
+        questions: &[String],
+        answers: Vec<String>,
+    ) -> tide::Result<Vec<ApplicationAnswer>> {
+        if answers.len() != questions.len() || answers.iter().any(|answer| answer.trim().is_empty())
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("answer each of the guild's {} questions", questions.len()),
+            ));
+        }
+        if answers
+            .iter()
+            .any(|answer| answer.chars().count() > MAX_ANSWER_LENGTH)
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("answers can be at most {MAX_ANSWER_LENGTH} characters"),
+            ));
+        }
+        Ok(questions
+            .iter()
+            .zip(answers)
+            .map(|(question, answer)| ApplicationAnswer {
+                question: question.clone(),
+                answer: answer.trim().to_owned(),
+            })
+            .collect())
+    }
+
+    pub async fn create(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+        answers: Vec<ApplicationAnswer>,
+    ) -> surrealdb::Result<Self> {
+        surreal
+            .create(Self::TABLE)
+            .content(GuildApplication {
+                id: None,
+                guild: guild.clone(),
+                user: user.clone(),
+                answers,
+                status: ApplicationStatus::Pending,
+                reviewed_by: None,
+                reason: None,
+                created_at: Datetime(Utc::now()),
+                reviewed_at: None,
+            })
+            .await
+    }
+
+    /// Oldest first, the order they're reviewed in.
+    pub async fn pending(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Vec<Self>> {
+        Q::select::<Self>()
+            .filter(field("guild").eq(guild).and(field("status").eq("pending")))
+            .order("created_at")
+            .all(surreal)
+            .await
+    }
+
+    /// The last time `user` applied to `guild`, whatever came of it.
+    pub async fn latest(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Option<Self>> {
+        Q::select::<Self>()
+            .filter(field("guild").eq(guild).and(field("user").eq(user)))
+            .order_desc("created_at")
+            .one(surreal)
+            .await
+    }
+
+    /// Trimmed, `None` if blank.
+    pub fn check_reason(reason: Option<String>) -> tide::Result<Option<String>> {
+        let reason = reason
+            .map(|reason| reason.trim().to_owned())
+            .filter(|reason| !reason.is_empty());
+        if reason
+            .as_ref()
+            .is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH)
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("the reason can be at most {MAX_REASON_LENGTH} characters"),
+            ));
+        }
+        Ok(reason)
+    }
+}
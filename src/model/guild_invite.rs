@@ -0,0 +1,154 @@
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{
+    query::{field, Q},
+    random_string, referrable, RecordId, Ref, Referrable, ReferrableExt,
+};
+
+use super::{
+    guild::{Guild, JoinConstraint, Member},
+    user::User,
+};
+
+/// Lets anyone who has the code join a guild. Nothing to do with
+/// [instance invites](super::instance::InstanceInvite).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildInvite {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub code: String,
+    pub guild: Ref<Guild>,
+    pub created_by: Ref<User>,
+    /// Unlimited if unset.
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub uses: u32,
+    #[serde(default)]
+    pub expires_at: Option<Datetime>,
+    pub created_at: Datetime,
+}
+
+referrable!(GuildInvite = "guild_invite" .id: Option<Thing>);
+
+/// What `invitePreview` shows before someone logs in or joins.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct InvitePreview {
+    pub guild: RecordId,
+    pub name: String,
+    pub icon_url: Option<String>,
+    /// A large picture to show behind the join screen.
+    pub splash_url: Option<String>,
+    pub member_count: u64,
+    /// Members who aren't offline.
+    pub online_count: u64,
+    /// RFC 3339.
+    pub expires_at: Option<String>,
+    pub join_constraint: JoinConstraint,
+    /// What joining asks with [JoinConstraint::Application], answered in `joinGuild`.
+    pub application_questions: Vec<String>,
+}
+
+impl GuildInvite {
+    const VALID: &'static str =
+        "(max_uses = NONE OR uses < max_uses) AND (expires_at = NONE OR expires_at > time::now())";
+
+    pub async fn create(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+        creator: &Ref<User>,
+        max_uses: Option<u32>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> surrealdb::Result<GuildInvite> {
+        surreal
+            .create(Self::TABLE)
+            .content(GuildInvite {
+                id: None,
+                code: random_string(10),
+                guild: guild.clone(),
+                created_by: creator.clone(),
+                max_uses,
+                uses: 0,
+                expires_at: expires_at.map(Datetime),
+                created_at: Datetime(Utc::now()),
+            })
+            .await
+    }
+
+    /// Newest first, used up and expired ones included.
+    pub async fn of_guild(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Vec<GuildInvite>> {
+        Q::select::<GuildInvite>()
+            .filter(field("guild").eq(guild))
+            .order_desc("created_at")
+            .all(surreal)
+            .await
+    }
+
+    /// `None` if there's no such code, or it's used up or expired.
+    pub async fn find(
+        surreal: &crate::Surreal,
+        code: &str,
+    ) -> surrealdb::Result<Option<GuildInvite>> {
+        surreal
+            .query(format!(
+                "SELECT * FROM guild_invite WHERE code = $code AND {}",
+                Self::VALID
+            ))
+            .bind(("code", code))
+            .await?
+            .take(0)
+    }
+
+    /// Counts a use of `code`, in one statement so two people can't both take the last use.
+    pub async fn redeem(surreal: &crate::Surreal, code: &str) -> tide::Result<GuildInvite> {
+        let redeemed: Option<GuildInvite> = surreal
+            .query(format!(
+                "UPDATE guild_invite SET uses += 1 WHERE code = $code AND {}",
+                Self::VALID
+            ))
+            .bind(("code", code))
+            .await?
+            .take(0)?;
+        redeemed.ok_or_else(|| {
+            tide::Error::new(
+                StatusCode::NotFound,
+                anyhow!("this invite is invalid, used up or expired"),
+            )
+        })
+    }
+
+    pub async fn preview(&self, surreal: &crate::Surreal) -> tide::Result<InvitePreview> {
+        let guild = self.guild.fetch(surreal).await?;
+        let online: Option<u64> = surreal
+            .query(
+                "SELECT VALUE count() FROM member WHERE guild = $guild AND pending != true \
+                 AND user.status IN ['online', 'idle', 'do_not_disturb'] GROUP ALL",
+            )
+            .bind(("guild", guild.record_id()))
+            .await?
+            .take(0)?;
+        Ok(InvitePreview {
+            guild: guild.gql_id(),
+            member_count: Q::select::<Member>()
+                .filter(field("guild").eq(guild.record_id()))
+                .filter(field("pending").ne(true))
+                .count(surreal)
+                .await? as u64,
+            online_count: online.unwrap_or(0),
+            icon_url: guild.icon.map(|path| format!("/{path}")),
+            splash_url: guild.splash.map(|path| format!("/{path}")),
+            name: guild.name,
+            join_constraint: guild.join_constraint,
+            application_questions: guild.application_questions,
+            expires_at: self.expires_at.as_ref().map(|at| at.0.to_rfc3339()),
+        })
+    }
+}
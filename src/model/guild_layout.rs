@@ -0,0 +1,121 @@
+//! How a user arranges their guild sidebar: in which order, and which guilds are grouped
+//! into folders. Stored per user so every device shows the same layout.
+
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use async_graphql::{InputObject, SimpleObject};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{referrable, RecordId, Ref, Referrable, ReferrableExt};
+
+use super::{guild::Guild, user::User};
+
+/// A folder of guilds, or a single guild outside any folder when `id` is `null`.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[graphql(input_name = "GuildFolderInput")]
+pub struct GuildFolder {
+    /// Chosen by the client, only needs to be unique within the layout.
+    pub id: Option<String>,
+    pub name: Option<String>,
+    /// `0xRRGGBB`.
+    pub color: Option<i32>,
+    /// In sidebar order.
+    pub guilds: Vec<RecordId>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GuildLayout {
+    pub id: Thing,
+    pub user: Ref<User>,
+    pub folders: Vec<GuildFolder>,
+    pub updated_at: Datetime,
+}
+
+referrable!(GuildLayout = "guild_layout" .id: Thing);
+
+impl GuildLayout {
+    pub const MAX_FOLDERS: usize = 200;
+    pub const MAX_NAME_LENGTH: usize = 32;
+
+    fn key(user: &Ref<User>) -> Thing {
+        Thing::from((Self::TABLE.to_owned(), user.id().to_owned()))
+    }
+
+    /// Empty until the user first rearranges something.
+    pub async fn of_user(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<GuildFolder>> {
+        let layout: Option<GuildLayout> = surreal.select(Self::key(user)).await?;
+        Ok(layout.map(|layout| layout.folders).unwrap_or_default())
+    }
+
+    /// Replaces the layout. Guilds the user isn't in, or that are in there twice, are left
+    /// out, and so are folders that end up empty.
+    pub async fn set(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        folders: Vec<GuildFolder>,
+        member_of: &[Guild],
+    ) -> tide::Result<Vec<GuildFolder>> {
+        let invalid = folders.len() > Self::MAX_FOLDERS
+            || folders.iter().any(|folder| {
+                folder
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| name.chars().count() > Self::MAX_NAME_LENGTH)
+                    || (folder.id.is_none() && folder.guilds.len() > 1)
+            });
+        if invalid {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!(
+                    "at most {} folders with names of at most {} characters, and guilds \
+                     outside folders one at a time",
+                    Self::MAX_FOLDERS,
+                    Self::MAX_NAME_LENGTH
+                ),
+            ));
+        }
+
+        let mut unplaced: HashSet<RecordId> =
+            member_of.iter().map(|guild| guild.gql_id()).collect();
+        let folders: Vec<_> = folders
+            .into_iter()
+            .map(|mut folder| {
+                folder.guilds.retain(|guild| unplaced.remove(guild));
+                folder
+            })
+            .filter(|folder| !folder.guilds.is_empty())
+            .collect();
+
+        let key = Self::key(user);
+        let layout: GuildLayout = surreal
+            .update(key.clone())
+            .content(GuildLayout {
+                id: key,
+                user: user.clone(),
+                folders,
+                updated_at: Datetime(Utc::now()),
+            })
+            .await?;
+        Ok(layout.folders)
+    }
+
+    /// Puts `guilds` in the order of `folders`. Guilds joined since the layout was saved go
+    /// last, as they came.
+    pub fn sort(guilds: &mut [Guild], folders: &[GuildFolder]) {
+        let order: Vec<&RecordId> = folders.iter().flat_map(|folder| &folder.guilds).collect();
+        guilds.sort_by_cached_key(|guild| {
+            let id = guild.gql_id();
+            order
+                .iter()
+                .position(|placed| **placed == id)
+                .unwrap_or(order.len())
+        });
+    }
+}
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_graphql::Enum;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    util::{random_string, referrable, Ref, Referrable},
+};
+
+use super::user::User;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    Open,
+    /// Only with an [InstanceInvite] code.
+    InviteOnly,
+    Closed,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(Self::Open),
+            "invite_only" => Ok(Self::InviteOnly),
+            "closed" => Ok(Self::Closed),
+            _ => Err(anyhow!("unknown registration mode {s}")),
+        }
+    }
+}
+
+/// Settings admins change at runtime, `instance:settings`. The config only has the defaults.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceSettings {
+    pub registration: RegistrationMode,
+    /// Days messages are kept, forever if unset.
+    #[serde(default)]
+    pub retention_days: Option<u32>,
+}
+
+impl InstanceSettings {
+    const ID: (&'static str, &'static str) = ("instance", "settings");
+
+    pub async fn get(surreal: &crate::Surreal) -> surrealdb::Result<Self> {
+        let settings: Option<Self> = surreal.select(Self::ID).await?;
+        Ok(settings.unwrap_or(Self {
+            registration: CONFIG.registration,
+            retention_days: None,
+        }))
+    }
+
+    pub async fn save(&self, surreal: &crate::Surreal) -> surrealdb::Result<()> {
+        let _: Option<Self> = surreal.update(Self::ID).content(self).await?;
+        Ok(())
+    }
+}
+
+/// Lets someone register while registration is [invite only](RegistrationMode::InviteOnly).
+/// Nothing to do with guild invites.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceInvite {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub code: String,
+    pub created_by: Ref<User>,
+    /// Unlimited if unset.
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub uses: u32,
+    #[serde(default)]
+    pub expires_at: Option<Datetime>,
+    pub created_at: Datetime,
+}
+
+referrable!(InstanceInvite = "instance_invite" .id: Option<Thing>);
+
+impl InstanceInvite {
+    pub async fn create(
+        surreal: &crate::Surreal,
+        creator: &Ref<User>,
+        max_uses: Option<u32>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> surrealdb::Result<InstanceInvite> {
+        surreal
+            .create(Self::TABLE)
+            .content(InstanceInvite {
+                id: None,
+                code: random_string(12),
+                created_by: creator.clone(),
+                max_uses,
+                uses: 0,
+                expires_at: expires_at.map(Datetime),
+                created_at: Datetime(Utc::now()),
+            })
+            .await
+    }
+
+    /// Newest first.
+    pub async fn all(surreal: &crate::Surreal) -> surrealdb::Result<Vec<InstanceInvite>> {
+        crate::util::query::Q::select::<InstanceInvite>()
+            .order_desc("created_at")
+            .all(surreal)
+            .await
+    }
+
+    /// Counts a use of `code`, in one statement so two registrations can't both take the
+    /// last use.
+    pub async fn redeem(surreal: &crate::Surreal, code: &str) -> tide::Result<InstanceInvite> {
+        let redeemed: Option<InstanceInvite> = surreal
+            .query(
+                "UPDATE instance_invite SET uses += 1 WHERE code = $code \
+                 AND (max_uses = NONE OR uses < max_uses) \
+                 AND (expires_at = NONE OR expires_at > time::now())",
+            )
+            .bind(("code", code))
+            .await?
+            .take(0)?;
+        redeemed.ok_or_else(|| {
+            tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("this invite code is invalid, used up or expired"),
+            )
+        })
+    }
+}
+
+/// Errors unless someone may register right now, redeeming `invite` if it takes one.
+pub async fn check_registration(
+    surreal: &crate::Surreal,
+    invite: Option<&str>,
+) -> tide::Result<()> {
+    match InstanceSettings::get(surreal).await?.registration {
+        RegistrationMode::Open => Ok(()),
+        RegistrationMode::Closed => Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("registration is closed"),
+        )),
+        RegistrationMode::InviteOnly => {
+            let invite = invite.ok_or_else(|| {
+                tide::Error::new(
+                    StatusCode::Forbidden,
+                    anyhow!("registration needs an invite code"),
+                )
+            })?;
+            InstanceInvite::redeem(surreal, invite).await?;
+            Ok(())
+        }
+    }
+}
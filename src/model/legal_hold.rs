@@ -0,0 +1,82 @@
+//! Legal holds keep a user's or a channel's messages as they are for as long as admins need
+//! them, say for a court case: [retention](super::retention) skips them, and deleting one only
+//! [tombstones](super::message::Message::deleted_at) it, keeping the content in the database.
+//! Placing and lifting holds goes in the [admin audit trail](super::admin_audit).
+
+use anyhow::anyhow;
+use tide::StatusCode;
+
+use crate::util::{RecordId, Ref, ReferrableExt};
+
+use super::{
+    admin_audit::{AdminAction, AdminActionKind},
+    message::Message,
+    user::User,
+};
+
+pub const MAX_REASON_LENGTH: usize = 1000;
+
+/// A condition on messages, for `WHERE` clauses: no hold covers them.
+pub const NOT_HELD: &str = "author.legal_hold != true \
+     AND (recipient.kind != 'Channel' OR recipient.id.legal_hold != true)";
+
+/// Same for attachments, which are held with their uploader or the channel they're for.
+pub const ATTACHMENT_NOT_HELD: &str = "owner.legal_hold != true \
+     AND (channel = NONE OR channel.legal_hold != true)";
+
+/// Whether a hold on its author or channel covers `message`.
+pub async fn covers(surreal: &crate::Surreal, message: &Message) -> surrealdb::Result<bool> {
+    let free: Option<bool> = surreal
+        .query(format!("SELECT VALUE {NOT_HELD} FROM $message"))
+        .bind(("message", message.record_id()))
+        .await?
+        .take(0)?;
+    Ok(free == Some(false))
+}
+
+/// Places a hold on `subject`, a user or a channel, or lifts it.
+pub async fn set(
+    surreal: &crate::Surreal,
+    admin: &Ref<User>,
+    subject: RecordId,
+    held: bool,
+    reason: String,
+) -> tide::Result<AdminAction> {
+    if !matches!(subject.0.tb.as_str(), "user" | "channel") {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("only users and channels can be held"),
+        ));
+    }
+    let reason = reason.trim().to_owned();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_LENGTH {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("give a reason of at most {MAX_REASON_LENGTH} characters"),
+        ));
+    }
+    // UPDATE would make up a record that isn't there
+    let found: Option<RecordId> = surreal
+        .query("SELECT VALUE id FROM $subject")
+        .bind(("subject", subject.clone()))
+        .await?
+        .take(0)?;
+    if found.is_none() {
+        return Err(tide::Error::new(
+            StatusCode::NotFound,
+            anyhow!("there's no {}", subject.0),
+        ));
+    }
+    surreal
+        .query("UPDATE $subject SET legal_hold = $held")
+        .bind(("subject", subject.clone()))
+        .bind(("held", held))
+        .await?
+        .check()?;
+    let kind = if held {
+        AdminActionKind::LegalHoldPlaced
+    } else {
+        AdminActionKind::LegalHoldLifted
+    };
+    Ok(AdminAction::log(surreal, admin, kind, subject, reason).await?)
+}
@@ -0,0 +1,91 @@
+//! How much of the instance one user or guild may take up, see [CONFIG]. Checked before
+//! adding, so a limit lowered later leaves those already over it alone.
+
+use async_graphql::ErrorExtensions;
+
+use crate::{
+    config::CONFIG,
+    util::{
+        query::{field, Q},
+        Ref,
+    },
+};
+
+use super::{
+    guild::{Guild, Member, TextChannel},
+    user::User,
+};
+
+/// Fails with `LIMIT_REACHED`, which `limit` it was and its `max`.
+fn reached(limit: &'static str, max: usize, message: String) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, extensions| {
+        extensions.set("code", "LIMIT_REACHED");
+        extensions.set("limit", limit);
+        extensions.set("max", max);
+    })
+}
+
+fn check(
+    limit: &'static str,
+    max: usize,
+    count: i64,
+    message: String,
+) -> async_graphql::Result<()> {
+    if max != 0 && count >= max as i64 {
+        return Err(reached(limit, max, message));
+    }
+    Ok(())
+}
+
+/// Whether `user` may join or create another guild.
+pub async fn check_guilds(surreal: &crate::Surreal, user: &Ref<User>) -> async_graphql::Result<()> {
+    let max = CONFIG.max_guilds_per_user;
+    let count = Q::select::<Member>()
+        .filter(field("user").eq(user))
+        .filter(field("pending").ne(true))
+        .count(surreal)
+        .await?;
+    check(
+        "GUILDS_PER_USER",
+        max,
+        count,
+        format!("you can be in at most {max} guilds, leave one first"),
+    )
+}
+
+/// Whether `guild` can let anyone else in.
+pub async fn check_members(
+    surreal: &crate::Surreal,
+    guild: &Ref<Guild>,
+) -> async_graphql::Result<()> {
+    let max = CONFIG.max_members_per_guild;
+    let count = Q::select::<Member>()
+        .filter(field("guild").eq(guild))
+        .filter(field("pending").ne(true))
+        .count(surreal)
+        .await?;
+    check(
+        "MEMBERS_PER_GUILD",
+        max,
+        count,
+        format!("this guild is full, it can have at most {max} members"),
+    )
+}
+
+/// Whether `guild` can have another channel.
+pub async fn check_channels(
+    surreal: &crate::Surreal,
+    guild: &Ref<Guild>,
+) -> async_graphql::Result<()> {
+    let max = CONFIG.max_channels_per_guild;
+    let count = Q::select::<TextChannel>()
+        .filter(field("guild").eq(guild))
+        .count(surreal)
+        .await?;
+    check(
+        "CHANNELS_PER_GUILD",
+        max,
+        count,
+        format!("guilds can have at most {max} channels"),
+    )
+}
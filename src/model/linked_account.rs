@@ -0,0 +1,307 @@
+//! Accounts elsewhere users show on their profile: GitHub, Steam or a website. Unverified
+//! ones are just claims; GitHub and Steam are verified by logging in there, see
+//! [crate::linked], websites by a meta tag with the [verification code](LinkedAccount::code).
+
+use anyhow::anyhow;
+use async_graphql::Enum;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::{http::Url, StatusCode};
+
+use crate::{
+    config::CONFIG,
+    util::{
+        fetch,
+        query::{field, Q},
+        random_string, referrable, Ref, Referrable, ReferrableExt,
+    },
+};
+
+use super::user::User;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkedAccountKind {
+    /// By username.
+    Github,
+    /// By SteamID64, which verifying fills in.
+    Steam,
+    /// By URL.
+    Website,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkedAccountVisibility {
+    /// Also on the public profile.
+    #[default]
+    Everyone,
+    Friends,
+    OnlyMe,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LinkedAccount {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    pub kind: LinkedAccountKind,
+    pub name: String,
+    pub verified: bool,
+    #[serde(default)]
+    pub visibility: LinkedAccountVisibility,
+    /// The OAuth `state` of verifying, or the content of the website's
+    /// `<meta name="netherite-verification">`.
+    pub code: String,
+    pub created_at: Datetime,
+}
+
+referrable!(LinkedAccount = "linked_account" .id: Option<Thing>);
+
+/// The name meta tag websites are verified with.
+pub const META_NAME: &str = "netherite-verification";
+
+fn bad_request(message: &str) -> tide::Error {
+    tide::Error::new(StatusCode::BadRequest, anyhow!("{message}"))
+}
+
+impl LinkedAccount {
+    pub const MAX_PER_USER: usize = 10;
+
+    /// Where GitHub and Steam send users back to after verifying, see [crate::linked].
+    pub fn callback_url(provider: &str) -> String {
+        format!("https://{}/linked/{provider}/callback", CONFIG.domain)
+    }
+
+    fn check_name(kind: LinkedAccountKind, name: &str) -> tide::Result<()> {
+        let valid = match kind {
+            LinkedAccountKind::Github => {
+                (1..=39).contains(&name.len())
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            }
+            LinkedAccountKind::Steam => {
+                name.len() == 17 && name.chars().all(|c| c.is_ascii_digit())
+            }
+            LinkedAccountKind::Website => Url::parse(name)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host()),
+        };
+        if !valid {
+            return Err(bad_request(match kind {
+                LinkedAccountKind::Github => "not a GitHub username",
+                LinkedAccountKind::Steam => "not a SteamID64, 17 digits",
+                LinkedAccountKind::Website => "not an http(s) URL",
+            }));
+        }
+        Ok(())
+    }
+
+    pub async fn add(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        kind: LinkedAccountKind,
+        name: String,
+        visibility: LinkedAccountVisibility,
+    ) -> tide::Result<LinkedAccount> {
+        let name = name.trim().to_owned();
+        Self::check_name(kind, &name)?;
+        let existing = Self::of_user(surreal, user).await?;
+        if existing.len() >= Self::MAX_PER_USER {
+            return Err(bad_request("that's enough linked accounts"));
+        }
+        if existing
+            .iter()
+            .any(|account| account.kind == kind && account.name.eq_ignore_ascii_case(&name))
+        {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("already linked"),
+            ));
+        }
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(LinkedAccount {
+                id: None,
+                user: user.clone(),
+                kind,
+                name,
+                verified: false,
+                visibility,
+                code: random_string(32),
+                created_at: Datetime(Utc::now()),
+            })
+            .await?)
+    }
+
+    /// Oldest first.
+    pub async fn of_user(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<LinkedAccount>> {
+        Q::select::<LinkedAccount>()
+            .filter(field("user").eq(user))
+            .order("created_at")
+            .all(surreal)
+            .await
+    }
+
+    pub async fn by_code(
+        surreal: &crate::Surreal,
+        code: &str,
+    ) -> surrealdb::Result<Option<LinkedAccount>> {
+        Q::select::<LinkedAccount>()
+            .filter(field("code").eq(code))
+            .one(surreal)
+            .await
+    }
+
+    /// `user`'s own account, or `404 Not Found`.
+    pub async fn owned(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        account: &Ref<LinkedAccount>,
+    ) -> tide::Result<LinkedAccount> {
+        let found: Option<LinkedAccount> = surreal.select(account.record_id().0).await?;
+        found
+            .filter(|found| found.user == *user)
+            .ok_or_else(|| tide::Error::new(StatusCode::NotFound, anyhow!("no such account")))
+    }
+
+    pub async fn remove(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        account: &Ref<LinkedAccount>,
+    ) -> tide::Result<bool> {
+        let account = Self::owned(surreal, user, account).await?;
+        let _: Option<LinkedAccount> = surreal.delete(account.id.unwrap()).await?;
+        Ok(true)
+    }
+
+    /// Marks the account verified as `name`, what the other side says it is. A fresh code
+    /// keeps the old one from being replayed.
+    pub async fn verify(
+        mut self,
+        surreal: &crate::Surreal,
+        name: String,
+    ) -> tide::Result<LinkedAccount> {
+        self.name = name;
+        self.verified = true;
+        self.code = random_string(32);
+        self.save(surreal).await
+    }
+
+    /// Steam's `openid.return_to`, the callback carrying the `state`.
+    pub fn steam_return_to(state: &str) -> String {
+        format!("{}?state={state}", Self::callback_url("steam"))
+    }
+
+    /// Where to send the user to verify a GitHub or Steam account, `None` for websites or
+    /// while GitHub isn't configured. It goes through [crate::linked] first, which ties the
+    /// browser to the `state` before handing over to [provider_url](Self::provider_url).
+    pub fn verification_url(&self) -> Option<String> {
+        let provider = match self.kind {
+            LinkedAccountKind::Github => {
+                CONFIG.github_client_id.as_ref()?;
+                "github"
+            }
+            LinkedAccountKind::Steam => "steam",
+            LinkedAccountKind::Website => return None,
+        };
+        let mut url = Url::parse(&format!(
+            "https://{}/linked/{provider}/start",
+            CONFIG.domain
+        ))
+        .unwrap();
+        url.query_pairs_mut().append_pair("state", &self.code);
+        Some(url.to_string())
+    }
+
+    /// The GitHub authorization or Steam login page, `None` for websites or while GitHub isn't
+    /// configured.
+    pub fn provider_url(&self) -> Option<String> {
+        match self.kind {
+            LinkedAccountKind::Github => {
+                let client_id = CONFIG.github_client_id.as_ref()?;
+                let mut url = Url::parse("https://github.com/login/oauth/authorize").unwrap();
+                url.query_pairs_mut()
+                    .append_pair("client_id", client_id)
+                    .append_pair("redirect_uri", &Self::callback_url("github"))
+                    .append_pair("state", &self.code)
+                    .append_pair("allow_signup", "false");
+                Some(url.to_string())
+            }
+            LinkedAccountKind::Steam => {
+                let return_to = Self::steam_return_to(&self.code);
+                let mut url = Url::parse("https://steamcommunity.com/openid/login").unwrap();
+                url.query_pairs_mut()
+                    .append_pair("openid.ns", "http://specs.openid.net/auth/2.0")
+                    .append_pair("openid.mode", "checkid_setup")
+                    .append_pair("openid.return_to", &return_to)
+                    .append_pair("openid.realm", &format!("https://{}", CONFIG.domain))
+                    .append_pair(
+                        "openid.identity",
+                        "http://specs.openid.net/auth/2.0/identifier_select",
+                    )
+                    .append_pair(
+                        "openid.claimed_id",
+                        "http://specs.openid.net/auth/2.0/identifier_select",
+                    );
+                Some(url.to_string())
+            }
+            LinkedAccountKind::Website => None,
+        }
+    }
+
+    /// Verifies a website by its `<meta name="netherite-verification" content="code">`.
+    pub async fn verify_website(self, surreal: &crate::Surreal) -> tide::Result<LinkedAccount> {
+        if self.kind != LinkedAccountKind::Website {
+            return Err(bad_request(
+                "GitHub and Steam accounts are verified at verifyUrl",
+            ));
+        }
+        let mut response = fetch::get_public(&self.name).await?;
+        let page = response.body_string().await?;
+        let tag = format!(r#"name="{META_NAME}" content="{}""#, self.code);
+        if !page.contains(&tag) {
+            return Err(bad_request(
+                "the page has no netherite-verification meta tag with this code",
+            ));
+        }
+        let name = self.name.clone();
+        Ok(self.verify(surreal, name).await?)
+    }
+
+    /// `owner`'s accounts `viewer` may see, `None` being whoever looks at the public profile.
+    pub async fn visible(
+        surreal: &crate::Surreal,
+        owner: &User,
+        viewer: Option<&Ref<User>>,
+    ) -> tide::Result<Vec<LinkedAccount>> {
+        let accounts = Self::of_user(surreal, &owner.refer()).await?;
+        let Some(viewer) = viewer else {
+            return Ok(accounts
+                .into_iter()
+                .filter(|account| account.visibility == LinkedAccountVisibility::Everyone)
+                .collect());
+        };
+        if *viewer == owner.refer() {
+            return Ok(accounts);
+        }
+        let friends = if accounts
+            .iter()
+            .any(|account| account.visibility == LinkedAccountVisibility::Friends)
+        {
+            owner.friend_refs(surreal).await?.contains(viewer)
+        } else {
+            false
+        };
+        Ok(accounts
+            .into_iter()
+            .filter(|account| match account.visibility {
+                LinkedAccountVisibility::Everyone => true,
+                LinkedAccountVisibility::Friends => friends,
+                LinkedAccountVisibility::OnlyMe => false,
+            })
+            .collect())
+    }
+}
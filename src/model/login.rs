@@ -0,0 +1,256 @@
+use std::net::{IpAddr, SocketAddr};
+
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::error;
+
+use crate::{
+    auth::JwtKind,
+    config::CONFIG,
+    mail::{self, Email},
+    pubsub::Relay,
+    util::{
+        query::{field, Q},
+        referrable, Ref, Referrable,
+    },
+};
+
+use super::user::User;
+
+/// Whether `ip` is one of the [trusted proxies](crate::config::Config::trusted_proxies).
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+    CONFIG.trusted_proxies.iter().any(|proxy| {
+        let (network, bits) = match proxy.split_once('/') {
+            Some((network, bits)) => (network, bits.parse().ok()),
+            None => (proxy.as_str(), None),
+        };
+        match (network.parse::<IpAddr>(), ip) {
+            (Ok(IpAddr::V4(network)), IpAddr::V4(ip)) => {
+                let bits = bits.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (Ok(IpAddr::V6(network)), IpAddr::V6(ip)) => {
+                let bits = bits.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    })
+}
+
+fn peer_ip<S>(request: &tide::Request<S>) -> Option<IpAddr> {
+    let peer = request.peer_addr()?;
+    peer.parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| peer.parse())
+        .ok()
+}
+
+/// Whether the request came through one of our own reverse proxies, so the headers they set
+/// can be believed.
+pub fn from_trusted_proxy<S>(request: &tide::Request<S>) -> bool {
+    peer_ip(request).is_some_and(is_trusted_proxy)
+}
+
+/// The client's IP: the socket's peer, unless that is a trusted proxy, then the last address
+/// in `X-Forwarded-For` that isn't one. Clients can put anything in that header, so it's
+/// only read from proxies, and only the part they appended.
+pub fn client_ip<S>(request: &tide::Request<S>) -> Option<String> {
+    let peer = peer_ip(request)?;
+    if !is_trusted_proxy(peer) {
+        return Some(peer.to_string());
+    }
+    let forwarded = request
+        .header("x-forwarded-for")
+        .map(|values| {
+            values
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let client = forwarded
+        .into_iter()
+        .rev()
+        .find(|ip| !is_trusted_proxy(*ip))
+        .unwrap_or(peer);
+    Some(client.to_string())
+}
+
+/// Where a login came from, as far as we can tell.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, SimpleObject)]
+pub struct Device {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Only known if a trusted reverse proxy tells us, see `NETHERITE_CHAT_GEO_HEADER`.
+    pub geo: Option<String>,
+}
+
+impl Device {
+    pub fn of<S>(request: &tide::Request<S>) -> Self {
+        let header = |name: &str| {
+            request
+                .header(name)
+                .map(|value| value.as_str().chars().take(256).collect::<String>())
+        };
+        Self {
+            user_agent: header("user-agent"),
+            ip: client_ip(request),
+            geo: CONFIG
+                .geo_header
+                .as_deref()
+                .filter(|_| from_trusted_proxy(request))
+                .and_then(header),
+        }
+    }
+
+    /// The same browser from the same country counts as the same device. The IP is left out,
+    /// it changes too often to mean much.
+    pub fn fingerprint(&self) -> String {
+        let mut hash = Sha1::new();
+        hash.update(self.user_agent.as_deref().unwrap_or_default());
+        hash.update([0]);
+        hash.update(self.geo.as_deref().unwrap_or_default());
+        format!("{:x}", hash.finalize())
+    }
+
+    fn describe(&self) -> String {
+        let mut description = self
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| String::from("an unknown client"));
+        match (&self.ip, &self.geo) {
+            (Some(ip), Some(geo)) => description += &format!(" ({ip}, {geo})"),
+            (Some(ip), None) => description += &format!(" ({ip})"),
+            (None, Some(geo)) => description += &format!(" ({geo})"),
+            (None, None) => {}
+        }
+        description
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Login {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    /// The token family the login started, see [`crate::auth::Jwt`].
+    pub family: String,
+    pub fingerprint: String,
+    #[serde(flatten)]
+    pub device: Device,
+    pub at: Datetime,
+    /// Whether the user had logged in before, but never from this device.
+    pub new_device: bool,
+}
+
+referrable!(Login = "login" .id: Option<Thing>);
+
+impl Login {
+    pub const RECENT: i64 = 20;
+
+    /// Remembers a successful login, telling the user about it if it came from a new device.
+    /// The tokens are already issued by then, so failing to is logged rather than failing the
+    /// login, which would leave the client with an error and live tokens.
+    pub async fn record(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        user: &Ref<User>,
+        family: String,
+        device: Device,
+    ) {
+        if let Err(e) = Self::try_record(surreal, relay, user, family, device).await {
+            error!("couldn't record a login of {}: {e}", user.id());
+        }
+    }
+
+    async fn try_record(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        user: &Ref<User>,
+        family: String,
+        device: Device,
+    ) -> tide::Result<Login> {
+        let fingerprint = device.fingerprint();
+        let of_user = || Q::select::<Login>().filter(field("user").eq(user));
+        let known = of_user().count(surreal).await? > 0;
+        let seen = of_user()
+            .filter(field("fingerprint").eq(fingerprint.as_str()))
+            .count(surreal)
+            .await?
+            > 0;
+
+        let login: Login = surreal
+            .create(Self::TABLE)
+            .content(Login {
+                id: None,
+                user: user.clone(),
+                family,
+                fingerprint,
+                device,
+                at: Datetime(Utc::now()),
+                new_device: known && !seen,
+            })
+            .await?;
+        if login.new_device {
+            login.notify(surreal, relay).await?;
+        }
+        Ok(login)
+    }
+
+    async fn notify(&self, surreal: &crate::Surreal, relay: &Relay) -> tide::Result<()> {
+        let text = format!(
+            "New login from {} at {}. If this wasn't you, change your password.",
+            self.device.describe(),
+            self.at.0.format("%Y-%m-%d %H:%M UTC"),
+        );
+        User::send_system_message(surreal, relay, &self.user, text.clone()).await?;
+
+        let user = self.user.fetch(surreal).await?;
+        if !user.notifications.login_emails {
+            return Ok(());
+        }
+        if let Some(email) = User::email(surreal, &self.user).await? {
+            mail::queue(
+                surreal,
+                Email {
+                    to: email,
+                    subject: String::from("New login to your account"),
+                    body: text,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn recent(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        limit: i64,
+    ) -> tide::Result<Vec<Login>> {
+        Ok(Q::select::<Login>()
+            .filter(field("user").eq(user))
+            .order_desc("at")
+            .limit(limit)
+            .all(surreal)
+            .await?)
+    }
+
+    /// Logins whose token family can still be refreshed.
+    pub async fn active(surreal: &crate::Surreal, user: &Ref<User>) -> tide::Result<Vec<Login>> {
+        let since = Datetime(Utc::now() - JwtKind::Refresh.expiry());
+        Ok(surreal
+            .query("SELECT * FROM login WHERE user = $user AND family INSIDE (SELECT VALUE family FROM jwt WHERE uid = $user AND kind = 'refresh' AND active = true AND issued_at > $since) ORDER BY at DESC")
+            .bind(("user", user))
+            .bind(("since", since))
+            .await?
+            .take(0)?)
+    }
+}
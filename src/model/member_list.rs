@@ -0,0 +1,373 @@
+//! The member sidebar, loaded lazily: clients ask for ranges of a channel's member list, like
+//! `[0, 99]`, and get a [MemberChunk] per range, again whenever what's in it changes. Only
+//! the requested ranges are ever sent, so big guilds don't sync every member up front.
+//!
+//! The lists someone is watching are kept in [MemberLists], one per channel however many
+//! subscribers it has. Changes are applied to them one member at a time, instead of every
+//! subscriber loading the whole list again.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use async_std::sync::Mutex;
+use serde::Deserialize;
+use surrealdb::sql::Thing;
+use tide::{log::error, StatusCode};
+
+use crate::{
+    pubsub::Relay,
+    util::{
+        query::{field, Q},
+        RecordId, Ref, ReferrableExt,
+    },
+};
+
+use super::{
+    guild::{Guild, Member, Permission, Role, TextableChannel},
+    user::{Status, User},
+};
+
+pub const MAX_RANGES: usize = 5;
+pub const MAX_RANGE_LENGTH: i32 = 100;
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct MemberListItem {
+    /// Position in the whole list.
+    pub index: i32,
+    pub member: Member,
+    pub status: Status,
+}
+
+/// One requested range of the member list.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct MemberChunk {
+    pub channel: RecordId,
+    /// Both ends included.
+    pub range: Vec<i32>,
+    /// Members in the whole list, for sizing the scrollbar.
+    pub total: i32,
+    pub online: i32,
+    pub members: Vec<MemberListItem>,
+}
+
+impl MemberChunk {
+    /// What clients would see change: the counts, who is where, their status and name.
+    pub fn fingerprint(&self) -> (i32, i32, Vec<(RecordId, Status, Option<String>)>) {
+        let members = self
+            .members
+            .iter()
+            .map(|item| {
+                (
+                    item.member.gql_id(),
+                    item.status,
+                    item.member.nickname.clone(),
+                )
+            })
+            .collect();
+        (self.total, self.online, members)
+    }
+}
+
+/// Errors unless `ranges` are at most [MAX_RANGES] pairs of `[start, end]`, each spanning at
+/// most [MAX_RANGE_LENGTH] members.
+pub fn check_ranges(ranges: &[Vec<i32>]) -> tide::Result<()> {
+    let valid = ranges.len() <= MAX_RANGES
+        && ranges.iter().all(|range| match range[..] {
+            [start, end] => 0 <= start && start <= end && end - start < MAX_RANGE_LENGTH,
+            _ => false,
+        });
+    if !valid {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!(
+                "ask for at most {MAX_RANGES} ranges like [0, 99], of at most \
+                 {MAX_RANGE_LENGTH} members each"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// What changed about a guild's members, for [MemberLists::changed].
+#[derive(Debug, Clone)]
+pub enum MemberListChange {
+    /// Joined, or changed how they're listed: their status, name or access.
+    Member(Ref<User>),
+    /// Left or were removed.
+    Left(Vec<Ref<User>>),
+    /// Anything else, like who may read a channel. Lists load again.
+    All,
+}
+
+#[derive(Debug)]
+struct Listed {
+    offline: bool,
+    /// Lowercased, what the list is sorted by after `offline`.
+    name: String,
+    member: Member,
+    status: Status,
+}
+
+impl Listed {
+    fn new(member: Member, user: &User) -> Self {
+        let name = member
+            .nickname
+            .clone()
+            .unwrap_or_else(|| user.display_name.clone())
+            .to_lowercase();
+        Self {
+            offline: user.status == Status::Offline,
+            name,
+            member,
+            status: user.status,
+        }
+    }
+
+    fn key(&self) -> (bool, &str) {
+        (self.offline, &self.name)
+    }
+}
+
+/// Everyone who can read a channel, online first, then by name.
+#[derive(Debug)]
+struct MemberList {
+    channel: TextableChannel,
+    listed: Vec<Listed>,
+}
+
+impl MemberList {
+    async fn load(surreal: &crate::Surreal, channel: TextableChannel) -> surrealdb::Result<Self> {
+        let mut members = Q::select::<Member>()
+            .filter(field("guild").eq(channel.guild()))
+            .filter(field("pending").ne(true));
+        if channel.nsfw() {
+            members = members.filter(field("age_verified").eq(true));
+        }
+        let mut members = members.all(surreal).await?;
+        let TextableChannel::Normal(ref text) = channel;
+        if text.private {
+            let roles: HashMap<_, _> = Q::select::<Role>()
+                .filter(field("guild").eq(channel.guild()))
+                .all(surreal)
+                .await?
+                .into_iter()
+                .map(|role| (role.record_id(), role.permissions))
+                .collect();
+            members.retain(|member| {
+                let permissions: Vec<Permission> = member
+                    .roles
+                    .iter()
+                    .filter_map(|role| roles.get(&role.record_id()))
+                    .flatten()
+                    .copied()
+                    .collect();
+                text.grants_access(member, &permissions)
+            });
+        }
+        let users: Vec<User> = surreal
+            .query("SELECT * FROM $users")
+            .bind((
+                "users",
+                members
+                    .iter()
+                    .map(|member| member.user.record_id())
+                    .collect::<Vec<_>>(),
+            ))
+            .await?
+            .take(0)?;
+        let users: HashMap<_, _> = users
+            .into_iter()
+            .map(|user| (user.id.clone(), user))
+            .collect();
+
+        let mut listed: Vec<_> = members
+            .into_iter()
+            .filter_map(|member| {
+                let user = users.get(&member.user.record_id().0)?;
+                Some(Listed::new(member, user))
+            })
+            .collect();
+        listed.sort_by(|a, b| a.key().cmp(&b.key()));
+        Ok(Self { channel, listed })
+    }
+
+    /// Looks up only the members `change` is about, unless it's [MemberListChange::All].
+    async fn apply(
+        &mut self,
+        surreal: &crate::Surreal,
+        change: &MemberListChange,
+    ) -> surrealdb::Result<()> {
+        match change {
+            MemberListChange::Member(user) => {
+                self.listed.retain(|listed| listed.member.user != *user);
+                let Some(member) = Member::find(surreal, self.channel.guild(), user).await? else {
+                    return Ok(());
+                };
+                if self.channel.nsfw() && !member.age_verified {
+                    return Ok(());
+                }
+                let TextableChannel::Normal(ref text) = self.channel;
+                if text.private {
+                    let permissions =
+                        Member::effective_permissions(surreal, self.channel.guild(), user).await?;
+                    if !text.grants_access(&member, &permissions) {
+                        return Ok(());
+                    }
+                }
+                let listed = Listed::new(member, &user.fetch(surreal).await?);
+                let at = self
+                    .listed
+                    .partition_point(|other| other.key() < listed.key());
+                self.listed.insert(at, listed);
+            }
+            MemberListChange::Left(users) => {
+                self.listed
+                    .retain(|listed| !users.contains(&listed.member.user));
+            }
+            MemberListChange::All => {
+                let channel = self.channel.refer().fetch(surreal).await?;
+                *self = Self::load(surreal, channel).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn chunks(&self, ranges: &[Vec<i32>]) -> Vec<MemberChunk> {
+        let online = self.listed.iter().filter(|listed| !listed.offline).count();
+        ranges
+            .iter()
+            .map(|range| MemberChunk {
+                channel: self.channel.gql_id(),
+                range: range.clone(),
+                total: self.listed.len() as i32,
+                online: online as i32,
+                members: self
+                    .listed
+                    .iter()
+                    .enumerate()
+                    .skip(range[0] as usize)
+                    .take((range[1] - range[0] + 1) as usize)
+                    .map(|(index, listed)| MemberListItem {
+                        index: index as i32,
+                        member: listed.member.clone(),
+                        status: listed.status,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// `None` until someone looks at it, or after a change couldn't be applied.
+type Shared = Mutex<Option<MemberList>>;
+
+/// The member lists being watched, by channel.
+#[derive(Clone, Default)]
+pub struct MemberLists(Arc<std::sync::Mutex<HashMap<Thing, Weak<Shared>>>>);
+
+/// A member list someone is watching, gone from [MemberLists] once nobody is.
+pub struct Watched {
+    channel: Ref<TextableChannel>,
+    list: Arc<Shared>,
+}
+
+impl Watched {
+    /// The chunks for `ranges` as the list is now, loading it if nobody has yet.
+    pub async fn chunks(
+        &self,
+        surreal: &crate::Surreal,
+        ranges: &[Vec<i32>],
+    ) -> surrealdb::Result<Vec<MemberChunk>> {
+        let mut list = self.list.lock().await;
+        if list.is_none() {
+            let channel = self.channel.fetch(surreal).await?;
+            *list = Some(MemberList::load(surreal, channel).await?);
+        }
+        Ok(list.as_ref().unwrap().chunks(ranges))
+    }
+}
+
+impl MemberLists {
+    /// Subscribe to the relay before looking at it, so no change is missed in between.
+    pub fn watch(&self, channel: &TextableChannel) -> Watched {
+        let mut lists = self.0.lock().unwrap();
+        lists.retain(|_, list| list.strong_count() > 0);
+        let key = channel.record_id().0;
+        let list = match lists.get(&key).and_then(Weak::upgrade) {
+            Some(list) => list,
+            None => {
+                let list = Arc::new(Mutex::new(None));
+                lists.insert(key, Arc::downgrade(&list));
+                list
+            }
+        };
+        Watched {
+            channel: channel.refer(),
+            list,
+        }
+    }
+
+    /// Applies `change` to the watched lists of `guild`, then tells whoever watches them.
+    pub async fn changed(
+        &self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        guild: &Ref<Guild>,
+        change: MemberListChange,
+    ) {
+        let lists: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for list in lists {
+            let mut list = list.lock().await;
+            let Some(ref mut loaded) = *list else {
+                continue;
+            };
+            if loaded.channel.guild() != guild {
+                continue;
+            }
+            if let Err(e) = loaded.apply(surreal, &change).await {
+                error!("couldn't update a member list, loading it again: {e}");
+                *list = None;
+            }
+        }
+        relay.member_list_changed(guild).await;
+    }
+
+    /// Tells the member lists of every guild `user` is in that they changed.
+    pub async fn user_changed(
+        &self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<()> {
+        #[derive(Deserialize)]
+        struct Membership {
+            guild: Ref<Guild>,
+        }
+
+        let memberships: Vec<Membership> = surreal
+            .query("SELECT guild FROM member WHERE user = $user")
+            .bind(("user", user.record_id()))
+            .await?
+            .take(0)?;
+        for membership in memberships {
+            self.changed(
+                surreal,
+                relay,
+                &membership.guild,
+                MemberListChange::Member(user.clone()),
+            )
+            .await;
+        }
+        Ok(())
+    }
+}
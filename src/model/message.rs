@@ -1,14 +1,31 @@
-use crate::util::{referrable, RecordId, Ref, ReferrableExt};
+use crate::{
+    config::CONFIG,
+    util::{
+        query::{field, Cond, Projection, Wanted, Q},
+        referrable, RecordId, Ref, ReferrableExt,
+    },
+};
+use std::{cmp::Reverse, collections::HashMap};
+
 use async_graphql::{
     connection::{query, Connection, Edge, EmptyFields},
     *,
 };
+use chrono::{DateTime, TimeZone, Utc};
 use derive_more::{IsVariant, Unwrap};
 use itertools::Itertools;
 use surrealdb::sql::{Datetime, Thing};
 use tide::log::{debug, info};
+use unicode_segmentation::UnicodeSegmentation;
 
-use super::{guild::TextableChannel, user::User};
+use super::{
+    conversation_name::ConversationName,
+    emoji,
+    event::{Event, EventKind},
+    guild::TextableChannel,
+    read::ReadMarker,
+    user::User,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,16 +34,132 @@ pub struct Message {
     pub author: Ref<User>,
     pub recipient: MessageRecipient,
     pub created_at: Datetime,
+    #[serde(default)]
     pub content: String,
     #[serde(default, with = "magic")]
     pub magic: Magic,
     #[serde(default)]
     pub reference: Option<Ref<Message>>,
+    /// Users mentioned as `<@id>` in the content.
+    #[serde(default)]
+    pub mentions: Vec<Ref<User>>,
+    #[serde(default)]
+    pub author_override: Option<AuthorOverride>,
+    /// Rich previews posted by integrations.
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    /// Pinned messages are kept past the retention period.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Set on tombstones of messages removed by the [retention policy](super::retention).
+    #[serde(default)]
+    pub deleted_at: Option<Datetime>,
+    /// Clients read it out loud as it comes in.
+    #[serde(default)]
+    pub tts: bool,
+}
+
+/// Shown instead of the author's name and avatar, for webhook posts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthorOverride {
+    pub name: String,
+    pub avatar_url: Option<String>,
+}
+
+/// A card shown under a message, like a feed entry or a push to a repository.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Embed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Where the title links to.
+    pub url: Option<String>,
+    /// `0xRRGGBB`, for the stripe along the side.
+    pub color: Option<i32>,
+    pub author: Option<String>,
+    pub image_url: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<EmbedField>,
+    pub timestamp: Option<Datetime>,
+    pub footer: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, SimpleObject)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+}
+
+impl Embed {
+    pub const MAX_PER_MESSAGE: usize = 10;
+    pub const MAX_TITLE_LENGTH: usize = 256;
+    pub const MAX_DESCRIPTION_LENGTH: usize = 4096;
+    pub const MAX_FIELDS: usize = 25;
+
+    /// Cuts everything down to size, so whatever an integration got from outside fits.
+    pub fn truncated(mut self) -> Self {
+        fn cut(text: &mut String, max: usize) {
+            if text.chars().count() > max {
+                *text = text.chars().take(max - 1).chain(['…']).collect();
+            }
+        }
+
+        let short = [&mut self.title, &mut self.author, &mut self.footer];
+        for text in short.into_iter().flatten() {
+            cut(text, Self::MAX_TITLE_LENGTH);
+        }
+        if let Some(ref mut description) = self.description {
+            cut(description, Self::MAX_DESCRIPTION_LENGTH);
+        }
+        self.fields.truncate(Self::MAX_FIELDS);
+        for field in &mut self.fields {
+            cut(&mut field.name, Self::MAX_TITLE_LENGTH);
+            cut(&mut field.value, Self::MAX_DESCRIPTION_LENGTH / 4);
+        }
+        self
+    }
 }
 
 referrable!(Message = "message" .id: Thing);
 
+impl Projection for Message {
+    const ALWAYS: &'static [&'static str] = &[
+        "id",
+        "author",
+        "recipient",
+        "created_at",
+        "reference",
+        "author_override",
+        "pinned",
+        "deleted_at",
+        "tts",
+    ];
+    const OPTIONAL: &'static [(&'static str, &'static [&'static str])] = &[
+        ("content", &["content", "translated"]),
+        ("embeds", &["embeds"]),
+        ("magic", &[]),
+        ("mentions", &[]),
+    ];
+}
+
+/// The users mentioned in `content`, written `<@id>` or `<@user:id>`.
+pub fn parse_mentions(content: &str) -> Vec<Ref<User>> {
+    content
+        .split("<@")
+        .skip(1)
+        .filter_map(|rest| {
+            let (id, _) = rest.split_once('>')?;
+            let id = id.trim_start_matches("user:");
+            (!id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                .then(|| Ref::new(id))
+        })
+        .unique_by(|user: &Ref<User>| user.id().to_owned())
+        .take(Message::MAX_MENTIONS)
+        .collect()
+}
+
 impl Message {
+    pub const MAX_MENTIONS: usize = 50;
+
     // linebreaks, tabs, CR, nbsp, zwsp, etc. which could change content
     const SANITIZE: [char; 10] = [
         // linebreaks, tabs, cr
@@ -35,11 +168,67 @@ impl Message {
         '\u{00A0}', '\u{200B}', '\u{FEFF}', '\u{2028}', '\u{2029}',
     ];
 
+    /// Whether `user` may read this: direct messages only either end can, channel messages
+    /// members of the guild who may [view](TextableChannel::require_viewable) the channel.
+    pub async fn readable_by(
+        &self,
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> tide::Result<bool> {
+        match self.recipient {
+            MessageRecipient::User(ref recipient) => {
+                Ok(*recipient == *user || self.author == *user)
+            }
+            MessageRecipient::Channel(ref channel) => {
+                channel
+                    .fetch(surreal)
+                    .await?
+                    .readable_by(surreal, user)
+                    .await
+            }
+        }
+    }
+
     pub async fn create(
+        surreal: &crate::Surreal,
+        author: &User,
+        init: MessageInit,
+    ) -> tide::Result<Self> {
+        Self::create_with(surreal, author, init, None, vec![]).await
+    }
+
+    /// Refuses `content` that [create](Self::create) would, for when it has to be known before
+    /// the message is stored, like before delivering it to another instance.
+    pub fn check_length(content: &str) -> tide::Result<()> {
+        Self::check_normalized_length(&emoji::normalize(content))
+    }
+
+    fn check_normalized_length(content: &str) -> tide::Result<()> {
+        if content.graphemes(true).count() > CONFIG.max_message_length {
+            return Err(tide::Error::new(
+                tide::StatusCode::BadRequest,
+                anyhow::anyhow!(
+                    "messages may be at most {} characters long",
+                    CONFIG.max_message_length
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// [create](Self::create), showing the message under another name and avatar, with embeds.
+    pub async fn create_with(
         surreal: &crate::Surreal,
         User { id: author, .. }: &User,
         init: MessageInit,
+        author_override: Option<AuthorOverride>,
+        embeds: Vec<Embed>,
     ) -> tide::Result<Self> {
+        let embeds: Vec<_> = embeds
+            .into_iter()
+            .take(Embed::MAX_PER_MESSAGE)
+            .map(Embed::truncated)
+            .collect();
         let author = author.to_raw();
         let recipient = init.recipient;
         let recipient_json = serde_json::to_string(&recipient)?;
@@ -47,8 +236,10 @@ impl Message {
         let reference_json = reference
             .map(|r| serde_json::to_string(&r))
             .unwrap_or_else(|| Ok(String::from("null")))?;
-        let content: String = init
-            .content
+        let mentions = parse_mentions(&init.content);
+        let content = emoji::normalize(&init.content);
+        Self::check_normalized_length(&content)?;
+        let content: String = content
             .chars()
             .filter(|cr| !Self::SANITIZE.contains(cr))
             .flat_map(|v| {
@@ -67,13 +258,85 @@ impl Message {
                 magic: 0,
                 content: "{content}",
                 created_at: time::now(),
-                reference: {reference_json}
+                reference: {reference_json},
+                mentions: $mentions,
+                author_override: $author_override,
+                embeds: $embeds,
+                tts: $tts
             }};
             "#
         );
-        Ok(Option::unwrap(
-            surreal.query(unindent::unindent(&query)).await?.take(0)?,
-        ))
+        let message: Self = Option::unwrap(
+            surreal
+                .query(unindent::unindent(&query))
+                .bind(("mentions", mentions))
+                .bind(("author_override", author_override))
+                .bind(("embeds", embeds))
+                .bind(("tts", init.tts))
+                .await?
+                .take(0)?,
+        );
+        Event::message(EventKind::MessageCreated, &message)
+            .log(surreal)
+            .await?;
+        if let MessageRecipient::User(ref other) = message.recipient {
+            Conversation::touch(surreal, &message.author, other, &message.created_at).await?;
+        }
+        Ok(message)
+    }
+
+    pub async fn count_replies(&self, surreal: &crate::Surreal) -> surrealdb::Result<i64> {
+        Q::select::<Message>()
+            .filter(field("reference").eq(self.record_id()))
+            .count(surreal)
+            .await
+    }
+
+    /// Messages whose [reference](Self::reference) is this one, oldest first.
+    pub async fn replies_paginate(
+        &self,
+        surreal: &crate::Surreal,
+        wanted: &Wanted,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<i64, Message, EmptyFields, EmptyFields>> {
+        let replies = || Q::select::<Message>().filter(field("reference").eq(self.record_id()));
+
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                let mut start = after.map(|a| a + 1).unwrap_or(0);
+                let count = replies().count(surreal).await?;
+                let mut end = before.unwrap_or(count);
+                if let Some(first) = first {
+                    end = (start + first as i64).min(end)
+                }
+                if let Some(last) = last {
+                    start = (end - last as i64).max(start);
+                }
+                let page = replies()
+                    .project(wanted)
+                    .order("created_at")
+                    .start(start)
+                    .limit((end - start).max(0))
+                    .all(surreal)
+                    .await?;
+
+                let mut connection = Connection::new(start > 0, end < count);
+                connection.edges.extend(
+                    (start..)
+                        .zip(page)
+                        .map(|(n, message)| Edge::new(n, message)),
+                );
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
     }
 }
 
@@ -124,7 +387,17 @@ pub enum MessageRecipientInKind {
 pub struct MessageRecipientIn {
     #[graphql(name = "type")]
     pub kind: MessageRecipientInKind,
-    pub id: ID,
+    /// Of a user or channel, like `type` says.
+    pub id: RecordId,
+}
+
+impl From<MessageRecipientIn> for MessageRecipient {
+    fn from(MessageRecipientIn { kind, id }: MessageRecipientIn) -> Self {
+        match kind {
+            MessageRecipientInKind::User => Self::User(Ref::new_owned(id.id())),
+            MessageRecipientInKind::Channel => Self::Channel(Ref::new_owned(id.id())),
+        }
+    }
 }
 
 impl MessageRecipient {
@@ -134,7 +407,7 @@ impl MessageRecipient {
             Self::Channel(channel) => channel.record_id(),
         }
     }
-    pub fn gql_id(&self) -> ID {
+    pub fn gql_id(&self) -> RecordId {
         match self {
             Self::User(user) => user.gql_id(),
             Self::Channel(channel) => channel.gql_id(),
@@ -147,11 +420,33 @@ pub struct MessageInit {
     pub recipient: MessageRecipientIn,
     pub content: String,
     pub reference: Option<Ref<Message>>,
+    /// Needs `SendTtsMessages` in channels.
+    #[graphql(default)]
+    #[serde(default)]
+    pub tts: bool,
+    /// The `id` of a `searchGifs` result, attached as an embed.
+    #[serde(default)]
+    pub gif: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Conversation(pub Ref<User>, pub MessageRecipient);
 
+/// On the edges of `conversations`.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ConversationActivity {
+    /// When the last message either way was sent, RFC 3339. `null` for friends there are no
+    /// messages with yet.
+    pub last_activity: Option<String>,
+}
+
+/// On the edges of `Conversation.participants`.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ParticipantActivity {
+    /// When they last sent a message here, RFC 3339.
+    pub last_active: String,
+}
+
 #[derive(Debug, Clone, SimpleObject)]
 pub struct MessageEdge {
     pub cursor: i32,
@@ -159,37 +454,82 @@ pub struct MessageEdge {
 }
 
 impl Conversation {
-    pub async fn all_messages(&self, surreal: &crate::Surreal) -> tide::Result<Vec<Message>> {
-        let query = format!(
-            r#"
-        SELECT * FROM message WHERE
-            (
-                author = {0} AND
-                recipient.id = {1}
-            ) OR
-            (
-                author = {1} AND
-                recipient.id = {0}
-            );
-        "#,
-            self.0.record_id(),
-            self.1.record_id()
-        );
-        Ok(surreal.query(unindent::unindent(&query)).await?.take(0)?)
+    /// Messages either way between the two ends.
+    fn filter(&self) -> Cond {
+        let (this, other) = (self.0.record_id(), self.1.record_id());
+        field("author")
+            .eq(this.clone())
+            .and(field("recipient.id").eq(other.clone()))
+            .or(field("author")
+                .eq(other)
+                .and(field("recipient.id").eq(this)))
     }
 
-    pub async fn messages_paginate(
+    /// Only authors of messages this recent count as [participants](Self::participants).
+    pub const PARTICIPANT_DAYS: i64 = 30;
+
+    /// Everyone who sent a message here in the last [Self::PARTICIPANT_DAYS] days, with when
+    /// they last did, most recently active first.
+    pub async fn participants(
+        &self,
+        surreal: &crate::Surreal,
+    ) -> surrealdb::Result<Vec<(User, DateTime<Utc>)>> {
+        #[derive(Deserialize)]
+        struct Participant {
+            author: Ref<User>,
+            last_active: Datetime,
+        }
+
+        let sql = match self.1 {
+            MessageRecipient::Channel(_) => {
+                "SELECT author, time::max(created_at) AS last_active FROM message \
+                WHERE recipient.id = $other AND created_at > $since \
+                GROUP BY author ORDER BY last_active DESC"
+            }
+            MessageRecipient::User(_) => {
+                "SELECT author, time::max(created_at) AS last_active FROM message \
+                WHERE ((author = $me AND recipient.id = $other) \
+                OR (author = $other AND recipient.id = $me)) AND created_at > $since \
+                GROUP BY author ORDER BY last_active DESC"
+            }
+        };
+        let since = Datetime(Utc::now() - chrono::Duration::days(Self::PARTICIPANT_DAYS));
+        let participants: Vec<Participant> = surreal
+            .query(sql)
+            .bind(("me", self.0.record_id()))
+            .bind(("other", self.1.record_id()))
+            .bind(("since", since))
+            .await?
+            .take(0)?;
+
+        let ids: Vec<Thing> = participants
+            .iter()
+            .map(|p| p.author.record_id().0)
+            .collect();
+        let users: Vec<User> = surreal
+            .query("SELECT * FROM $users")
+            .bind(("users", ids))
+            .await?
+            .take(0)?;
+        let mut users: HashMap<_, _> = users
+            .into_iter()
+            .map(|user| (user.record_id(), user))
+            .collect();
+        Ok(participants
+            .into_iter()
+            .filter_map(|p| Some((users.remove(&p.author.record_id())?, p.last_active.0)))
+            .collect())
+    }
+
+    pub async fn participants_paginate(
         &self,
         surreal: &crate::Surreal,
         after: Option<String>,
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
-    ) -> Result<Connection<i64, Message, EmptyFields, EmptyFields>> {
-        #[derive(Deserialize)]
-        struct Counted {
-            counted: i64,
-        }
+    ) -> Result<Connection<i64, User, EmptyFields, ParticipantActivity>> {
+        let participants = self.participants(surreal).await?;
 
         query(
             after,
@@ -197,71 +537,32 @@ impl Conversation {
             first,
             last,
             |after, before, first, last| async move {
-                let mut start = after.map(|a| a + 1).unwrap_or(0);
-                let Counted { counted: count }: Counted = Option::unwrap_or(
-                    surreal
-                        .query(format!(
-                            r#"SELECT count() as counted FROM message WHERE
-                                (
-                                    author = {0} AND
-                                    recipient.id = {1}
-                                ) OR
-                                (
-                                    author = {1} AND
-                                    recipient.id = {0}
-                                ) GROUP BY counted"#,
-                            self.0.record_id(),
-                            self.1.record_id()
-                        ))
-                        .await?
-                        .take(0)?,
-                    Counted { counted: 0 },
-                );
-                info!(
-                    "count for {} <-> {}: {count}",
-                    self.0.record_id(),
-                    self.1.record_id()
-                );
-                let mut end = before.unwrap_or(count);
+                let count = participants.len() as i64;
+                let mut start = after.map(|a| a + 1).unwrap_or(0).min(count);
+                let mut end = before.unwrap_or(count).min(count);
                 if let Some(first) = first {
                     end = (start + first as i64).min(end)
                 }
                 if let Some(last) = last {
-                    start = if last as i64 > end - start && end < count {
-                        end
-                    } else {
-                        (end - last as i64).max(0)
-                    };
+                    start = (end - last as i64).max(start)
                 }
-                let before_surreal = (end > 0)
-                    .then(|| format!("LIMIT BY {end}"))
-                    .unwrap_or_default();
-                let after_surreal = format!("START AT {start}");
-                let query = format!(
-                    r#"
-                            SELECT * FROM message WHERE
-                                (
-                                    author = {0} AND
-                                    recipient.id = {1}
-                                ) OR
-                                (
-                                    author = {1} AND
-                                    recipient.id = {0}
-                                ) ORDER BY created_at {before_surreal} {after_surreal};
-                        "#,
-                    self.0.record_id(),
-                    self.1.record_id()
-                );
-                debug!("{}", query);
-                let messages: Vec<Message> =
-                    surreal.query(unindent::unindent(&query)).await?.take(0)?;
-                let mut messages = messages.into_iter().map(Some).collect::<Vec<_>>();
 
                 let mut connection = Connection::new(start > 0, end < count);
                 connection.edges.extend(
-                    (start..end)
+                    participants
+                        .into_iter()
                         .enumerate()
-                        .map(|(i, n)| Edge::new(n, messages.get_mut(i).unwrap().take().unwrap())),
+                        .skip(start as usize)
+                        .take((end - start).max(0) as usize)
+                        .map(|(n, (user, at))| {
+                            Edge::with_additional_fields(
+                                n as i64,
+                                user,
+                                ParticipantActivity {
+                                    last_active: at.to_rfc3339(),
+                                },
+                            )
+                        }),
                 );
                 Ok::<_, async_graphql::Error>(connection)
             },
@@ -269,41 +570,361 @@ impl Conversation {
         .await
     }
 
-    pub async fn all(surreal: &crate::Surreal, user: &User) -> tide::Result<Vec<Self>> {
-        #[derive(Deserialize, Debug)]
-        struct Just {
-            author: Ref<User>,
-            recipient: MessageRecipient,
+    /// Marks the conversation read up to `until`, or up to now.
+    pub async fn mark_read(
+        &self,
+        surreal: &crate::Surreal,
+        until: Option<&Ref<Message>>,
+    ) -> tide::Result<ReadMarker> {
+        let until = match until {
+            Some(message) => {
+                let message = message.fetch(surreal).await?;
+                let in_conversation = match self.1 {
+                    MessageRecipient::User(_) => {
+                        let ends = [self.0.record_id(), self.1.record_id()];
+                        ends.contains(&message.author.record_id())
+                            && ends.contains(&message.recipient.record_id())
+                    }
+                    // anyone may have written it, it only has to be a channel the user reads
+                    MessageRecipient::Channel(_) => {
+                        message.recipient.record_id() == self.1.record_id()
+                            && message.readable_by(surreal, &self.0).await?
+                    }
+                };
+                if !in_conversation {
+                    return Err(tide::Error::new(
+                        tide::StatusCode::NotFound,
+                        anyhow::anyhow!("message is not in this conversation"),
+                    ));
+                }
+                message.created_at.0
+            }
+            None => Utc::now(),
+        };
+        Ok(ReadMarker::mark(surreal, &self.0, self.1.record_id(), until).await?)
+    }
+
+    /// What the conversation is shown as: the name the user gave it, or else the other user's
+    /// display name, or the channel's name.
+    pub async fn resolve_name(&self, surreal: &crate::Surreal) -> tide::Result<String> {
+        if let Some(name) = ConversationName::get(surreal, &self.0, &self.1.record_id()).await? {
+            return Ok(name);
         }
+        Ok(match self.1 {
+            MessageRecipient::User(ref user) => user.fetch(surreal).await?.display_name,
+            MessageRecipient::Channel(ref channel) => {
+                channel.fetch(surreal).await?.name().to_owned()
+            }
+        })
+    }
 
-        let query = format!(
-            r#"
-                SELECT author, recipient FROM message WHERE
-                    author = {0} OR
-                    recipient.id = {0};
-                "#,
-            &user.id
-        );
+    /// Errors if this is an NSFW channel the user may not read, see
+    /// [TextableChannel::require_viewable].
+    pub async fn require_viewable(&self, surreal: &crate::Surreal) -> tide::Result<()> {
+        match self.1 {
+            MessageRecipient::User(_) => Ok(()),
+            MessageRecipient::Channel(ref channel) => {
+                channel
+                    .fetch(surreal)
+                    .await?
+                    .require_viewable(surreal, &self.0)
+                    .await
+            }
+        }
+    }
+
+    /// Oldest first.
+    pub async fn all_messages(&self, surreal: &crate::Surreal) -> tide::Result<Vec<Message>> {
+        Ok(Q::select::<Message>()
+            .filter(self.filter())
+            .order("created_at")
+            .all(surreal)
+            .await?)
+    }
+
+    /// Cursors are `created_at` in microseconds, so every page is a range scan over the
+    /// `message_history` index rather than counting and skipping what came before.
+    pub async fn messages_paginate(
+        &self,
+        surreal: &crate::Surreal,
+        wanted: &Wanted,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<i64, Message, EmptyFields, EmptyFields>> {
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<i64>, before: Option<i64>, first, last| async move {
+                let at = |micros: i64| Datetime(Utc.timestamp_nanos(micros * 1000));
+                let mut page = Q::select::<Message>().project(wanted).filter(self.filter());
+                if let Some(after) = after {
+                    page = page.filter(field("created_at").gt(at(after)));
+                }
+                if let Some(before) = before {
+                    page = page.filter(field("created_at").lt(at(before)));
+                }
+                // `last` pages back from the end, newest first, and is turned around below
+                let (limit, from_end) = match (first, last) {
+                    (Some(first), _) => (Some(first), false),
+                    (None, Some(last)) => (Some(last), true),
+                    (None, None) => (None, false),
+                };
+                page = if from_end {
+                    page.order_desc("created_at")
+                } else {
+                    page.order("created_at")
+                };
+                if let Some(limit) = limit {
+                    // one more, to tell whether there's another page
+                    page = page.limit(limit as i64 + 1);
+                }
+                debug!("{}", page.to_sql().0);
+                let mut messages = page.all(surreal).await?;
+                let more = limit.is_some_and(|limit| messages.len() > limit);
+                if let Some(limit) = limit {
+                    messages.truncate(limit);
+                }
+                if from_end {
+                    messages.reverse();
+                }
+
+                let mut connection = if from_end {
+                    Connection::new(more, before.is_some())
+                } else {
+                    Connection::new(after.is_some(), more)
+                };
+                connection.edges.extend(
+                    messages
+                        .into_iter()
+                        .map(|message| Edge::new(message.created_at.0.timestamp_micros(), message)),
+                );
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    /// Up to `before` messages preceding `message` and `after` following it, with `message`
+    /// itself in between, oldest first. Fetched in one round trip. Messages sent at the same
+    /// time are ordered by id, so none go missing around `message`.
+    pub async fn messages_window(
+        &self,
+        surreal: &crate::Surreal,
+        message: &Ref<Message>,
+        before: u32,
+        after: u32,
+    ) -> tide::Result<Vec<Message>> {
+        let query = r#"
+            LET $pivot = $message.created_at;
+            SELECT * FROM message WHERE
+                (
+                    (author = $a AND recipient.id = $b) OR
+                    (author = $b AND recipient.id = $a)
+                ) AND (created_at < $pivot OR (created_at = $pivot AND id < $message))
+                ORDER BY created_at DESC, id DESC LIMIT $before;
+            SELECT * FROM message WHERE
+                (
+                    (author = $a AND recipient.id = $b) OR
+                    (author = $b AND recipient.id = $a)
+                ) AND (created_at > $pivot OR (created_at = $pivot AND id >= $message))
+                ORDER BY created_at, id LIMIT $after;
+        "#;
+        let mut response = surreal
+            .query(unindent::unindent(query))
+            .bind(("a", self.0.record_id()))
+            .bind(("b", self.1.record_id()))
+            .bind(("message", message.record_id()))
+            .bind(("before", before))
+            // the pivot comes first
+            .bind(("after", after + 1))
+            .await?;
+        let mut preceding: Vec<Message> = response.take(1)?;
+        let following: Vec<Message> = response.take(2)?;
+        if !following.iter().any(|m| m.refer() == *message) {
+            return Err(tide::Error::new(
+                tide::StatusCode::NotFound,
+                anyhow::anyhow!("message is not in this conversation"),
+            ));
+        }
 
-        let messages: Vec<Just> = surreal.query(unindent::unindent(&query)).await?.take(0)?;
+        preceding.reverse();
+        preceding.extend(following);
+        Ok(preceding)
+    }
 
-        let groups = messages.into_iter().group_by(|a| a.author.clone());
+    /// `user`'s direct conversations, most recently active first. Friends there are no
+    /// messages with yet come last, without an activity time.
+    pub async fn all(
+        surreal: &crate::Surreal,
+        user: &User,
+    ) -> tide::Result<Vec<(Self, Option<DateTime<Utc>>)>> {
+        #[derive(Deserialize, Debug)]
+        struct Edge {
+            out: Ref<User>,
+            last_activity: Datetime,
+        }
 
-        let friends = user
-            .get_friends(surreal)
+        let me = user.refer();
+        let edges: Vec<Edge> = surreal
+            .query("SELECT out, last_activity FROM conversation WHERE in = $user")
+            .bind(("user", me.record_id()))
             .await?
+            .take(0)?;
+
+        let latest: HashMap<String, (Ref<User>, DateTime<Utc>)> = edges
             .into_iter()
-            .map(|friend| Conversation(user.refer(), MessageRecipient::User(friend.refer())));
+            .map(|edge| (edge.out.id().to_owned(), (edge.out, edge.last_activity.0)))
+            .collect();
+        let mut convos: Vec<_> = latest
+            .values()
+            .map(|(other, at)| (other.clone(), Some(*at)))
+            .collect();
+        for friend in user.friend_refs(surreal).await? {
+            if !latest.contains_key(friend.id()) {
+                convos.push((friend, None));
+            }
+        }
+        convos.sort_by_key(|(other, at)| (Reverse(*at), other.id().to_owned()));
 
-        let convos = groups
+        Ok(convos
             .into_iter()
-            .filter_map(|(author, mut convo)| Some(Conversation(author, convo.next()?.recipient)))
-            .chain(friends)
-            .unique_by(|Conversation(_, a)| a.record_id().id());
+            .map(|(other, at)| (Conversation(me.clone(), MessageRecipient::User(other)), at))
+            .collect())
+    }
+
+    /// The `conversation` edge from `from` to `to`. There's one each way for every pair of
+    /// users who messaged, so listing someone's conversations doesn't go through their messages.
+    fn edge(from: &Ref<User>, to: &Ref<User>) -> Thing {
+        Thing::from((
+            String::from("conversation"),
+            format!("{}-{}", from.id(), to.id()),
+        ))
+    }
+
+    /// Moves the last activity between `a` and `b` up to `at`, relating them if they weren't.
+    pub async fn touch(
+        surreal: &crate::Surreal,
+        a: &Ref<User>,
+        b: &Ref<User>,
+        at: &Datetime,
+    ) -> surrealdb::Result<()> {
+        if a == b {
+            return Ok(());
+        }
+        let (a_id, b_id) = (a.record_id(), b.record_id());
+        let (ab, ba) = (Self::edge(a, b), Self::edge(b, a));
+        surreal
+            .query(format!(
+                "BEGIN TRANSACTION; \
+                IF (SELECT VALUE id FROM {ab}) THEN (UPDATE {ab} SET last_activity = $at) \
+                ELSE (RELATE {a_id}->{ab}->{b_id} SET last_activity = $at) END; \
+                IF (SELECT VALUE id FROM {ba}) THEN (UPDATE {ba} SET last_activity = $at) \
+                ELSE (RELATE {b_id}->{ba}->{a_id} SET last_activity = $at) END; \
+                COMMIT TRANSACTION;"
+            ))
+            .bind(("at", at))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Relates everyone who already messaged before conversations were kept as edges. Does
+    /// nothing once there are any.
+    pub async fn materialize(surreal: &crate::Surreal) -> surrealdb::Result<()> {
+        #[derive(Deserialize)]
+        struct Just {
+            author: Ref<User>,
+            recipient: Ref<User>,
+            created_at: Datetime,
+        }
+
+        let any: Vec<Thing> = surreal
+            .query("SELECT VALUE id FROM conversation LIMIT 1")
+            .await?
+            .take(0)?;
+        if !any.is_empty() {
+            return Ok(());
+        }
+        let messages: Vec<Just> = surreal
+            .query(
+                "SELECT author, recipient.id AS recipient, created_at FROM message \
+                 WHERE recipient.kind = 'User'",
+            )
+            .await?
+            .take(0)?;
+        let mut latest: HashMap<(String, String), (Ref<User>, Ref<User>, Datetime)> =
+            HashMap::new();
+        for message in messages {
+            let mut pair = [message.author, message.recipient];
+            pair.sort_by(|a, b| a.id().cmp(b.id()));
+            let [a, b] = pair;
+            let key = (a.id().to_owned(), b.id().to_owned());
+            match latest.get_mut(&key) {
+                Some((_, _, at)) if at.0 >= message.created_at.0 => {}
+                Some((_, _, at)) => *at = message.created_at,
+                None => {
+                    latest.insert(key, (a, b, message.created_at));
+                }
+            }
+        }
+        info!("relating {} conversations", latest.len());
+        for (a, b, at) in latest.into_values() {
+            Self::touch(surreal, &a, &b, &at).await?;
+        }
+        Ok(())
+    }
 
-        let mut convos: Vec<_> = convos.collect();
-        convos.retain(|a| a.1.is_user() && a.1.record_id().0 != user.id);
+    /// [Conversation::all] a page at a time, with each one's last activity on its edge.
+    pub async fn all_paginate(
+        surreal: &crate::Surreal,
+        user: &User,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<i64, Conversation, EmptyFields, ConversationActivity>> {
+        let convos = Self::all(surreal, user).await?;
 
-        Ok(convos)
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after, before, first, last| async move {
+                let count = convos.len() as i64;
+                let mut start = after.map(|a| a + 1).unwrap_or(0).min(count);
+                let mut end = before.unwrap_or(count).min(count);
+                if let Some(first) = first {
+                    end = (start + first as i64).min(end)
+                }
+                if let Some(last) = last {
+                    start = (end - last as i64).max(start)
+                }
+
+                let mut connection = Connection::new(start > 0, end < count);
+                connection.edges.extend(
+                    convos
+                        .into_iter()
+                        .enumerate()
+                        .skip(start as usize)
+                        .take((end - start).max(0) as usize)
+                        .map(|(n, (convo, at))| {
+                            Edge::with_additional_fields(
+                                n as i64,
+                                convo,
+                                ConversationActivity {
+                                    last_activity: at.map(|at| at.to_rfc3339()),
+                                },
+                            )
+                        }),
+                );
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
     }
 }
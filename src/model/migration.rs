@@ -0,0 +1,74 @@
+//! Schema changes, applied in order at startup. Each is recorded in `migration` once it has
+//! run, so it never runs twice: append new ones, don't edit or reorder the old ones.
+
+use surrealdb::sql::Thing;
+use tide::log::info;
+
+use super::naming;
+
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "message_reference",
+        "DEFINE INDEX message_reference ON TABLE message COLUMNS reference",
+    ),
+    // conversation history, both ways round
+    (
+        "message_history",
+        "DEFINE INDEX message_history ON TABLE message COLUMNS author, recipient.id, created_at",
+    ),
+    (
+        "message_recipient",
+        "DEFINE INDEX message_recipient ON TABLE message COLUMNS recipient.id, created_at",
+    ),
+    // friendships, looked up from either end
+    (
+        "friends_in",
+        "DEFINE INDEX friends_in ON TABLE friends COLUMNS in",
+    ),
+    (
+        "friends_out",
+        "DEFINE INDEX friends_out ON TABLE friends COLUMNS out",
+    ),
+    // shadow users of remote instances used to get an empty email and password hash
+    (
+        "remote_user_credentials",
+        "UPDATE user SET email = NONE, password_hash = '!remote' WHERE domain != NONE",
+    ),
+    // channel slugs, unique within a guild, see `prepare` for the ones already there
+    (
+        "channel_slug",
+        "DEFINE INDEX channel_slug ON TABLE channel COLUMNS guild, slug UNIQUE",
+    ),
+];
+
+/// Runs before the migration called `name`, for what SurrealQL can't do. Has to be safe to
+/// run again, in case the migration itself fails.
+async fn prepare(surreal: &crate::Surreal, name: &str) -> surrealdb::Result<()> {
+    match name {
+        "channel_slug" => naming::backfill_channel_slugs(surreal).await,
+        _ => Ok(()),
+    }
+}
+
+pub async fn run(surreal: &crate::Surreal) -> surrealdb::Result<()> {
+    let applied: Vec<Thing> = surreal
+        .query("SELECT VALUE id FROM migration")
+        .await?
+        .take(0)?;
+    for (name, sql) in MIGRATIONS {
+        let id = Thing::from((String::from("migration"), name.to_string()));
+        if applied.contains(&id) {
+            continue;
+        }
+        info!("migrating: {name}");
+        prepare(surreal, name).await?;
+        surreal
+            .query(format!(
+                "BEGIN TRANSACTION; {sql}; CREATE $id SET at = time::now(); COMMIT TRANSACTION;"
+            ))
+            .bind(("id", id))
+            .await?
+            .check()?;
+    }
+    Ok(())
+}
@@ -1,4 +1,40 @@
 pub mod user;
 pub mod guild;
+pub mod guild_application;
+pub mod guild_invite;
+pub mod legal_hold;
+pub mod admin_audit;
 pub mod audit;
+pub mod automod;
+pub mod transcript;
+pub mod trust;
 pub mod message;
+pub mod oauth;
+pub mod token;
+pub mod attachment;
+pub mod upload;
+pub mod voice;
+pub mod webhook;
+pub mod event;
+pub mod login;
+pub mod name_rule;
+pub mod naming;
+pub mod limits;
+pub mod read;
+pub mod feed;
+pub mod reminder;
+pub mod emoji;
+pub mod instance;
+pub mod terms;
+pub mod retention;
+pub mod erasure;
+pub mod member_list;
+pub mod linked_account;
+pub mod user_note;
+pub mod conversation_name;
+pub mod guild_layout;
+pub mod quick_search;
+pub mod spam;
+pub mod security_event;
+pub mod stats;
+pub mod migration;
@@ -0,0 +1,113 @@
+use anyhow::anyhow;
+use async_graphql::Enum;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    util::{
+        query::{field, Q},
+        referrable, Referrable,
+    },
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum NameRuleKind {
+    /// Nobody may be called exactly this, like `admin` or `system`.
+    Reserved,
+    /// Names may not contain this anywhere.
+    Profanity,
+}
+
+/// A word names are checked against, on top of the ones from the config.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NameRule {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub kind: NameRuleKind,
+    /// Stored [normalized](normalize).
+    pub word: String,
+    pub created_at: Datetime,
+}
+
+referrable!(NameRule = "name_rule" .id: Option<Thing>);
+
+/// Lowercases and drops everything but letters and digits, undoing the usual letter
+/// substitutions, so `4dm1n` and `A.D.M.I.N` are both `admin`.
+pub fn normalize(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            '0' => Some('o'),
+            '1' | '!' | '|' => Some('i'),
+            '3' => Some('e'),
+            '4' | '@' => Some('a'),
+            '5' | '$' => Some('s'),
+            '7' => Some('t'),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+impl NameRule {
+    pub async fn all(surreal: &crate::Surreal) -> surrealdb::Result<Vec<NameRule>> {
+        Q::select::<NameRule>().order("word").all(surreal).await
+    }
+
+    pub async fn add(
+        surreal: &crate::Surreal,
+        kind: NameRuleKind,
+        word: &str,
+    ) -> tide::Result<NameRule> {
+        let word = normalize(word);
+        if word.is_empty() {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("nothing left of the word once normalized"),
+            ));
+        }
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(NameRule {
+                id: None,
+                kind,
+                word,
+                created_at: Datetime(Utc::now()),
+            })
+            .await?)
+    }
+
+    /// Refuses `name` if it is reserved or contains profanity, whether that comes from the
+    /// config or was added at runtime.
+    pub async fn check(surreal: &crate::Surreal, name: &str) -> tide::Result<()> {
+        let name = normalize(name);
+        let configured =
+            |words: &'static [String], kind| words.iter().map(move |word| (kind, normalize(word)));
+        let rules = configured(&CONFIG.reserved_names, NameRuleKind::Reserved)
+            .chain(configured(&CONFIG.profanity, NameRuleKind::Profanity))
+            .chain(
+                Self::all(surreal)
+                    .await?
+                    .into_iter()
+                    .map(|rule| (rule.kind, rule.word)),
+            );
+
+        for (kind, word) in rules {
+            let (matches, why) = match kind {
+                NameRuleKind::Reserved => (name == word, "that name is reserved"),
+                NameRuleKind::Profanity => (
+                    !word.is_empty() && name.contains(&word),
+                    "that name isn't allowed",
+                ),
+            };
+            if matches {
+                return Err(tide::Error::new(StatusCode::BadRequest, anyhow!(why)));
+            }
+        }
+        Ok(())
+    }
+}
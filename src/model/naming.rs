@@ -0,0 +1,198 @@
+//! What guilds and channels may be called. Channels get a slug from their name, which is what
+//! has to be unique within a guild, so `Off Topic` and `off-topic` can't both exist. Words
+//! that aren't allowed in any name are [NameRule]s.
+//!
+//! Channel slugs are stored, and the `channel_slug` index keeps them unique even when two
+//! channels are named at once.
+
+use std::collections::HashSet;
+
+use async_graphql::ErrorExtensions;
+use serde::Deserialize;
+use surrealdb::sql::Thing;
+
+use crate::util::{
+    query::{field, Q},
+    RecordId, Ref, ReferrableExt,
+};
+
+use super::{
+    guild::{Guild, TextChannel},
+    name_rule::NameRule,
+    user::User,
+};
+
+pub const GUILD_MIN_LENGTH: usize = 2;
+pub const GUILD_MAX_LENGTH: usize = 100;
+pub const CHANNEL_MAX_LENGTH: usize = 100;
+
+/// Fails with `INVALID_NAME`, the `field` that was wrong, and why as `reason`: `TOO_SHORT`,
+/// `TOO_LONG`, `CHARACTERS` or `TAKEN`.
+fn invalid(reason: &'static str, message: String) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, extensions| {
+        extensions.set("code", "INVALID_NAME");
+        extensions.set("field", "name");
+        extensions.set("reason", reason);
+    })
+}
+
+fn check_length(name: &str, min: usize, max: usize) -> async_graphql::Result<()> {
+    let length = name.chars().count();
+    if length < min {
+        return Err(invalid(
+            "TOO_SHORT",
+            format!("names need at least {min} characters"),
+        ));
+    }
+    if length > max {
+        return Err(invalid(
+            "TOO_LONG",
+            format!("names can have at most {max} characters"),
+        ));
+    }
+    Ok(())
+}
+
+/// Lowercases `name` and joins its words with single dashes, so `Off Topic`, `off_topic` and
+/// `--off-topic` all become `off-topic`.
+pub fn slug(name: &str) -> String {
+    name.split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Trims `name` and checks it can be a guild's: no control characters, and not the name of
+/// another guild `user` is already in, which they'd have no way of telling apart.
+pub async fn check_guild(
+    surreal: &crate::Surreal,
+    user: &Ref<User>,
+    name: &str,
+    renaming: Option<&Guild>,
+) -> async_graphql::Result<String> {
+    let name = name.trim();
+    check_length(name, GUILD_MIN_LENGTH, GUILD_MAX_LENGTH)?;
+    if name.chars().any(char::is_control) {
+        return Err(invalid(
+            "CHARACTERS",
+            String::from("guild names can't have line breaks or control characters"),
+        ));
+    }
+    NameRule::check(surreal, name).await?;
+
+    #[derive(Deserialize)]
+    struct Named {
+        name: String,
+    }
+    let others: Vec<Named> = surreal
+        .query(
+            "SELECT guild.name AS name FROM member \
+             WHERE user = $user AND pending != true AND guild != $renaming",
+        )
+        .bind(("user", user.record_id()))
+        .bind(("renaming", renaming.map(|guild| guild.record_id())))
+        .await?
+        .take(0)?;
+    if others
+        .iter()
+        .any(|other| other.name.trim().to_lowercase() == name.to_lowercase())
+    {
+        return Err(invalid(
+            "TAKEN",
+            format!("you're already in a guild called {name}"),
+        ));
+    }
+    Ok(name.to_owned())
+}
+
+/// Trims `name` and checks it can be a channel's in `guild`: letters, digits, spaces, `-` and
+/// `_` only, and a [slug] no other channel there has. `renaming` is left out of the comparison.
+pub async fn check_channel(
+    surreal: &crate::Surreal,
+    guild: &Ref<Guild>,
+    name: &str,
+    renaming: Option<&TextChannel>,
+) -> async_graphql::Result<String> {
+    let name = name.trim();
+    check_length(name, 1, CHANNEL_MAX_LENGTH)?;
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_')
+    {
+        return Err(invalid(
+            "CHARACTERS",
+            String::from("channel names can only have letters, digits, spaces, - and _"),
+        ));
+    }
+    let slugged = slug(name);
+    if slugged.is_empty() {
+        return Err(invalid(
+            "CHARACTERS",
+            String::from("channel names need a letter or digit"),
+        ));
+    }
+    NameRule::check(surreal, name).await?;
+
+    let taken = Q::select::<TextChannel>()
+        .filter(field("guild").eq(guild))
+        .filter(field("slug").eq(slugged.clone()))
+        .all(surreal)
+        .await?
+        .into_iter()
+        .any(|channel| renaming.map_or(true, |renaming| renaming.id != channel.id));
+    if taken {
+        return Err(channel_taken(&slugged));
+    }
+    Ok(name.to_owned())
+}
+
+fn channel_taken(slugged: &str) -> async_graphql::Error {
+    invalid(
+        "TAKEN",
+        format!("there's already a channel called #{slugged} in this guild"),
+    )
+}
+
+/// What saving a channel as `slugged` fails with: `TAKEN` if another channel got the slug
+/// since [check_channel] looked, `error` as it is otherwise.
+pub fn channel_write_failed(error: impl std::fmt::Display, slugged: &str) -> async_graphql::Error {
+    if error.to_string().contains("channel_slug") {
+        channel_taken(slugged)
+    } else {
+        async_graphql::Error::new(error.to_string())
+    }
+}
+
+/// Stores the slugs of channels from before they were stored, for the `channel_slug`
+/// migration. Channels that already shared one get a number after it.
+pub async fn backfill_channel_slugs(surreal: &crate::Surreal) -> surrealdb::Result<()> {
+    #[derive(Deserialize)]
+    struct Unslugged {
+        id: Thing,
+        guild: Ref<Guild>,
+        name: String,
+    }
+
+    let channels: Vec<Unslugged> = surreal
+        .query("SELECT id, guild, name FROM channel WHERE slug = NONE ORDER BY id")
+        .await?
+        .take(0)?;
+    let mut taken: HashSet<(RecordId, String)> = HashSet::new();
+    for channel in channels {
+        let base = slug(&channel.name);
+        let mut slugged = base.clone();
+        let mut n = 1;
+        while !taken.insert((channel.guild.record_id(), slugged.clone())) {
+            n += 1;
+            slugged = format!("{base}-{n}");
+        }
+        surreal
+            .query("UPDATE $channel SET slug = $slug")
+            .bind(("channel", channel.id))
+            .bind(("slug", slugged))
+            .await?
+            .check()?;
+    }
+    Ok(())
+}
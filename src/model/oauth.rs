@@ -0,0 +1,69 @@
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::util::{random_string, referrable, Ref, ReferrableExt};
+
+use super::user::User;
+
+/// A third-party application that may sign users in through this instance.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OAuthApp {
+    pub id: Thing,
+    pub name: String,
+    pub owner: Ref<User>,
+    /// Shared with the app; its ID tokens are signed (HS256) with this.
+    pub secret: String,
+    pub redirect_uris: Vec<String>,
+}
+
+referrable!(OAuthApp = "oauth_app" .id: Thing);
+
+/// What the app owner gets back once, right after registering an app.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct OAuthAppCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl OAuthApp {
+    pub async fn create(
+        surreal: &crate::Surreal,
+        owner: &User,
+        name: String,
+        redirect_uris: Vec<String>,
+    ) -> surrealdb::Result<Option<Self>> {
+        surreal
+            .query(
+                "CREATE oauth_app SET name = $name, owner = $owner, secret = $secret, redirect_uris = $redirect_uris",
+            )
+            .bind(("name", name))
+            .bind(("owner", owner.refer()))
+            .bind(("secret", random_string(48)))
+            .bind(("redirect_uris", redirect_uris))
+            .await?
+            .take(0)
+    }
+
+    pub fn credentials(&self) -> OAuthAppCredentials {
+        OAuthAppCredentials {
+            client_id: self.id.id.to_raw(),
+            client_secret: self.secret.clone(),
+        }
+    }
+}
+
+/// A single-use authorization code, exchanged by the app for tokens.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuthorizationCode {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub app: Ref<OAuthApp>,
+    pub user: Ref<User>,
+    pub redirect_uri: String,
+    pub scope: Vec<String>,
+    pub nonce: Option<String>,
+    pub issued_at: Datetime,
+}
+
+referrable!(AuthorizationCode = "oauth_code" .id: Option<Thing>);
@@ -0,0 +1,220 @@
+//! The Ctrl-K switcher: one search over the user's guilds, their channels, DMs and friends,
+//! with whatever the user was last active in first. Activity is when they last wrote or
+//! read there, from message history and read markers.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use surrealdb::sql::Datetime;
+
+use crate::util::{RecordId, Ref, ReferrableExt};
+
+use super::{
+    guild::{Channel, Guild, TextChannel},
+    message::MessageRecipient,
+    read::ReadMarker,
+    user::User,
+};
+
+pub const MAX_RESULTS: usize = 20;
+/// How far back message history counts as activity.
+const HISTORY: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum QuickSearchKind {
+    Guild,
+    Channel,
+    /// A user there are direct messages with.
+    Direct,
+    /// A friend without direct messages yet.
+    User,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct QuickSearchHit {
+    pub kind: QuickSearchKind,
+    pub id: RecordId,
+    pub name: String,
+    /// The guild's name for channels, the tag for users.
+    pub detail: Option<String>,
+    /// When the user last wrote or read there, RFC 3339.
+    pub last_active: Option<String>,
+    #[graphql(skip)]
+    active: Option<DateTime<Utc>>,
+}
+
+/// When `user` was last active in each conversation, by its other end.
+async fn activity(
+    surreal: &crate::Surreal,
+    user: &Ref<User>,
+) -> surrealdb::Result<HashMap<RecordId, DateTime<Utc>>> {
+    #[derive(Deserialize)]
+    struct Sent {
+        author: Ref<User>,
+        recipient: MessageRecipient,
+        created_at: Datetime,
+    }
+
+    let sent: Vec<Sent> = surreal
+        .query(
+            "SELECT author, recipient, created_at FROM message \
+             WHERE author = $user OR recipient.id = $user \
+             ORDER BY created_at DESC LIMIT $limit",
+        )
+        .bind(("user", user.record_id()))
+        .bind(("limit", HISTORY))
+        .await?
+        .take(0)?;
+
+    let mut activity = ReadMarker::of_user(surreal, user).await?;
+    for message in sent {
+        let other = if message.author == *user {
+            message.recipient.record_id()
+        } else {
+            message.author.record_id()
+        };
+        let at = activity.entry(other).or_insert(message.created_at.0);
+        *at = (*at).max(message.created_at.0);
+    }
+    Ok(activity)
+}
+
+/// Everything of `user`'s whose name contains `query`, most recently active first. An empty
+/// query lists the most recent.
+pub async fn search(
+    surreal: &crate::Surreal,
+    user: &User,
+    query: &str,
+) -> tide::Result<Vec<QuickSearchHit>> {
+    #[derive(Deserialize)]
+    struct Membership {
+        guild: Guild,
+    }
+
+    let me = user.refer();
+    let query = query.trim().to_lowercase();
+    let activity = activity(surreal, &me).await?;
+
+    let guilds: Vec<Membership> = surreal
+        .query("SELECT guild FROM member WHERE user = $user AND pending != true FETCH guild")
+        .bind(("user", me.record_id()))
+        .await?
+        .take(0)?;
+    let guilds: HashMap<_, _> = guilds
+        .into_iter()
+        .map(|membership| (membership.guild.record_id(), membership.guild))
+        .collect();
+    let found: Vec<TextChannel> = surreal
+        .query("SELECT * FROM channel WHERE guild INSIDE $guilds")
+        .bind(("guilds", guilds.keys().cloned().collect::<Vec<_>>()))
+        .await?
+        .take(0)?;
+    let mut channels = Vec::with_capacity(found.len());
+    for channel in found {
+        if !channel.private
+            || Channel::Text(channel.clone())
+                .visible_to(surreal, &me)
+                .await?
+        {
+            channels.push(channel);
+        }
+    }
+
+    let partners: Vec<RecordId> = activity
+        .keys()
+        .filter(|other| other.0.tb == User::TABLE && other.0 != me.record_id().0)
+        .cloned()
+        .collect();
+    let partners: Vec<User> = surreal
+        .query("SELECT * FROM $users")
+        .bind(("users", partners))
+        .await?
+        .take(0)?;
+    let mut users: HashMap<_, _> = user
+        .get_friends(surreal)
+        .await?
+        .into_iter()
+        .map(|friend| (friend.user.record_id(), friend.user))
+        .collect();
+    users.extend(
+        partners
+            .into_iter()
+            .map(|partner| (partner.record_id(), partner)),
+    );
+
+    let mut guild_activity: HashMap<RecordId, DateTime<Utc>> = HashMap::new();
+    for channel in &channels {
+        if let Some(at) = activity.get(&channel.record_id()) {
+            let latest = guild_activity
+                .entry(channel.guild.record_id())
+                .or_insert(*at);
+            *latest = (*latest).max(*at);
+        }
+    }
+
+    let hit = |kind, id, name: &str, detail: Option<String>, active: Option<DateTime<Utc>>| {
+        QuickSearchHit {
+            kind,
+            id,
+            name: name.to_owned(),
+            detail,
+            last_active: active.map(|at| at.to_rfc3339()),
+            active,
+        }
+    };
+    let mut hits: Vec<QuickSearchHit> = vec![];
+    for (id, guild) in &guilds {
+        if guild.name.to_lowercase().contains(&query) {
+            let active = guild_activity.get(id).copied();
+            hits.push(hit(
+                QuickSearchKind::Guild,
+                guild.gql_id(),
+                &guild.name,
+                None,
+                active,
+            ));
+        }
+    }
+    for channel in &channels {
+        if channel.name.to_lowercase().contains(&query) {
+            let guild = guilds
+                .get(&channel.guild.record_id())
+                .map(|g| g.name.clone());
+            let active = activity.get(&channel.record_id()).copied();
+            hits.push(hit(
+                QuickSearchKind::Channel,
+                channel.gql_id(),
+                &channel.name,
+                guild,
+                active,
+            ));
+        }
+    }
+    for (id, other) in &users {
+        let tag = other.tag_fmt();
+        if other.display_name.to_lowercase().contains(&query) || tag.to_lowercase().contains(&query)
+        {
+            let active = activity.get(id).copied();
+            let kind = match active {
+                Some(_) => QuickSearchKind::Direct,
+                None => QuickSearchKind::User,
+            };
+            hits.push(hit(
+                kind,
+                other.gql_id(),
+                &other.display_name,
+                Some(tag),
+                active,
+            ));
+        }
+    }
+
+    hits.sort_by_cached_key(|hit| {
+        let name = hit.name.to_lowercase();
+        (Reverse(hit.active), !name.starts_with(&query), name)
+    });
+    hits.truncate(MAX_RESULTS);
+    Ok(hits)
+}
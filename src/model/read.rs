@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::util::{
+    query::{field, Q},
+    referrable, RecordId, Ref, Referrable,
+};
+
+use super::{
+    guild::{Guild, TextableChannel},
+    user::User,
+};
+
+/// How far a user has read a conversation: everything sent up to `until` counts as read.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadMarker {
+    pub id: Thing,
+    pub user: Ref<User>,
+    /// The other end of the conversation, a user or a channel.
+    pub target: RecordId,
+    pub until: Datetime,
+}
+
+referrable!(ReadMarker = "read_marker" .id: Thing);
+
+impl ReadMarker {
+    /// One marker per user and conversation.
+    fn key(user: &Ref<User>, target: &RecordId) -> Thing {
+        Thing::from((
+            Self::TABLE.to_owned(),
+            format!("{}-{}-{}", user.id(), target.0.tb, target.id()),
+        ))
+    }
+
+    /// Moves the marker forward to `until`. Never backwards, so a client catching up on an
+    /// old device doesn't mark newer messages unread again.
+    pub async fn mark(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        target: RecordId,
+        until: DateTime<Utc>,
+    ) -> surrealdb::Result<ReadMarker> {
+        let key = Self::key(user, &target);
+        let existing: Option<ReadMarker> = surreal.select(key.clone()).await?;
+        if let Some(existing) = existing.filter(|marker| marker.until.0 >= until) {
+            return Ok(existing);
+        }
+        surreal
+            .update(key.clone())
+            .content(ReadMarker {
+                id: key,
+                user: user.clone(),
+                target,
+                until: Datetime(until),
+            })
+            .await
+    }
+
+    /// Moves the markers of all `targets` to now at once, for marking whole guilds read. Now
+    /// is never backwards, so they're simply replaced.
+    pub async fn mark_all(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        targets: Vec<RecordId>,
+    ) -> surrealdb::Result<ReadState> {
+        let until = Utc::now();
+        let markers: Vec<ReadMarker> = targets
+            .into_iter()
+            .map(|target| ReadMarker {
+                id: Self::key(user, &target),
+                user: user.clone(),
+                target,
+                until: Datetime(until),
+            })
+            .collect();
+        let ids: Vec<Thing> = markers.iter().map(|marker| marker.id.clone()).collect();
+        surreal
+            .query(
+                "BEGIN TRANSACTION; DELETE $ids; INSERT INTO read_marker $markers; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("ids", ids))
+            .bind(("markers", &markers))
+            .await?
+            .check()?;
+        Ok(ReadState::new(
+            markers.iter().map(|marker| &marker.target),
+            until,
+        ))
+    }
+
+    /// The channels of `guild`.
+    pub async fn guild_targets(
+        surreal: &crate::Surreal,
+        guild: &Ref<Guild>,
+    ) -> surrealdb::Result<Vec<RecordId>> {
+        surreal
+            .query("SELECT VALUE id FROM channel WHERE guild = $guild")
+            .bind(("guild", guild.record_id()))
+            .await?
+            .take(0)
+    }
+
+    /// The channels of every guild `user` is in, and everyone who sent them direct messages.
+    pub async fn all_targets(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<RecordId>> {
+        #[derive(Deserialize)]
+        struct Sender {
+            author: RecordId,
+        }
+
+        let mut response = surreal
+            .query(unindent::unindent(
+                r#"
+                SELECT VALUE id FROM channel WHERE guild INSIDE (SELECT VALUE guild FROM member WHERE user = $user AND pending != true);
+                SELECT author FROM message WHERE recipient.id = $user AND author != $user GROUP BY author;
+                "#,
+            ))
+            .bind(("user", user.record_id()))
+            .await?;
+        let mut targets: Vec<RecordId> = response.take(0)?;
+        let senders: Vec<Sender> = response.take(1)?;
+        targets.extend(senders.into_iter().map(|sender| sender.author));
+        Ok(targets)
+    }
+
+    /// Where `user` has read up to, by conversation.
+    pub async fn of_user(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<HashMap<RecordId, DateTime<Utc>>> {
+        let markers = Q::select::<ReadMarker>()
+            .filter(field("user").eq(user))
+            .all(surreal)
+            .await?;
+        Ok(markers
+            .into_iter()
+            .map(|marker| (marker.target, marker.until.0))
+            .collect())
+    }
+}
+
+/// What a user marked read, for their other devices to clear the same badges.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ReadState {
+    /// The other ends of the conversations, users or channels.
+    pub conversations: Vec<RecordId>,
+    /// Read up to here, RFC 3339.
+    pub until: String,
+}
+
+impl ReadState {
+    pub fn new<'a>(targets: impl IntoIterator<Item = &'a RecordId>, until: DateTime<Utc>) -> Self {
+        Self {
+            conversations: targets.into_iter().cloned().collect(),
+            until: until.to_rfc3339(),
+        }
+    }
+}
+
+/// Unread counts for badges, without loading any conversation.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Inbox {
+    pub direct: Vec<UnreadDirect>,
+    pub guilds: Vec<UnreadMentions>,
+    /// All unread DMs.
+    pub direct_total: i64,
+    /// All unread mentions.
+    pub mentions_total: i64,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct UnreadDirect {
+    /// Who sent them.
+    pub user: RecordId,
+    pub unread: i64,
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct UnreadMentions {
+    pub guild: RecordId,
+    pub mentions: i64,
+}
+
+impl Inbox {
+    /// Counted by the database in one round trip. A message is unread if it's newer than the
+    /// [ReadMarker] of its conversation, or the conversation has none. Mentions only count in
+    /// channels the user can still [read](TextableChannel::readable_by).
+    pub async fn of(surreal: &crate::Surreal, user: &Ref<User>) -> tide::Result<Inbox> {
+        #[derive(Deserialize)]
+        struct Direct {
+            author: Ref<User>,
+            unread: i64,
+        }
+        #[derive(Deserialize)]
+        struct Mentions {
+            channel: Ref<TextableChannel>,
+            unread: i64,
+        }
+
+        let mut response = surreal
+            .query(unindent::unindent(
+                r#"
+                SELECT author, count() AS unread FROM message WHERE
+                    recipient.id = $user AND author != $user AND
+                    created_at > array::first((SELECT VALUE until FROM type::thing('read_marker', string::concat($prefix, meta::tb($parent.author), '-', meta::id($parent.author)))))
+                    GROUP BY author;
+                SELECT recipient.id AS channel, count() AS unread FROM message WHERE
+                    recipient.kind = 'Channel' AND mentions CONTAINS $user AND author != $user AND
+                    created_at > array::first((SELECT VALUE until FROM type::thing('read_marker', string::concat($prefix, meta::tb($parent.recipient.id), '-', meta::id($parent.recipient.id)))))
+                    GROUP BY channel;
+                "#,
+            ))
+            .bind(("user", user.record_id()))
+            // the start of the marker keys, see ReadMarker::key
+            .bind(("prefix", format!("{}-", user.id())))
+            .await?;
+        let direct: Vec<Direct> = response.take(0)?;
+        let mentions: Vec<Mentions> = response.take(1)?;
+
+        // by guild, in the order their channels came
+        let mut by_guild: Vec<(Ref<Guild>, i64)> = vec![];
+        for Mentions { channel, unread } in mentions {
+            let channel: Option<TextableChannel> = surreal.select(channel.record_id().0).await?;
+            let Some(channel) = channel else {
+                continue;
+            };
+            if !channel.readable_by(surreal, user).await? {
+                continue;
+            }
+            match by_guild
+                .iter_mut()
+                .find(|(guild, _)| guild == channel.guild())
+            {
+                Some((_, count)) => *count += unread,
+                None => by_guild.push((channel.guild().clone(), unread)),
+            }
+        }
+
+        Ok(Inbox {
+            direct_total: direct.iter().map(|direct| direct.unread).sum(),
+            mentions_total: by_guild.iter().map(|(_, unread)| unread).sum(),
+            direct: direct
+                .into_iter()
+                .map(|direct| UnreadDirect {
+                    user: direct.author.gql_id(),
+                    unread: direct.unread,
+                })
+                .collect(),
+            guilds: by_guild
+                .into_iter()
+                .map(|(guild, mentions)| UnreadMentions {
+                    guild: guild.gql_id(),
+                    mentions,
+                })
+                .collect(),
+        })
+    }
+}
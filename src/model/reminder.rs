@@ -0,0 +1,172 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::{
+    jobs,
+    pubsub::Relay,
+    util::{
+        query::{field, Q},
+        referrable, Ref, Referrable, ReferrableExt,
+    },
+};
+
+use super::{
+    message::{Message, MessageInit, MessageRecipientIn, MessageRecipientInKind},
+    user::User,
+};
+
+/// A message a user asked to be reminded of later, with a system DM.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Reminder {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    pub message: Ref<Message>,
+    #[serde(default)]
+    pub note: Option<String>,
+    pub remind_at: Datetime,
+    pub created_at: Datetime,
+}
+
+referrable!(Reminder = "reminder" .id: Option<Thing>);
+
+impl Reminder {
+    pub const JOB: &'static str = "reminder";
+    /// When to remind if no time was given.
+    pub const DEFAULT_DELAY_HOURS: i64 = 3;
+    pub const MAX_DELAY_DAYS: i64 = 365;
+    pub const MAX_NOTE_LENGTH: usize = 200;
+    pub const MAX_PENDING: i64 = 100;
+
+    /// Only messages the user can read can be set reminders on.
+    async fn require_readable(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        message: &Message,
+    ) -> tide::Result<()> {
+        if !message.readable_by(surreal, user).await? {
+            return Err(tide::Error::new(
+                StatusCode::NotFound,
+                anyhow!("no such message"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn create(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        message: &Ref<Message>,
+        remind_at: Option<DateTime<Utc>>,
+        note: Option<String>,
+    ) -> tide::Result<Reminder> {
+        let now = Utc::now();
+        let remind_at =
+            remind_at.unwrap_or_else(|| now + Duration::hours(Self::DEFAULT_DELAY_HOURS));
+        if remind_at <= now || remind_at > now + Duration::days(Self::MAX_DELAY_DAYS) {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!(
+                    "reminders have to be in the future, within {} days",
+                    Self::MAX_DELAY_DAYS
+                ),
+            ));
+        }
+        let note = note
+            .map(|note| note.trim().to_owned())
+            .filter(|note| !note.is_empty());
+        if note
+            .as_ref()
+            .is_some_and(|note| note.chars().count() > Self::MAX_NOTE_LENGTH)
+        {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("note is too long"),
+            ));
+        }
+        let pending = Q::select::<Reminder>()
+            .filter(field("user").eq(user))
+            .count(surreal)
+            .await?;
+        if pending >= Self::MAX_PENDING {
+            return Err(tide::Error::new(
+                StatusCode::TooManyRequests,
+                anyhow!("too many pending reminders"),
+            ));
+        }
+        Self::require_readable(surreal, user, &message.fetch(surreal).await?).await?;
+
+        let reminder: Reminder = surreal
+            .create(Self::TABLE)
+            .content(Reminder {
+                id: None,
+                user: user.clone(),
+                message: message.clone(),
+                note,
+                remind_at: Datetime(remind_at),
+                created_at: Datetime(now),
+            })
+            .await?;
+        jobs::enqueue_at(surreal, Self::JOB, reminder.refer(), remind_at).await?;
+        Ok(reminder)
+    }
+
+    /// The user's pending reminders, soonest first.
+    pub async fn of_user(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Vec<Reminder>> {
+        Q::select::<Reminder>()
+            .filter(field("user").eq(user))
+            .order("remind_at")
+            .all(surreal)
+            .await
+    }
+
+    /// The job is left alone and finds nothing to do once it runs.
+    pub async fn cancel(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        reminder: &Ref<Reminder>,
+    ) -> surrealdb::Result<bool> {
+        let found: Option<Reminder> = surreal.select(reminder.record_id().0).await?;
+        if !found.is_some_and(|found| found.user == *user) {
+            return Ok(false);
+        }
+        let _: Option<Reminder> = surreal.delete(reminder.record_id().0).await?;
+        Ok(true)
+    }
+
+    /// Sends the DM, replying to the message so clients link back to it, and forgets the
+    /// reminder.
+    pub async fn fire(self, surreal: &crate::Surreal, relay: &Relay) -> tide::Result<()> {
+        let id = self.id.clone().unwrap();
+        let content = match self.note {
+            Some(ref note) => format!("Reminder: {note}"),
+            None => String::from("Reminder: you asked to be reminded of this message."),
+        };
+        let system = User::system(surreal).await?;
+        let init = MessageInit {
+            recipient: MessageRecipientIn {
+                kind: MessageRecipientInKind::User,
+                id: self.user.record_id(),
+            },
+            content,
+            // the message may have been deleted since
+            reference: self
+                .message
+                .fetch(surreal)
+                .await
+                .ok()
+                .map(|_| self.message.clone()),
+            tts: false,
+            gif: None,
+        };
+        system.send_message(surreal, relay, init).await?;
+        let _: Option<Reminder> = surreal.delete(id).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Messages older than a guild's or the instance's retention are soft-deleted: their content
+//! goes but the record stays as a tombstone, so replies and read markers still point
+//! somewhere. Tombstones are purged for good once the grace period is over.
+
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use surrealdb::sql::Datetime;
+use tide::{log::info, StatusCode};
+
+use crate::{config::CONFIG, util::ReferrableExt};
+
+use super::{
+    guild::{Guild, TextChannel},
+    instance::InstanceSettings,
+    legal_hold::NOT_HELD,
+};
+
+pub const JOB: &str = "retention";
+pub const PURGE_JOB: &str = "retention_purge";
+
+pub const MAX_DAYS: u32 = 36500;
+
+/// Errors unless `days` makes sense as a retention period.
+pub fn check_days(days: Option<u32>) -> tide::Result<()> {
+    if days.is_some_and(|days| days == 0 || days > MAX_DAYS) {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("retention is 1 to {MAX_DAYS} days"),
+        ));
+    }
+    Ok(())
+}
+
+/// The most restrictive of `policies`, `None` if none of them limits retention.
+pub fn shortest(policies: impl IntoIterator<Item = Option<u32>>) -> Option<u32> {
+    policies.into_iter().flatten().min()
+}
+
+/// How long messages in `channel` are kept, going by the channel's, its guild's and the
+/// instance's retention.
+pub async fn of_channel(
+    surreal: &crate::Surreal,
+    channel: &TextChannel,
+) -> surrealdb::Result<Option<u32>> {
+    let guild = channel.guild.fetch(surreal).await?;
+    Ok(shortest([
+        InstanceSettings::get(surreal).await?.retention_days,
+        guild.retention_days,
+        channel.retention_days,
+    ]))
+}
+
+fn cutoff(days: u32) -> Datetime {
+    Datetime(Utc::now() - Duration::days(days.into()))
+}
+
+/// Tombstones everything past its retention. The instance's applies to guilds too, a guild's
+/// own can only be shorter, and a channel's shorter still. Nothing under a
+/// [legal hold](super::legal_hold) is touched.
+pub async fn apply(surreal: &crate::Surreal) -> anyhow::Result<()> {
+    let tombstone = format!(
+        "UPDATE message SET content = '', embeds = [], mentions = [], \
+         author_override = NONE, deleted_at = time::now() \
+         WHERE deleted_at = NONE AND pinned != true AND created_at < $cutoff AND {NOT_HELD}"
+    );
+
+    if let Some(days) = InstanceSettings::get(surreal).await?.retention_days {
+        surreal
+            .query(tombstone.as_str())
+            .bind(("cutoff", cutoff(days)))
+            .await?
+            .check()?;
+    }
+    let guilds: Vec<Guild> = surreal
+        .query("SELECT * FROM guild WHERE retention_days != NONE")
+        .await?
+        .take(0)?;
+    for guild in guilds {
+        let Some(days) = guild.retention_days else {
+            continue;
+        };
+        surreal
+            .query(format!(
+                "{tombstone} AND recipient.kind = 'Channel' AND recipient.id.guild = $guild"
+            ))
+            .bind(("cutoff", cutoff(days)))
+            .bind(("guild", guild.record_id()))
+            .await?
+            .check()?;
+    }
+    let channels: Vec<TextChannel> = surreal
+        .query("SELECT * FROM channel WHERE retention_days != NONE")
+        .await?
+        .take(0)?;
+    for channel in channels {
+        let Some(days) = channel.retention_days else {
+            continue;
+        };
+        surreal
+            .query(format!(
+                "{tombstone} AND recipient.kind = 'Channel' AND recipient.id = $channel"
+            ))
+            .bind(("cutoff", cutoff(days)))
+            .bind(("channel", channel.record_id()))
+            .await?
+            .check()?;
+    }
+    // translations would keep the content around
+    surreal
+        .query("DELETE translation WHERE message.deleted_at != NONE")
+        .await?
+        .check()?;
+    info!("applied message retention");
+    Ok(())
+}
+
+/// Deletes tombstones older than the grace period for good.
+pub async fn purge(surreal: &crate::Surreal) -> anyhow::Result<()> {
+    surreal
+        .query(format!(
+            "DELETE message WHERE deleted_at != NONE AND deleted_at < $cutoff AND {NOT_HELD}"
+        ))
+        .bind(("cutoff", cutoff(CONFIG.retention_grace_days)))
+        .await?
+        .check()?;
+    Ok(())
+}
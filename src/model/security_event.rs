@@ -0,0 +1,102 @@
+//! What happened to a user's account itself: logins, failed ones, token refreshes and
+//! revocations, password resets. Only the user sees these, as `Viewer.securityEvents`; guild
+//! moderation has its own [audit log](super::audit).
+
+use async_graphql::Enum;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::error;
+
+use crate::util::{
+    query::{field, Q},
+    referrable, Ref, Referrable,
+};
+
+use super::{login::Device, user::User};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    Registered,
+    LoginSucceeded,
+    /// The password was wrong.
+    LoginFailed,
+    TokenRefreshed,
+    /// A replaced refresh token was used again, so its whole family was signed out.
+    TokenReuseRevoked,
+    /// With a mailed link, which also signed out every session.
+    PasswordReset,
+    ApiTokenCreated,
+    ApiTokenRevoked,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SecurityEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    pub kind: SecurityEventKind,
+    /// Where it came from, when it came over HTTP.
+    #[serde(default)]
+    pub device: Option<Device>,
+    /// Like the name of the API token.
+    #[serde(default)]
+    pub detail: Option<String>,
+    pub at: Datetime,
+}
+
+referrable!(SecurityEvent = "security_event" .id: Option<Thing>);
+
+impl SecurityEvent {
+    /// How many `Viewer.securityEvents` hands out at once.
+    pub const MAX_PAGE: i64 = 200;
+
+    pub fn new(user: Ref<User>, kind: SecurityEventKind) -> Self {
+        Self {
+            id: None,
+            user,
+            kind,
+            device: None,
+            detail: None,
+            at: Datetime(Utc::now()),
+        }
+    }
+
+    pub fn from_device(self, device: Device) -> Self {
+        Self {
+            device: Some(device),
+            ..self
+        }
+    }
+
+    pub fn with_detail(self, detail: impl Into<String>) -> Self {
+        Self {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+
+    /// Stores the event. Failing to is logged rather than failing what it's about.
+    pub async fn log(self, surreal: &crate::Surreal) {
+        let result: surrealdb::Result<SecurityEvent> =
+            surreal.create(Self::TABLE).content(&self).await;
+        if let Err(e) = result {
+            error!("couldn't log {:?} for {}: {e}", self.kind, self.user.id());
+        }
+    }
+
+    /// Newest first.
+    pub async fn of_user(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        limit: i64,
+    ) -> tide::Result<Vec<SecurityEvent>> {
+        Ok(Q::select::<SecurityEvent>()
+            .filter(field("user").eq(user))
+            .order_desc("at")
+            .limit(limit)
+            .all(surreal)
+            .await?)
+    }
+}
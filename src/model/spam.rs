@@ -0,0 +1,244 @@
+//! Scores messages as they're sent for signs of spam: the same content over and over, links
+//! and nothing else from brand new accounts, and direct messages to many people at once.
+//! Past the configured scores the sender is slowed down without being told, has to solve a
+//! [captcha](crate::captcha) before sending more, or is flagged for admins to look at.
+
+use std::{collections::HashSet, time::Duration};
+
+use async_graphql::{Enum, ErrorExtensions};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::info;
+
+use crate::{
+    captcha::Captcha,
+    config::CONFIG,
+    util::{referrable, Ref, Referrable, ReferrableExt},
+};
+
+use super::{
+    emoji,
+    message::{MessageInit, MessageRecipient},
+    user::{Badge, User},
+};
+
+/// How far back the author's messages are looked at.
+const WINDOW: &str = "10m";
+/// Duplicates only count within this many seconds.
+const BURST_SECONDS: i64 = 60;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamSignal {
+    /// The same content several times within a minute.
+    DuplicateBurst,
+    /// Nothing but links, from an account younger than `NETHERITE_CHAT_SPAM_NEW_ACCOUNT_HOURS`.
+    NewAccountLinks,
+    /// Direct messages to many different users within ten minutes.
+    MassDirect,
+}
+
+impl SpamSignal {
+    fn weight(self) -> u32 {
+        match self {
+            Self::DuplicateBurst => 40,
+            Self::NewAccountLinks => 30,
+            Self::MassDirect => 50,
+        }
+    }
+}
+
+/// A sender who scored past `NETHERITE_CHAT_SPAM_FLAG_SCORE`, for admins to review. There is
+/// one per sender and message content, sending it again updates that one.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SpamFlag {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub user: Ref<User>,
+    pub score: u32,
+    pub signals: Vec<SpamSignal>,
+    /// The start of the message that got them flagged.
+    pub excerpt: String,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub resolved: bool,
+}
+
+referrable!(SpamFlag = "spam_flag" .id: Option<Thing>);
+
+/// Someone who has to solve a captcha before sending again.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct CaptchaHold {
+    id: Thing,
+    user: Ref<User>,
+    since: Datetime,
+}
+
+impl SpamFlag {
+    const EXCERPT_LENGTH: usize = 200;
+
+    /// Unresolved ones, newest first.
+    pub async fn pending(surreal: &crate::Surreal) -> surrealdb::Result<Vec<SpamFlag>> {
+        surreal
+            .query("SELECT * FROM spam_flag WHERE resolved = false ORDER BY created_at DESC")
+            .await?
+            .take(0)
+    }
+
+    pub async fn resolve(surreal: &crate::Surreal, flag: &Ref<SpamFlag>) -> tide::Result<SpamFlag> {
+        let mut flag = flag.fetch(surreal).await?;
+        flag.resolved = true;
+        Ok(flag.save(surreal).await?)
+    }
+}
+
+fn flag_key(user: &Ref<User>, content: &str) -> Thing {
+    let mut hash = Sha1::new();
+    hash.update(content.as_bytes());
+    Thing::from((
+        SpamFlag::TABLE.to_owned(),
+        format!("{}_{:x}", user.id(), hash.finalize()),
+    ))
+}
+
+fn hold_key(user: &Ref<User>) -> Thing {
+    Thing::from(("captcha_hold".to_owned(), user.id().to_owned()))
+}
+
+/// Lets `user` send again after they solved a captcha.
+pub async fn release(surreal: &crate::Surreal, user: &Ref<User>) -> surrealdb::Result<()> {
+    let _: Option<CaptchaHold> = surreal.delete(hold_key(user)).await?;
+    Ok(())
+}
+
+fn captcha_required() -> async_graphql::Error {
+    async_graphql::Error::new(
+        "solve a captcha at POST /api/v1/spam/captcha before sending more messages",
+    )
+    .extend_with(|_, extensions| extensions.set("code", "CAPTCHA_REQUIRED"))
+}
+
+fn only_links(content: &str) -> bool {
+    let mut words = content.split_whitespace().peekable();
+    words.peek().is_some()
+        && words.all(|word| word.starts_with("https://") || word.starts_with("http://"))
+}
+
+/// What about `init` looks like spam, judging by what `author` sent lately.
+async fn signals(
+    surreal: &crate::Surreal,
+    author: &User,
+    init: &MessageInit,
+) -> surrealdb::Result<Vec<SpamSignal>> {
+    #[derive(Deserialize)]
+    struct Recent {
+        recipient: MessageRecipient,
+        content: String,
+        created_at: Datetime,
+    }
+
+    let recent: Vec<Recent> = surreal
+        .query(format!(
+            "SELECT recipient, content, created_at FROM message \
+             WHERE author = $user AND created_at > time::now() - {WINDOW} \
+             ORDER BY created_at DESC LIMIT 200"
+        ))
+        .bind(("user", author.record_id()))
+        .await?
+        .take(0)?;
+
+    let mut signals = vec![];
+    let content = emoji::normalize(&init.content);
+    let burst_start = Utc::now() - chrono::Duration::seconds(BURST_SECONDS);
+    let duplicates = recent
+        .iter()
+        .filter(|message| message.created_at.0 > burst_start && message.content == content)
+        .count();
+    if CONFIG.spam_duplicate_burst > 0 && duplicates + 1 >= CONFIG.spam_duplicate_burst {
+        signals.push(SpamSignal::DuplicateBurst);
+    }
+
+    let new_account = author.created_at.as_ref().is_some_and(|created_at| {
+        Utc::now() - created_at.0 < chrono::Duration::hours(CONFIG.spam_new_account_hours)
+    });
+    if new_account && only_links(&content) {
+        signals.push(SpamSignal::NewAccountLinks);
+    }
+
+    let recipient = MessageRecipient::from(init.recipient.clone());
+    if recipient.is_user() {
+        let direct: HashSet<_> = recent
+            .iter()
+            .filter(|message| message.recipient.is_user())
+            .map(|message| message.recipient.record_id())
+            .chain([recipient.record_id()])
+            .collect();
+        if CONFIG.spam_mass_direct > 0 && direct.len() >= CONFIG.spam_mass_direct {
+            signals.push(SpamSignal::MassDirect);
+        }
+    }
+    Ok(signals)
+}
+
+/// Run before `author` sends `init`. Errors with `CAPTCHA_REQUIRED` while they have to solve
+/// one, and may take a while to return when they're being slowed down. Admins and bots
+/// aren't checked.
+pub async fn check(
+    surreal: &crate::Surreal,
+    author: &User,
+    init: &MessageInit,
+) -> async_graphql::Result<()> {
+    if author.bot || author.badges.contains(&Badge::Admin) {
+        return Ok(());
+    }
+    let held: Option<CaptchaHold> = surreal.select(hold_key(&author.refer())).await?;
+    if held.is_some() {
+        return Err(captcha_required());
+    }
+
+    let signals = signals(surreal, author, init).await?;
+    let score: u32 = signals.iter().map(|signal| signal.weight()).sum();
+    if score == 0 {
+        return Ok(());
+    }
+    let past = |threshold: u32| threshold > 0 && score >= threshold;
+
+    if past(CONFIG.spam_flag_score) {
+        info!("flagging {} as a spammer: {signals:?}", author.record_id());
+        let key = flag_key(&author.refer(), &init.content);
+        let _: SpamFlag = surreal
+            .update(key.clone())
+            .content(SpamFlag {
+                id: Some(key),
+                user: author.refer(),
+                score,
+                signals: signals.clone(),
+                excerpt: init
+                    .content
+                    .chars()
+                    .take(SpamFlag::EXCERPT_LENGTH)
+                    .collect(),
+                created_at: Datetime(Utc::now()),
+                resolved: false,
+            })
+            .await?;
+    }
+    if past(CONFIG.spam_captcha_score) && CONFIG.captcha != Captcha::None {
+        let key = hold_key(&author.refer());
+        let _: CaptchaHold = surreal
+            .update(key.clone())
+            .content(CaptchaHold {
+                id: key,
+                user: author.refer(),
+                since: Datetime(Utc::now()),
+            })
+            .await?;
+        return Err(captcha_required());
+    }
+    if past(CONFIG.spam_throttle_score) {
+        async_std::task::sleep(Duration::from_secs(CONFIG.spam_throttle_seconds)).await;
+    }
+    Ok(())
+}
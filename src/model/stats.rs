@@ -0,0 +1,145 @@
+//! Instance-wide numbers for admins, rolled up nightly so looking at them doesn't count every
+//! message. Each day gets a `daily_stats` record once it's over, and the totals are recounted
+//! in `stats:totals` at the same time, so they're up to a day old.
+
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::log::info;
+
+pub const JOB: &str = "stats_rollup";
+
+/// How far back a rollup catches up on days it missed, and how many days can be asked for.
+pub const MAX_DAYS: u32 = 90;
+
+/// What the instance has, as of the last rollup. Bots and users of other instances don't
+/// count as users, messages deleted by retention don't count either.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StatsTotals {
+    pub users: u64,
+    pub guilds: u64,
+    pub messages: u64,
+    /// The size of all uploaded attachments.
+    pub storage_bytes: u64,
+    pub at: Datetime,
+}
+
+impl StatsTotals {
+    const ID: (&'static str, &'static str) = ("stats", "totals");
+
+    /// `None` until the first rollup.
+    pub async fn get(surreal: &crate::Surreal) -> surrealdb::Result<Option<Self>> {
+        surreal.select(Self::ID).await
+    }
+
+    async fn count(surreal: &crate::Surreal) -> surrealdb::Result<Self> {
+        let mut counted = surreal
+            .query(
+                "SELECT VALUE count() FROM user WHERE bot != true AND domain = NONE GROUP ALL;
+                 SELECT VALUE count() FROM guild GROUP ALL;
+                 SELECT VALUE count() FROM message WHERE deleted_at = NONE GROUP ALL;
+                 RETURN math::sum((SELECT VALUE size FROM attachment));",
+            )
+            .await?;
+        let mut take = |index: usize| -> surrealdb::Result<u64> {
+            let value: Option<u64> = counted.take(index)?;
+            Ok(value.unwrap_or(0))
+        };
+        Ok(Self {
+            users: take(0)?,
+            guilds: take(1)?,
+            messages: take(2)?,
+            storage_bytes: take(3)?,
+            at: Datetime(Utc::now()),
+        })
+    }
+}
+
+/// One UTC day's activity.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DailyStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub day: NaiveDate,
+    /// Users who logged in or sent a message.
+    pub active_users: u64,
+    pub registrations: u64,
+    pub messages: u64,
+}
+
+impl DailyStats {
+    const TABLE: &'static str = "daily_stats";
+
+    /// The last `days` days rolled up, oldest first.
+    pub async fn last(surreal: &crate::Surreal, days: u32) -> surrealdb::Result<Vec<Self>> {
+        let mut stats: Vec<Self> = surreal
+            .query("SELECT * FROM daily_stats ORDER BY day DESC LIMIT $days")
+            .bind(("days", days.min(MAX_DAYS)))
+            .await?
+            .take(0)?;
+        stats.reverse();
+        Ok(stats)
+    }
+
+    async fn count(surreal: &crate::Surreal, day: NaiveDate) -> surrealdb::Result<Self> {
+        let midnight =
+            |day: NaiveDate| Datetime(Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).unwrap()));
+        let (from, to) = (midnight(day), midnight(day + Duration::days(1)));
+        let mut counted = surreal
+            .query(
+                "RETURN array::len(array::union(
+                     (SELECT VALUE author FROM message WHERE created_at >= $from AND created_at < $to),
+                     (SELECT VALUE user FROM login WHERE at >= $from AND at < $to)
+                 ));
+                 SELECT VALUE count() FROM user
+                     WHERE bot != true AND domain = NONE AND created_at >= $from AND created_at < $to
+                     GROUP ALL;
+                 SELECT VALUE count() FROM message
+                     WHERE created_at >= $from AND created_at < $to GROUP ALL;",
+            )
+            .bind(("from", from))
+            .bind(("to", to))
+            .await?;
+        let mut take = |index: usize| -> surrealdb::Result<u64> {
+            let value: Option<u64> = counted.take(index)?;
+            Ok(value.unwrap_or(0))
+        };
+        Ok(Self {
+            id: Some(Thing::from((Self::TABLE.to_owned(), day.to_string()))),
+            day,
+            active_users: take(0)?,
+            registrations: take(1)?,
+            messages: take(2)?,
+        })
+    }
+}
+
+/// What `stats` answers with.
+pub struct InstanceStats {
+    pub totals: Option<StatsTotals>,
+    pub days: Vec<DailyStats>,
+}
+
+/// Rolls up every finished day since the last rollup, at most [MAX_DAYS] of them, and
+/// recounts the totals.
+pub async fn rollup(surreal: &crate::Surreal) -> anyhow::Result<()> {
+    let yesterday = Utc::now().date_naive() - Duration::days(1);
+    let last = DailyStats::last(surreal, 1)
+        .await?
+        .pop()
+        .map(|stats| stats.day);
+    let mut day = match last {
+        Some(last) => (last + Duration::days(1)).max(yesterday - Duration::days(MAX_DAYS.into())),
+        None => yesterday,
+    };
+    while day <= yesterday {
+        let stats = DailyStats::count(surreal, day).await?;
+        let id = stats.id.clone().unwrap();
+        let _: DailyStats = surreal.update(id).content(stats).await?;
+        day += Duration::days(1);
+    }
+    let totals = StatsTotals::count(surreal).await?;
+    let _: Option<StatsTotals> = surreal.update(StatsTotals::ID).content(totals).await?;
+    info!("rolled up instance stats");
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{query::Q, referrable, Ref, Referrable};
+
+use super::user::User;
+
+/// A version of the terms of service. Publishing a new one makes everyone accept it again.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Terms {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    /// Counts up from 1.
+    pub version: u32,
+    /// Markdown.
+    pub content: String,
+    pub created_at: Datetime,
+}
+
+referrable!(Terms = "terms" .id: Option<Thing>);
+
+impl Terms {
+    pub const MAX_LENGTH: usize = 100_000;
+
+    /// `None` while no terms were ever published, then nobody has to accept anything.
+    pub async fn current(surreal: &crate::Surreal) -> surrealdb::Result<Option<Terms>> {
+        Q::select::<Terms>()
+            .order_desc("version")
+            .one(surreal)
+            .await
+    }
+
+    pub async fn publish(surreal: &crate::Surreal, content: String) -> tide::Result<Terms> {
+        let content = content.trim().to_owned();
+        if content.is_empty() || content.len() > Self::MAX_LENGTH {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("terms are 1 to {} bytes", Self::MAX_LENGTH),
+            ));
+        }
+        let version = Self::current(surreal)
+            .await?
+            .map_or(1, |current| current.version + 1);
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(Terms {
+                id: None,
+                version,
+                content,
+                created_at: Datetime(Utc::now()),
+            })
+            .await?)
+    }
+
+    /// Bots never accept anything, whoever runs them did.
+    pub async fn must_accept(surreal: &crate::Surreal, user: &User) -> surrealdb::Result<bool> {
+        if user.bot {
+            return Ok(false);
+        }
+        Ok(Self::current(surreal)
+            .await?
+            .is_some_and(|current| user.accepted_terms < Some(current.version)))
+    }
+
+    /// Checks `version` is the current one, so nobody accepts terms they haven't seen.
+    /// Returns the version to record, `None` if there are no terms.
+    pub async fn check_accepted(
+        surreal: &crate::Surreal,
+        version: Option<u32>,
+    ) -> tide::Result<Option<u32>> {
+        let Some(current) = Self::current(surreal).await? else {
+            return Ok(None);
+        };
+        if version != Some(current.version) {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!(
+                    "the current terms of service are version {}, accept those",
+                    current.version
+                ),
+            ));
+        }
+        Ok(Some(current.version))
+    }
+
+    pub async fn accept(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        version: Option<u32>,
+    ) -> tide::Result<()> {
+        if let Some(version) = Self::check_accepted(surreal, version).await? {
+            Self::record(surreal, user, version).await?;
+        }
+        Ok(())
+    }
+
+    /// Records the acceptance without checking, for after [check_accepted](Self::check_accepted).
+    pub async fn record(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+        version: u32,
+    ) -> surrealdb::Result<()> {
+        surreal
+            .query("UPDATE $user SET accepted_terms = $version")
+            .bind(("user", user.record_id()))
+            .bind(("version", version))
+            .await?
+            .check()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use async_graphql::{Enum, SimpleObject};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::util::{random_string, referrable, secure_eq, RecordId, Ref, ReferrableExt};
+
+use super::{oauth::OAuthApp, user::User};
+
+/// What a restricted (non-session) token is allowed to do.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Enum)]
+pub enum Scope {
+    /// See who you are: your tag, display name and profile.
+    #[serde(rename = "identify")]
+    Identify,
+    /// See which guilds you are in.
+    #[serde(rename = "guilds")]
+    Guilds,
+    /// Read your messages.
+    #[serde(rename = "messages.read")]
+    MessagesRead,
+    /// Send messages as you.
+    #[serde(rename = "messages.send")]
+    MessagesSend,
+    /// Create and manage guilds and channels as you.
+    #[serde(rename = "guilds.manage")]
+    GuildsManage,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Self::Identify,
+        Self::Guilds,
+        Self::MessagesRead,
+        Self::MessagesSend,
+        Self::GuildsManage,
+    ];
+
+    /// The OAuth2 name of the scope, as it appears in `scope=` parameters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Identify => "identify",
+            Self::Guilds => "guilds",
+            Self::MessagesRead => "messages.read",
+            Self::MessagesSend => "messages.send",
+            Self::GuildsManage => "guilds.manage",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.name() == name)
+    }
+
+    /// What the consent screen tells the user the app will be able to do.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Identify => "See who you are: your tag, display name and profile",
+            Self::Guilds => "See which guilds you are in",
+            Self::MessagesRead => "Read your messages",
+            Self::MessagesSend => "Send messages as you",
+            Self::GuildsManage => "Create and manage guilds and channels as you",
+        }
+    }
+}
+
+/// A long-lived personal access token. Only the hash of its secret is stored.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiToken {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub owner: Ref<User>,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub secret_hash: String,
+    pub created_at: Datetime,
+    #[serde(default)]
+    pub revoked: bool,
+    /// Set for tokens issued to a third-party app through OAuth2, `None` for personal ones.
+    #[serde(default)]
+    pub app: Option<Ref<OAuthApp>>,
+}
+
+referrable!(ApiToken = "api_token" .id: Option<Thing>);
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct CreatedApiToken {
+    pub token: ApiToken,
+    /// The only time the full token is shown.
+    pub secret: String,
+}
+
+/// Put into the request by the auth middleware when an API token was presented.
+#[derive(Debug, Clone)]
+pub struct ApiTokenAuth {
+    pub token: Ref<ApiToken>,
+    pub uid: RecordId,
+    pub scopes: Vec<Scope>,
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha1::digest(secret.as_bytes()))
+}
+
+impl ApiToken {
+    /// Tokens look like `nca_<id>.<secret>`, which is how the middleware tells them from JWTs.
+    pub const PREFIX: &'static str = "nca_";
+
+    /// Creates a token, returning it along with the full token string to hand to the user.
+    pub async fn create(
+        surreal: &crate::Surreal,
+        owner: &User,
+        name: String,
+        scopes: Vec<Scope>,
+        app: Option<Ref<OAuthApp>>,
+    ) -> surrealdb::Result<(Self, String)> {
+        let secret = random_string(40);
+        let token: Self = surreal
+            .create(Self::TABLE)
+            .content(Self {
+                id: None,
+                owner: owner.refer(),
+                name,
+                scopes,
+                secret_hash: hash(&secret),
+                created_at: Datetime(Utc::now()),
+                revoked: false,
+                app,
+            })
+            .await?;
+        let full = format!(
+            "{}{}.{secret}",
+            Self::PREFIX,
+            token.id.as_ref().unwrap().id.to_raw()
+        );
+        Ok((token, full))
+    }
+
+    /// `Ok(None)` if `token` is not an API token at all, an error if it is one but isn't valid.
+    pub async fn authenticate(
+        surreal: &crate::Surreal,
+        token: &str,
+    ) -> anyhow::Result<Option<ApiTokenAuth>> {
+        let Some(token) = token.strip_prefix(Self::PREFIX) else {
+            return Ok(None);
+        };
+        let (id, secret) = token
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("malformed api token"))?;
+        let stored: Option<Self> = surreal.select((Self::TABLE, id)).await?;
+        let stored = stored
+            .filter(|stored| !stored.revoked && secure_eq(&stored.secret_hash, &hash(secret)))
+            .ok_or_else(|| anyhow::anyhow!("invalid api token"))?;
+
+        Ok(Some(ApiTokenAuth {
+            token: stored.refer(),
+            uid: stored.owner.record_id(),
+            scopes: stored.scopes,
+        }))
+    }
+}
@@ -0,0 +1,143 @@
+//! Static HTML transcripts of a channel over a time range, which moderators keep along with
+//! incident reports. They're written once to [Storage](crate::storage::Storage) and linked
+//! from the guild's [audit log](super::audit), so later edits and retention don't change them.
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use surrealdb::sql::Datetime;
+use tide::StatusCode;
+
+use crate::util::{RecordId, ReferrableExt};
+
+use super::{attachment::Attachment, guild::TextChannel, message::Message, user::User};
+
+pub const MAX_MESSAGES: usize = 10000;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// A transcript and how many messages are in it.
+pub struct Rendered {
+    pub html: String,
+    pub message_count: u64,
+}
+
+/// The messages sent to `channel` from `from` up to `to`, oldest first, with the attachments
+/// they link to.
+pub async fn render(
+    surreal: &crate::Surreal,
+    channel: &TextChannel,
+    guild_name: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> tide::Result<Rendered> {
+    let messages: Vec<Message> = surreal
+        .query(
+            "SELECT * FROM message WHERE recipient.kind = 'Channel' AND recipient.id = $channel \
+             AND created_at >= $from AND created_at < $to ORDER BY created_at LIMIT $limit",
+        )
+        .bind(("channel", channel.record_id()))
+        .bind(("from", Datetime(from)))
+        .bind(("to", Datetime(to)))
+        .bind(("limit", MAX_MESSAGES + 1))
+        .await?
+        .take(0)?;
+    if messages.len() > MAX_MESSAGES {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("transcripts hold at most {MAX_MESSAGES} messages, pick a shorter range"),
+        ));
+    }
+
+    let authors: Vec<User> = surreal
+        .query("SELECT * FROM $users")
+        .bind((
+            "users",
+            messages
+                .iter()
+                .map(|message| message.author.record_id())
+                .collect::<Vec<_>>(),
+        ))
+        .await?
+        .take(0)?;
+    let authors: HashMap<RecordId, User> = authors
+        .into_iter()
+        .map(|author| (author.record_id(), author))
+        .collect();
+    let attachments: Vec<Attachment> = surreal
+        .query("SELECT * FROM attachment WHERE channel = $channel AND created_at < $to")
+        .bind(("channel", channel.record_id()))
+        .bind(("to", Datetime(to)))
+        .await?
+        .take(0)?;
+
+    let mut html = String::new();
+    let title = format!("#{} in {}", channel.name, guild_name);
+    // writing to a String can't fail
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title><style>\
+         body{{font-family:sans-serif;max-width:60em;margin:auto}}\
+         article{{margin:1em 0}}time{{color:#777;font-size:.85em}}\
+         p{{white-space:pre-wrap;margin:.2em 0}}</style></head><body>\n\
+         <h1>{0}</h1>\n<p>{1} to {2}, {3} messages</p>\n",
+        escape(&title),
+        from.to_rfc3339(),
+        to.to_rfc3339(),
+        messages.len(),
+    );
+    for message in &messages {
+        let author = authors.get(&message.author.record_id());
+        let name = match (&message.author_override, author) {
+            (Some(shown), _) => format!("{} (webhook)", shown.name),
+            (None, Some(author)) => format!("{} ({})", author.display_name, author.tag_fmt()),
+            (None, None) => String::from("deleted user"),
+        };
+        let _ = write!(
+            html,
+            "<article id=\"{}\"><b>{}</b> <time>{}</time>",
+            escape(message.refer().id()),
+            escape(&name),
+            message.created_at.0.to_rfc3339(),
+        );
+        if message.deleted_at.is_some() {
+            html.push_str("<p><i>deleted</i></p>");
+        } else {
+            let _ = write!(html, "<p>{}</p>", escape(&message.content));
+        }
+        let linked: Vec<_> = attachments
+            .iter()
+            .filter(|attachment| message.content.contains(&attachment.path))
+            .collect();
+        if !linked.is_empty() {
+            html.push_str("<ul>");
+            for attachment in linked {
+                if attachment.blocked {
+                    let _ = write!(html, "<li>{} (blocked)</li>", escape(&attachment.filename));
+                } else {
+                    let _ = write!(
+                        html,
+                        "<li><a href=\"/{}\">{}</a></li>",
+                        escape(&attachment.path),
+                        escape(&attachment.filename)
+                    );
+                }
+            }
+            html.push_str("</ul>");
+        }
+        html.push_str("</article>\n");
+    }
+    html.push_str("</body></html>\n");
+
+    Ok(Rendered {
+        html,
+        message_count: messages.len() as u64,
+    })
+}
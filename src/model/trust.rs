@@ -0,0 +1,201 @@
+//! Trust levels: guilds can hold members back from posting links, uploading large files and
+//! mentioning many people until their account is old enough and they've written enough in
+//! the guild. Levels are worked out as messages are sent, nothing is stored.
+
+use async_graphql::{Enum, ErrorExtensions, InputObject, SimpleObject};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::util::{Ref, ReferrableExt};
+
+use super::{
+    guild::{Guild, Member, Permission, TextableChannel},
+    message::parse_mentions,
+    user::User,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustLevel {
+    New,
+    Basic,
+    /// Not held back at all.
+    Member,
+}
+
+/// What members below [TrustLevel::Member] may do. Unset limits don't apply.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[graphql(input_name = "TrustRestrictionsInput")]
+pub struct TrustRestrictions {
+    pub allow_links: bool,
+    pub max_upload_bytes: Option<u64>,
+    /// Different users mentioned in one message.
+    pub max_mentions: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[serde(default)]
+#[graphql(input_name = "TrustSettingsInput")]
+pub struct TrustSettings {
+    /// Off by default, then everyone is a [TrustLevel::Member].
+    pub enabled: bool,
+    pub basic_account_hours: u32,
+    /// Messages written in the guild.
+    pub basic_messages: u32,
+    pub member_account_days: u32,
+    pub member_messages: u32,
+    pub new_restrictions: TrustRestrictions,
+    pub basic_restrictions: TrustRestrictions,
+}
+
+impl Default for TrustSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            basic_account_hours: 24,
+            basic_messages: 3,
+            member_account_days: 7,
+            member_messages: 50,
+            new_restrictions: TrustRestrictions {
+                allow_links: false,
+                max_upload_bytes: Some(1024 * 1024),
+                max_mentions: Some(2),
+            },
+            basic_restrictions: TrustRestrictions {
+                allow_links: true,
+                max_upload_bytes: Some(8 * 1024 * 1024),
+                max_mentions: Some(5),
+            },
+        }
+    }
+}
+
+impl TrustSettings {
+    fn restrictions(&self, level: TrustLevel) -> Option<&TrustRestrictions> {
+        match level {
+            TrustLevel::New => Some(&self.new_restrictions),
+            TrustLevel::Basic => Some(&self.basic_restrictions),
+            TrustLevel::Member => None,
+        }
+    }
+}
+
+impl TrustLevel {
+    /// `user`'s in `guild`. Whoever can [manage messages](Permission::ManageMessages) is a
+    /// member, and so are accounts from before creation times were recorded once they've
+    /// written enough.
+    pub async fn of(
+        surreal: &crate::Surreal,
+        guild: &Guild,
+        user: &User,
+    ) -> surrealdb::Result<Self> {
+        let settings = &guild.trust;
+        if !settings.enabled {
+            return Ok(Self::Member);
+        }
+        if Member::effective_permissions(surreal, &guild.refer(), &user.refer())
+            .await?
+            .contains(&Permission::ManageMessages)
+        {
+            return Ok(Self::Member);
+        }
+        let age = user
+            .created_at
+            .as_ref()
+            .map_or(Duration::max_value(), |at| Utc::now() - at.0);
+        let written: Option<u32> = surreal
+            .query(
+                "SELECT VALUE count() FROM message WHERE author = $user \
+                 AND recipient.kind = 'Channel' AND recipient.id.guild = $guild GROUP ALL",
+            )
+            .bind(("user", user.record_id()))
+            .bind(("guild", guild.record_id()))
+            .await?
+            .take(0)?;
+        let written = written.unwrap_or(0);
+        Ok(
+            if age >= Duration::days(settings.member_account_days.into())
+                && written >= settings.member_messages
+            {
+                Self::Member
+            } else if age >= Duration::hours(settings.basic_account_hours.into())
+                && written >= settings.basic_messages
+            {
+                Self::Basic
+            } else {
+                Self::New
+            },
+        )
+    }
+}
+
+/// Fails with `TRUST_RESTRICTED`, and the `trustLevel` that was too low.
+fn restricted(level: TrustLevel, message: String) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, extensions| {
+        extensions.set("code", "TRUST_RESTRICTED");
+        extensions.set("trustLevel", format!("{level:?}"));
+    })
+}
+
+/// Holds `author` to their trust level's restrictions on links and mentions in `channel`.
+pub async fn check_message(
+    surreal: &crate::Surreal,
+    channel: &TextableChannel,
+    author: &User,
+    content: &str,
+) -> async_graphql::Result<()> {
+    let guild = channel.guild().fetch(surreal).await?;
+    let level = TrustLevel::of(surreal, &guild, author).await?;
+    let Some(restrictions) = guild.trust.restrictions(level) else {
+        return Ok(());
+    };
+    if !restrictions.allow_links
+        && content
+            .split_whitespace()
+            .any(|word| word.contains("https://") || word.contains("http://"))
+    {
+        return Err(restricted(
+            level,
+            String::from("you can't post links in this guild yet"),
+        ));
+    }
+    if let Some(max) = restrictions.max_mentions {
+        if parse_mentions(content).len() > max as usize {
+            return Err(restricted(
+                level,
+                format!("you can mention at most {max} people at once in this guild for now"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Holds `owner` to their trust level's upload limit in `channel`'s guild, after checking
+/// they could send to it at all: its moderators hear about what they upload for it.
+pub async fn check_upload(
+    surreal: &crate::Surreal,
+    channel: &Ref<TextableChannel>,
+    owner: &User,
+    size: u64,
+) -> async_graphql::Result<()> {
+    let channel = channel.fetch(surreal).await?;
+    if !channel.readable_by(surreal, &owner.refer()).await? {
+        return Err(async_graphql::Error::new("you can't send to this channel"));
+    }
+    let guild = channel.guild().fetch(surreal).await?;
+    let level = TrustLevel::of(surreal, &guild, owner).await?;
+    let Some(max) = guild
+        .trust
+        .restrictions(level)
+        .and_then(|restrictions| restrictions.max_upload_bytes)
+    else {
+        return Ok(());
+    };
+    if size > max {
+        return Err(restricted(
+            level,
+            format!("you can upload at most {max} bytes at a time in this guild for now"),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use futures_util::AsyncRead;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::{log::warn, StatusCode};
+
+use crate::{
+    pubsub::Relay,
+    storage::{self, Storage, UploadError, UploadKind},
+    util::{
+        query::{field, Q},
+        referrable, Ref, Referrable, ReferrableExt,
+    },
+};
+
+use super::{
+    attachment::{Attachment, AttachmentInit},
+    guild::TextableChannel,
+    trust,
+    user::User,
+};
+
+/// An attachment being uploaded in chunks over [tus](crate::tus). What arrived so far is in
+/// the file at `path`, its size is how far along the upload is.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UploadSession {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub owner: Ref<User>,
+    #[serde(default)]
+    pub channel: Option<Ref<TextableChannel>>,
+    pub filename: String,
+    #[serde(default)]
+    pub alt: Option<String>,
+    /// See [Attachment::record].
+    #[serde(default)]
+    pub voice: bool,
+    /// The whole size, announced upfront.
+    pub length: u64,
+    /// Under the storage root's `upload` directory, see [Storage::start_upload].
+    pub path: String,
+    pub created_at: Datetime,
+}
+
+referrable!(UploadSession = "upload_session" .id: Option<Thing>);
+
+lazy_static::lazy_static! {
+    /// The paths of the uploads a chunk is being written to. They're on this node's disk, so no
+    /// other node could be writing to them.
+    static ref WRITING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Proof that nothing else is writing to an upload, see [UploadSession::writing].
+pub struct Writing(String);
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        WRITING.lock().unwrap().remove(&self.0);
+    }
+}
+
+impl UploadSession {
+    pub const JOB: &'static str = "upload_session_cleanup";
+    /// Unfinished uploads are dropped after this long.
+    pub const EXPIRES_HOURS: i64 = 24;
+    pub const MAX_PENDING: i64 = 10;
+
+    /// Checks what can be checked before anything is sent: the size, the alt text, and the
+    /// channel's upload limit for `owner`.
+    pub async fn create(
+        surreal: &crate::Surreal,
+        storage: &Storage,
+        owner: &User,
+        filename: String,
+        length: u64,
+        init: AttachmentInit,
+    ) -> tide::Result<Self> {
+        let AttachmentInit {
+            channel,
+            alt,
+            voice,
+        } = init;
+        let limit = UploadKind::Attachment.max_size();
+        if length > limit {
+            return Err(UploadError::TooLarge { limit }.into());
+        }
+        let alt = Attachment::check_alt(alt)?;
+        if let Some(ref channel) = channel {
+            trust::check_upload(surreal, channel, owner, length)
+                .await
+                .map_err(|e| tide::Error::from_str(StatusCode::Forbidden, e.message))?;
+        }
+        let pending = Q::select::<UploadSession>()
+            .filter(field("owner").eq(&owner.refer()))
+            .count(surreal)
+            .await?;
+        if pending >= Self::MAX_PENDING {
+            return Err(tide::Error::new(
+                StatusCode::TooManyRequests,
+                anyhow!("too many unfinished uploads"),
+            ));
+        }
+
+        let path = storage.start_upload().await?;
+        Ok(surreal
+            .create(Self::TABLE)
+            .content(UploadSession {
+                id: None,
+                owner: owner.refer(),
+                channel,
+                filename,
+                alt,
+                voice,
+                length,
+                path,
+                created_at: Datetime(Utc::now()),
+            })
+            .await?)
+    }
+
+    /// `user`'s upload `id`. Anyone else's is not found either, so ids can't be probed.
+    pub async fn of(surreal: &crate::Surreal, user: &Ref<User>, id: &str) -> tide::Result<Self> {
+        let session: Option<Self> = surreal.select(Ref::<Self>::new(id).record_id().0).await?;
+        session
+            .filter(|session| session.owner == *user)
+            .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "no such upload"))
+    }
+
+    /// How many bytes have arrived.
+    pub async fn offset(&self) -> tide::Result<u64> {
+        Ok(storage::upload_offset(&self.path).await?)
+    }
+
+    /// The upload to write to, `409 Conflict` while another chunk is being written. Two chunks
+    /// at the same offset would otherwise both pass [append](Self::append)'s check.
+    pub fn writing(&self) -> tide::Result<Writing> {
+        if !WRITING.lock().unwrap().insert(self.path.clone()) {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("another chunk of this upload is being sent"),
+            ));
+        }
+        Ok(Writing(self.path.clone()))
+    }
+
+    /// Appends `body`, which has to pick up at `offset`, where the upload is at. Returns where
+    /// it's at after.
+    pub async fn append(
+        &self,
+        _: &Writing,
+        offset: u64,
+        body: impl AsyncRead + Unpin,
+    ) -> tide::Result<u64> {
+        let at = self.offset().await?;
+        if offset != at {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("the upload is at {at}, not {offset}"),
+            ));
+        }
+        match storage::append_upload(&self.path, body, self.length - at).await {
+            Ok(written) => Ok(at + written),
+            Err(UploadError::TooLarge { .. }) => Err(tide::Error::new(
+                StatusCode::PayloadTooLarge,
+                anyhow!("that's more than the {} bytes announced", self.length),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Turns a complete upload into an [Attachment], the same as `uploadAttachment` makes.
+    pub async fn finish(
+        self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        storage: &Storage,
+        owner: &User,
+        _: &Writing,
+    ) -> tide::Result<Attachment> {
+        let at = self.offset().await?;
+        if at != self.length {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("the upload is at {at} of {} bytes", self.length),
+            ));
+        }
+        let finished = storage.finish_upload(&self.path, &self.filename).await;
+        self.clone().cancel(surreal).await?;
+        let (path, mime) = finished?;
+        Attachment {
+            id: None,
+            owner: self.owner,
+            channel: self.channel,
+            filename: self.filename,
+            mime: mime.to_owned(),
+            size: self.length,
+            path,
+            created_at: Datetime(Utc::now()),
+            blocked: false,
+            held: false,
+            alt: self.alt,
+            voice_note: None,
+        }
+        .record(surreal, relay, owner, self.voice)
+        .await
+    }
+
+    /// Drops the session along with whatever arrived.
+    pub async fn cancel(self, surreal: &crate::Surreal) -> tide::Result<()> {
+        storage::remove_upload(&self.path).await?;
+        let _: Option<Self> = surreal.delete(self.record_id().0).await?;
+        Ok(())
+    }
+
+    /// Drops the uploads nobody finished within [Self::EXPIRES_HOURS].
+    pub async fn expire(surreal: &crate::Surreal) -> anyhow::Result<()> {
+        let expired: Vec<Self> = surreal
+            .query("SELECT * FROM upload_session WHERE created_at < $before")
+            .bind((
+                "before",
+                Datetime(Utc::now() - Duration::hours(Self::EXPIRES_HOURS)),
+            ))
+            .await?
+            .take(0)?;
+        for session in expired {
+            let path = session.path.clone();
+            if let Err(e) = session.cancel(surreal).await {
+                warn!("couldn't drop the expired upload {path}: {e}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,21 @@
 use crate::pubsub::Relay;
 use anyhow::anyhow;
-use async_graphql::Enum;
-use serde::{Deserialize, Serialize};
-use surrealdb::sql::Thing;
+use async_graphql::{Enum, InputObject, SimpleObject};
+use itertools::Itertools;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
 use tide::StatusCode;
 
-use crate::util::{referrable, ReferrableExt};
+use crate::{
+    auth::{make_tag, SALT_ROUNDS},
+    federation::Address,
+    util::{
+        query::{self, field, Bound, Q},
+        referrable, save_versioned, Ref, ReferrableExt, Versioned,
+    },
+};
 
-use super::message::{Message, MessageInit};
+use super::message::{Message, MessageInit, MessageRecipientIn, MessageRecipientInKind};
 
 pub type Tag = (String, [i32; 4]);
 
@@ -18,7 +26,9 @@ pub fn parse_tag(tag: &str) -> Option<Tag> {
         .take(4)
         .map(|c| c.to_digit(16).map(|w| w as i32))
         .collect::<Vec<Option<i32>>>();
-    let [x, y, z, w] = discriminator.as_slice() else { unreachable!() };
+    let [x, y, z, w] = discriminator.as_slice() else {
+        return None;
+    };
 
     Some((
         name.to_owned(),
@@ -31,19 +41,108 @@ pub fn parse_tag(tag: &str) -> Option<Tag> {
     ))
 }
 
+fn tag_bound((name, discriminator): &Tag) -> Bound {
+    Bound::List(vec![name.as_str().into(), discriminator.to_vec().into()])
+}
+
+/// What shadow users of remote instances have for a password hash. It isn't a bcrypt hash, so
+/// no password matches it.
+pub const REMOTE_PASSWORD_HASH: &str = "!remote";
+
+/// What anyone may fetch of a user. The record also has `email` and `password_hash`, which
+/// are left out so they can't end up in a response by accident: read them with
+/// [User::email], or the login query.
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
     pub id: Thing,
     pub tag: Tag,
     pub display_name: String,
-    pub email: String,
-    pub password_hash: String,
     #[serde(default)]
     pub badges: Vec<Badge>,
     #[serde(default)]
     pub status: Status,
     #[serde(default)]
-    pub theme: Theme
+    pub theme: Theme,
+    /// The instance this user lives on, `None` for local users.
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub privacy: PrivacySettings,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    /// Accounts nobody logs into, like the ones webhooks post as.
+    #[serde(default)]
+    pub bot: bool,
+    /// The [terms](super::terms::Terms) version the user last accepted.
+    #[serde(default)]
+    pub accepted_terms: Option<u32>,
+    /// `None` for accounts from before this was recorded.
+    #[serde(default)]
+    pub created_at: Option<Datetime>,
+    /// Of the verified phone number, see [crate::phone].
+    #[serde(default)]
+    pub phone_hash: Option<String>,
+    /// Keeps everything they wrote, see [legal_hold](super::legal_hold).
+    #[serde(default)]
+    pub legal_hold: bool,
+    /// When they last used a client, to within [User::LAST_SEEN_MINUTES]. Only ever written by
+    /// [User::touch_last_seen], so saving a user doesn't move it back.
+    #[serde(default, skip_serializing)]
+    pub last_seen_at: Option<Datetime>,
+    /// How often the user was saved, see [Versioned].
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// What a user shares with people who aren't logged in.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[serde(default)]
+#[graphql(input_name = "PrivacySettingsInput")]
+pub struct PrivacySettings {
+    /// Whether the profile can be fetched from `/api/v1/users/:tag/profile` at all.
+    pub public_profile: bool,
+    pub show_badges: bool,
+    pub show_bio: bool,
+    /// Whether others see when they were last around.
+    pub show_last_seen: bool,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            public_profile: true,
+            show_badges: true,
+            show_bio: true,
+            show_last_seen: true,
+        }
+    }
+}
+
+/// Which notifications a user wants besides the in-app ones.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject, InputObject)]
+#[serde(default)]
+#[graphql(input_name = "NotificationSettingsInput")]
+pub struct NotificationSettings {
+    /// Email about logins from new devices.
+    pub login_emails: bool,
+    /// Email a summary of unread DMs and mentions once away for `digest_after_hours`.
+    pub digest: bool,
+    pub digest_after_hours: u32,
+    /// Quote the messages in digests instead of only counting them.
+    pub digest_previews: bool,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            login_emails: true,
+            digest: false,
+            digest_after_hours: 24,
+            digest_previews: false,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, Enum, PartialEq, Eq, Default)]
@@ -54,6 +153,11 @@ pub enum Theme {
 }
 
 impl User {
+    pub const MAX_BIO_LENGTH: usize = 190;
+    pub const MAX_NAME_LENGTH: usize = 32;
+    /// How stale `last_seen_at` may get, so busy clients don't write on every request.
+    pub const LAST_SEEN_MINUTES: i64 = 5;
+
     pub fn tag_fmt(&self) -> String {
         let [x, y, z, w] = self.tag.1;
         format!("{}#{x:x}{y:x}{z:x}{w:x}", self.tag.0)
@@ -79,14 +183,43 @@ pub enum Badge {
 
 referrable!(User = "user" .id: Thing);
 
+/// Someone on a user's friends list, and since when. `None` for friendships from before that
+/// was recorded.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Friend<U = User> {
+    pub user: U,
+    pub friended_at: Option<Datetime>,
+    /// Whichever of the two added the other.
+    pub requester: Ref<User>,
+}
+
+/// How someone is related to a user. Friends are all there is for now, adding someone
+/// befriends them straight away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum RelationshipKind {
+    Friend,
+}
+
+impl Versioned for User {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+}
+
+impl ReferrableExt for User {
+    /// Merges instead, to keep the fields [User] doesn't have.
+    async fn save(&self, surreal: &crate::Surreal) -> tide::Result<Self> {
+        save_versioned(surreal, self, true).await
+    }
+}
+
 impl User {
     pub async fn add_friend(&self, surreal: &crate::Surreal, other: User) -> tide::Result<Self> {
-        if self
-            .get_friends(surreal)
-            .await?
-            .iter()
-            .any(|a| a.id == other.id)
-        {
+        if self.friend_refs(surreal).await?.contains(&other.refer()) {
             return Err(tide::Error::new(
                 StatusCode::Conflict,
                 anyhow!("already friends"),
@@ -100,43 +233,189 @@ impl User {
             .await?;
         Ok(other)
     }
-    pub async fn get_friends(&self, surreal: &crate::Surreal) -> tide::Result<Vec<User>> {
-        #[derive(serde::Deserialize)]
-        struct Friends {
-            friends_direct: Vec<User>,
-            friends_back: Vec<User>,
+    /// Everyone `self` is friends with, whichever of them asked, earliest friends first.
+    pub async fn get_friends(&self, surreal: &crate::Surreal) -> surrealdb::Result<Vec<Friend>> {
+        self.friendships(surreal, "FETCH user").await
+    }
+
+    /// [Self::get_friends] without fetching them, for when only who they are matters.
+    pub async fn friend_refs(&self, surreal: &crate::Surreal) -> surrealdb::Result<Vec<Ref<User>>> {
+        let friends = self.friendships(surreal, "").await?;
+        Ok(friends.into_iter().map(|friend| friend.user).collect())
+    }
+
+    async fn friendships<U: DeserializeOwned>(
+        &self,
+        surreal: &crate::Surreal,
+        fetch: &str,
+    ) -> surrealdb::Result<Vec<Friend<U>>> {
+        #[derive(Deserialize)]
+        struct Row<U> {
+            other: Thing,
+            user: U,
+            friended_at: Option<Datetime>,
+            requester: Ref<User>,
         }
 
-        let friends: Option<Friends> = surreal
+        query::count_query();
+        let rows: Vec<Row<U>> = surreal
             .query(format!(
-                "select ->friends->user.* as friends_direct, <-friends<-user.* as friends_back from {};",
-                self.id
+                "SELECT (IF in = $user THEN out ELSE in END) AS other, \
+                 (IF in = $user THEN out ELSE in END) AS user, time.friended AS friended_at, \
+                 in AS requester FROM friends WHERE (in = $user OR out = $user) AND in != out \
+                 ORDER BY friended_at {fetch}"
             ))
+            .bind(("user", self.record_id()))
             .await?
-            .check()?
             .take(0)?;
-        let friends = friends.unwrap();
-        let Friends {
-            mut friends_direct,
-            friends_back,
-        } = friends;
-        friends_direct.extend(friends_back);
-        friends_direct.retain(|a| a.id != self.id);
+        // asking each other makes two edges
+        Ok(rows
+            .into_iter()
+            .unique_by(|row| row.other.clone())
+            .map(|row| Friend {
+                user: row.user,
+                friended_at: row.friended_at,
+                requester: row.requester,
+            })
+            .collect())
+    }
 
-        Ok(friends_direct)
+    pub async fn touch_last_seen(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<()> {
+        surreal
+            .query("UPDATE $user SET last_seen_at = time::now()")
+            .bind(("user", user.record_id()))
+            .await?
+            .check()?;
+        Ok(())
     }
 
-    pub async fn find_tag(
+    /// `None` for accounts without one, like remote users and bots.
+    pub async fn email(
         surreal: &crate::Surreal,
-        (name, [x, y, z, w]): &Tag,
-    ) -> tide::Result<Option<Self>> {
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Option<String>> {
+        let email: Option<String> = surreal
+            .query("SELECT VALUE email FROM $user")
+            .bind(("user", user.record_id()))
+            .await?
+            .take(0)?;
+        Ok(email.filter(|email| !email.is_empty()))
+    }
+
+    pub async fn find_tag(surreal: &crate::Surreal, tag: &Tag) -> tide::Result<Option<Self>> {
+        Ok(Q::select::<Self>()
+            .filter(field("tag").eq(tag_bound(tag)))
+            .filter(field("domain").is_none())
+            .one(surreal)
+            .await?)
+    }
+
+    /// Finds or creates the local shadow record of a user living on another instance. It has
+    /// no email and [REMOTE_PASSWORD_HASH], so nobody can log in as it.
+    pub async fn remote(surreal: &crate::Surreal, address: &Address) -> tide::Result<Self> {
+        let found = Q::select::<Self>()
+            .filter(field("tag").eq(tag_bound(&address.tag)))
+            .filter(field("domain").eq(address.domain.as_deref().unwrap_or_default()))
+            .one(surreal)
+            .await?;
+        if let Some(user) = found {
+            return Ok(user);
+        }
+
         let user: Option<Self> = surreal
-            .query(format!(
-                "SELECT * FROM user WHERE tag = ['{name}', [{x}, {y}, {z}, {w}]]"
-            ))
+            .query(
+                "CREATE user SET tag = $tag, domain = $domain, display_name = $name, \
+                password_hash = $password_hash",
+            )
+            .bind(("tag", &address.tag))
+            .bind(("password_hash", REMOTE_PASSWORD_HASH))
+            .bind(("domain", &address.domain))
+            .bind(("name", &address.tag.0))
+            .await?
+            .take(0)?;
+        Ok(user.ok_or_else(|| anyhow!("remote user no makey"))?)
+    }
+
+    /// Creates an account to log in to with `email` and `password`. Whether it may be created
+    /// is up to the caller.
+    pub async fn with_password(
+        surreal: &crate::Surreal,
+        email: &str,
+        password: &str,
+        tag: &str,
+        display_name: &str,
+    ) -> tide::Result<Self> {
+        let password_hash = bcrypt::hash(password.as_bytes(), SALT_ROUNDS)?;
+        let discriminator = make_tag(surreal, tag).await?;
+        let user: Option<Self> = surreal
+            .query(
+                "CREATE user SET email = $email, password_hash = $password_hash, \
+                tag = [$tag, $discriminator], display_name = $display_name, \
+                created_at = time::now()",
+            )
+            .bind(("email", email))
+            .bind(("password_hash", password_hash))
+            .bind(("tag", tag))
+            .bind(("discriminator", discriminator))
+            .bind(("display_name", display_name))
             .await?
+            .check()?
             .take(0)?;
-        Ok(user)
+        Ok(user.ok_or_else(|| anyhow!("user no makey???"))?)
+    }
+
+    /// Creates a bot account for an integration to post as, like a webhook or a feed.
+    pub async fn bot(surreal: &crate::Surreal, name: &str) -> tide::Result<Self> {
+        let discriminator = make_tag(surreal, name).await?;
+        let user: Option<Self> = surreal
+            .query("CREATE user SET tag = [$name, $discriminator], display_name = $name, email = '', password_hash = '', bot = true")
+            .bind(("name", name))
+            .bind(("discriminator", discriminator))
+            .await?
+            .take(0)?;
+        Ok(user.ok_or_else(|| anyhow!("bot user no makey"))?)
+    }
+
+    /// The account notices (moderation, security...) are sent from, created on first use.
+    pub async fn system(surreal: &crate::Surreal) -> tide::Result<Self> {
+        let existing: Option<Self> = surreal.select((Self::TABLE, "system")).await?;
+        if let Some(system) = existing {
+            return Ok(system);
+        }
+        let system: Option<Self> = surreal
+            .query("CREATE user:system SET tag = ['System', [0, 0, 0, 0]], display_name = 'System', email = '', password_hash = '', badges = ['admin']")
+            .await?
+            .take(0)?;
+        Ok(system.ok_or_else(|| anyhow!("system user no makey"))?)
+    }
+
+    /// DMs `to` from the [system](Self::system) account.
+    pub async fn send_system_message(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        to: &Ref<User>,
+        content: String,
+    ) -> tide::Result<Message> {
+        Self::system(surreal)
+            .await?
+            .send_message(
+                surreal,
+                relay,
+                MessageInit {
+                    recipient: MessageRecipientIn {
+                        kind: MessageRecipientInKind::User,
+                        id: to.record_id(),
+                    },
+                    content,
+                    reference: None,
+                    tts: false,
+                    gif: None,
+                },
+            )
+            .await
     }
 
     pub async fn send_message(
@@ -0,0 +1,76 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::util::{referrable, Ref, Referrable};
+
+use super::user::User;
+
+/// What `owner` wrote down about `subject`, for nobody's eyes but their own.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UserNote {
+    pub id: Thing,
+    pub owner: Ref<User>,
+    pub subject: Ref<User>,
+    pub note: String,
+    pub updated_at: Datetime,
+}
+
+referrable!(UserNote = "user_note" .id: Thing);
+
+impl UserNote {
+    pub const MAX_LENGTH: usize = 256;
+
+    /// One note per owner and subject.
+    fn key(owner: &Ref<User>, subject: &Ref<User>) -> Thing {
+        Thing::from((
+            Self::TABLE.to_owned(),
+            format!("{}-{}", owner.id(), subject.id()),
+        ))
+    }
+
+    pub async fn get(
+        surreal: &crate::Surreal,
+        owner: &Ref<User>,
+        subject: &Ref<User>,
+    ) -> surrealdb::Result<Option<UserNote>> {
+        surreal.select(Self::key(owner, subject)).await
+    }
+
+    /// Blank notes remove it.
+    pub async fn set(
+        surreal: &crate::Surreal,
+        owner: &Ref<User>,
+        subject: &Ref<User>,
+        note: Option<String>,
+    ) -> tide::Result<Option<UserNote>> {
+        let key = Self::key(owner, subject);
+        let Some(note) = note
+            .map(|note| note.trim().to_owned())
+            .filter(|note| !note.is_empty())
+        else {
+            let _: Option<UserNote> = surreal.delete(key).await?;
+            return Ok(None);
+        };
+        if note.chars().count() > Self::MAX_LENGTH {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("note is too long"),
+            ));
+        }
+        Ok(Some(
+            surreal
+                .update(key.clone())
+                .content(UserNote {
+                    id: key,
+                    owner: owner.clone(),
+                    subject: subject.clone(),
+                    note,
+                    updated_at: Datetime(Utc::now()),
+                })
+                .await?,
+        ))
+    }
+}
@@ -0,0 +1,109 @@
+use async_graphql::{InputObject, SimpleObject};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+
+use crate::{
+    pubsub::Relay,
+    util::{referrable, Ref, ReferrableWithId},
+};
+
+use super::{
+    guild::{Channel, Guild},
+    user::User,
+};
+
+/// Where a user is connected for voice and what they're doing there. There is at most one
+/// per user, keyed by the user's id.
+#[derive(Deserialize, Serialize, Debug, Clone, SimpleObject)]
+#[graphql(complex)]
+pub struct VoiceState {
+    #[graphql(skip)]
+    pub id: Thing,
+    #[graphql(skip)]
+    pub user: Ref<User>,
+    #[graphql(skip)]
+    pub guild: Ref<Guild>,
+    #[graphql(skip)]
+    pub channel: Ref<Channel>,
+    #[graphql(flatten)]
+    #[serde(flatten)]
+    pub flags: VoiceFlags,
+    /// Set by a moderator, and only a moderator can lift it.
+    #[serde(default)]
+    pub server_mute: bool,
+    /// `false` in the update sent when the user disconnects.
+    #[serde(default = "connected")]
+    pub connected: bool,
+}
+
+fn connected() -> bool {
+    true
+}
+
+/// What the participant controls themselves.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, SimpleObject, InputObject)]
+#[serde(default)]
+#[graphql(input_name = "VoiceFlagsInput")]
+pub struct VoiceFlags {
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub video: bool,
+    pub screen_sharing: bool,
+}
+
+referrable!(VoiceState = "voice_state" .id: Thing);
+
+impl VoiceState {
+    pub async fn find(
+        surreal: &crate::Surreal,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<Option<Self>> {
+        surreal.select((Self::TABLE, user.id())).await
+    }
+
+    /// Connects `user` to `channel`, or updates their flags if they already are. A server
+    /// mute sticks across channels of the same guild, but not to other guilds.
+    pub async fn join(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        user: &Ref<User>,
+        channel: &Channel,
+        flags: VoiceFlags,
+    ) -> surrealdb::Result<Self> {
+        let server_mute = Self::find(surreal, user)
+            .await?
+            .is_some_and(|state| state.server_mute && state.guild == *channel.guild());
+        Self {
+            id: Thing::from((Self::TABLE, user.id())),
+            user: user.clone(),
+            guild: channel.guild().clone(),
+            channel: Ref::new(<Channel as ReferrableWithId>::id(channel)),
+            flags,
+            server_mute,
+            connected: true,
+        }
+        .update(surreal, relay)
+        .await
+    }
+
+    /// Stores the state and tells everyone watching the guild.
+    pub async fn update(self, surreal: &crate::Surreal, relay: &Relay) -> surrealdb::Result<Self> {
+        let state: Self = surreal.update(self.id.clone()).content(self).await?;
+        relay.send_voice_state(&state).await;
+        Ok(state)
+    }
+
+    pub async fn leave(
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        user: &Ref<User>,
+    ) -> surrealdb::Result<bool> {
+        let state: Option<Self> = surreal.delete((Self::TABLE, user.id())).await?;
+        let Some(mut state) = state else {
+            return Ok(false);
+        };
+        state.connected = false;
+        relay.send_voice_state(&state).await;
+        Ok(true)
+    }
+}
@@ -0,0 +1,181 @@
+use anyhow::anyhow;
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::{Datetime, Thing};
+use tide::StatusCode;
+
+use crate::{
+    pubsub::Relay,
+    util::{random_string, referrable, secure_eq, Ref, Referrable, ReferrableExt},
+};
+
+use super::{
+    guild::{Guild, TextChannel, TextableChannel},
+    message::{
+        AuthorOverride, Embed, Message, MessageInit, MessageRecipientIn, MessageRecipientInKind,
+    },
+    name_rule::NameRule,
+    user::User,
+};
+
+/// Lets outside services post into a channel with a secret URL, `/webhooks/<id>/<token>`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Webhook {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Thing>,
+    pub guild: Ref<Guild>,
+    pub channel: Ref<TextableChannel>,
+    pub name: String,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    /// The bot account the webhook's messages are sent as.
+    pub user: Ref<User>,
+    pub created_by: Ref<User>,
+    pub secret_hash: String,
+    pub created_at: Datetime,
+}
+
+referrable!(Webhook = "webhook" .id: Option<Thing>);
+
+#[derive(Debug, Clone, SimpleObject)]
+pub struct CreatedWebhook {
+    pub webhook: Webhook,
+    /// The URL to post to. Only ever shown here.
+    pub url: String,
+}
+
+/// What gets posted to a webhook.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct WebhookPayload {
+    #[serde(default)]
+    pub content: String,
+    /// Shown instead of the webhook's name on this message.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Shown instead of the webhook's avatar on this message.
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha1::digest(secret.as_bytes()))
+}
+
+pub(crate) fn check_avatar_url(url: &Option<String>) -> tide::Result<()> {
+    if url
+        .as_ref()
+        .is_some_and(|url| !url.starts_with("https://") && !url.starts_with("http://"))
+    {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("avatar urls must be http(s)"),
+        ));
+    }
+    Ok(())
+}
+
+impl Webhook {
+    pub const PATH: &'static str = "/webhooks";
+
+    /// Creates the webhook and its bot account, returning it along with its URL.
+    pub async fn create(
+        surreal: &crate::Surreal,
+        creator: &Ref<User>,
+        channel: &TextChannel,
+        name: String,
+        avatar_url: Option<String>,
+    ) -> tide::Result<CreatedWebhook> {
+        NameRule::check(surreal, &name).await?;
+        check_avatar_url(&avatar_url)?;
+        let user = User::bot(surreal, &name).await?;
+
+        let secret = random_string(40);
+        let webhook: Webhook = surreal
+            .create(Self::TABLE)
+            .content(Webhook {
+                id: None,
+                guild: channel.guild.clone(),
+                channel: Ref::new_owned(channel.id.id.to_raw()),
+                name,
+                avatar_url,
+                user: user.refer(),
+                created_by: creator.clone(),
+                secret_hash: hash(&secret),
+                created_at: Datetime(Utc::now()),
+            })
+            .await?;
+        let url = format!("{}/{}/{secret}", Self::PATH, webhook.refer().id());
+        Ok(CreatedWebhook { webhook, url })
+    }
+
+    pub async fn authenticate(
+        surreal: &crate::Surreal,
+        id: &str,
+        secret: &str,
+    ) -> surrealdb::Result<Option<Webhook>> {
+        let webhook: Option<Self> = surreal.select((Self::TABLE, id)).await?;
+        Ok(webhook.filter(|webhook| secure_eq(&webhook.secret_hash, &hash(secret))))
+    }
+
+    /// Posts `payload` to the webhook's channel, branded with the overrides it carries or
+    /// else the webhook's own name and avatar.
+    pub async fn execute(
+        &self,
+        surreal: &crate::Surreal,
+        relay: &Relay,
+        payload: WebhookPayload,
+    ) -> tide::Result<Message> {
+        let WebhookPayload {
+            content,
+            username,
+            avatar_url,
+            embeds,
+        } = payload;
+        if content.trim().is_empty() && embeds.is_empty() {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("content is empty"),
+            ));
+        }
+        if embeds.len() > Embed::MAX_PER_MESSAGE {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("at most {} embeds per message", Embed::MAX_PER_MESSAGE),
+            ));
+        }
+        if let Some(ref username) = username {
+            if username.chars().count() > User::MAX_NAME_LENGTH {
+                return Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!("username is too long"),
+                ));
+            }
+            NameRule::check(surreal, username).await?;
+        }
+        check_avatar_url(&avatar_url)?;
+
+        let author = self.user.fetch(surreal).await?;
+        let init = MessageInit {
+            recipient: MessageRecipientIn {
+                kind: MessageRecipientInKind::Channel,
+                id: self.channel.record_id(),
+            },
+            content,
+            reference: None,
+            tts: false,
+            gif: None,
+        };
+        let author_override = AuthorOverride {
+            name: username.unwrap_or_else(|| self.name.clone()),
+            avatar_url: avatar_url.or_else(|| self.avatar_url.clone()),
+        };
+        let message =
+            Message::create_with(surreal, &author, init, Some(author_override), embeds).await?;
+        relay.send_message(&message).await;
+        Ok(message)
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::sql::Datetime;
+use tide::{http::mime::JSON, log::info, Body, Request, Response, StatusCode};
+
+use crate::{
+    auth::{self, Claims_, JwtKind},
+    config::CONFIG,
+    http::HttpState as State,
+    model::{
+        oauth::{AuthorizationCode, OAuthApp},
+        token::{ApiToken, ApiTokenAuth, Scope},
+        user::User,
+    },
+    tenant,
+    util::{random_string, secure_eq, Ref, Referrable, ReferrableExt, ReferrableWithId},
+};
+
+/// Scopes that only shape the ID token; everything else is a [Scope] on the access token.
+const OIDC_SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+fn valid_scope(scope: &str) -> bool {
+    OIDC_SCOPES.contains(&scope) || Scope::parse(scope).is_some()
+}
+
+fn issuer() -> String {
+    format!("https://{}", CONFIG.domain)
+}
+
+pub fn routes(tide: &mut tide::Server<State>) {
+    tide.at("/.well-known/openid-configuration")
+        .get(http_discovery);
+    tide.at("/oauth/consent").get(http_consent);
+    tide.at("/oauth/authorize")
+        .with(auth::make_tide_authware())
+        .post(http_authorize);
+    tide.at("/oauth/token").post(http_token);
+    tide.at("/oauth/userinfo")
+        .with(auth::make_tide_authware())
+        .get(http_userinfo);
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> tide::Result {
+    Ok(Response::builder(status)
+        .body(Body::from_json(body)?)
+        .content_type(JSON)
+        .build())
+}
+
+/// OAuth2 style error responses, `{"error": "invalid_grant"}`.
+fn oauth_error(status: StatusCode, error: &str) -> tide::Result {
+    json_response(status, &json!({ "error": error }))
+}
+
+async fn http_discovery(_: Request<State>) -> tide::Result {
+    let issuer = issuer();
+    json_response(
+        StatusCode::Ok,
+        &json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/oauth/authorize"),
+            "token_endpoint": format!("{issuer}/oauth/token"),
+            "userinfo_endpoint": format!("{issuer}/oauth/userinfo"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["HS256"],
+            "token_endpoint_auth_methods_supported": ["client_secret_post"],
+            "scopes_supported": OIDC_SCOPES
+                .into_iter()
+                .chain(Scope::ALL.iter().map(Scope::name))
+                .collect::<Vec<_>>(),
+            "claims_supported": ["sub", "name", "preferred_username", "email", "nonce"],
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct ConsentQuery {
+    client_id: String,
+    scope: String,
+}
+
+/// What the consent screen needs to render: who is asking, and for what.
+async fn http_consent(request: Request<State>) -> tide::Result {
+    let ConsentQuery { client_id, scope } = request.query()?;
+    let app: Option<OAuthApp> = tenant::of(&request)?
+        .surreal()
+        .select((OAuthApp::TABLE, client_id.as_str()))
+        .await?;
+    let Some(app) = app else {
+        return oauth_error(StatusCode::NotFound, "invalid_client");
+    };
+    if !scope.split_whitespace().all(valid_scope) {
+        return oauth_error(StatusCode::BadRequest, "invalid_scope");
+    }
+
+    let scopes: Vec<_> = scope
+        .split_whitespace()
+        .filter_map(Scope::parse)
+        .map(|scope| json!({ "scope": scope.name(), "description": scope.describe() }))
+        .collect();
+    json_response(
+        StatusCode::Ok,
+        &json!({
+            "app": app.name,
+            "signs_in": scope.split_whitespace().any(|s| s == "openid"),
+            "scopes": scopes,
+        }),
+    )
+}
+
+#[derive(Deserialize)]
+struct AuthorizeRequest {
+    client_id: String,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+}
+
+/// Called by our own client once the logged-in user consented on the consent screen.
+/// Responds with the URI to send the user back to, carrying the authorization code.
+async fn http_authorize(mut request: Request<State>) -> tide::Result {
+    let uid = request
+        .ext::<Claims_>()
+        .filter(|claims| matches!(claims.sub, JwtKind::Access))
+        .map(|claims| claims.claims.uid.clone());
+    let Some(uid) = uid else {
+        return Ok(Response::new(StatusCode::Unauthorized));
+    };
+    let AuthorizeRequest {
+        client_id,
+        redirect_uri,
+        scope,
+        state,
+        nonce,
+    } = request.body_json().await?;
+
+    let app: Option<OAuthApp> = tenant::of(&request)?
+        .surreal()
+        .select((OAuthApp::TABLE, client_id.as_str()))
+        .await?;
+    let Some(app) = app.filter(|app| app.redirect_uris.contains(&redirect_uri)) else {
+        return oauth_error(StatusCode::BadRequest, "invalid_client");
+    };
+    let scope: Vec<String> = scope.split_whitespace().map(str::to_owned).collect();
+    if scope.is_empty() || !scope.iter().all(|s| valid_scope(s)) {
+        return oauth_error(StatusCode::BadRequest, "invalid_scope");
+    }
+
+    let code = random_string(32);
+    let _: AuthorizationCode = tenant::of(&request)?
+        .surreal()
+        .create(("oauth_code", code.as_str()))
+        .content(AuthorizationCode {
+            id: None,
+            app: app.refer(),
+            user: Ref::try_from(uid)?,
+            redirect_uri: redirect_uri.clone(),
+            scope,
+            nonce,
+            issued_at: Datetime(Utc::now()),
+        })
+        .await?;
+
+    let mut redirect_to = tide::http::Url::parse(&redirect_uri)?;
+    redirect_to.query_pairs_mut().append_pair("code", &code);
+    if let Some(state) = state {
+        redirect_to.query_pairs_mut().append_pair("state", &state);
+    }
+
+    json_response(
+        StatusCode::Ok,
+        &json!({ "redirect_to": redirect_to.to_string() }),
+    )
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    client_secret: String,
+}
+
+#[derive(Serialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: i64,
+    iat: i64,
+    jti: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+}
+
+async fn http_token(mut request: Request<State>) -> tide::Result {
+    let TokenRequest {
+        grant_type,
+        code,
+        redirect_uri,
+        client_id,
+        client_secret,
+    } = request.body_form().await?;
+    if grant_type != "authorization_code" {
+        return oauth_error(StatusCode::BadRequest, "unsupported_grant_type");
+    }
+
+    let state = tenant::of(&request)?;
+    let app: Option<OAuthApp> = state
+        .surreal()
+        .select((OAuthApp::TABLE, client_id.as_str()))
+        .await?;
+    let Some(app) = app.filter(|app| secure_eq(&app.secret, &client_secret)) else {
+        return oauth_error(StatusCode::Unauthorized, "invalid_client");
+    };
+
+    // codes are single use, whatever happens next
+    let code: Option<AuthorizationCode> = state
+        .surreal()
+        .delete(("oauth_code", code.as_str()))
+        .await?;
+    let Some(code) = code.filter(|code| {
+        code.app == app.refer()
+            && code.redirect_uri == redirect_uri
+            && Utc::now() < code.issued_at.0 + Duration::minutes(5)
+    }) else {
+        return oauth_error(StatusCode::BadRequest, "invalid_grant");
+    };
+
+    let user = code.user.fetch(state.surreal()).await?;
+    let has = |scope: &str| code.scope.iter().any(|s| s == scope);
+    let mut scopes: Vec<Scope> = code.scope.iter().filter_map(|s| Scope::parse(s)).collect();
+    if has("openid") && !scopes.contains(&Scope::Identify) {
+        scopes.push(Scope::Identify);
+    }
+    let (_, access_token) = ApiToken::create(
+        state.surreal(),
+        &user,
+        app.name.clone(),
+        scopes,
+        Some(app.refer()),
+    )
+    .await?;
+
+    info!("granted {} access to {}", app.name, user.tag_fmt());
+    if !has("openid") {
+        return json_response(
+            StatusCode::Ok,
+            &json!({
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "scope": code.scope.join(" "),
+            }),
+        );
+    }
+
+    let email = if has("email") {
+        User::email(state.surreal(), &code.user).await?
+    } else {
+        None
+    };
+    let (jti, iat) = JwtKind::Id.record(state, user.record_id(), None).await?;
+    let claims = IdClaims {
+        iss: issuer(),
+        sub: <User as ReferrableWithId>::id(&user).clone(),
+        aud: client_id,
+        exp: (iat + JwtKind::Id.expiry()).timestamp(),
+        iat: iat.timestamp(),
+        jti: jti.id(),
+        nonce: code.nonce,
+        name: has("profile").then(|| user.display_name.clone()),
+        preferred_username: has("profile").then(|| user.tag_fmt()),
+        email,
+    };
+    let id_token = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(app.secret.as_bytes()),
+    )?;
+
+    json_response(
+        StatusCode::Ok,
+        &json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "scope": code.scope.join(" "),
+            "id_token": id_token,
+        }),
+    )
+}
+
+async fn http_userinfo(request: Request<State>) -> tide::Result {
+    let uid = match request.ext::<ApiTokenAuth>() {
+        Some(api) if !api.scopes.contains(&Scope::Identify) => {
+            return oauth_error(StatusCode::Forbidden, "insufficient_scope");
+        }
+        Some(api) => Some(api.uid.clone()),
+        None => request
+            .ext::<Claims_>()
+            .map(|claims| claims.claims.uid.clone()),
+    };
+    let Some(uid) = uid else {
+        return Ok(Response::new(StatusCode::Unauthorized));
+    };
+    let user: Option<User> = uid.fetch(tenant::of(&request)?.surreal()).await?;
+    let user = user.ok_or_else(|| tide::Error::new(StatusCode::NotFound, anyhow!("no user")))?;
+
+    json_response(
+        StatusCode::Ok,
+        &json!({
+            "sub": uid.id(),
+            "name": user.display_name,
+            "preferred_username": user.tag_fmt(),
+        }),
+    )
+}
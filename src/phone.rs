@@ -0,0 +1,283 @@
+//! Phone verification, for guilds with
+//! [JoinConstraint::Phone](crate::model::guild::JoinConstraint::Phone). The provider sends
+//! the code by SMS and checks it, this only keeps an HMAC of the number on the user, so the
+//! same number can't verify a second account and a leaked database doesn't leak numbers.
+//! Deployments pick a provider with `NETHERITE_CHAT_PHONE_VERIFICATION`, there is none by
+//! default.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use surrealdb::sql::Datetime;
+use tide::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    model::user::User,
+    util::{Ref, ReferrableExt},
+};
+
+/// How long to wait before sending another code.
+const RESEND_SECONDS: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhoneVerification {
+    None,
+    Twilio,
+}
+
+impl FromStr for PhoneVerification {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "twilio" => Ok(Self::Twilio),
+            _ => Err(anyhow!("unknown phone verification provider {s}")),
+        }
+    }
+}
+
+#[async_trait]
+pub trait SmsVerifier: Send + Sync {
+    /// Texts a code to `phone`, in E.164.
+    async fn start(&self, phone: &str) -> tide::Result<()>;
+    /// Whether `code` is the one last sent to `phone`.
+    async fn check(&self, phone: &str, code: &str) -> tide::Result<bool>;
+}
+
+/// Twilio Verify, which makes up the codes and keeps track of them itself.
+pub struct Twilio {
+    pub account_sid: String,
+    pub auth_token: String,
+    /// The Verify service, `VA…`.
+    pub service_sid: String,
+}
+
+impl Twilio {
+    fn post(&self, path: &str) -> surf::RequestBuilder {
+        let credentials = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", self.account_sid, self.auth_token));
+        surf::post(format!(
+            "https://verify.twilio.com/v2/Services/{}/{path}",
+            self.service_sid
+        ))
+        .header("Authorization", format!("Basic {credentials}"))
+    }
+}
+
+#[async_trait]
+impl SmsVerifier for Twilio {
+    async fn start(&self, phone: &str) -> tide::Result<()> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Start<'a> {
+            to: &'a str,
+            channel: &'static str,
+        }
+
+        let response = self
+            .post("Verifications")
+            .body_form(&Start {
+                to: phone,
+                channel: "sms",
+            })?
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::TooManyRequests => Err(tide::Error::new(
+                StatusCode::TooManyRequests,
+                anyhow!("too many codes were sent to this number, try again later"),
+            )),
+            StatusCode::BadRequest => Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("can't send a code to this number"),
+            )),
+            status => Err(anyhow!("Twilio answered {status}").into()),
+        }
+    }
+
+    async fn check(&self, phone: &str, code: &str) -> tide::Result<bool> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Check<'a> {
+            to: &'a str,
+            code: &'a str,
+        }
+        #[derive(Deserialize)]
+        struct Checked {
+            status: String,
+        }
+
+        let mut response = self
+            .post("VerificationCheck")
+            .body_form(&Check { to: phone, code })?
+            .await?;
+        match response.status() {
+            status if status.is_success() => {
+                let checked: Checked = response.body_json().await?;
+                Ok(checked.status == "approved")
+            }
+            // expired, or too many wrong codes
+            StatusCode::NotFound => Ok(false),
+            status => Err(anyhow!("Twilio answered {status}").into()),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// `None` unless a provider is configured, with what it needs and a key to hash numbers
+    /// with.
+    pub static ref VERIFIER: Option<Box<dyn SmsVerifier>> = match CONFIG.phone_verification {
+        _ if CONFIG.phone_hash_key.is_none() => None,
+        PhoneVerification::None => None,
+        PhoneVerification::Twilio => match (
+            &CONFIG.twilio_account_sid,
+            &CONFIG.twilio_auth_token,
+            &CONFIG.twilio_verify_service,
+        ) {
+            (Some(account_sid), Some(auth_token), Some(service_sid)) => Some(Box::new(Twilio {
+                account_sid: account_sid.clone(),
+                auth_token: auth_token.clone(),
+                service_sid: service_sid.clone(),
+            }) as Box<dyn SmsVerifier>),
+            _ => None,
+        },
+    };
+}
+
+fn provider() -> tide::Result<&'static dyn SmsVerifier> {
+    VERIFIER.as_deref().ok_or_else(|| {
+        tide::Error::new(
+            StatusCode::NotImplemented,
+            anyhow!("phone verification isn't set up on this instance"),
+        )
+    })
+}
+
+/// `phone` in E.164, like `+14155550123`, without the spaces, dashes and parentheses people
+/// write numbers with.
+pub fn normalize(phone: &str) -> tide::Result<String> {
+    let phone: String = phone
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '(' | ')' | '.'))
+        .collect();
+    let valid = phone.strip_prefix('+').is_some_and(|digits| {
+        (8..=15).contains(&digits.len())
+            && digits.chars().all(|c| c.is_ascii_digit())
+            && !digits.starts_with('0')
+    });
+    if !valid {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("phone numbers start with + and the country code"),
+        ));
+    }
+    Ok(phone)
+}
+
+/// What's kept of a number.
+fn hash(phone: &str) -> String {
+    let key = CONFIG.phone_hash_key.as_deref().unwrap_or_default();
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(key.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(phone.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// A code on its way, `phone_verification:⟨<user>⟩`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Started {
+    user: Ref<User>,
+    phone: String,
+    at: Datetime,
+}
+
+/// Another account verified with `phone` already.
+async fn taken(surreal: &crate::Surreal, user: &Ref<User>, phone: &str) -> tide::Result<bool> {
+    let other: Option<Ref<User>> = surreal
+        .query("SELECT VALUE id FROM user WHERE phone_hash = $phone AND id != $user LIMIT 1")
+        .bind(("phone", phone))
+        .bind(("user", user.record_id()))
+        .await?
+        .take(0)?;
+    Ok(other.is_some())
+}
+
+/// Texts a code to `phone` for [confirm].
+pub async fn start(surreal: &crate::Surreal, user: &Ref<User>, phone: &str) -> tide::Result<()> {
+    let verifier = provider()?;
+    let phone = normalize(phone)?;
+    let hashed = hash(&phone);
+    // whether another account has the number is only told in [confirm], to whoever owns it,
+    // or this would look up anyone's number for the price of a request
+    let id = ("phone_verification", user.id().to_owned());
+    let started: Option<Started> = surreal.select(id.clone()).await?;
+    if let Some(started) = started {
+        let wait = started.at.0 + Duration::seconds(RESEND_SECONDS) - Utc::now();
+        if wait > Duration::zero() {
+            return Err(tide::Error::new(
+                StatusCode::TooManyRequests,
+                anyhow!(
+                    "wait {} seconds before sending another code",
+                    wait.num_seconds() + 1
+                ),
+            ));
+        }
+    }
+
+    verifier.start(&phone).await?;
+    let _: Option<Started> = surreal
+        .update(id)
+        .content(Started {
+            user: user.clone(),
+            phone: hashed,
+            at: Datetime(Utc::now()),
+        })
+        .await?;
+    Ok(())
+}
+
+/// Verifies `user`'s phone with the `code` [start] sent to it.
+pub async fn confirm(
+    surreal: &crate::Surreal,
+    user: &Ref<User>,
+    phone: &str,
+    code: &str,
+) -> tide::Result<User> {
+    let verifier = provider()?;
+    let phone = normalize(phone)?;
+    let hashed = hash(&phone);
+    let id = ("phone_verification", user.id().to_owned());
+    let started: Option<Started> = surreal.select(id.clone()).await?;
+    if !started.is_some_and(|started| started.phone == hashed) {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("no code was sent to this number, start over"),
+        ));
+    }
+    if !verifier.check(&phone, code.trim()).await? {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("wrong or expired code"),
+        ));
+    }
+    // checked only now that the code proved `phone` is theirs
+    if taken(surreal, user, &hashed).await? {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow!("this number verified another account already"),
+        ));
+    }
+
+    let _: Option<Started> = surreal.delete(id).await?;
+    let mut user = user.fetch(surreal).await?;
+    user.phone_hash = Some(hashed);
+    Ok(user.save(surreal).await?)
+}
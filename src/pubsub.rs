@@ -1,28 +1,427 @@
-use async_std::{sync::RwLock, stream::Stream};
-use flo_stream::{Publisher, MessagePublisher};
+//! Publishing events from resolvers, jobs and webhooks to subscriptions.
+//!
+//! Voice states go to every subscriber, who filter them. Messages are [fanned out](Fanout)
+//! instead: a channel with thousands of members on one node would otherwise run thousands of
+//! filters per message.
 
-use crate::model::message::Message;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
 
-struct RelayInfo {
-    pub sent_messages: RwLock<Publisher<Message>>
+use async_std::{
+    channel::{self, Receiver, Sender},
+    stream::Stream,
+    sync::RwLock,
+};
+use chrono::{DateTime, Utc};
+use flo_stream::{MessagePublisher, Publisher, Subscriber};
+use futures_util::StreamExt;
+use serde::Serialize;
+use surrealdb::sql::Thing;
+
+use crate::{
+    model::{guild::Guild, message::Message, read::ReadState, user::User, voice::VoiceState},
+    util::Ref,
+};
+
+/// How many items a subscriber may fall behind before publishing waits for it.
+const BUFFER: usize = 30;
+
+/// What a subscriber looks like to the `/debug/relay` view.
+struct SubscriberStats {
+    id: u64,
+    since: DateTime<Utc>,
+    /// How many had been published when it subscribed.
+    offset: u64,
+    received: AtomicU64,
+}
+
+type Subscribers = Arc<Mutex<HashMap<u64, Arc<SubscriberStats>>>>;
+
+/// One kind of thing that gets published, with the numbers the metrics need.
+struct Topic<T: Clone> {
+    name: &'static str,
+    publisher: RwLock<Publisher<T>>,
+    published: AtomicU64,
+    publish_nanos: AtomicU64,
+    subscribers: Subscribers,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LaggingSubscriber {
+    pub id: u64,
+    pub since: DateTime<Utc>,
+    /// Published but not yet taken by the subscriber.
+    pub behind: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopicStats {
+    pub topic: &'static str,
+    pub subscribers: usize,
+    pub published: u64,
+    /// Total time spent publishing, which includes waiting for slow subscribers.
+    pub publish_seconds: f64,
+    /// Subscribers at least half a buffer behind, about to hold up publishing.
+    pub lagging: Vec<LaggingSubscriber>,
+}
+
+impl<T: Clone + Send + 'static> Topic<T> {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            publisher: RwLock::new(Publisher::new(BUFFER)),
+            published: AtomicU64::new(0),
+            publish_nanos: AtomicU64::new(0),
+            subscribers: Default::default(),
+        }
+    }
+
+    async fn publish(&self, item: T) {
+        let started = Instant::now();
+        self.publisher.write().await.publish(item).await;
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.publish_nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    async fn subscribe(&self, id: u64) -> Tracked<T> {
+        let inner = self.publisher.write().await.subscribe();
+        let stats = Arc::new(SubscriberStats {
+            id,
+            since: Utc::now(),
+            offset: self.published.load(Ordering::Relaxed),
+            received: AtomicU64::new(0),
+        });
+        self.subscribers.lock().unwrap().insert(id, stats.clone());
+        Tracked {
+            inner,
+            stats,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    fn stats(&self) -> TopicStats {
+        let published = self.published.load(Ordering::Relaxed);
+        let subscribers = self.subscribers.lock().unwrap();
+        let mut lagging: Vec<_> = subscribers
+            .values()
+            .map(|stats| LaggingSubscriber {
+                id: stats.id,
+                since: stats.since,
+                behind: published
+                    .saturating_sub(stats.offset)
+                    .saturating_sub(stats.received.load(Ordering::Relaxed)),
+            })
+            .filter(|subscriber| subscriber.behind >= BUFFER as u64 / 2)
+            .collect();
+        lagging.sort_by_key(|subscriber| std::cmp::Reverse(subscriber.behind));
+        TopicStats {
+            topic: self.name,
+            subscribers: subscribers.len(),
+            published,
+            publish_seconds: self.publish_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+            lagging,
+        }
+    }
+}
+
+/// Delivery by recipient: subscribers register the keys they want, a user for their DMs or
+/// the channels they have open, and publishing only touches the subscribers of one key.
+struct Fanout<T> {
+    name: &'static str,
+    registry: Arc<Mutex<Registry<T>>>,
+    published: AtomicU64,
+    publish_nanos: AtomicU64,
+}
+
+struct Registry<T> {
+    by_key: HashMap<Thing, HashMap<u64, Sender<T>>>,
+    /// Every subscriber once, with when it subscribed.
+    subscribers: HashMap<u64, (DateTime<Utc>, Sender<T>)>,
+}
+
+impl<T: Clone + Send + 'static> Fanout<T> {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            registry: Arc::new(Mutex::new(Registry {
+                by_key: HashMap::new(),
+                subscribers: HashMap::new(),
+            })),
+            published: AtomicU64::new(0),
+            publish_nanos: AtomicU64::new(0),
+        }
+    }
+
+    async fn publish(&self, key: &Thing, item: T) {
+        let started = Instant::now();
+        let senders: Vec<_> = match self.registry.lock().unwrap().by_key.get(key) {
+            Some(subscribers) => subscribers.values().cloned().collect(),
+            None => vec![],
+        };
+        for sender in senders {
+            // a subscription that just went away, nothing to do about it
+            let _ = sender.send(item.clone()).await;
+        }
+        self.published.fetch_add(1, Ordering::Relaxed);
+        self.publish_nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn subscribe(&self, id: u64, keys: Vec<Thing>) -> Delivered<T> {
+        let (sender, receiver) = channel::bounded(BUFFER);
+        let mut registry = self.registry.lock().unwrap();
+        for key in &keys {
+            registry
+                .by_key
+                .entry(key.clone())
+                .or_default()
+                .insert(id, sender.clone());
+        }
+        registry.subscribers.insert(id, (Utc::now(), sender));
+        Delivered {
+            receiver,
+            id,
+            keys,
+            registry: self.registry.clone(),
+        }
+    }
+
+    fn stats(&self) -> TopicStats {
+        let registry = self.registry.lock().unwrap();
+        let mut lagging: Vec<_> = registry
+            .subscribers
+            .iter()
+            .map(|(id, (since, sender))| LaggingSubscriber {
+                id: *id,
+                since: *since,
+                behind: sender.len() as u64,
+            })
+            .filter(|subscriber| subscriber.behind >= BUFFER as u64 / 2)
+            .collect();
+        lagging.sort_by_key(|subscriber| std::cmp::Reverse(subscriber.behind));
+        TopicStats {
+            topic: self.name,
+            subscribers: registry.subscribers.len(),
+            published: self.published.load(Ordering::Relaxed),
+            publish_seconds: self.publish_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+            lagging,
+        }
+    }
+}
+
+/// A [Fanout] subscription, unregistered from all its keys once dropped.
+struct Delivered<T> {
+    receiver: Receiver<T>,
+    id: u64,
+    keys: Vec<Thing>,
+    registry: Arc<Mutex<Registry<T>>>,
+}
+
+impl<T> Stream for Delivered<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl<T> Drop for Delivered<T> {
+    fn drop(&mut self) {
+        let mut registry = self.registry.lock().unwrap();
+        for key in &self.keys {
+            if let Some(subscribers) = registry.by_key.get_mut(key) {
+                subscribers.remove(&self.id);
+                if subscribers.is_empty() {
+                    registry.by_key.remove(key);
+                }
+            }
+        }
+        registry.subscribers.remove(&self.id);
+    }
+}
+
+/// A subscription that counts what it takes, and unregisters itself once dropped.
+struct Tracked<T> {
+    inner: Subscriber<T>,
+    stats: Arc<SubscriberStats>,
+    subscribers: Subscribers,
+}
+
+impl<T: Clone> Stream for Tracked<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let polled = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(Some(_)) = polled {
+            self.stats.received.fetch_add(1, Ordering::Relaxed);
+        }
+        polled
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        self.subscribers.lock().unwrap().remove(&self.stats.id);
+    }
 }
 
 pub struct Relay {
-    info: RelayInfo,
+    sent_messages: Fanout<Message>,
+    voice_states: Topic<VoiceState>,
+    /// Guilds whose member lists changed.
+    member_lists: Fanout<()>,
+    /// By user, what they marked read.
+    read_states: Fanout<ReadState>,
+    /// Channels that went away or turned private, which ends the subscriptions following them.
+    closed: Fanout<()>,
+    next_subscriber: AtomicU64,
 }
 
 impl Relay {
     pub fn new() -> Relay {
         Relay {
-            info: RelayInfo { sent_messages: RwLock::new(Publisher::new(30)) }
+            sent_messages: Fanout::new("messages"),
+            voice_states: Topic::new("voice_states"),
+            member_lists: Fanout::new("member_lists"),
+            read_states: Fanout::new("read_states"),
+            closed: Fanout::new("closed"),
+            next_subscriber: AtomicU64::new(0),
         }
     }
 
+    fn subscriber_id(&self) -> u64 {
+        self.next_subscriber.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Delivers the message to whoever subscribed to its recipient, a user or a channel.
     pub async fn send_message(&self, message: &Message) {
-        self.info.sent_messages.write().await.publish(message.clone()).await
+        let key = message.recipient.record_id().0;
+        self.sent_messages.publish(&key, message.clone()).await
+    }
+
+    /// Messages sent to any of `recipients`, users or channels. Checking they may be read is
+    /// up to the caller.
+    pub fn stream_messages(&self, recipients: Vec<Thing>) -> impl Stream<Item = Message> {
+        self.sent_messages
+            .subscribe(self.subscriber_id(), recipients)
+    }
+
+    pub async fn send_voice_state(&self, state: &VoiceState) {
+        self.voice_states.publish(state.clone()).await
+    }
+
+    pub async fn stream_voice_states(&self) -> impl Stream<Item = VoiceState> {
+        self.voice_states.subscribe(self.subscriber_id()).await
     }
 
-    pub async fn stream_sent_messages(&self) -> impl Stream<Item = Message> {
-        self.info.sent_messages.write().await.subscribe()
+    /// Tells whoever has `guild`'s member list open to refresh it.
+    pub async fn member_list_changed(&self, guild: &Ref<Guild>) {
+        self.member_lists.publish(&guild.record_id().0, ()).await
+    }
+
+    pub fn stream_member_list_changes(&self, guild: &Ref<Guild>) -> impl Stream<Item = ()> {
+        self.member_lists
+            .subscribe(self.subscriber_id(), vec![guild.record_id().0])
+    }
+
+    /// Tells `user`'s other devices what they marked read.
+    pub async fn read_state_changed(&self, user: &Ref<User>, state: ReadState) {
+        self.read_states.publish(&user.record_id().0, state).await
+    }
+
+    pub fn stream_read_states(&self, user: &Ref<User>) -> impl Stream<Item = ReadState> {
+        self.read_states
+            .subscribe(self.subscriber_id(), vec![user.record_id().0])
+    }
+
+    /// Ends every subscription following `object`, see [Relay::until_closed].
+    pub async fn close(&self, object: &Thing) {
+        self.closed.publish(object, ()).await
+    }
+
+    /// `stream` until any of `objects` is [closed](Relay::close). The subscription it feeds
+    /// then completes, so the client gets a `complete` for that operation while its others
+    /// on the same socket go on.
+    pub fn until_closed<S: Stream>(
+        &self,
+        stream: S,
+        objects: Vec<Thing>,
+    ) -> impl Stream<Item = S::Item> {
+        let closed = Box::pin(self.closed.subscribe(self.subscriber_id(), objects));
+        stream.take_until(closed.into_future())
+    }
+
+    pub fn stats(&self) -> Vec<TopicStats> {
+        vec![
+            self.sent_messages.stats(),
+            self.voice_states.stats(),
+            self.member_lists.stats(),
+            self.read_states.stats(),
+            self.closed.stats(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use super::*;
+
+    fn channel(id: &str) -> Thing {
+        Thing::from(("channel", id))
+    }
+
+    #[async_std::test]
+    async fn dropped_subscriptions_unregister() {
+        let fanout = Fanout::<u32>::new("test");
+        let keys: Vec<Thing> = (0..10).map(|n| channel(&n.to_string())).collect();
+        for round in 0..100 {
+            let subscriptions: Vec<_> = (0..10)
+                .map(|n| fanout.subscribe(round * 10 + n, keys.clone()))
+                .collect();
+            assert_eq!(fanout.stats().subscribers, 10);
+            drop(subscriptions);
+        }
+        assert_eq!(fanout.stats().subscribers, 0);
+        assert!(fanout.registry.lock().unwrap().by_key.is_empty());
+        // nobody left to wait for
+        fanout.publish(&keys[0], 1).await;
+    }
+
+    #[async_std::test]
+    async fn dropped_topic_subscriptions_unregister() {
+        let topic = Topic::<u32>::new("test");
+        for id in 0..100 {
+            let subscription = topic.subscribe(id).await;
+            assert_eq!(topic.stats().subscribers, 1);
+            drop(subscription);
+        }
+        assert_eq!(topic.stats().subscribers, 0);
+    }
+
+    #[async_std::test]
+    async fn closing_completes_only_what_follows_it() {
+        let relay = Relay::new();
+        let fanout = Fanout::<u32>::new("test");
+        let (a, b) = (channel("a"), channel("b"));
+        let mut on_a =
+            pin!(relay.until_closed(fanout.subscribe(1, vec![a.clone()]), vec![a.clone()]));
+        let mut on_b =
+            pin!(relay.until_closed(fanout.subscribe(2, vec![b.clone()]), vec![b.clone()]));
+
+        relay.close(&a).await;
+        assert_eq!(on_a.next().await, None);
+        fanout.publish(&b, 7).await;
+        assert_eq!(on_b.next().await, Some(7));
     }
 }
@@ -0,0 +1,113 @@
+use serde::Serialize;
+use tide::{http::mime::JSON, Body, Request, Response, StatusCode};
+
+use crate::{
+    auth, captcha,
+    config::ServerConfiguration,
+    http::{HttpState, State},
+    model::{
+        linked_account::{LinkedAccount, LinkedAccountKind},
+        spam,
+        user::{parse_tag, Badge, User},
+    },
+    storage::AvatarKind,
+    tenant,
+    util::ReferrableWithId,
+};
+
+pub fn routes(tide: &mut tide::Server<HttpState>) {
+    let mut api = tide.at("/api/v1");
+    api.at("/users/:tag/profile").get(http_user_profile);
+    api.at("/config").get(http_config);
+    api.at("/spam/captcha")
+        .with(auth::make_tide_authware())
+        .post(http_spam_captcha);
+}
+
+/// Lets users the spam checks stopped send again, with a solved captcha in the `X-Captcha`
+/// header.
+async fn http_spam_captcha(request: Request<HttpState>) -> tide::Result {
+    captcha::check(&request).await?;
+    let user = State::of(&request).await?.ref_user()?;
+    spam::release(tenant::of(&request)?.surreal(), &user).await?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn http_config(request: Request<HttpState>) -> tide::Result {
+    let config = ServerConfiguration::current(tenant::of(&request)?.surreal()).await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&config)?)
+        .content_type(JSON)
+        .build())
+}
+
+/// The subset of a user anyone may see, without logging in.
+#[derive(Serialize)]
+struct PublicProfile {
+    tag: String,
+    display_name: String,
+    avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    badges: Option<Vec<Badge>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bio: Option<String>,
+    /// RFC 3339.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen_at: Option<String>,
+    /// Only those visible to everyone.
+    connections: Vec<PublicConnection>,
+}
+
+#[derive(Serialize)]
+struct PublicConnection {
+    kind: LinkedAccountKind,
+    name: String,
+    verified: bool,
+}
+
+async fn http_user_profile(request: Request<HttpState>) -> tide::Result {
+    // the '#' has to be percent-encoded in the path
+    let tag = request.param("tag")?.replace("%23", "#");
+    let Some(tag) = parse_tag(&tag) else {
+        return Ok(Response::new(StatusCode::BadRequest));
+    };
+    let user = User::find_tag(tenant::of(&request)?.surreal(), &tag).await?;
+    let Some(user) = user.filter(|user| user.privacy.public_profile) else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+
+    let avatar = tenant::of(&request)?
+        .storage
+        .read()
+        .await
+        .get_user_avatar(<User as ReferrableWithId>::id(&user).clone(), AvatarKind::U)
+        .map(|path| format!("/{path}"));
+    let connections = LinkedAccount::visible(tenant::of(&request)?.surreal(), &user, None)
+        .await?
+        .into_iter()
+        .map(|account| PublicConnection {
+            kind: account.kind,
+            name: account.name,
+            verified: account.verified,
+        })
+        .collect();
+
+    let profile = PublicProfile {
+        tag: user.tag_fmt(),
+        avatar,
+        badges: user.privacy.show_badges.then(|| user.badges.clone()),
+        bio: user.bio.clone().filter(|_| user.privacy.show_bio),
+        last_seen_at: user
+            .last_seen_at
+            .as_ref()
+            .filter(|_| user.privacy.show_last_seen)
+            .map(|at| at.0.to_rfc3339()),
+        connections,
+        display_name: user.display_name,
+    };
+
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_json(&profile)?)
+        .content_type(JSON)
+        .build())
+}
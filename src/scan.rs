@@ -0,0 +1,74 @@
+use anyhow::anyhow;
+use async_std::{fs::File, net::TcpStream};
+use async_trait::async_trait;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::{data_path, CONFIG};
+
+pub enum Verdict {
+    Clean,
+    /// Carries what the scanner thinks the file is, e.g. the signature name.
+    Flagged(String),
+}
+
+/// Looks at uploaded files once they are stored, before anyone gets to download them.
+#[async_trait]
+pub trait UploadScanner: Send + Sync {
+    async fn scan(&self, path: &str) -> anyhow::Result<Verdict>;
+}
+
+/// Lets everything through, for instances without a scanner.
+pub struct NoopScanner;
+
+#[async_trait]
+impl UploadScanner for NoopScanner {
+    async fn scan(&self, _: &str) -> anyhow::Result<Verdict> {
+        Ok(Verdict::Clean)
+    }
+}
+
+/// Streams files to clamd over TCP with the `INSTREAM` command.
+pub struct ClamAvScanner {
+    pub addr: String,
+}
+
+#[async_trait]
+impl UploadScanner for ClamAvScanner {
+    async fn scan(&self, path: &str) -> anyhow::Result<Verdict> {
+        let mut file = File::open(data_path(path)).await?;
+        let mut clamd = TcpStream::connect(&self.addr).await?;
+        clamd.write_all(b"zINSTREAM\0").await?;
+
+        // chunks are prefixed with their length, a zero length ends the stream
+        let mut chunk = vec![0u8; 64 * 1024];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            clamd.write_all(&(read as u32).to_be_bytes()).await?;
+            clamd.write_all(&chunk[..read]).await?;
+        }
+        clamd.write_all(&0u32.to_be_bytes()).await?;
+
+        let mut reply = vec![];
+        clamd.read_to_end(&mut reply).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches('\0').trim();
+        // "stream: OK" or "stream: Eicar-Signature FOUND"
+        match reply.strip_prefix("stream: ") {
+            Some("OK") => Ok(Verdict::Clean),
+            Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Flagged(
+                found.trim_end_matches(" FOUND").to_owned(),
+            )),
+            _ => Err(anyhow!("unexpected reply from clamd: {reply}")),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref SCANNER: Box<dyn UploadScanner> = match CONFIG.clamav_addr {
+        Some(ref addr) => Box::new(ClamAvScanner { addr: addr.clone() }),
+        None => Box::new(NoopScanner),
+    };
+}
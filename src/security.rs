@@ -0,0 +1,220 @@
+//! Throttles for `/auth/register` and `/auth/login`, counted per IP, per subnet (/24 for
+//! IPv4, /48 for IPv6) and per ASN when a trusted reverse proxy says which, in sliding
+//! windows. The IP is only taken from forwarding headers set by a trusted proxy, see
+//! [client_ip](login::client_ip).
+//! Going past a limit blocks that key for a while, twice as long each time it happens again.
+//! Admins can see and clear them with the `throttles` query and `clearThrottle`.
+//!
+//! Registrations all count, logins only when they fail. Kept in memory, so every node
+//! counts for itself.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+};
+
+use anyhow::anyhow;
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use tide::{Request, StatusCode};
+
+use crate::{config::CONFIG, model::login};
+
+/// Longest a key is blocked for, however often it came back.
+const MAX_BLOCK_HOURS: i64 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Enum)]
+pub enum AttemptKind {
+    Register,
+    Login,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Enum)]
+pub enum ThrottleScope {
+    Ip,
+    Subnet,
+    Asn,
+}
+
+impl AttemptKind {
+    fn window(self) -> Duration {
+        match self {
+            Self::Register => Duration::hours(1),
+            Self::Login => Duration::minutes(10),
+        }
+    }
+
+    /// Attempts allowed per window, 0 for no limit.
+    fn limit(self, scope: ThrottleScope) -> usize {
+        match (self, scope) {
+            (Self::Register, ThrottleScope::Ip) => CONFIG.throttle_register_per_ip,
+            (Self::Register, ThrottleScope::Subnet) => CONFIG.throttle_register_per_subnet,
+            (Self::Register, ThrottleScope::Asn) => CONFIG.throttle_register_per_asn,
+            (Self::Login, ThrottleScope::Ip) => CONFIG.throttle_login_per_ip,
+            (Self::Login, ThrottleScope::Subnet) => CONFIG.throttle_login_per_subnet,
+            (Self::Login, ThrottleScope::Asn) => CONFIG.throttle_login_per_asn,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    attempts: VecDeque<DateTime<Utc>>,
+    blocked_until: Option<DateTime<Utc>>,
+    /// How often the key was blocked, for the backoff.
+    strikes: u32,
+}
+
+impl Window {
+    fn blocked(&self, now: DateTime<Utc>) -> bool {
+        self.blocked_until.is_some_and(|until| until > now)
+    }
+}
+
+type Key = (AttemptKind, ThrottleScope, String);
+
+/// What the `throttles` query shows of a key.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Throttle {
+    pub kind: AttemptKind,
+    pub scope: ThrottleScope,
+    /// The IP, the subnet like `203.0.113.0/24`, or the ASN.
+    pub key: String,
+    /// In the current window.
+    pub attempts: i32,
+    pub strikes: u32,
+    /// RFC 3339, `null` unless blocked right now.
+    pub blocked_until: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Throttles {
+    windows: Mutex<HashMap<Key, Window>>,
+}
+
+lazy_static::lazy_static! {
+    pub static ref THROTTLES: Throttles = Throttles::default();
+}
+
+fn subnet(ip: &str) -> Option<String> {
+    match ip.parse().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Some(format!("{a}.{b}.{c}.0/24"))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            Some(format!("{a:x}:{b:x}:{c:x}::/48"))
+        }
+    }
+}
+
+/// The keys `request` counts against.
+fn keys<S>(request: &Request<S>) -> Vec<(ThrottleScope, String)> {
+    let ip = login::client_ip(request);
+    // anyone else could name a victim's ASN and get it blocked
+    let asn = CONFIG
+        .asn_header
+        .as_deref()
+        .filter(|_| login::from_trusted_proxy(request))
+        .and_then(|name| request.header(name))
+        .map(|value| value.as_str().chars().take(32).collect());
+    [
+        (ThrottleScope::Subnet, ip.as_deref().and_then(subnet)),
+        (ThrottleScope::Ip, ip),
+        (ThrottleScope::Asn, asn),
+    ]
+    .into_iter()
+    .filter_map(|(scope, key)| Some((scope, key?)))
+    .collect()
+}
+
+impl Throttles {
+    /// `429 Too Many Requests` while any of `request`'s keys are blocked.
+    pub fn check<S>(&self, request: &Request<S>, kind: AttemptKind) -> tide::Result<()> {
+        let now = Utc::now();
+        let windows = self.windows.lock().unwrap();
+        let blocked_until = keys(request)
+            .into_iter()
+            .filter_map(|(scope, key)| windows.get(&(kind, scope, key)))
+            .filter(|window| window.blocked(now))
+            .filter_map(|window| window.blocked_until)
+            .max();
+        let Some(until) = blocked_until else {
+            return Ok(());
+        };
+        Err(tide::Error::new(
+            StatusCode::TooManyRequests,
+            anyhow!("too many attempts, try again after {}", until.to_rfc3339()),
+        ))
+    }
+
+    /// Counts an attempt from `request`, blocking the keys it takes past their limit.
+    pub fn record<S>(&self, request: &Request<S>, kind: AttemptKind) {
+        let now = Utc::now();
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|(kind, ..), window| {
+            window
+                .attempts
+                .back()
+                .is_some_and(|last| now - *last < kind.window())
+                || window.blocked(now)
+        });
+        for (scope, key) in keys(request) {
+            let limit = kind.limit(scope);
+            if limit == 0 {
+                continue;
+            }
+            let window = windows.entry((kind, scope, key)).or_default();
+            window.attempts.push_back(now);
+            while window
+                .attempts
+                .front()
+                .is_some_and(|first| now - *first >= kind.window())
+            {
+                window.attempts.pop_front();
+            }
+            if window.attempts.len() > limit && !window.blocked(now) {
+                let backoff = Duration::seconds(CONFIG.throttle_backoff_seconds as i64)
+                    * 2i32.saturating_pow(window.strikes.min(16));
+                window.blocked_until = Some(now + backoff.min(Duration::hours(MAX_BLOCK_HOURS)));
+                window.strikes += 1;
+            }
+        }
+    }
+
+    /// Keys with attempts in their window or blocked, blocked ones first.
+    pub fn list(&self) -> Vec<Throttle> {
+        let now = Utc::now();
+        let windows = self.windows.lock().unwrap();
+        let mut throttles: Vec<_> = windows
+            .iter()
+            .map(|((kind, scope, key), window)| Throttle {
+                kind: *kind,
+                scope: *scope,
+                key: key.clone(),
+                attempts: window
+                    .attempts
+                    .iter()
+                    .filter(|at| now - **at < kind.window())
+                    .count() as i32,
+                strikes: window.strikes,
+                blocked_until: window
+                    .blocked_until
+                    .filter(|until| *until > now)
+                    .map(|until| until.to_rfc3339()),
+            })
+            .collect();
+        throttles.sort_by_key(|throttle| (throttle.blocked_until.is_none(), -throttle.attempts));
+        throttles
+    }
+
+    /// Forgets `key`, of every kind and scope. `false` if there was nothing to forget.
+    pub fn clear(&self, key: &str) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        let before = windows.len();
+        windows.retain(|(_, _, throttled), _| throttled != key);
+        windows.len() != before
+    }
+}
@@ -0,0 +1,191 @@
+//! The `seed` command: fills an empty database with demo users, a guild with channels and
+//! roles, and a few thousand messages, so there's something to look at when working on a
+//! client locally. Every account's password is [PASSWORD].
+
+use anyhow::anyhow;
+use surrealdb::sql::Thing;
+use tide::log::info;
+
+use crate::{
+    config::CONFIG,
+    model::{
+        guild::{ChannelInit, ChannelKind, Guild, GuildInit, Member, Permission, Role},
+        message::{MessageInit, MessageRecipientIn, MessageRecipientInKind},
+        user::User,
+    },
+    tenant::Tenant,
+    util::ReferrableExt,
+};
+
+pub const PASSWORD: &str = "netherite";
+
+/// Logged in as `<name>@seed.local`. The first one owns the guild.
+const USERS: [&str; 6] = ["Alice", "Bob", "Carol", "Dave", "Erin", "Frank"];
+const CHANNELS: [&str; 4] = ["general", "random", "memes", "dev"];
+const MESSAGES: usize = 3000;
+/// Every tenth message is a direct message between the first two users.
+const DIRECT_EVERY: usize = 10;
+
+const LINES: [&str; 12] = [
+    "hey everyone",
+    "did anyone see the update?",
+    "lol",
+    "that's so cursed",
+    "brb, getting coffee",
+    "can someone review my PR",
+    "works on my machine",
+    "the build is green again",
+    "who broke prod",
+    "good morning!",
+    "I'll look into it tomorrow",
+    "this channel is my favorite",
+];
+
+pub async fn run(tenant: &Tenant) -> tide::Result<()> {
+    if CONFIG.production {
+        return Err(anyhow!("not seeding a production instance").into());
+    }
+    let surreal = tenant.surreal();
+    let existing: Vec<Thing> = surreal
+        .query("SELECT VALUE id FROM user WHERE email = $email")
+        .bind(("email", email(USERS[0])))
+        .await?
+        .take(0)?;
+    if !existing.is_empty() {
+        info!("already seeded, log in as {} / {PASSWORD}", email(USERS[0]));
+        return Ok(());
+    }
+
+    let (relay, services) = (&tenant.relay, &tenant.services);
+
+    let mut users = vec![];
+    for name in USERS {
+        let user = User::with_password(surreal, &email(name), PASSWORD, name, name).await?;
+        info!("seeded {} as {}", user.tag_fmt(), email(name));
+        users.push(user);
+    }
+    let (owner, others) = users.split_first().unwrap();
+    for other in others {
+        owner.add_friend(surreal, other.clone()).await?;
+    }
+
+    let guild = services
+        .guilds
+        .create(
+            owner,
+            GuildInit {
+                name: String::from("Netherite Demo"),
+            },
+        )
+        .await?;
+    for user in others {
+        Member::create(surreal, user, &guild).await?;
+    }
+    let admin = create_role(
+        surreal,
+        &guild,
+        "Admin",
+        0xe74c3c,
+        vec![Permission::Administrator],
+    )
+    .await?;
+    let moderator = create_role(
+        surreal,
+        &guild,
+        "Moderator",
+        0x3498db,
+        vec![
+            Permission::Kick,
+            Permission::Ban,
+            Permission::Timeout,
+            Permission::ManageMessages,
+        ],
+    )
+    .await?;
+    give_role(surreal, &guild, owner, &admin).await?;
+    give_role(surreal, &guild, &users[1], &moderator).await?;
+
+    let mut channels = vec![];
+    for name in CHANNELS {
+        let channel = services
+            .guilds
+            .create_channel(
+                &guild,
+                ChannelInit {
+                    kind: ChannelKind::Text,
+                    name: name.to_owned(),
+                },
+            )
+            .await?;
+        channels.push(channel);
+    }
+
+    for n in 0..MESSAGES {
+        let author = &users[n % users.len()];
+        let recipient = if n % DIRECT_EVERY == 0 {
+            let other = if n % 2 == 0 { &users[1] } else { owner };
+            MessageRecipientIn {
+                kind: MessageRecipientInKind::User,
+                id: other.record_id(),
+            }
+        } else {
+            MessageRecipientIn {
+                kind: MessageRecipientInKind::Channel,
+                id: channels[n % channels.len()].record_id(),
+            }
+        };
+        let init = MessageInit {
+            recipient,
+            content: format!("{} ({n})", LINES[n % LINES.len()]),
+            reference: None,
+            tts: false,
+            gif: None,
+        };
+        author.send_message(surreal, relay, init).await?;
+    }
+    info!(
+        "seeded {} users, {} channels and {MESSAGES} messages, log in as {} / {PASSWORD}",
+        users.len(),
+        channels.len(),
+        email(USERS[0])
+    );
+    Ok(())
+}
+
+fn email(name: &str) -> String {
+    format!("{}@seed.local", name.to_lowercase())
+}
+
+async fn create_role(
+    surreal: &crate::Surreal,
+    guild: &Guild,
+    name: &str,
+    color: u32,
+    permissions: Vec<Permission>,
+) -> tide::Result<Role> {
+    let role: Option<Role> = surreal
+        .query("CREATE role SET name = $name, color = $color, permissions = $permissions, guild = $guild")
+        .bind(("name", name))
+        .bind(("color", color))
+        .bind(("permissions", permissions))
+        .bind(("guild", guild.record_id()))
+        .await?
+        .take(0)?;
+    Ok(role.ok_or_else(|| anyhow!("role no makey"))?)
+}
+
+async fn give_role(
+    surreal: &crate::Surreal,
+    guild: &Guild,
+    user: &User,
+    role: &Role,
+) -> tide::Result<()> {
+    surreal
+        .query("UPDATE member SET roles += $role WHERE guild = $guild AND user = $user")
+        .bind(("role", role.record_id()))
+        .bind(("guild", guild.record_id()))
+        .bind(("user", user.record_id()))
+        .await?
+        .check()?;
+    Ok(())
+}
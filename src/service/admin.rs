@@ -0,0 +1,258 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use tide::StatusCode;
+
+use crate::{
+    backup::{self, Backup, RestoreReport},
+    jobs,
+    model::{
+        admin_audit::AdminAction,
+        erasure::Erasure,
+        instance::{InstanceInvite, InstanceSettings, RegistrationMode},
+        legal_hold,
+        name_rule::{NameRule, NameRuleKind},
+        retention,
+        spam::SpamFlag,
+        stats::{self, DailyStats, InstanceStats, StatsTotals},
+        terms::Terms,
+        user::{Badge, User},
+    },
+    security::{Throttle, THROTTLES},
+    tenant::Tenancy,
+    util::{RecordId, Ref, ReferrableExt},
+};
+
+/// Instance administration, only for users with the [admin badge](Badge::Admin).
+#[derive(Clone)]
+pub struct AdminService {
+    pub(super) surreal: &'static crate::Surreal,
+    /// Backups are of this tenant's namespace and files only.
+    pub(super) tenancy: Tenancy,
+}
+
+impl AdminService {
+    pub fn require_admin(&self, user: &User) -> tide::Result<()> {
+        if !user.badges.contains(&Badge::Admin) {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("only admins can do this"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn name_rules(&self, admin: &User) -> tide::Result<Vec<NameRule>> {
+        self.require_admin(admin)?;
+        Ok(NameRule::all(self.surreal).await?)
+    }
+
+    pub async fn add_name_rule(
+        &self,
+        admin: &User,
+        kind: NameRuleKind,
+        word: &str,
+    ) -> tide::Result<NameRule> {
+        self.require_admin(admin)?;
+        NameRule::add(self.surreal, kind, word).await
+    }
+
+    /// `false` if there was no such rule.
+    pub async fn remove_name_rule(&self, admin: &User, rule: RecordId) -> tide::Result<bool> {
+        self.require_admin(admin)?;
+        if rule.0.tb != "name_rule" {
+            return Ok(false);
+        }
+        let removed: Option<NameRule> = self.surreal.delete(rule.0).await?;
+        Ok(removed.is_some())
+    }
+
+    /// Everyone has to accept the new version before they can do anything else.
+    pub async fn publish_terms(&self, admin: &User, content: String) -> tide::Result<Terms> {
+        self.require_admin(admin)?;
+        Terms::publish(self.surreal, content).await
+    }
+
+    pub async fn backups(&self, admin: &User) -> tide::Result<Vec<Backup>> {
+        self.require_admin(admin)?;
+        Ok(backup::all(self.surreal).await?)
+    }
+
+    /// Queues a backup besides the scheduled ones.
+    pub async fn create_backup(&self, admin: &User) -> tide::Result<()> {
+        self.require_admin(admin)?;
+        jobs::enqueue(self.surreal, backup::JOB, serde_json::Value::Null).await?;
+        Ok(())
+    }
+
+    pub async fn restore_backup(
+        &self,
+        admin: &User,
+        backup: &Ref<Backup>,
+        dry_run: bool,
+    ) -> tide::Result<RestoreReport> {
+        self.require_admin(admin)?;
+        let backup = backup.fetch(self.surreal).await?;
+        backup::restore(self.surreal, self.tenancy, &admin.refer(), &backup, dry_run).await
+    }
+
+    /// Queues erasing `user`, see [crate::model::erasure].
+    pub async fn erase_user(
+        &self,
+        admin: &User,
+        user: &Ref<User>,
+        anonymize_content: bool,
+    ) -> tide::Result<Erasure> {
+        self.require_admin(admin)?;
+        if *user == admin.refer() {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("admins can't erase themselves"),
+            ));
+        }
+        if user.fetch(self.surreal).await?.legal_hold {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("this user is under a legal hold, lift it first"),
+            ));
+        }
+        Erasure::request(self.surreal, &admin.refer(), user, anonymize_content).await
+    }
+
+    /// Places a [legal hold](legal_hold) on a user or channel, or lifts it with `held` unset.
+    pub async fn set_legal_hold(
+        &self,
+        admin: &User,
+        subject: RecordId,
+        held: bool,
+        reason: String,
+    ) -> tide::Result<AdminAction> {
+        self.require_admin(admin)?;
+        legal_hold::set(self.surreal, &admin.refer(), subject, held, reason).await
+    }
+
+    /// Newest first, only what was done to `subject` if given.
+    pub async fn admin_actions(
+        &self,
+        admin: &User,
+        subject: Option<RecordId>,
+    ) -> tide::Result<Vec<AdminAction>> {
+        self.require_admin(admin)?;
+        Ok(AdminAction::latest(self.surreal, subject).await?)
+    }
+
+    /// Blocked first, then by attempts. Only this node's, see [crate::security].
+    pub fn throttles(&self, admin: &User) -> tide::Result<Vec<Throttle>> {
+        self.require_admin(admin)?;
+        Ok(THROTTLES.list())
+    }
+
+    /// Unblocks `key`, an IP, subnet or ASN, and forgets its attempts.
+    pub fn clear_throttle(&self, admin: &User, key: &str) -> tide::Result<bool> {
+        self.require_admin(admin)?;
+        Ok(THROTTLES.clear(key))
+    }
+
+    /// Unresolved, newest first.
+    pub async fn spam_flags(&self, admin: &User) -> tide::Result<Vec<SpamFlag>> {
+        self.require_admin(admin)?;
+        Ok(SpamFlag::pending(self.surreal).await?)
+    }
+
+    pub async fn resolve_spam_flag(
+        &self,
+        admin: &User,
+        flag: &Ref<SpamFlag>,
+    ) -> tide::Result<SpamFlag> {
+        self.require_admin(admin)?;
+        SpamFlag::resolve(self.surreal, flag).await
+    }
+
+    pub async fn erasures(&self, admin: &User) -> tide::Result<Vec<Erasure>> {
+        self.require_admin(admin)?;
+        Ok(Erasure::all(self.surreal).await?)
+    }
+
+    /// The last `days` days, oldest first. Without any rollups yet, one is queued.
+    pub async fn stats(&self, admin: &User, days: u32) -> tide::Result<InstanceStats> {
+        self.require_admin(admin)?;
+        let totals = StatsTotals::get(self.surreal).await?;
+        if totals.is_none() {
+            jobs::enqueue(self.surreal, stats::JOB, serde_json::Value::Null).await?;
+        }
+        Ok(InstanceStats {
+            totals,
+            days: DailyStats::last(self.surreal, days).await?,
+        })
+    }
+
+    pub async fn retention(&self, admin: &User) -> tide::Result<Option<u32>> {
+        self.require_admin(admin)?;
+        Ok(InstanceSettings::get(self.surreal).await?.retention_days)
+    }
+
+    /// `None` keeps messages forever, unless a guild says otherwise.
+    pub async fn set_retention(
+        &self,
+        admin: &User,
+        days: Option<u32>,
+    ) -> tide::Result<Option<u32>> {
+        self.require_admin(admin)?;
+        retention::check_days(days)?;
+        let mut settings = InstanceSettings::get(self.surreal).await?;
+        settings.retention_days = days;
+        settings.save(self.surreal).await?;
+        Ok(days)
+    }
+
+    pub async fn registration_mode(&self, admin: &User) -> tide::Result<RegistrationMode> {
+        self.require_admin(admin)?;
+        Ok(InstanceSettings::get(self.surreal).await?.registration)
+    }
+
+    pub async fn set_registration_mode(
+        &self,
+        admin: &User,
+        mode: RegistrationMode,
+    ) -> tide::Result<RegistrationMode> {
+        self.require_admin(admin)?;
+        let mut settings = InstanceSettings::get(self.surreal).await?;
+        settings.registration = mode;
+        settings.save(self.surreal).await?;
+        Ok(mode)
+    }
+
+    pub async fn instance_invites(&self, admin: &User) -> tide::Result<Vec<InstanceInvite>> {
+        self.require_admin(admin)?;
+        Ok(InstanceInvite::all(self.surreal).await?)
+    }
+
+    pub async fn create_instance_invite(
+        &self,
+        admin: &User,
+        max_uses: Option<u32>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> tide::Result<InstanceInvite> {
+        self.require_admin(admin)?;
+        if max_uses == Some(0) || expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("this invite could never be used"),
+            ));
+        }
+        Ok(InstanceInvite::create(self.surreal, &admin.refer(), max_uses, expires_at).await?)
+    }
+
+    /// `false` if there was no such invite.
+    pub async fn revoke_instance_invite(
+        &self,
+        admin: &User,
+        invite: RecordId,
+    ) -> tide::Result<bool> {
+        self.require_admin(admin)?;
+        if invite.0.tb != "instance_invite" {
+            return Ok(false);
+        }
+        let removed: Option<InstanceInvite> = self.surreal.delete(invite.0).await?;
+        Ok(removed.is_some())
+    }
+}
@@ -0,0 +1,1230 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use async_graphql::ErrorExtensions;
+use async_std::sync::RwLock;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use surrealdb::sql::Datetime;
+use tide::{log::error, StatusCode};
+
+use crate::{
+    config::{data_path, CONFIG},
+    model::{
+        audit::{AuditLogEntry, AuditLogEntryType, Prune, Transcript},
+        automod::AutomodSettings,
+        emoji::{self, EmojiMatch, GuildEmoji},
+        event::{Changes, Event, EventKind},
+        feed::Feed,
+        guild::{
+            Channel, ChannelInit, ChannelUpdate, Guild, GuildImage, GuildInit, JoinConstraint,
+            Member, Permission, Role, TextChannel, TextableChannel,
+        },
+        guild_application::{ApplicationStatus, GuildApplication},
+        guild_invite::{GuildInvite, InvitePreview},
+        guild_layout::{GuildFolder, GuildLayout},
+        instance::InstanceSettings,
+        legal_hold::NOT_HELD,
+        limits,
+        member_list::{self, MemberChunk, MemberListChange, MemberLists},
+        naming, retention, transcript,
+        trust::TrustSettings,
+        user::User,
+        voice::{VoiceFlags, VoiceState},
+        webhook::{CreatedWebhook, Webhook},
+    },
+    pubsub::Relay,
+    storage::{ProcessedAvatar, Storage},
+    util::{
+        self,
+        query::{field, Q},
+        random_string, update_fields, Ref, ReferrableExt,
+    },
+};
+
+const EMOJI_SEARCH_LIMIT: usize = 50;
+
+#[derive(Clone)]
+pub struct GuildService {
+    pub(super) surreal: &'static crate::Surreal,
+    pub(super) relay: Arc<Relay>,
+    /// The tenant's [Storage::root], emoji are stored under it.
+    pub(super) storage_root: &'static str,
+    pub(super) member_lists: MemberLists,
+}
+
+impl GuildService {
+    pub async fn create(&self, owner: &User, init: GuildInit) -> async_graphql::Result<Guild> {
+        let name = naming::check_guild(self.surreal, &owner.refer(), &init.name, None).await?;
+        limits::check_guilds(self.surreal, &owner.refer()).await?;
+        Guild::create(self.surreal, owner, GuildInit { name }).await
+    }
+
+    /// Needs [Permission::ManageServer].
+    pub async fn rename(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        name: String,
+    ) -> async_graphql::Result<Guild> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.name = naming::check_guild(self.surreal, user, &name, Some(&guild)).await?;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// The guilds `user` is a member of.
+    /// In the order of the user's [GuildLayout].
+    pub async fn of_user(&self, user: &Ref<User>) -> tide::Result<Vec<Guild>> {
+        #[derive(Deserialize)]
+        struct Memer {
+            guild: Guild,
+        }
+
+        let memers: Vec<Memer> = self
+            .surreal
+            .query("SELECT guild FROM member WHERE user = $user AND pending != true FETCH guild")
+            .bind(("user", user.record_id()))
+            .await?
+            .take(0)?;
+        let mut guilds: Vec<_> = memers.into_iter().map(|memer| memer.guild).collect();
+        let folders = GuildLayout::of_user(self.surreal, user).await?;
+        GuildLayout::sort(&mut guilds, &folders);
+        Ok(guilds)
+    }
+
+    pub async fn folders(&self, user: &Ref<User>) -> tide::Result<Vec<GuildFolder>> {
+        Ok(GuildLayout::of_user(self.surreal, user).await?)
+    }
+
+    pub async fn update_positions(
+        &self,
+        user: &Ref<User>,
+        folders: Vec<GuildFolder>,
+    ) -> tide::Result<Vec<GuildFolder>> {
+        let member_of = self.of_user(user).await?;
+        GuildLayout::set(self.surreal, user, folders, &member_of).await
+    }
+
+    pub async fn create_channel(
+        &self,
+        guild: &Guild,
+        init: ChannelInit,
+    ) -> async_graphql::Result<Channel> {
+        let ChannelInit { name, kind } = init;
+        let name = naming::check_channel(self.surreal, &guild.refer(), &name, None).await?;
+        limits::check_channels(self.surreal, &guild.refer()).await?;
+        let slugged = naming::slug(&name);
+        let channel: Option<Channel> = self
+            .surreal
+            .query(
+                "CREATE channel CONTENT { guild: $guild, name: $name, slug: $slug, kind: $kind }",
+            )
+            .bind(("guild", guild.record_id()))
+            .bind(("name", name))
+            .bind(("slug", slugged.as_str()))
+            .bind(("kind", kind.to_string()))
+            .await?
+            .take(0)
+            .map_err(|e| naming::channel_write_failed(e, &slugged))?;
+        let channel = channel.ok_or_else(|| anyhow!("channel no makey"))?;
+        Event::new(EventKind::ChannelCreated, channel.record_id())
+            .in_guild(guild.refer())
+            .log(self.surreal)
+            .await?;
+        Ok(channel)
+    }
+
+    /// Needs [Permission::ManageServer]. `None` keeps messages as long as the instance does.
+    pub async fn set_retention(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        days: Option<u32>,
+    ) -> tide::Result<Guild> {
+        retention::check_days(days)?;
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.retention_days = days;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// Needs [Permission::ManageChannels]. Only shorter than what the guild and instance keep
+    /// messages for, `None` goes by theirs.
+    pub async fn set_channel_retention(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        days: Option<u32>,
+    ) -> tide::Result<Channel> {
+        retention::check_days(days)?;
+        let before = channel.fetch(self.surreal).await?;
+        let Channel::Text(mut text) = before.clone();
+        Member::require_permission(self.surreal, &text.guild, user, Permission::ManageChannels)
+            .await?;
+        let guild = text.guild.fetch(self.surreal).await?;
+        let default = retention::shortest([
+            InstanceSettings::get(self.surreal).await?.retention_days,
+            guild.retention_days,
+        ]);
+        if let (Some(days), Some(default)) = (days, default) {
+            if days >= default {
+                return Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!("messages are kept for {default} days already, go shorter than that"),
+                ));
+            }
+        }
+        text.retention_days = days;
+        Ok(update_fields(self.surreal, &before, Channel::Text(text)).await?)
+    }
+
+    /// Writes the messages `channel` got between `from` and `to` to a [transcript], which the
+    /// guild's audit log links to. Needs [Permission::ManageMessages] in the channel.
+    pub async fn export_transcript(
+        &self,
+        storage: &RwLock<Storage>,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> tide::Result<AuditLogEntry> {
+        let Channel::Text(text) = channel.fetch(self.surreal).await?;
+        text.require_permission(self.surreal, user, Permission::ManageMessages)
+            .await?;
+        if from >= to {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("the range has to end after it starts"),
+            ));
+        }
+
+        let guild = text.guild.fetch(self.surreal).await?;
+        let rendered = transcript::render(self.surreal, &text, &guild.name, from, to).await?;
+        let path = storage.read().await.put_transcript(&rendered.html).await?;
+        Ok(AuditLogEntry::log(
+            self.surreal,
+            &text.guild,
+            user,
+            AuditLogEntryType::Transcript(Transcript {
+                channel: text.id.clone(),
+                path,
+                from: util::Datetime(from),
+                to: util::Datetime(to),
+                message_count: rendered.message_count,
+            }),
+        )
+        .await?)
+    }
+
+    /// Removes the [inactive](Member::inactive) members of `guild`, or with `dry_run` only
+    /// counts them. Needs [Permission::Kick].
+    pub async fn prune_members(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        inactive_days: u32,
+        dry_run: bool,
+    ) -> tide::Result<usize> {
+        Member::require_permission(self.surreal, guild, user, Permission::Kick).await?;
+        if inactive_days == 0 {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("members have to be inactive for at least a day"),
+            ));
+        }
+        let inactive = Member::inactive(self.surreal, guild, inactive_days, user).await?;
+        if dry_run || inactive.is_empty() {
+            return Ok(inactive.len());
+        }
+
+        for member in &inactive {
+            let _: Option<Member> = self.surreal.delete(member.record_id().0).await?;
+        }
+        AuditLogEntry::log(
+            self.surreal,
+            guild,
+            user,
+            AuditLogEntryType::Prune(Prune {
+                inactive_days,
+                users: inactive
+                    .iter()
+                    .map(|member| member.user.record_id().0)
+                    .collect(),
+            }),
+        )
+        .await?;
+        self.member_lists
+            .changed(
+                self.surreal,
+                &self.relay,
+                guild,
+                MemberListChange::Left(inactive.into_iter().map(|member| member.user).collect()),
+            )
+            .await;
+        Ok(inactive.len())
+    }
+
+    /// Newest first. Needs [Permission::ManageMessages].
+    pub async fn audit_log(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+    ) -> tide::Result<Vec<AuditLogEntry>> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageMessages).await?;
+        Ok(AuditLogEntry::of_guild(self.surreal, guild).await?)
+    }
+
+    /// Channels created and members joined since `cursor`, only for members.
+    pub async fn changes_since(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        cursor: Option<&str>,
+    ) -> tide::Result<Changes> {
+        self.require_member(guild, user).await?;
+        Event::since_in_guild(self.surreal, guild, cursor).await
+    }
+
+    /// Needs [Permission::ManageChannels] in the channel's guild. Making it private completes
+    /// the subscriptions following it, resubscribing checks who may still read it.
+    pub async fn update_channel(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        update: ChannelUpdate,
+    ) -> async_graphql::Result<Channel> {
+        let before = channel.fetch(self.surreal).await?;
+        let Channel::Text(mut text) = before.clone();
+        Member::require_permission(self.surreal, &text.guild, user, Permission::ManageChannels)
+            .await?;
+
+        let ChannelUpdate {
+            name,
+            topic,
+            nsfw,
+            private,
+        } = update;
+        if let Some(name) = name {
+            text.name =
+                naming::check_channel(self.surreal, &text.guild, &name, Some(&text)).await?;
+            text.slug = naming::slug(&text.name);
+        }
+        if let Some(topic) = topic {
+            if topic.chars().count() > TextChannel::MAX_TOPIC_LENGTH {
+                return Err(async_graphql::Error::new("topic is too long"));
+            }
+            text.topic = Some(topic).filter(|topic| !topic.trim().is_empty());
+        }
+        let nsfw_changed = nsfw.is_some_and(|nsfw| nsfw != text.nsfw);
+        if let Some(nsfw) = nsfw {
+            text.nsfw = nsfw;
+        }
+        let private_changed = private.is_some_and(|private| private != text.private);
+        if let Some(private) = private {
+            text.private = private;
+        }
+        // diffed as the enum so the kind tag stays
+        let slugged = text.slug.clone();
+        let channel = update_fields(self.surreal, &before, Channel::Text(text))
+            .await
+            .map_err(|e| naming::channel_write_failed(e, &slugged))?;
+        if nsfw_changed || private_changed {
+            self.member_lists
+                .changed(
+                    self.surreal,
+                    &self.relay,
+                    channel.guild(),
+                    MemberListChange::All,
+                )
+                .await;
+        }
+        if private_changed {
+            let Channel::Text(ref text) = channel;
+            if text.private {
+                self.relay.close(&channel.record_id().0).await;
+            }
+        }
+        Ok(channel)
+    }
+
+    /// Lets `roles` and `users` see `channel` while it's private, or with `grant` unset stops
+    /// letting them, which completes the subscriptions following it like making it private
+    /// does. Needs [Permission::ManageChannels].
+    pub async fn set_channel_access(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        roles: Vec<Ref<Role>>,
+        users: Vec<Ref<User>>,
+        grant: bool,
+    ) -> tide::Result<Channel> {
+        let Channel::Text(mut text) = channel.fetch(self.surreal).await?;
+        Member::require_permission(self.surreal, &text.guild, user, Permission::ManageChannels)
+            .await?;
+
+        if grant {
+            for role in &roles {
+                if role.fetch(self.surreal).await?.guild != text.guild {
+                    return Err(tide::Error::new(
+                        StatusCode::BadRequest,
+                        anyhow!("{} isn't a role of this guild", role.record_id()),
+                    ));
+                }
+            }
+            for allowed in &users {
+                if Member::find(self.surreal, &text.guild, allowed)
+                    .await?
+                    .is_none()
+                {
+                    return Err(tide::Error::new(
+                        StatusCode::BadRequest,
+                        anyhow!("{} isn't a member of this guild", allowed.record_id()),
+                    ));
+                }
+            }
+            for role in roles {
+                if !text.allowed_roles.contains(&role) {
+                    text.allowed_roles.push(role);
+                }
+            }
+            for allowed in users {
+                if !text.allowed_users.contains(&allowed) {
+                    text.allowed_users.push(allowed);
+                }
+            }
+            if text.allowed_roles.len() + text.allowed_users.len() > TextChannel::MAX_ALLOWED {
+                return Err(tide::Error::new(
+                    StatusCode::BadRequest,
+                    anyhow!(
+                        "at most {} roles and members can be allowed, use roles for more",
+                        TextChannel::MAX_ALLOWED
+                    ),
+                ));
+            }
+        } else {
+            text.allowed_roles.retain(|role| !roles.contains(role));
+            text.allowed_users
+                .retain(|allowed| !users.contains(allowed));
+        }
+        let revoked = !grant && text.private;
+        let channel = Channel::Text(text).save(self.surreal).await?;
+        self.member_lists
+            .changed(
+                self.surreal,
+                &self.relay,
+                channel.guild(),
+                MemberListChange::All,
+            )
+            .await;
+        if revoked {
+            self.relay.close(&channel.record_id().0).await;
+        }
+        Ok(channel)
+    }
+
+    /// Needs [Permission::ManageChannels]. Its messages, read markers, reminders, webhooks and
+    /// feeds go with it, and whoever follows it gets their subscriptions completed. Messages by
+    /// authors under a [legal hold](crate::model::legal_hold) are only tombstoned.
+    pub async fn delete_channel(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+    ) -> tide::Result<bool> {
+        let Some(found) = self
+            .surreal
+            .select::<Option<Channel>>(channel.record_id().0)
+            .await?
+        else {
+            return Ok(false);
+        };
+        Member::require_permission(
+            self.surreal,
+            found.guild(),
+            user,
+            Permission::ManageChannels,
+        )
+        .await?;
+        let Channel::Text(ref text) = found;
+        if text.legal_hold {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow!("this channel is under a legal hold and can't be deleted"),
+            ));
+        }
+        let in_channel = "recipient.kind = 'Channel' AND recipient.id = $channel";
+        self.surreal
+            .query(format!(
+                "DELETE reminder WHERE message.recipient.id = $channel; \
+                DELETE translation WHERE message.recipient.id = $channel; \
+                DELETE message WHERE {in_channel} AND {NOT_HELD}; \
+                UPDATE message SET deleted_at = time::now() \
+                    WHERE {in_channel} AND deleted_at = NONE; \
+                DELETE read_marker WHERE target = $channel; \
+                DELETE conversation_name WHERE target = $channel; \
+                DELETE webhook WHERE channel = $channel; \
+                DELETE feed WHERE channel = $channel; \
+                DELETE $channel"
+            ))
+            .bind(("channel", channel.record_id()))
+            .await?
+            .check()?;
+        Event::new(EventKind::ChannelDeleted, channel.record_id())
+            .in_guild(found.guild().clone())
+            .log(self.surreal)
+            .await?;
+        self.relay.close(&channel.record_id().0).await;
+        Ok(true)
+    }
+
+    pub async fn create_webhook(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        name: String,
+        avatar_url: Option<String>,
+    ) -> tide::Result<CreatedWebhook> {
+        let Channel::Text(text) = channel.fetch(self.surreal).await?;
+        Member::require_permission(self.surreal, &text.guild, user, Permission::ManageWebhooks)
+            .await?;
+        Webhook::create(self.surreal, user, &text, name, avatar_url).await
+    }
+
+    pub async fn delete_webhook(
+        &self,
+        user: &Ref<User>,
+        webhook: &Ref<Webhook>,
+    ) -> tide::Result<bool> {
+        let Some(found) = self
+            .surreal
+            .select::<Option<Webhook>>(webhook.record_id().0)
+            .await?
+        else {
+            return Ok(false);
+        };
+        Member::require_permission(self.surreal, &found.guild, user, Permission::ManageWebhooks)
+            .await?;
+        let _: Option<Webhook> = self.surreal.delete(webhook.record_id().0).await?;
+        Ok(true)
+    }
+
+    /// The webhooks posting to `channel`.
+    pub async fn webhooks(
+        &self,
+        user: &Ref<User>,
+        channel: &TextChannel,
+    ) -> tide::Result<Vec<Webhook>> {
+        Member::require_permission(
+            self.surreal,
+            &channel.guild,
+            user,
+            Permission::ManageWebhooks,
+        )
+        .await?;
+        Ok(Q::select::<Webhook>()
+            .filter(field("channel").eq(channel.record_id()))
+            .all(self.surreal)
+            .await?)
+    }
+
+    /// Needs `ManageWebhooks`, like webhooks since feeds post the same way.
+    pub async fn add_feed(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        url: String,
+    ) -> tide::Result<Feed> {
+        let Channel::Text(text) = channel.fetch(self.surreal).await?;
+        Member::require_permission(self.surreal, &text.guild, user, Permission::ManageWebhooks)
+            .await?;
+        Feed::create(self.surreal, user, &text, url).await
+    }
+
+    pub async fn remove_feed(&self, user: &Ref<User>, feed: &Ref<Feed>) -> tide::Result<bool> {
+        let Some(found) = self
+            .surreal
+            .select::<Option<Feed>>(feed.record_id().0)
+            .await?
+        else {
+            return Ok(false);
+        };
+        Member::require_permission(self.surreal, &found.guild, user, Permission::ManageWebhooks)
+            .await?;
+        let _: Option<Feed> = self.surreal.delete(feed.record_id().0).await?;
+        Ok(true)
+    }
+
+    /// The feeds posting to `channel`.
+    pub async fn feeds(&self, user: &Ref<User>, channel: &TextChannel) -> tide::Result<Vec<Feed>> {
+        Member::require_permission(
+            self.surreal,
+            &channel.guild,
+            user,
+            Permission::ManageWebhooks,
+        )
+        .await?;
+        Ok(Feed::of_channel(self.surreal, channel).await?)
+    }
+
+    pub async fn create_emoji(
+        &self,
+        storage: &RwLock<Storage>,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        name: String,
+        image: ProcessedAvatar,
+    ) -> tide::Result<GuildEmoji> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageEmojis).await?;
+        let emoji =
+            GuildEmoji::create(self.surreal, user, guild, name, image.animated.is_some()).await?;
+        let path = emoji.path(self.storage_root);
+        if let Err(e) = storage.read().await.put_image(&path, image).await {
+            let _: Option<GuildEmoji> = self.surreal.delete(emoji.record_id().0).await?;
+            return Err(tide::Error::new(
+                StatusCode::InternalServerError,
+                anyhow!("{e}"),
+            ));
+        }
+        Ok(emoji)
+    }
+
+    pub async fn delete_emoji(
+        &self,
+        user: &Ref<User>,
+        emoji: &Ref<GuildEmoji>,
+    ) -> tide::Result<bool> {
+        let Some(found) = self
+            .surreal
+            .select::<Option<GuildEmoji>>(emoji.record_id().0)
+            .await?
+        else {
+            return Ok(false);
+        };
+        Member::require_permission(self.surreal, &found.guild, user, Permission::ManageEmojis)
+            .await?;
+        let _: Option<GuildEmoji> = self.surreal.delete(emoji.record_id().0).await?;
+        async_std::fs::remove_file(data_path(&found.path(self.storage_root))).await?;
+        Ok(true)
+    }
+
+    /// Unicode emoji and the custom ones of `guild`, or of every guild `user` is in.
+    pub async fn search_emoji(
+        &self,
+        user: &Ref<User>,
+        query: &str,
+        guild: Option<Ref<Guild>>,
+    ) -> tide::Result<Vec<EmojiMatch>> {
+        let guilds = match guild {
+            Some(guild) => {
+                if Member::find(self.surreal, &guild, user).await?.is_none() {
+                    return Err(tide::Error::new(
+                        StatusCode::Forbidden,
+                        anyhow!("not a member of this guild"),
+                    ));
+                }
+                vec![guild]
+            }
+            None => self
+                .of_user(user)
+                .await?
+                .iter()
+                .map(|guild| guild.refer())
+                .collect(),
+        };
+        Ok(emoji::search(
+            self.surreal,
+            self.storage_root,
+            query,
+            &guilds,
+            EMOJI_SEARCH_LIMIT,
+        )
+        .await?)
+    }
+
+    /// Needs [Permission::Invite].
+    pub async fn create_invite(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        max_uses: Option<u32>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> tide::Result<GuildInvite> {
+        Member::require_permission(self.surreal, guild, user, Permission::Invite).await?;
+        if max_uses == Some(0) || expires_at.is_some_and(|at| at <= Utc::now()) {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("this invite could never be used"),
+            ));
+        }
+        Ok(GuildInvite::create(self.surreal, guild, user, max_uses, expires_at).await?)
+    }
+
+    /// Needs [Permission::Invite].
+    pub async fn invites(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+    ) -> tide::Result<Vec<GuildInvite>> {
+        Member::require_permission(self.surreal, guild, user, Permission::Invite).await?;
+        Ok(GuildInvite::of_guild(self.surreal, guild).await?)
+    }
+
+    /// Needs [Permission::Invite]. `false` if there was no such invite.
+    pub async fn revoke_invite(
+        &self,
+        user: &Ref<User>,
+        invite: &Ref<GuildInvite>,
+    ) -> tide::Result<bool> {
+        let Some(found) = self
+            .surreal
+            .select::<Option<GuildInvite>>(invite.record_id().0)
+            .await?
+        else {
+            return Ok(false);
+        };
+        Member::require_permission(self.surreal, &found.guild, user, Permission::Invite).await?;
+        let _: Option<GuildInvite> = self.surreal.delete(invite.record_id().0).await?;
+        Ok(true)
+    }
+
+    /// `None` if there's no such invite, or it can't be used anymore.
+    pub async fn invite_preview(&self, code: &str) -> tide::Result<Option<InvitePreview>> {
+        match GuildInvite::find(self.surreal, code).await? {
+            Some(invite) => Ok(Some(invite.preview(self.surreal).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Makes `user` a member of the guild `code` invites to. Members only get the guild back,
+    /// without using up the invite. Guilds that take [applications](JoinConstraint::Application)
+    /// need `answers` to their questions, and let `user` in once it's approved.
+    pub async fn join(
+        &self,
+        user: &Ref<User>,
+        code: &str,
+        answers: Vec<String>,
+    ) -> async_graphql::Result<Guild> {
+        let Some(invite) = GuildInvite::find(self.surreal, code).await? else {
+            return Err(async_graphql::Error::new(
+                "this invite is invalid, used up or expired",
+            ));
+        };
+        let guild = invite.guild.fetch(self.surreal).await?;
+        if Member::find(self.surreal, &invite.guild, user)
+            .await?
+            .is_some()
+        {
+            return Ok(guild);
+        }
+        if Member::find_pending(self.surreal, &invite.guild, user)
+            .await?
+            .is_some()
+        {
+            return Err(async_graphql::Error::new(
+                "your application to this guild is still pending",
+            ));
+        }
+        limits::check_members(self.surreal, &invite.guild).await?;
+        limits::check_guilds(self.surreal, user).await?;
+        let user = user.fetch(self.surreal).await?;
+        check_join_constraint(&guild, &user)?;
+        if guild.join_constraint == JoinConstraint::Application {
+            let answers = GuildApplication::answer(&guild.application_questions, answers)?;
+            GuildInvite::redeem(self.surreal, code).await?;
+            Member::create_pending(self.surreal, &user, &guild).await?;
+            GuildApplication::create(self.surreal, &invite.guild, &user.refer(), answers).await?;
+            return Ok(guild);
+        }
+        GuildInvite::redeem(self.surreal, code).await?;
+        Member::create(self.surreal, &user, &guild).await?;
+        self.member_lists
+            .changed(
+                self.surreal,
+                &self.relay,
+                &invite.guild,
+                MemberListChange::Member(user.refer()),
+            )
+            .await;
+        Ok(guild)
+    }
+
+    /// Needs [Permission::ManageServer].
+    pub async fn set_join_constraint(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        constraint: JoinConstraint,
+    ) -> tide::Result<Guild> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.join_constraint = constraint;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// Needs [Permission::ManageServer]. Applications already sent keep the questions they
+    /// answered.
+    pub async fn set_application_questions(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        questions: Vec<String>,
+    ) -> tide::Result<Guild> {
+        let questions = GuildApplication::check_questions(questions)?;
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.application_questions = questions;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// Needs [Permission::ManageServer].
+    pub async fn set_trust(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        trust: TrustSettings,
+    ) -> tide::Result<Guild> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.trust = trust;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// Needs [Permission::ManageServer].
+    pub async fn set_automod(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        automod: AutomodSettings,
+    ) -> tide::Result<Guild> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let before = guild.fetch(self.surreal).await?;
+        let mut guild = before.clone();
+        guild.automod = automod;
+        Ok(update_fields(self.surreal, &before, guild).await?)
+    }
+
+    /// Needs [Permission::ReviewApplications]. Oldest first.
+    pub async fn applications(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+    ) -> tide::Result<Vec<GuildApplication>> {
+        Member::require_permission(self.surreal, guild, user, Permission::ReviewApplications)
+            .await?;
+        Ok(GuildApplication::pending(self.surreal, guild).await?)
+    }
+
+    /// Needs [Permission::ReviewApplications]. Approving lets the applicant in, denying
+    /// removes their pending membership, and either way they're told with a system message,
+    /// along with `reason` if there is one.
+    pub async fn review_application(
+        &self,
+        moderator: &Ref<User>,
+        application: &Ref<GuildApplication>,
+        approve: bool,
+        reason: Option<String>,
+    ) -> async_graphql::Result<GuildApplication> {
+        let reason = GuildApplication::check_reason(reason)?;
+        let mut application = application.fetch(self.surreal).await?;
+        Member::require_permission(
+            self.surreal,
+            &application.guild,
+            moderator,
+            Permission::ReviewApplications,
+        )
+        .await?;
+        if application.status != ApplicationStatus::Pending {
+            return Err(async_graphql::Error::new(
+                "this application was already reviewed",
+            ));
+        }
+
+        let member =
+            Member::find_pending(self.surreal, &application.guild, &application.user).await?;
+        match member {
+            Some(member) if approve => {
+                limits::check_members(self.surreal, &application.guild).await?;
+                limits::check_guilds(self.surreal, &application.user).await?;
+                member.approve(self.surreal).await?;
+                self.member_lists
+                    .changed(
+                        self.surreal,
+                        &self.relay,
+                        &application.guild,
+                        MemberListChange::Member(application.user.clone()),
+                    )
+                    .await;
+            }
+            Some(member) => {
+                let _: Option<Member> = self.surreal.delete(member.record_id().0).await?;
+            }
+            None => {}
+        }
+        application.status = if approve {
+            ApplicationStatus::Approved
+        } else {
+            ApplicationStatus::Denied
+        };
+        application.reviewed_by = Some(moderator.clone());
+        application.reason = reason;
+        application.reviewed_at = Some(Datetime(Utc::now()));
+        let application = application.save(self.surreal).await?;
+
+        let guild = application.guild.fetch(self.surreal).await?;
+        let mut text = format!(
+            "Your application to join {} was {}.",
+            guild.name,
+            if approve { "approved" } else { "denied" }
+        );
+        if let Some(ref reason) = application.reason {
+            text.push_str(&format!(" Reason: {reason}"));
+        }
+        User::send_system_message(self.surreal, &self.relay, &application.user, text).await?;
+        Ok(application)
+    }
+
+    /// Needs [Permission::ManageServer]. `None` removes the image. Icons go through
+    /// [crate::storage::process_avatar_upload], splashes through
+    /// [crate::storage::process_splash_upload].
+    pub async fn set_image(
+        &self,
+        storage: &RwLock<Storage>,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+        kind: GuildImage,
+        image: Option<ProcessedAvatar>,
+    ) -> tide::Result<Guild> {
+        Member::require_permission(self.surreal, guild, user, Permission::ManageServer).await?;
+        let mut found = guild.fetch(self.surreal).await?;
+        // a new name each time, so clients and caches don't keep showing the old one
+        let path = match image {
+            Some(image) => {
+                let extension = if image.animated.is_some() {
+                    "gif"
+                } else {
+                    "png"
+                };
+                let path = format!(
+                    "{}/guild/{}-{kind}-{}.{extension}",
+                    self.storage_root,
+                    found.refer().id(),
+                    random_string(8)
+                );
+                storage
+                    .read()
+                    .await
+                    .put_image(&path, image)
+                    .await
+                    .map_err(|e| {
+                        tide::Error::new(StatusCode::InternalServerError, anyhow!("{e}"))
+                    })?;
+                Some(path)
+            }
+            None => None,
+        };
+        let before = found.clone();
+        let old = match kind {
+            GuildImage::Icon => std::mem::replace(&mut found.icon, path),
+            GuildImage::Splash => std::mem::replace(&mut found.splash, path),
+        };
+        let found = update_fields(self.surreal, &before, found).await?;
+        if let Some(old) = old {
+            match async_std::fs::remove_file(data_path(&old)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    error!("couldn't remove {old}: {e}")
+                }
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    /// The user confirms they're old enough for the NSFW channels of `guild`.
+    pub async fn verify_age(&self, guild: &Ref<Guild>, user: &Ref<User>) -> tide::Result<Member> {
+        let mut member = Member::find(self.surreal, guild, user)
+            .await?
+            .ok_or_else(|| {
+                tide::Error::new(StatusCode::Forbidden, anyhow!("not a member of this guild"))
+            })?;
+        member.age_verified = true;
+        let id = member.id.clone().unwrap();
+        let member = self.surreal.update(id).content(member).await?;
+        // NSFW channels list only the verified
+        self.member_lists
+            .changed(
+                self.surreal,
+                &self.relay,
+                guild,
+                MemberListChange::Member(user.clone()),
+            )
+            .await;
+        Ok(member)
+    }
+
+    async fn require_member(&self, guild: &Ref<Guild>, user: &Ref<User>) -> tide::Result<()> {
+        if Member::find(self.surreal, guild, user).await?.is_none() {
+            return Err(tide::Error::new(
+                StatusCode::Forbidden,
+                anyhow!("not a member of this guild"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Connects `user` to voice in `channel`, or updates what they're doing there.
+    pub async fn join_voice(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<Channel>,
+        flags: VoiceFlags,
+    ) -> tide::Result<VoiceState> {
+        let channel = channel.fetch(self.surreal).await?;
+        self.require_member(channel.guild(), user).await?;
+        Ok(VoiceState::join(self.surreal, &self.relay, user, &channel, flags).await?)
+    }
+
+    pub async fn leave_voice(&self, user: &Ref<User>) -> tide::Result<bool> {
+        Ok(VoiceState::leave(self.surreal, &self.relay, user).await?)
+    }
+
+    async fn voice_state_of(&self, user: &Ref<User>) -> tide::Result<VoiceState> {
+        VoiceState::find(self.surreal, user)
+            .await?
+            .ok_or_else(|| tide::Error::new(StatusCode::NotFound, anyhow!("user is not in voice")))
+    }
+
+    pub async fn server_mute(
+        &self,
+        moderator: &Ref<User>,
+        user: &Ref<User>,
+        muted: bool,
+    ) -> tide::Result<VoiceState> {
+        let mut state = self.voice_state_of(user).await?;
+        Member::require_permission(
+            self.surreal,
+            &state.guild,
+            moderator,
+            Permission::MuteMembers,
+        )
+        .await?;
+
+        state.server_mute = muted;
+        Ok(state.update(self.surreal, &self.relay).await?)
+    }
+
+    /// Moves someone already in voice to another channel of the same guild.
+    pub async fn move_to_channel(
+        &self,
+        moderator: &Ref<User>,
+        user: &Ref<User>,
+        channel: Ref<Channel>,
+    ) -> tide::Result<VoiceState> {
+        let mut state = self.voice_state_of(user).await?;
+        Member::require_permission(
+            self.surreal,
+            &state.guild,
+            moderator,
+            Permission::MoveMembers,
+        )
+        .await?;
+        if *channel.fetch(self.surreal).await?.guild() != state.guild {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("can't move to a channel of another guild"),
+            ));
+        }
+
+        state.channel = channel;
+        Ok(state.update(self.surreal, &self.relay).await?)
+    }
+
+    /// `ranges` of `channel`'s member list: every chunk at first, then the ones that changed.
+    /// Looking at other ranges takes subscribing again.
+    pub async fn watch_member_list(
+        &self,
+        user: &Ref<User>,
+        channel: &Ref<TextableChannel>,
+        ranges: Vec<Vec<i32>>,
+    ) -> tide::Result<impl futures_util::Stream<Item = MemberChunk>> {
+        member_list::check_ranges(&ranges)?;
+        let channel = channel.fetch(self.surreal).await?;
+        self.require_member(channel.guild(), user).await?;
+        channel.require_viewable(self.surreal, user).await?;
+
+        let surreal = self.surreal;
+        let changes = self.relay.until_closed(
+            self.relay.stream_member_list_changes(channel.guild()),
+            vec![channel.record_id().0],
+        );
+        let list = self.member_lists.watch(&channel);
+        Ok(async_stream::stream! {
+            let mut sent = vec![None; ranges.len()];
+            // a first round for the initial chunks
+            let mut changes = std::pin::pin!(futures_util::stream::once(async {}).chain(changes));
+            while changes.next().await.is_some() {
+                let chunks = match list.chunks(surreal, &ranges).await {
+                    Ok(chunks) => chunks,
+                    Err(e) => {
+                        error!("couldn't load a member list: {e}");
+                        continue;
+                    }
+                };
+                for (chunk, sent) in chunks.into_iter().zip(&mut sent) {
+                    let fingerprint = Some(chunk.fingerprint());
+                    if *sent != fingerprint {
+                        *sent = fingerprint;
+                        yield chunk;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Voice state updates in `guild`, for members only.
+    pub async fn watch_voice(
+        &self,
+        user: &Ref<User>,
+        guild: &Ref<Guild>,
+    ) -> tide::Result<impl futures_util::Stream<Item = VoiceState>> {
+        self.require_member(guild, user).await?;
+        Ok(self.relay.stream_voice_states().await)
+    }
+}
+
+/// What the guild's [JoinConstraint] asks of new members. Email addresses aren't verified on
+/// this instance, so that constraint lets everyone in for now.
+///
+/// Accounts too new for [JoinConstraint::JustRegistered] fail with `ACCOUNT_TOO_NEW`, and
+/// `retryAfter` in seconds. Accounts from before creation times were recorded are old enough.
+fn check_join_constraint(guild: &Guild, user: &User) -> async_graphql::Result<()> {
+    match guild.join_constraint {
+        JoinConstraint::JustRegistered => {
+            let Some(ref created_at) = user.created_at else {
+                return Ok(());
+            };
+            let minutes = CONFIG.just_registered_minutes;
+            let wait = created_at.0 + Duration::minutes(minutes) - Utc::now();
+            if wait <= Duration::zero() {
+                return Ok(());
+            }
+            // rounded up, so waiting that long is always enough
+            let seconds = wait.num_seconds() + 1;
+            Err(async_graphql::Error::new(format!(
+                "this guild only lets in accounts older than {minutes} minutes, try again in {}",
+                if seconds < 60 {
+                    format!("{seconds} seconds")
+                } else {
+                    format!("{} minutes", (seconds + 59) / 60)
+                }
+            ))
+            .extend_with(|_, extensions| {
+                extensions.set("code", "ACCOUNT_TOO_NEW");
+                extensions.set("retryAfter", seconds);
+            }))
+        }
+        JoinConstraint::Phone if user.phone_hash.is_none() => Err(async_graphql::Error::new(
+            "this guild only lets in accounts with a verified phone number",
+        )),
+        JoinConstraint::None
+        | JoinConstraint::VerifiedEmail
+        | JoinConstraint::Phone
+        | JoinConstraint::Application => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Thing;
+
+    use super::*;
+
+    fn guild(join_constraint: JoinConstraint) -> Guild {
+        Guild {
+            id: Thing::from(("guild", "test")),
+            name: "Test".to_owned(),
+            join_constraint,
+            retention_days: None,
+            icon: None,
+            splash: None,
+            application_questions: vec![],
+            trust: Default::default(),
+            automod: Default::default(),
+            version: 0,
+        }
+    }
+
+    fn user(created_at: Option<DateTime<Utc>>, phone_hash: Option<&str>) -> User {
+        User {
+            id: Thing::from(("user", "test")),
+            tag: ("test".to_owned(), [0; 4]),
+            display_name: "Test".to_owned(),
+            badges: vec![],
+            status: Default::default(),
+            theme: Default::default(),
+            domain: None,
+            bio: None,
+            privacy: Default::default(),
+            notifications: Default::default(),
+            bot: false,
+            accepted_terms: None,
+            created_at: created_at.map(Datetime),
+            phone_hash: phone_hash.map(str::to_owned),
+            legal_hold: false,
+            last_seen_at: None,
+            version: 0,
+        }
+    }
+
+    fn code(error: &async_graphql::Error) -> Option<String> {
+        match error.extensions.as_ref()?.get("code")? {
+            async_graphql::Value::String(code) => Some(code.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn open_guilds_let_everyone_in() {
+        let newcomer = user(Some(Utc::now()), None);
+        for constraint in [
+            JoinConstraint::None,
+            JoinConstraint::VerifiedEmail,
+            JoinConstraint::Application,
+        ] {
+            assert!(check_join_constraint(&guild(constraint), &newcomer).is_ok());
+        }
+    }
+
+    #[test]
+    fn new_accounts_wait() {
+        let guild = guild(JoinConstraint::JustRegistered);
+        let error = check_join_constraint(&guild, &user(Some(Utc::now()), None)).unwrap_err();
+        assert_eq!(code(&error).as_deref(), Some("ACCOUNT_TOO_NEW"));
+        let Some(async_graphql::Value::Number(retry_after)) = error
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get("retryAfter"))
+        else {
+            panic!("no retryAfter in {error:?}");
+        };
+        let minutes = CONFIG.just_registered_minutes;
+        assert!(retry_after.as_i64().unwrap() <= minutes * 60 + 1);
+
+        let old = Utc::now() - Duration::minutes(minutes) - Duration::seconds(1);
+        assert!(check_join_constraint(&guild, &user(Some(old), None)).is_ok());
+        // from before creation times were recorded
+        assert!(check_join_constraint(&guild, &user(None, None)).is_ok());
+    }
+
+    #[test]
+    fn phone_guilds_need_a_verified_number() {
+        let guild = guild(JoinConstraint::Phone);
+        assert!(check_join_constraint(&guild, &user(None, None)).is_err());
+        assert!(check_join_constraint(&guild, &user(None, Some("hash"))).is_ok());
+    }
+}
@@ -0,0 +1,256 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use async_graphql::connection::{Connection, EmptyFields};
+use chrono::{DateTime, Utc};
+use tide::StatusCode;
+
+use crate::{
+    federation::{self, Address},
+    gif,
+    model::{
+        automod,
+        conversation_name::ConversationName,
+        guild::{Guild, Member, Permission, TextableChannel},
+        message::{
+            Conversation, ConversationActivity, Message, MessageInit, MessageRecipient,
+            MessageRecipientIn,
+        },
+        read::{Inbox, ReadMarker, ReadState},
+        reminder::Reminder,
+        spam, trust,
+        user::User,
+    },
+    pubsub::Relay,
+    util::{RecordId, Ref, ReferrableExt},
+};
+
+#[derive(Clone)]
+pub struct MessageService {
+    pub(super) surreal: &'static crate::Surreal,
+    pub(super) relay: Arc<Relay>,
+}
+
+impl MessageService {
+    /// Most channels one `channelMessages` subscription may follow.
+    pub const MAX_WATCHED_CHANNELS: usize = 200;
+
+    pub async fn get(&self, message: &RecordId) -> tide::Result<Option<Message>> {
+        Ok(self.surreal.select(message.0.clone()).await?)
+    }
+
+    /// Stores the message, with its GIF as an embed, and publishes it to subscribers, unless
+    /// it looks like [spam](spam::check). Only members may send to channels, not applicants who
+    /// are still pending.
+    pub async fn send(&self, author: &User, init: MessageInit) -> async_graphql::Result<Message> {
+        if let MessageRecipient::Channel(channel) = init.recipient.clone().into() {
+            let channel = channel.fetch(self.surreal).await?;
+            if Member::find(self.surreal, channel.guild(), &author.refer())
+                .await?
+                .is_none()
+            {
+                return Err(tide::Error::new(
+                    StatusCode::Forbidden,
+                    anyhow!("not a member of the guild of {}", channel.record_id()),
+                )
+                .into());
+            }
+            channel
+                .require_viewable(self.surreal, &author.refer())
+                .await?;
+            trust::check_message(self.surreal, &channel, author, &init.content).await?;
+            automod::check(self.surreal, &self.relay, &channel, author, &init.content).await?;
+            if init.tts {
+                let TextableChannel::Normal(channel) = channel;
+                channel
+                    .require_permission(self.surreal, &author.refer(), Permission::SendTtsMessages)
+                    .await?;
+            }
+        }
+        spam::check(self.surreal, author, &init).await?;
+        let Some(gif) = init.gif.clone() else {
+            return Ok(author.send_message(self.surreal, &self.relay, init).await?);
+        };
+        let embed = gif::embed(&gif).await?;
+        let message = Message::create_with(self.surreal, author, init, None, vec![embed]).await?;
+        self.relay.send_message(&message).await;
+        Ok(message)
+    }
+
+    /// Sends a direct message to a `name#abcd` or `name#abcd@domain` address, delivering it
+    /// to the remote instance if needed, unless it looks like [spam](spam::check).
+    pub async fn send_direct(
+        &self,
+        author: &User,
+        to: &str,
+        content: String,
+    ) -> async_graphql::Result<Message> {
+        let to = Address::parse(to)
+            .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("invalid address")))?;
+        federation::send_direct(self.surreal, &self.relay, author, &to, content).await
+    }
+
+    /// New messages in `channels`, all of which `user` has to be able to read.
+    pub async fn watch_channels(
+        &self,
+        user: &Ref<User>,
+        channels: Vec<Ref<TextableChannel>>,
+    ) -> tide::Result<impl futures_util::Stream<Item = Message>> {
+        if channels.len() > Self::MAX_WATCHED_CHANNELS {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!(
+                    "subscriptions follow at most {} channels",
+                    Self::MAX_WATCHED_CHANNELS
+                ),
+            ));
+        }
+        for channel in &channels {
+            let found = channel.fetch(self.surreal).await?;
+            if Member::find(self.surreal, found.guild(), user)
+                .await?
+                .is_none()
+            {
+                return Err(tide::Error::new(
+                    StatusCode::Forbidden,
+                    anyhow!("not a member of the guild of {}", channel.record_id()),
+                ));
+            }
+            found.require_viewable(self.surreal, user).await?;
+        }
+        let channels: Vec<_> = channels.iter().map(|c| c.record_id().0).collect();
+        Ok(self
+            .relay
+            .until_closed(self.relay.stream_messages(channels.clone()), channels))
+    }
+
+    /// Most recently active first.
+    pub async fn conversations(
+        &self,
+        user: &User,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<i64, Conversation, EmptyFields, ConversationActivity>>
+    {
+        Conversation::all_paginate(self.surreal, user, after, before, first, last).await
+    }
+
+    pub async fn remind(
+        &self,
+        user: &Ref<User>,
+        message: &Ref<Message>,
+        at: Option<DateTime<Utc>>,
+        note: Option<String>,
+    ) -> tide::Result<Reminder> {
+        Reminder::create(self.surreal, user, message, at, note).await
+    }
+
+    pub async fn reminders(&self, user: &Ref<User>) -> tide::Result<Vec<Reminder>> {
+        Ok(Reminder::of_user(self.surreal, user).await?)
+    }
+
+    pub async fn cancel_reminder(
+        &self,
+        user: &Ref<User>,
+        reminder: &Ref<Reminder>,
+    ) -> tide::Result<bool> {
+        Ok(Reminder::cancel(self.surreal, user, reminder).await?)
+    }
+
+    /// In channels this needs [Permission::ManageMessages], in DMs either side can pin.
+    pub async fn set_pinned(
+        &self,
+        user: &Ref<User>,
+        message: &Ref<Message>,
+        pinned: bool,
+    ) -> tide::Result<Message> {
+        let found = message.fetch(self.surreal).await?;
+        match found.recipient {
+            MessageRecipient::Channel(ref channel) => {
+                let channel = channel.fetch(self.surreal).await?;
+                Member::require_permission(
+                    self.surreal,
+                    channel.guild(),
+                    user,
+                    Permission::ManageMessages,
+                )
+                .await?;
+            }
+            MessageRecipient::User(ref recipient) => {
+                if *recipient != *user && found.author != *user {
+                    return Err(tide::Error::new(
+                        StatusCode::NotFound,
+                        anyhow!("no such message"),
+                    ));
+                }
+            }
+        }
+        let pinned: Option<Message> = self
+            .surreal
+            .query("UPDATE $message SET pinned = $pinned")
+            .bind(("message", message.record_id()))
+            .bind(("pinned", pinned))
+            .await?
+            .take(0)?;
+        Ok(pinned.ok_or_else(|| anyhow!("message gone while pinning"))?)
+    }
+
+    pub async fn inbox(&self, user: &Ref<User>) -> tide::Result<Inbox> {
+        Ok(Inbox::of(self.surreal, user).await?)
+    }
+
+    pub async fn mark_read(
+        &self,
+        user: &Ref<User>,
+        with: MessageRecipientIn,
+        until: Option<Ref<Message>>,
+    ) -> tide::Result<()> {
+        let marker = Conversation(user.clone(), with.into())
+            .mark_read(self.surreal, until.as_ref())
+            .await?;
+        let state = ReadState::new([&marker.target], marker.until.0);
+        self.relay.read_state_changed(user, state).await;
+        Ok(())
+    }
+
+    /// Marks every channel of `guild` read.
+    pub async fn mark_guild_read(&self, user: &Ref<User>, guild: &Ref<Guild>) -> tide::Result<()> {
+        if Member::find(self.surreal, guild, user).await?.is_none() {
+            return Err(tide::Error::new(
+                StatusCode::NotFound,
+                anyhow!("not a member of this guild"),
+            ));
+        }
+        let targets = ReadMarker::guild_targets(self.surreal, guild).await?;
+        let state = ReadMarker::mark_all(self.surreal, user, targets).await?;
+        self.relay.read_state_changed(user, state).await;
+        Ok(())
+    }
+
+    /// Marks every channel of every guild, and every direct conversation, read.
+    pub async fn mark_all_read(&self, user: &Ref<User>) -> tide::Result<()> {
+        let targets = ReadMarker::all_targets(self.surreal, user).await?;
+        let state = ReadMarker::mark_all(self.surreal, user, targets).await?;
+        self.relay.read_state_changed(user, state).await;
+        Ok(())
+    }
+
+    /// Names a direct conversation for `user` alone. Blank names remove it.
+    pub async fn rename_conversation(
+        &self,
+        user: &Ref<User>,
+        with: MessageRecipientIn,
+        name: Option<String>,
+    ) -> tide::Result<Option<String>> {
+        let MessageRecipient::User(other) = MessageRecipient::from(with) else {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("channels are renamed for everyone with updateChannel"),
+            ));
+        };
+        other.fetch(self.surreal).await?;
+        ConversationName::set(self.surreal, user, other.record_id(), name).await
+    }
+}
@@ -0,0 +1,49 @@
+//! Business rules and persistence, shared by every transport. The GraphQL resolvers (and the
+//! REST routes) only translate their inputs and outputs to these.
+
+use std::sync::Arc;
+
+use crate::{model::member_list::MemberLists, pubsub::Relay, tenant::Tenancy};
+
+pub mod admin;
+pub mod guild;
+pub mod message;
+pub mod user;
+
+pub use self::{
+    admin::AdminService, guild::GuildService, message::MessageService, user::UserService,
+};
+
+#[derive(Clone)]
+pub struct Services {
+    pub users: UserService,
+    pub messages: MessageService,
+    pub guilds: GuildService,
+    pub admin: AdminService,
+}
+
+impl Services {
+    pub fn new(surreal: &'static crate::Surreal, relay: Arc<Relay>, tenancy: Tenancy) -> Self {
+        // shared, so status changes reach the lists guild subscriptions watch
+        let member_lists = MemberLists::default();
+        Self {
+            users: UserService {
+                surreal,
+                relay: relay.clone(),
+                seen: Default::default(),
+                member_lists: member_lists.clone(),
+            },
+            messages: MessageService {
+                surreal,
+                relay: relay.clone(),
+            },
+            guilds: GuildService {
+                surreal,
+                relay,
+                storage_root: tenancy.storage_root,
+                member_lists,
+            },
+            admin: AdminService { surreal, tenancy },
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use tide::{log::warn, StatusCode};
+
+use crate::{
+    auth::make_tag,
+    model::{
+        linked_account::{LinkedAccount, LinkedAccountKind, LinkedAccountVisibility},
+        login::Login,
+        member_list::MemberLists,
+        name_rule::NameRule,
+        quick_search::{self, QuickSearchHit},
+        security_event::SecurityEvent,
+        terms::Terms,
+        user::{parse_tag, Friend, NotificationSettings, PrivacySettings, Status, Theme, User},
+        user_note::UserNote,
+    },
+    phone,
+    pubsub::Relay,
+    util::{update_fields, Ref, ReferrableExt},
+};
+
+#[derive(Clone)]
+pub struct UserService {
+    pub(super) surreal: &'static crate::Surreal,
+    pub(super) relay: Arc<Relay>,
+    /// When each user's `last_seen_at` was last written from here.
+    pub(super) seen: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    pub(super) member_lists: MemberLists,
+}
+
+impl UserService {
+    /// Notes that `user` is around, writing it at most every [User::LAST_SEEN_MINUTES]. Only
+    /// logs failures, it's not worth failing their request over.
+    pub async fn seen(&self, user: &Ref<User>) {
+        let now = Utc::now();
+        let every = Duration::minutes(User::LAST_SEEN_MINUTES);
+        {
+            let mut seen = self.seen.lock().unwrap();
+            if seen.get(user.id()).is_some_and(|at| now - *at < every) {
+                return;
+            }
+            seen.retain(|_, at| now - *at < every);
+            seen.insert(user.id().to_owned(), now);
+        }
+        if let Err(e) = User::touch_last_seen(self.surreal, user).await {
+            warn!("couldn't update when {} was last seen: {e}", user.id());
+        }
+    }
+
+    pub async fn get(&self, user: &Ref<User>) -> tide::Result<User> {
+        Ok(user.fetch(self.surreal).await?)
+    }
+
+    /// Looks up a local user by their `name#abcd` tag.
+    pub async fn find_tag(&self, tag: &str) -> tide::Result<Option<User>> {
+        let tag = parse_tag(tag)
+            .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("invalid tag")))?;
+        User::find_tag(self.surreal, &tag).await
+    }
+
+    /// `None` if nobody has the tag.
+    pub async fn add_friend(&self, user: &User, tag: &str) -> tide::Result<Option<User>> {
+        let Some(other) = self.find_tag(tag).await? else {
+            return Ok(None);
+        };
+        Ok(Some(user.add_friend(self.surreal, other).await?))
+    }
+
+    pub async fn friends(&self, user: &User) -> tide::Result<Vec<User>> {
+        let friends = user.get_friends(self.surreal).await?;
+        Ok(friends.into_iter().map(|friend| friend.user).collect())
+    }
+
+    pub async fn relationships(&self, user: &User) -> tide::Result<Vec<Friend>> {
+        Ok(user.get_friends(self.surreal).await?)
+    }
+
+    pub async fn terms(&self) -> tide::Result<Option<Terms>> {
+        Ok(Terms::current(self.surreal).await?)
+    }
+
+    pub async fn must_accept_terms(&self, user: &User) -> tide::Result<bool> {
+        Ok(Terms::must_accept(self.surreal, user).await?)
+    }
+
+    pub async fn accept_terms(&self, user: &Ref<User>, version: u32) -> tide::Result<()> {
+        Terms::accept(self.surreal, user, Some(version)).await
+    }
+
+    pub async fn set_theme(&self, mut user: User, theme: Theme) -> tide::Result<User> {
+        let before = user.clone();
+        user.theme = theme;
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    pub async fn set_display_name(&self, mut user: User, name: String) -> tide::Result<User> {
+        let name = check_display_name(&name)?;
+        NameRule::check(self.surreal, name).await?;
+        let before = user.clone();
+        user.display_name = name.to_owned();
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    /// Texts a code to `phone` for [UserService::confirm_phone_verification].
+    pub async fn start_phone_verification(
+        &self,
+        user: &Ref<User>,
+        phone: &str,
+    ) -> tide::Result<()> {
+        phone::start(self.surreal, user, phone).await
+    }
+
+    /// Replaces whatever number `user` verified before.
+    pub async fn confirm_phone_verification(
+        &self,
+        user: &Ref<User>,
+        phone: &str,
+        code: &str,
+    ) -> tide::Result<User> {
+        phone::confirm(self.surreal, user, phone, code).await
+    }
+
+    /// Keeps the discriminator unless someone already has it with the new name.
+    pub async fn set_tag_name(&self, mut user: User, name: String) -> tide::Result<User> {
+        check_tag_name(&name)?;
+        NameRule::check(self.surreal, &name).await?;
+        let before = user.clone();
+        let taken = User::find_tag(self.surreal, &(name.clone(), user.tag.1))
+            .await?
+            .is_some_and(|other| other.id != user.id);
+        if taken {
+            let discriminator = make_tag(self.surreal, &name).await?;
+            user.tag.1 = discriminator.map(i32::from);
+        }
+        user.tag.0 = name;
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    pub async fn set_status(&self, mut user: User, status: Status) -> tide::Result<User> {
+        let before = user.clone();
+        user.status = status;
+        let user = update_fields(self.surreal, &before, user).await?;
+        self.member_lists
+            .user_changed(self.surreal, &self.relay, &user.refer())
+            .await?;
+        Ok(user)
+    }
+
+    /// Blank bios clear it.
+    pub async fn set_bio(&self, mut user: User, bio: Option<String>) -> tide::Result<User> {
+        let bio = check_bio(bio)?;
+        let before = user.clone();
+        user.bio = bio;
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    pub async fn set_privacy(
+        &self,
+        mut user: User,
+        privacy: PrivacySettings,
+    ) -> tide::Result<User> {
+        let before = user.clone();
+        user.privacy = privacy;
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    pub async fn set_notifications(
+        &self,
+        mut user: User,
+        notifications: NotificationSettings,
+    ) -> tide::Result<User> {
+        let before = user.clone();
+        user.notifications = notifications;
+        Ok(update_fields(self.surreal, &before, user).await?)
+    }
+
+    /// The newest `limit` logins, at most [`Login::RECENT`] of them.
+    pub async fn recent_logins(&self, user: &Ref<User>, limit: i64) -> tide::Result<Vec<Login>> {
+        Login::recent(self.surreal, user, limit.clamp(1, Login::RECENT)).await
+    }
+
+    /// The newest `limit` entries of their security log, at most [`SecurityEvent::MAX_PAGE`].
+    pub async fn security_events(
+        &self,
+        user: &Ref<User>,
+        limit: i64,
+    ) -> tide::Result<Vec<SecurityEvent>> {
+        SecurityEvent::of_user(self.surreal, user, limit.clamp(1, SecurityEvent::MAX_PAGE)).await
+    }
+
+    pub async fn active_logins(&self, user: &Ref<User>) -> tide::Result<Vec<Login>> {
+        Login::active(self.surreal, user).await
+    }
+
+    /// For the Ctrl-K switcher, see [quick_search].
+    pub async fn quick_search(
+        &self,
+        user: &User,
+        query: &str,
+    ) -> tide::Result<Vec<QuickSearchHit>> {
+        quick_search::search(self.surreal, user, query).await
+    }
+
+    /// What `owner` noted about `subject`.
+    pub async fn note(
+        &self,
+        owner: &Ref<User>,
+        subject: &Ref<User>,
+    ) -> tide::Result<Option<String>> {
+        let note = UserNote::get(self.surreal, owner, subject).await?;
+        Ok(note.map(|note| note.note))
+    }
+
+    /// Blank notes remove it.
+    pub async fn set_note(
+        &self,
+        owner: &Ref<User>,
+        subject: &Ref<User>,
+        note: Option<String>,
+    ) -> tide::Result<Option<String>> {
+        if owner == subject {
+            return Err(tide::Error::new(
+                StatusCode::BadRequest,
+                anyhow!("notes are about other users"),
+            ));
+        }
+        subject.fetch(self.surreal).await?;
+        let note = UserNote::set(self.surreal, owner, subject, note).await?;
+        Ok(note.map(|note| note.note))
+    }
+
+    /// `owner`'s linked accounts that `viewer` may see.
+    pub async fn connections(
+        &self,
+        owner: &User,
+        viewer: &Ref<User>,
+    ) -> tide::Result<Vec<LinkedAccount>> {
+        LinkedAccount::visible(self.surreal, owner, Some(viewer)).await
+    }
+
+    pub async fn add_connection(
+        &self,
+        user: &Ref<User>,
+        kind: LinkedAccountKind,
+        name: String,
+        visibility: LinkedAccountVisibility,
+    ) -> tide::Result<LinkedAccount> {
+        LinkedAccount::add(self.surreal, user, kind, name, visibility).await
+    }
+
+    pub async fn remove_connection(
+        &self,
+        user: &Ref<User>,
+        account: &Ref<LinkedAccount>,
+    ) -> tide::Result<bool> {
+        LinkedAccount::remove(self.surreal, user, account).await
+    }
+
+    /// Checks a website for its verification meta tag. GitHub and Steam accounts are
+    /// verified at their `verifyUrl` instead.
+    pub async fn verify_connection(
+        &self,
+        user: &Ref<User>,
+        account: &Ref<LinkedAccount>,
+    ) -> tide::Result<LinkedAccount> {
+        LinkedAccount::owned(self.surreal, user, account)
+            .await?
+            .verify_website(self.surreal)
+            .await
+    }
+}
+
+/// `name` without surrounding whitespace.
+fn check_display_name(name: &str) -> tide::Result<&str> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > User::MAX_NAME_LENGTH {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!(
+                "display names are 1 to {} characters",
+                User::MAX_NAME_LENGTH
+            ),
+        ));
+    }
+    Ok(name)
+}
+
+/// Tag names appear in mentions like `@name#1234`, so they can't contain `@`, `#` or spaces.
+fn check_tag_name(name: &str) -> tide::Result<()> {
+    if name.is_empty()
+        || name.chars().count() > User::MAX_NAME_LENGTH
+        || name.contains(['#', '@'])
+        || name.chars().any(char::is_whitespace)
+    {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("invalid tag name"),
+        ));
+    }
+    Ok(())
+}
+
+/// `None` for blank bios.
+fn check_bio(bio: Option<String>) -> tide::Result<Option<String>> {
+    if bio
+        .as_ref()
+        .is_some_and(|bio| bio.chars().count() > User::MAX_BIO_LENGTH)
+    {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("bio is too long"),
+        ));
+    }
+    Ok(bio.filter(|bio| !bio.trim().is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_names_are_trimmed() {
+        assert_eq!(check_display_name("  Alice \n").unwrap(), "Alice");
+        assert!(check_display_name("   ").is_err());
+        assert!(check_display_name("").is_err());
+    }
+
+    #[test]
+    fn display_names_are_counted_in_characters() {
+        let longest = "é".repeat(User::MAX_NAME_LENGTH);
+        assert_eq!(check_display_name(&longest).unwrap(), longest);
+        assert!(check_display_name(&format!("{longest}é")).is_err());
+    }
+
+    #[test]
+    fn tag_names_fit_in_mentions() {
+        assert!(check_tag_name("alice_2").is_ok());
+        for name in ["", "al ice", "al#ice", "@alice", "alice\t"] {
+            let error = check_tag_name(name).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BadRequest, "{name:?}");
+        }
+        assert!(check_tag_name(&"a".repeat(User::MAX_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn blank_bios_clear_it() {
+        assert_eq!(check_bio(None).unwrap(), None);
+        assert_eq!(check_bio(Some(" \n ".to_owned())).unwrap(), None);
+        assert_eq!(
+            check_bio(Some(" hi ".to_owned())).unwrap().as_deref(),
+            Some(" hi ")
+        );
+        assert!(check_bio(Some("a".repeat(User::MAX_BIO_LENGTH + 1))).is_err());
+    }
+}
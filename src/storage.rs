@@ -1,8 +1,33 @@
-use std::{collections::HashMap, io::Read, default::default};
+use std::{
+    collections::HashMap,
+    default::default,
+    fmt::Display,
+    io::{Cursor, Read, Seek, SeekFrom},
+};
 
-use crate::{model::user::User, util::Ref};
+use anyhow::anyhow;
+use async_graphql::{ErrorExtensions, SimpleObject};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    io::{Limits, Reader},
+    AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat,
+};
+
+use crate::{
+    config::{data_path, CONFIG},
+    media_proxy,
+    model::user::User,
+    util::{random_string, Ref},
+};
 
 pub struct Storage {
+    /// Where this tenant's files are, relative to the data dir, and the path they're served
+    /// under. `storage` for the default tenant.
+    root: String,
     avatars: HashMap<avatar::AvRef, avatar::Av>,
 }
 
@@ -10,7 +35,7 @@ mod avatar {
     use derive_more::Display;
 
     #[derive(Display, Debug, Clone, PartialEq, Eq)]
-    #[display(fmt = "storage/avatar/{r}.{ft}")]
+    #[display(fmt = "avatar/{r}.{ft}")]
     pub struct Av {
         pub r: AvRef,
         pub ft: AvFt,
@@ -42,12 +67,453 @@ mod avatar {
 
 use async_graphql::UploadValue;
 use async_std::{
-    fs::{create_dir_all, File},
-    path::{Path, PathBuf},
+    fs::{create_dir_all, File, OpenOptions},
+    path::Path,
 };
 pub use avatar::AvFt as AvatarFiletype;
 pub use avatar::AvK as AvatarKind;
-use futures_util::AsyncWriteExt;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+/// What an upload is for, which decides how big it may be and what it may contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadKind {
+    Avatar,
+    Attachment,
+}
+
+impl UploadKind {
+    pub fn max_size(&self) -> u64 {
+        match self {
+            Self::Avatar => CONFIG.avatar_max_size,
+            Self::Attachment => CONFIG.attachment_max_size,
+        }
+    }
+
+    pub fn allows(&self, mime: &str) -> bool {
+        let allowed = match self {
+            Self::Avatar => &CONFIG.avatar_types,
+            Self::Attachment => &CONFIG.attachment_types,
+        };
+        allowed
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == mime)
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    TooLarge { limit: u64 },
+    NotAllowed { mime: &'static str },
+    Invalid(String),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { limit } => write!(f, "upload is larger than {limit} bytes"),
+            Self::NotAllowed { mime } => write!(f, "uploads of type {mime} are not allowed here"),
+            Self::Invalid(reason) => write!(f, "upload is not a usable image: {reason}"),
+            Self::Io(e) => write!(f, "upload failed: {e}"),
+        }
+    }
+}
+
+impl From<UploadError> for tide::Error {
+    fn from(e: UploadError) -> Self {
+        let status = match e {
+            UploadError::TooLarge { .. } => tide::StatusCode::PayloadTooLarge,
+            UploadError::NotAllowed { .. } => tide::StatusCode::UnsupportedMediaType,
+            UploadError::Invalid(_) => tide::StatusCode::BadRequest,
+            UploadError::Io(_) => tide::StatusCode::InternalServerError,
+        };
+        tide::Error::new(status, anyhow!("{e}"))
+    }
+}
+
+impl ErrorExtensions for UploadError {
+    fn extend(&self) -> async_graphql::Error {
+        async_graphql::Error::new(self.to_string()).extend_with(|_, e| match self {
+            Self::TooLarge { limit } => {
+                e.set("code", "UPLOAD_TOO_LARGE");
+                e.set("limit", *limit);
+            }
+            Self::NotAllowed { mime } => {
+                e.set("code", "UPLOAD_TYPE_NOT_ALLOWED");
+                e.set("mime", *mime);
+            }
+            Self::Invalid(_) => e.set("code", "UPLOAD_INVALID"),
+            Self::Io(_) => e.set("code", "UPLOAD_FAILED"),
+        })
+    }
+}
+
+/// Guesses the type of a file from its first bytes. Whatever the client claims is ignored.
+pub fn sniff(head: &[u8]) -> &'static str {
+    match head {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => "audio/wav",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => "video/mp4",
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => "video/webm",
+        [b'O', b'g', b'g', b'S', ..] => "audio/ogg",
+        [b'I', b'D', b'3', ..] | [0xFF, 0xFB | 0xF3 | 0xF2, ..] => "audio/mpeg",
+        [b'%', b'P', b'D', b'F', ..] => "application/pdf",
+        [b'P', b'K', 3, 4, ..] => "application/zip",
+        // a multibyte char cut off at the end of the head is still text
+        head if !head.is_empty()
+            && !head.contains(&0)
+            && std::str::from_utf8(head).map_or_else(|e| e.error_len().is_none(), |_| true) =>
+        {
+            "text/plain"
+        }
+        _ => "application/octet-stream",
+    }
+}
+
+/// Rejects uploads that are too large or of a disallowed type before reading them in,
+/// returning the sniffed MIME type.
+pub fn check_upload(
+    kind: UploadKind,
+    upload: &mut UploadValue,
+) -> Result<&'static str, UploadError> {
+    let limit = kind.max_size();
+    if upload.size()? > limit {
+        return Err(UploadError::TooLarge { limit });
+    }
+
+    let mut head = [0u8; 512];
+    let read = upload.content.read(&mut head)?;
+    upload.content.seek(SeekFrom::Start(0))?;
+    let mime = sniff(&head[..read]);
+    if !kind.allows(mime) {
+        return Err(UploadError::NotAllowed { mime });
+    }
+    Ok(mime)
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stills are scaled down to this.
+const AVATAR_MAX_DIMENSION: u32 = 1024;
+/// Every frame of an animation is decoded at once, so animations are held to less.
+const ANIMATED_AVATAR_MAX_DIMENSION: u32 = 320;
+const ANIMATED_AVATAR_MAX_FRAMES: usize = 200;
+/// Invite splashes are shown large, but only as stills.
+const SPLASH_MAX_WIDTH: u32 = 1920;
+const SPLASH_MAX_HEIGHT: u32 = 1080;
+/// What uploaded images may be before they're scaled down. A small file can claim to be huge,
+/// so decoders are held to this before they allocate anything.
+const UPLOAD_MAX_DIMENSION: u32 = 8192;
+const UPLOAD_MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+fn limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(UPLOAD_MAX_DIMENSION);
+    limits.max_image_height = Some(UPLOAD_MAX_DIMENSION);
+    limits.max_alloc = Some(UPLOAD_MAX_ALLOC);
+    limits
+}
+
+/// Decodes a still within [limits], whatever format it turns out to be.
+fn decode(bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+    let mut reader = Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(limits());
+    Ok(reader.decode()?)
+}
+
+/// An avatar ready to be stored: always a PNG still, plus a GIF if the upload was animated.
+pub struct ProcessedAvatar {
+    pub still: Vec<u8>,
+    pub animated: Option<Vec<u8>>,
+}
+
+/// The frames of an animated GIF, APNG or WebP. `None` for stills, including single-frame
+/// GIFs.
+fn animation(bytes: &[u8], mime: &str) -> anyhow::Result<Option<Vec<Frame>>> {
+    let cursor = Cursor::new(bytes);
+    let (dimensions, frames) = match mime {
+        "image/gif" => {
+            let mut decoder = GifDecoder::new(cursor)?;
+            decoder.set_limits(limits())?;
+            (decoder.dimensions(), decoder.into_frames())
+        }
+        "image/png" => {
+            let mut decoder = PngDecoder::new(cursor)?;
+            decoder.set_limits(limits())?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            (decoder.dimensions(), decoder.apng().into_frames())
+        }
+        "image/webp" => {
+            let mut decoder = WebPDecoder::new(cursor)?;
+            decoder.set_limits(limits())?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            (decoder.dimensions(), decoder.into_frames())
+        }
+        _ => return Ok(None),
+    };
+    let (width, height) = dimensions;
+    if width > ANIMATED_AVATAR_MAX_DIMENSION || height > ANIMATED_AVATAR_MAX_DIMENSION {
+        return Err(anyhow!(
+            "animated avatars may be at most {ANIMATED_AVATAR_MAX_DIMENSION}x{ANIMATED_AVATAR_MAX_DIMENSION}"
+        ));
+    }
+    let frames = frames
+        .take(ANIMATED_AVATAR_MAX_FRAMES + 1)
+        .collect::<Result<Vec<_>, _>>()?;
+    if frames.len() > ANIMATED_AVATAR_MAX_FRAMES {
+        return Err(anyhow!(
+            "animated avatars may have at most {ANIMATED_AVATAR_MAX_FRAMES} frames"
+        ));
+    }
+    Ok(Some(frames).filter(|frames| frames.len() > 1))
+}
+
+fn encode_png(image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+    let mut encoded = Cursor::new(vec![]);
+    image.write_to(&mut encoded, ImageFormat::Png)?;
+    Ok(encoded.into_inner())
+}
+
+/// Decodes the upload, which also validates it, and re-encodes it: animations as a GIF with
+/// their first frame as the still, stills as a PNG. Blocks for a while on large animations.
+pub fn process_avatar(bytes: &[u8], mime: &str) -> anyhow::Result<ProcessedAvatar> {
+    if let Some(frames) = animation(bytes, mime)? {
+        let still = encode_png(&DynamicImage::ImageRgba8(frames[0].buffer().clone()))?;
+        let mut animated = vec![];
+        {
+            let mut encoder = GifEncoder::new(&mut animated);
+            encoder.set_repeat(Repeat::Infinite)?;
+            encoder.encode_frames(frames)?;
+        }
+        return Ok(ProcessedAvatar {
+            still,
+            animated: Some(animated),
+        });
+    }
+
+    let image = decode(bytes)?;
+    let image = if image.width() > AVATAR_MAX_DIMENSION || image.height() > AVATAR_MAX_DIMENSION {
+        image.thumbnail(AVATAR_MAX_DIMENSION, AVATAR_MAX_DIMENSION)
+    } else {
+        image
+    };
+    Ok(ProcessedAvatar {
+        still: encode_png(&image)?,
+        animated: None,
+    })
+}
+
+/// [process_avatar] for an upload, after checking it's allowed as an avatar.
+pub async fn process_avatar_upload(
+    mut upload: UploadValue,
+) -> Result<ProcessedAvatar, UploadError> {
+    let mime = check_upload(UploadKind::Avatar, &mut upload)?;
+    let mut bytes = vec![];
+    upload.content.read_to_end(&mut bytes)?;
+    async_std::task::spawn_blocking(move || process_avatar(&bytes, mime))
+        .await
+        .map_err(|e| UploadError::Invalid(e.to_string()))
+}
+
+/// Scales a guild's invite splash down to fit [SPLASH_MAX_WIDTH]x[SPLASH_MAX_HEIGHT] and
+/// re-encodes it as a PNG. Animations keep only their first frame.
+pub fn process_splash(bytes: &[u8]) -> anyhow::Result<ProcessedAvatar> {
+    let image = decode(bytes)?;
+    let image = if image.width() > SPLASH_MAX_WIDTH || image.height() > SPLASH_MAX_HEIGHT {
+        image.thumbnail(SPLASH_MAX_WIDTH, SPLASH_MAX_HEIGHT)
+    } else {
+        image
+    };
+    Ok(ProcessedAvatar {
+        still: encode_png(&image)?,
+        animated: None,
+    })
+}
+
+/// [process_splash] for an upload, which is held to what avatars are.
+pub async fn process_splash_upload(
+    mut upload: UploadValue,
+) -> Result<ProcessedAvatar, UploadError> {
+    check_upload(UploadKind::Avatar, &mut upload)?;
+    let mut bytes = vec![];
+    upload.content.read_to_end(&mut bytes)?;
+    async_std::task::spawn_blocking(move || process_splash(&bytes))
+        .await
+        .map_err(|e| UploadError::Invalid(e.to_string()))
+}
+
+/// Where clients load an avatar from. Clients that prefer stills, or have animations turned
+/// off, use `static_url` even when there's an `animated_url`.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct AvatarUrls {
+    pub static_url: String,
+    pub animated_url: Option<String>,
+}
+
+/// Copies `reader` into a new file at `path` one chunk at a time, so uploads never sit in
+/// memory whole. Gives up, removing the partial file, once more than `limit` bytes came through.
+async fn stream_to_file(
+    mut reader: impl AsyncRead + Unpin,
+    path: &Path,
+    limit: u64,
+) -> Result<u64, UploadError> {
+    let mut file = File::create(path).await?;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut written = 0u64;
+    loop {
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        written += read as u64;
+        if written > limit {
+            drop(file);
+            async_std::fs::remove_file(path).await?;
+            return Err(UploadError::TooLarge { limit });
+        }
+        file.write_all(&chunk[..read]).await?;
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Keeps the client's name for display, minus anything that could escape the directory.
+fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .take(100)
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => String::from("file"),
+        name => name.to_owned(),
+    }
+}
+
+/// How much of a resumable upload has arrived, see [Storage::start_upload].
+pub async fn upload_offset(path: &str) -> async_std::io::Result<u64> {
+    Ok(async_std::fs::metadata(data_path(path)).await?.len())
+}
+
+/// Appends `reader` to a resumable upload one chunk at a time, returning how much was written.
+/// What came through before the client went away is kept for it to pick up from, but going
+/// over `limit` drops the whole chunk.
+pub async fn append_upload(
+    path: &str,
+    mut reader: impl AsyncRead + Unpin,
+    limit: u64,
+) -> Result<u64, UploadError> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(data_path(path))
+        .await?;
+    let start = file.metadata().await?.len();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut written = 0u64;
+    loop {
+        let read = match reader.read(&mut chunk).await {
+            Ok(read) => read,
+            Err(e) => {
+                file.flush().await?;
+                return Err(e.into());
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        written += read as u64;
+        if written > limit {
+            file.flush().await?;
+            file.set_len(start).await?;
+            return Err(UploadError::TooLarge { limit });
+        }
+        file.write_all(&chunk[..read]).await?;
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Removes what a resumable upload left, `false` if it was finished or already gone.
+pub async fn remove_upload(path: &str) -> async_std::io::Result<bool> {
+    remove_if_exists(data_path(path)).await
+}
+
+/// `false` if there was nothing to remove.
+async fn remove_if_exists(path: impl AsRef<Path>) -> async_std::io::Result<bool> {
+    match async_std::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where [quarantine] moves an attachment, next to the `attachment` directory it's in.
+pub fn quarantined(path: &str) -> String {
+    match path.split_once("/attachment/") {
+        Some((root, rest)) => format!("{root}/quarantine/attachment_{}", rest.replace('/', "_")),
+        None => format!("storage/quarantine/{}", path.replace('/', "_")),
+    }
+}
+
+/// Moves an attachment out of the served directories, until it's scanned clean or for good if
+/// it was flagged.
+pub async fn quarantine(path: &str) -> async_std::io::Result<()> {
+    async_std::fs::rename(data_path(path), data_path(&quarantined(path))).await
+}
+
+/// Serves a [quarantine]d attachment again, once it was scanned clean.
+pub async fn release(path: &str) -> async_std::io::Result<()> {
+    async_std::fs::rename(data_path(&quarantined(path)), data_path(path)).await
+}
+
+/// Removes an attachment's file, its directory and a quarantined copy, returning how many
+/// files there were.
+pub async fn remove_attachment_files(path: &str) -> async_std::io::Result<u64> {
+    let quarantined = quarantined(path);
+    let path = data_path(path);
+    let removed = remove_if_exists(&path).await? as u64
+        + remove_if_exists(data_path(&quarantined)).await? as u64;
+    if let Some(dir) = Path::new(&path).parent() {
+        // only ever holds the one file
+        let _ = async_std::fs::remove_dir(dir).await;
+    }
+    Ok(removed)
+}
+
+/// Removes both the still and the animated avatar from under `root`, returning how many there
+/// were.
+pub async fn remove_avatar_files(
+    root: &str,
+    id: &str,
+    kind: AvatarKind,
+) -> async_std::io::Result<u64> {
+    let r = avatar::AvRef {
+        k: kind,
+        i: id.to_owned(),
+    };
+    let mut removed = 0;
+    for ft in [AvatarFiletype::Static, AvatarFiletype::Anim] {
+        let av = avatar::Av { r: r.clone(), ft };
+        removed += remove_if_exists(data_path(&format!("{root}/{av}"))).await? as u64;
+    }
+    Ok(removed)
+}
 
 async fn just_create_or_something(path: impl AsRef<Path>) -> async_std::io::Result<()> {
     if let Err(e) = create_dir_all(path).await {
@@ -60,21 +526,52 @@ async fn just_create_or_something(path: impl AsRef<Path>) -> async_std::io::Resu
 }
 
 impl Storage {
-    pub fn new() -> Self {
-        Self { avatars: default() }
+    pub fn new(root: String) -> Self {
+        Self {
+            root,
+            avatars: default(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Where `path`, relative to [Self::root], is on disk.
+    fn path(&self, path: impl Display) -> String {
+        data_path(&format!("{}/{path}", self.root))
     }
 
     pub async fn init_fs(&self) -> async_std::io::Result<()> {
-        just_create_or_something("./storage/avatar/user").await?;
-        just_create_or_something("./storage/avatar/guild").await?;
+        for dir in [
+            "avatar/user",
+            "avatar/guild",
+            "attachment",
+            "upload",
+            "quarantine",
+            "emoji",
+            "guild",
+            "transcript",
+        ] {
+            just_create_or_something(self.path(dir)).await?;
+        }
+        just_create_or_something(data_path(media_proxy::CACHE_DIR)).await?;
         Ok(())
     }
 
     pub fn tide(&self, tide: &mut tide::Server<crate::http::HttpState>) -> std::io::Result<()> {
-        let mut storage = tide.at("/storage");
+        let mut storage = tide.at(&format!("/{}", self.root));
         storage
             .at("/avatar/user")
-            .serve_dir("storage/avatar/user")?;
+            .serve_dir(self.path("avatar/user"))?;
+        storage
+            .at("/attachment")
+            .serve_dir(self.path("attachment"))?;
+        storage.at("/emoji").serve_dir(self.path("emoji"))?;
+        storage.at("/guild").serve_dir(self.path("guild"))?;
+        storage
+            .at("/transcript")
+            .serve_dir(self.path("transcript"))?;
         Ok(())
     }
 
@@ -83,41 +580,126 @@ impl Storage {
             k: kind,
             i: id,
         };
-        self.avatars.get(&r).map(ToString::to_string)
+        self.avatars.get(&r).map(|a| format!("{}/{a}", self.root))
+    }
+
+    pub fn get_avatar_urls(&self, id: String, kind: AvatarKind) -> Option<AvatarUrls> {
+        let r = avatar::AvRef { k: kind, i: id };
+        let a = self.avatars.get(&r)?;
+        let still = avatar::Av {
+            r: r.clone(),
+            ft: AvatarFiletype::Static,
+        };
+        Some(AvatarUrls {
+            static_url: format!("/{}/{still}", self.root),
+            animated_url: (a.ft == AvatarFiletype::Anim).then(|| format!("/{}/{a}", self.root)),
+        })
     }
 
     pub async fn put_avatar(
         &mut self,
         id: String,
         kind: AvatarKind,
-        avatar: Vec<u8>,
-        ft: AvatarFiletype,
-    ) -> async_std::io::Result<()> {
+        avatar: ProcessedAvatar,
+    ) -> Result<(), UploadError> {
         let r = avatar::AvRef { k: kind, i: id };
-        let a = avatar::Av {
-            ft,
+        let still = avatar::Av {
+            ft: AvatarFiletype::Static,
+            r: r.clone(),
+        };
+        let animated = avatar::Av {
+            ft: AvatarFiletype::Anim,
             r: r.clone(),
         };
 
-        let path = PathBuf::from(a.to_string());
-        let mut file = File::create(&path).await?;
-        file.write_all(&avatar).await?;
+        async_std::fs::write(self.path(&still), avatar.still).await?;
+        let a = match avatar.animated {
+            Some(gif) => {
+                async_std::fs::write(self.path(&animated), gif).await?;
+                animated
+            }
+            None => {
+                // an earlier animated one would otherwise stay reachable
+                if let Err(e) = async_std::fs::remove_file(self.path(&animated)).await {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+                still
+            }
+        };
 
         self.avatars.insert(r, a);
 
         Ok(())
     }
 
-    pub async fn put_avatar_graphql(
-        &mut self,
-        id: String,
-        kind: AvatarKind,
-        ft: AvatarFiletype,
-        upload: UploadValue,
-    ) -> async_std::io::Result<()> {
-        let mut reader = upload.into_read();
-        let mut avatar = vec![];
-        reader.read(&mut avatar)?;
-        self.put_avatar(id, kind, avatar, ft).await
+    /// Stops serving an avatar whose files were removed with [remove_avatar_files].
+    pub fn forget_avatar(&mut self, id: String, kind: AvatarKind) {
+        self.avatars.remove(&avatar::AvRef { k: kind, i: id });
+    }
+
+    /// Stores a custom emoji or a guild's icon or splash at its `path`, the GIF if it is
+    /// animated.
+    pub async fn put_image(&self, path: &str, image: ProcessedAvatar) -> Result<(), UploadError> {
+        async_std::fs::write(data_path(path), image.animated.unwrap_or(image.still)).await?;
+        Ok(())
+    }
+
+    /// Writes a [transcript](crate::model::transcript) to `<root>/transcript/<random>.html`,
+    /// returning that path. The name is all that keeps it private, like attachments.
+    pub async fn put_transcript(&self, html: &str) -> async_std::io::Result<String> {
+        let path = format!("{}/transcript/{}.html", self.root, random_string(24));
+        async_std::fs::write(data_path(&path), html).await?;
+        Ok(path)
+    }
+
+    /// Streams an attachment to `<root>/attachment/<random>/<filename>`, returning that path
+    /// along with the sniffed type and the size.
+    pub async fn put_attachment(
+        &self,
+        mut upload: UploadValue,
+    ) -> Result<(String, &'static str, u64), UploadError> {
+        let mime = check_upload(UploadKind::Attachment, &mut upload)?;
+        let dir = format!("{}/attachment/{}", self.root, random_string(16));
+        just_create_or_something(data_path(&dir)).await?;
+        let path = format!("{dir}/{}", sanitize_filename(&upload.filename));
+
+        let reader = upload.into_async_read();
+        futures_util::pin_mut!(reader);
+        let size = stream_to_file(
+            reader,
+            Path::new(&data_path(&path)),
+            UploadKind::Attachment.max_size(),
+        )
+        .await?;
+        Ok((path, mime, size))
+    }
+
+    /// An empty file under `<root>/upload`, which isn't served, for [append_upload] to fill.
+    pub async fn start_upload(&self) -> async_std::io::Result<String> {
+        let path = format!("{}/upload/{}", self.root, random_string(16));
+        File::create(data_path(&path)).await?;
+        Ok(path)
+    }
+
+    /// Checks what a finished resumable upload turned out to be, and moves it to where
+    /// [Self::put_attachment] would have put it. Returns that path and the sniffed type.
+    pub async fn finish_upload(
+        &self,
+        upload: &str,
+        filename: &str,
+    ) -> Result<(String, &'static str), UploadError> {
+        let mut head = [0u8; 512];
+        let read = File::open(data_path(upload)).await?.read(&mut head).await?;
+        let mime = sniff(&head[..read]);
+        if !UploadKind::Attachment.allows(mime) {
+            return Err(UploadError::NotAllowed { mime });
+        }
+        let dir = format!("{}/attachment/{}", self.root, random_string(16));
+        just_create_or_something(data_path(&dir)).await?;
+        let path = format!("{dir}/{}", sanitize_filename(filename));
+        async_std::fs::rename(data_path(upload), data_path(&path)).await?;
+        Ok((path, mime))
     }
 }
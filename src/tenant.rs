@@ -0,0 +1,266 @@
+//! Several isolated chat instances in one process, say one per customer. Each tenant has its
+//! own SurrealDB namespace, its own files under `storage/tenants/<name>`, and its own relay,
+//! services and jobs. The GraphQL schema is shared, what a request works with comes from the
+//! tenant it was resolved to.
+//!
+//! Requests go to the tenant whose host they were sent to. On any other host, one listed
+//! nowhere, the `tenant` claim of the access token decides, and without a token it's the
+//! default tenant: namespace `netherite`, files under `storage`, which is all there is while
+//! `NETHERITE_CHAT_TENANTS` is unset.
+
+use std::{collections::HashMap, env, fmt::Debug, sync::Arc};
+
+use anyhow::anyhow;
+use async_std::sync::RwLock;
+use surrealdb::{engine::remote::ws, opt::auth::Root};
+use tide::{log::info, Request, StatusCode};
+
+use crate::{
+    auth::{self, Claims_},
+    config::CONFIG,
+    http::{HttpState, SURREAL},
+    jobs,
+    model::{message::Conversation, migration},
+    pubsub::Relay,
+    service::Services,
+    storage::Storage,
+};
+
+/// The name of the tenant requests go to when nothing says otherwise.
+pub const DEFAULT: &str = "default";
+
+/// Where a tenant's data lives, so it never mixes with another's.
+#[derive(Debug, Clone, Copy)]
+pub struct Tenancy {
+    pub name: &'static str,
+    /// The SurrealDB namespace, the database is always `chat`.
+    pub namespace: &'static str,
+    /// See [Storage::root].
+    pub storage_root: &'static str,
+    /// Where its backups go, relative to the data dir.
+    pub backup_dir: &'static str,
+}
+
+impl Tenancy {
+    fn new(name: &str) -> Self {
+        let leak = |s: String| -> &'static str { Box::leak(s.into_boxed_str()) };
+        if name == DEFAULT {
+            return Self {
+                name: DEFAULT,
+                namespace: "netherite",
+                storage_root: "storage",
+                backup_dir: leak(CONFIG.backup_dir.clone()),
+            };
+        }
+        Self {
+            name: leak(name.to_owned()),
+            namespace: leak(format!("netherite_{name}")),
+            storage_root: leak(format!("storage/tenants/{name}")),
+            backup_dir: leak(format!("{}/{name}", CONFIG.backup_dir)),
+        }
+    }
+}
+
+pub struct Tenant {
+    pub tenancy: Tenancy,
+    surreal: &'static crate::Surreal,
+    pub relay: Arc<Relay>,
+    pub storage: Arc<RwLock<Storage>>,
+    pub services: Services,
+}
+
+impl Debug for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tenant")
+            .field("tenancy", &self.tenancy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Tenant {
+    /// Connects to the tenant's namespace and brings it up to date. Tenants live as long as
+    /// the process, so they're leaked like [SURREAL] is static.
+    async fn connect(
+        tenancy: Tenancy,
+        surreal: &'static crate::Surreal,
+    ) -> tide::Result<&'static Self> {
+        surreal
+            .connect::<ws::Ws>(env::var("NETHERITE_CHAT_SURREALDB_URL")?)
+            .await?;
+        surreal
+            .signin(Root {
+                username: "root",
+                password: "root",
+            })
+            .await?;
+        surreal.use_ns(tenancy.namespace).use_db("chat").await?;
+        migration::run(surreal).await?;
+        Conversation::materialize(surreal).await?;
+
+        let relay = Arc::new(Relay::new());
+        let storage = Storage::new(tenancy.storage_root.to_owned());
+        storage.init_fs().await?;
+        let services = Services::new(surreal, relay.clone(), tenancy);
+        info!("tenant {} on namespace {}", tenancy.name, tenancy.namespace);
+        Ok(Box::leak(Box::new(Self {
+            tenancy,
+            surreal,
+            relay,
+            storage: Arc::new(RwLock::new(storage)),
+            services,
+        })))
+    }
+
+    pub fn surreal(&self) -> &'static crate::Surreal {
+        self.surreal
+    }
+
+    /// What goes in the `tenant` claim of tokens issued here. Not there for the default
+    /// tenant, so tokens from before there were tenants keep working.
+    pub fn claim(&self) -> Option<String> {
+        (self.tenancy.name != DEFAULT).then(|| self.tenancy.name.to_owned())
+    }
+
+    fn owns(&self, claims: &Claims_) -> bool {
+        claims.tenant.as_deref().unwrap_or(DEFAULT) == self.tenancy.name
+    }
+
+    /// What the GraphQL resolvers reach through [crate::util::Cx].
+    pub fn insert_data(&self, data: &mut async_graphql::Data) {
+        data.insert(self.relay.clone());
+        data.insert(self.storage.clone());
+        data.insert(self.services.clone());
+    }
+
+    pub fn start_jobs(&self) {
+        jobs::registry(self.surreal, self.relay.clone(), self.tenancy)
+            .start(self.surreal, CONFIG.job_workers);
+    }
+}
+
+pub struct Tenants {
+    default: &'static Tenant,
+    all: Vec<&'static Tenant>,
+    hosts: HashMap<String, &'static Tenant>,
+    /// The first host listed for each tenant, by name, what links mailed out point to.
+    primary_hosts: HashMap<&'static str, String>,
+}
+
+impl Tenants {
+    /// The default tenant and every one in `NETHERITE_CHAT_TENANTS`, as `name=host` pairs. A
+    /// tenant can be listed more than once to have more hosts.
+    pub async fn connect() -> tide::Result<&'static Self> {
+        let default = Tenant::connect(Tenancy::new(DEFAULT), &SURREAL).await?;
+        let mut tenants = Self {
+            default,
+            all: vec![default],
+            hosts: HashMap::new(),
+            primary_hosts: HashMap::new(),
+        };
+        for entry in &CONFIG.tenants {
+            let (name, host) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{entry} in NETHERITE_CHAT_TENANTS isn't name=host"))?;
+            let (name, host) = (name.trim(), host.trim().to_lowercase());
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            {
+                return Err(anyhow!("tenant names are lowercase letters, digits and _").into());
+            }
+            let tenant = match tenants.named(name) {
+                Some(tenant) => tenant,
+                None => {
+                    let surreal = Box::leak(Box::new(crate::Surreal::init()));
+                    let tenant = Tenant::connect(Tenancy::new(name), surreal).await?;
+                    tenants.all.push(tenant);
+                    tenant
+                }
+            };
+            if tenants.hosts.insert(host.clone(), tenant).is_some() {
+                return Err(anyhow!("{host} is in NETHERITE_CHAT_TENANTS twice").into());
+            }
+            tenants
+                .primary_hosts
+                .entry(tenant.tenancy.name)
+                .or_insert(host);
+        }
+        Ok(Box::leak(Box::new(tenants)))
+    }
+
+    pub fn default(&self) -> &'static Tenant {
+        self.default
+    }
+
+    pub fn all(&self) -> &[&'static Tenant] {
+        &self.all
+    }
+
+    /// Where `tenant` is reached, its first host in `NETHERITE_CHAT_TENANTS` or else
+    /// `NETHERITE_CHAT_DOMAIN`. Never the `Host` a request came with, anyone can send any.
+    pub fn host(&self, tenant: &Tenant) -> &str {
+        self.primary_hosts
+            .get(tenant.tenancy.name)
+            .map_or(&CONFIG.domain, |host| host)
+    }
+
+    fn named(&self, name: &str) -> Option<&'static Tenant> {
+        self.all
+            .iter()
+            .copied()
+            .find(|tenant| tenant.tenancy.name == name)
+    }
+
+    /// The tenant of `host`, or the one `claims` were issued by. Refuses tokens another
+    /// tenant issued, their records aren't in this tenant's namespace.
+    pub fn resolve(
+        &self,
+        host: Option<&str>,
+        claims: Option<&Claims_>,
+    ) -> tide::Result<&'static Tenant> {
+        let host = host.map(|host| {
+            // the port, not part of an IPv6 address
+            host.rsplit_once(':')
+                .filter(|(_, port)| port.parse::<u16>().is_ok())
+                .map_or(host, |(host, _)| host)
+                .to_lowercase()
+        });
+        let tenant = match (host.and_then(|host| self.hosts.get(&host)), claims) {
+            (Some(tenant), _) => *tenant,
+            (None, Some(claims)) => self
+                .named(claims.tenant.as_deref().unwrap_or(DEFAULT))
+                .ok_or_else(|| {
+                    tide::Error::new(StatusCode::Unauthorized, anyhow!("unknown tenant"))
+                })?,
+            (None, None) => self.default,
+        };
+        match claims {
+            Some(claims) if !tenant.owns(claims) => Err(tide::Error::new(
+                StatusCode::Unauthorized,
+                anyhow!("this token belongs to another tenant"),
+            )),
+            _ => Ok(tenant),
+        }
+    }
+}
+
+/// A `test` tenant of its own for tests against the database, so they never touch the
+/// default tenant's data.
+#[cfg(test)]
+pub async fn connect_test() -> tide::Result<&'static Tenant> {
+    let surreal = Box::leak(Box::new(crate::Surreal::init()));
+    Tenant::connect(Tenancy::new("test"), surreal).await
+}
+
+/// The tenant a request is for, by its host or the access token it came with.
+pub fn of(request: &Request<HttpState>) -> tide::Result<&'static Tenant> {
+    let claims = request
+        .header("Authorization")
+        .and_then(|header| header.as_str().strip_prefix("Bearer "))
+        .and_then(auth::access_claims);
+    request
+        .state()
+        .tenants
+        .resolve(request.host(), claims.as_ref())
+}
@@ -0,0 +1,258 @@
+//! Machine translation of messages, for `Message.translated`. Deployments pick a provider with
+//! `NETHERITE_CHAT_TRANSLATION`, there is none by default. Results are kept per message and
+//! locale, and only asked for again once the message was edited.
+
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::anyhow;
+use async_graphql::{InputValueError, InputValueResult, Scalar, ScalarType, Value};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use surrealdb::sql::Datetime;
+use tide::StatusCode;
+
+use crate::{
+    config::CONFIG,
+    model::message::Message,
+    util::{Ref, ReferrableExt, ReferrableWithId},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Translation {
+    None,
+    DeepL,
+    LibreTranslate,
+}
+
+impl FromStr for Translation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "deepl" => Ok(Self::DeepL),
+            "libretranslate" => Ok(Self::LibreTranslate),
+            _ => Err(anyhow!("unknown translation provider {s}")),
+        }
+    }
+}
+
+/// A language to translate to, like `de` or `pt-BR`: an ISO 639 language code, optionally
+/// followed by a region.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Locale {
+    pub language: String,
+    pub region: Option<String>,
+}
+
+impl FromStr for Locale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (language, region) = match s.split_once(['-', '_']) {
+            Some((language, region)) => (language, Some(region)),
+            None => (s, None),
+        };
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return Err(anyhow!("{s} isn't a locale"));
+        }
+        if region.is_some_and(|region| {
+            !(2..=4).contains(&region.len()) || !region.chars().all(|c| c.is_ascii_alphanumeric())
+        }) {
+            return Err(anyhow!("{s} isn't a locale"));
+        }
+        Ok(Self {
+            language: language.to_lowercase(),
+            region: region.map(|region| region.to_uppercase()),
+        })
+    }
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.region {
+            Some(ref region) => write!(f, "{}-{region}", self.language),
+            None => write!(f, "{}", self.language),
+        }
+    }
+}
+
+#[Scalar]
+impl ScalarType for Locale {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match value {
+            Value::String(ref s) => s.parse().map_err(InputValueError::custom),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+#[async_trait]
+pub trait Translator: Send + Sync {
+    /// `text` in whatever language it's in, the provider works that out.
+    async fn translate(&self, text: &str, to: &Locale) -> tide::Result<String>;
+}
+
+/// The DeepL API. Free plan keys end in `:fx` and go to the free API.
+pub struct DeepL {
+    pub key: String,
+    pub url: Option<String>,
+}
+
+#[async_trait]
+impl Translator for DeepL {
+    async fn translate(&self, text: &str, to: &Locale) -> tide::Result<String> {
+        #[derive(Serialize)]
+        struct Translate<'a> {
+            text: [&'a str; 1],
+            target_lang: String,
+        }
+        #[derive(Deserialize)]
+        struct Translated {
+            translations: Vec<Text>,
+        }
+        #[derive(Deserialize)]
+        struct Text {
+            text: String,
+        }
+
+        let url = match self.url {
+            Some(ref url) => url.trim_end_matches('/'),
+            None if self.key.ends_with(":fx") => "https://api-free.deepl.com",
+            None => "https://api.deepl.com",
+        };
+        let mut response = surf::post(format!("{url}/v2/translate"))
+            .header("Authorization", format!("DeepL-Auth-Key {}", self.key))
+            .body_json(&Translate {
+                text: [text],
+                // DeepL only knows regions for some languages, like EN-GB and PT-BR
+                target_lang: to.to_string().to_uppercase(),
+            })?
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("DeepL answered {}", response.status()).into());
+        }
+        let translated: Translated = response.body_json().await?;
+        translated
+            .translations
+            .into_iter()
+            .next()
+            .map(|translation| translation.text)
+            .ok_or_else(|| tide::Error::from(anyhow!("DeepL returned no translation")))
+    }
+}
+
+/// A LibreTranslate instance, self-hosted or not. Those only go by language.
+pub struct LibreTranslate {
+    pub url: String,
+    pub key: Option<String>,
+}
+
+#[async_trait]
+impl Translator for LibreTranslate {
+    async fn translate(&self, text: &str, to: &Locale) -> tide::Result<String> {
+        #[derive(Serialize)]
+        struct Translate<'a> {
+            q: &'a str,
+            source: &'static str,
+            target: &'a str,
+            format: &'static str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            api_key: Option<&'a str>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Translated {
+            translated_text: String,
+        }
+
+        let mut response = surf::post(format!("{}/translate", self.url.trim_end_matches('/')))
+            .body_json(&Translate {
+                q: text,
+                source: "auto",
+                target: &to.language,
+                format: "text",
+                api_key: self.key.as_deref(),
+            })?
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("LibreTranslate answered {}", response.status()).into());
+        }
+        let translated: Translated = response.body_json().await?;
+        Ok(translated.translated_text)
+    }
+}
+
+lazy_static::lazy_static! {
+    /// `None` unless a provider is configured, with what it needs.
+    pub static ref TRANSLATOR: Option<Box<dyn Translator>> = match CONFIG.translation {
+        Translation::None => None,
+        Translation::DeepL => CONFIG.translation_key.clone().map(|key| {
+            let url = CONFIG.translation_url.clone();
+            Box::new(DeepL { key, url }) as Box<dyn Translator>
+        }),
+        Translation::LibreTranslate => CONFIG.translation_url.clone().map(|url| {
+            let key = CONFIG.translation_key.clone();
+            Box::new(LibreTranslate { url, key }) as Box<dyn Translator>
+        }),
+    };
+}
+
+/// A message translated once, `translation:⟨<message>-<locale>⟩`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Translated {
+    message: Ref<Message>,
+    locale: String,
+    /// Of the content that was translated, it's stale once this doesn't match.
+    source: String,
+    content: String,
+    at: Datetime,
+}
+
+fn source_hash(content: &str) -> String {
+    format!("{:x}", Sha1::digest(content.as_bytes()))
+}
+
+/// `message` in `to`, from the cache if it was translated before.
+pub async fn translate(
+    surreal: &crate::Surreal,
+    message: &Message,
+    to: &Locale,
+) -> tide::Result<String> {
+    let Some(ref translator) = *TRANSLATOR else {
+        return Err(tide::Error::new(
+            StatusCode::NotImplemented,
+            anyhow!("translation isn't set up on this instance"),
+        ));
+    };
+    if message.content.trim().is_empty() {
+        return Ok(String::new());
+    }
+
+    let id = ("translation", format!("{}-{to}", message.id()));
+    let source = source_hash(&message.content);
+    let cached: Option<Translated> = surreal.select(id.clone()).await?;
+    if let Some(cached) = cached.filter(|cached| cached.source == source) {
+        return Ok(cached.content);
+    }
+
+    let content = translator.translate(&message.content, to).await?;
+    let _: Option<Translated> = surreal
+        .update(id)
+        .content(Translated {
+            message: message.refer(),
+            locale: to.to_string(),
+            source,
+            content: content.clone(),
+            at: Datetime(Utc::now()),
+        })
+        .await?;
+    Ok(content)
+}
@@ -0,0 +1,202 @@
+//! Resumable attachment uploads over [tus](https://tus.io/protocols/resumable-upload) 1.0,
+//! with the creation and termination extensions. Finished uploads become attachments like
+//! `uploadAttachment` makes, their id in the `Attachment-Id` header of the last `PATCH`.
+
+use std::collections::HashMap;
+
+use base64::Engine;
+use tide::{Request, Response, StatusCode};
+
+use crate::{
+    auth,
+    http::{HttpState, State},
+    model::{
+        attachment::AttachmentInit, guild::TextableChannel, token::Scope, upload::UploadSession,
+    },
+    storage::UploadKind,
+    tenant::Tenant,
+    util::{RecordId, Ref, Referrable, ReferrableExt},
+};
+
+pub const PATH: &str = "/api/v1/uploads";
+const VERSION: &str = "1.0.0";
+
+pub fn routes(tide: &mut tide::Server<HttpState>) {
+    tide.at(PATH)
+        .options(http_options)
+        .with(auth::make_tide_authware())
+        .post(http_create);
+    tide.at(&format!("{PATH}/:id"))
+        .with(auth::make_tide_authware())
+        .head(http_offset)
+        .patch(http_append)
+        .delete(http_cancel);
+}
+
+fn response(status: StatusCode) -> Response {
+    let mut response = Response::new(status);
+    response.insert_header("Tus-Resumable", VERSION);
+    response
+}
+
+/// Clients have to say they speak the version this does, `None` if they did.
+fn version_mismatch(request: &Request<HttpState>) -> Option<Response> {
+    if request
+        .header("Tus-Resumable")
+        .is_some_and(|version| version.as_str() == VERSION)
+    {
+        return None;
+    }
+    let mut response = Response::new(StatusCode::PreconditionFailed);
+    response.insert_header("Tus-Version", VERSION);
+    Some(response)
+}
+
+fn header_u64(request: &Request<HttpState>, name: &str) -> tide::Result<u64> {
+    request
+        .header(name)
+        .and_then(|value| value.as_str().parse().ok())
+        .ok_or_else(|| tide::Error::from_str(StatusCode::BadRequest, format!("missing {name}")))
+}
+
+/// `Upload-Metadata` is comma separated `key value` pairs, the values base64.
+fn metadata(request: &Request<HttpState>) -> tide::Result<HashMap<String, String>> {
+    let Some(header) = request.header("Upload-Metadata") else {
+        return Ok(HashMap::new());
+    };
+    header
+        .as_str()
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(|| {
+                    tide::Error::from_str(
+                        StatusCode::BadRequest,
+                        format!("{key} in Upload-Metadata isn't base64 UTF-8"),
+                    )
+                })?;
+            Ok((key.to_owned(), value))
+        })
+        .collect()
+}
+
+/// The session in the path, if it's the caller's.
+async fn session(request: &Request<HttpState>) -> tide::Result<(UploadSession, &'static Tenant)> {
+    let state = State::of(request).await?;
+    let user = state.ref_user()?;
+    let session = UploadSession::of(state.surreal(), &user, request.param("id")?).await?;
+    Ok((session, state.tenant))
+}
+
+async fn http_options(_: Request<HttpState>) -> tide::Result {
+    let mut response = response(StatusCode::NoContent);
+    response.insert_header("Tus-Version", VERSION);
+    response.insert_header("Tus-Extension", "creation,termination");
+    response.insert_header(
+        "Tus-Max-Size",
+        UploadKind::Attachment.max_size().to_string(),
+    );
+    Ok(response)
+}
+
+/// Opens an upload of `Upload-Length` bytes. `Upload-Metadata` has its `filename`, and
+/// optionally the `channel` it's for, its `alt` text, and `voice` for voice messages.
+async fn http_create(request: Request<HttpState>) -> tide::Result {
+    if let Some(mismatch) = version_mismatch(&request) {
+        return Ok(mismatch);
+    }
+    let state = State::of(&request).await?;
+    state.require_scope(Scope::MessagesSend)?;
+    let user = state.user().await?;
+    let length = header_u64(&request, "Upload-Length")?;
+    let mut metadata = metadata(&request)?;
+    let channel = metadata
+        .get("channel")
+        .map(|id| RecordId::from_gql(id, Some(TextableChannel::TABLE)))
+        .transpose()
+        .map_err(|e| tide::Error::new(StatusCode::BadRequest, e))?
+        .map(|id| Ref::new_owned(id.id()));
+    let tenant = state.tenant;
+    let session = UploadSession::create(
+        tenant.surreal(),
+        &*tenant.storage.read().await,
+        &user,
+        metadata.remove("filename").unwrap_or_default(),
+        length,
+        AttachmentInit {
+            channel,
+            alt: metadata.remove("alt"),
+            voice: metadata.contains_key("voice"),
+        },
+    )
+    .await?;
+
+    let mut response = response(StatusCode::Created);
+    response.insert_header("Location", format!("{PATH}/{}", session.refer().id()));
+    Ok(response)
+}
+
+/// How far along the upload is, to resume from or show progress with.
+async fn http_offset(request: Request<HttpState>) -> tide::Result {
+    let (session, _) = session(&request).await?;
+    let mut response = response(StatusCode::Ok);
+    response.insert_header("Upload-Offset", session.offset().await?.to_string());
+    response.insert_header("Upload-Length", session.length.to_string());
+    response.insert_header("Cache-Control", "no-store");
+    Ok(response)
+}
+
+/// Appends the body at `Upload-Offset`, which has to be where the upload is at. The chunk
+/// that completes it makes the attachment.
+async fn http_append(mut request: Request<HttpState>) -> tide::Result {
+    if let Some(mismatch) = version_mismatch(&request) {
+        return Ok(mismatch);
+    }
+    if !request
+        .content_type()
+        .is_some_and(|mime| mime.essence() == "application/offset+octet-stream")
+    {
+        return Err(tide::Error::from_str(
+            StatusCode::UnsupportedMediaType,
+            "chunks are application/offset+octet-stream",
+        ));
+    }
+    let (session, tenant) = session(&request).await?;
+    let offset = header_u64(&request, "Upload-Offset")?;
+    let writing = session.writing()?;
+    let offset = session
+        .append(&writing, offset, request.take_body())
+        .await?;
+
+    let mut response = response(StatusCode::NoContent);
+    response.insert_header("Upload-Offset", offset.to_string());
+    if offset == session.length {
+        let owner = session.owner.fetch(tenant.surreal()).await?;
+        let attachment = session
+            .finish(
+                tenant.surreal(),
+                &tenant.relay,
+                &*tenant.storage.read().await,
+                &owner,
+                &writing,
+            )
+            .await?;
+        response.insert_header("Attachment-Id", attachment.record_id().to_gql());
+    }
+    Ok(response)
+}
+
+async fn http_cancel(request: Request<HttpState>) -> tide::Result {
+    if let Some(mismatch) = version_mismatch(&request) {
+        return Ok(mismatch);
+    }
+    let (session, tenant) = session(&request).await?;
+    session.cancel(tenant.surreal()).await?;
+    Ok(response(StatusCode::NoContent))
+}
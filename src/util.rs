@@ -5,10 +5,11 @@ use std::{
     fmt::Display,
     marker::PhantomData,
     str::FromStr, future::IntoFuture, pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use async_graphql::{
-    InputObjectType, InputType, InputValueError, InputValueResult, ScalarType, ID,
+    InputObjectType, InputType, InputValueError, InputValueResult, Scalar, ScalarType, ID,
 };
 use async_std::sync::RwLock;
 use chrono::{DateTime, Duration, Utc};
@@ -17,9 +18,12 @@ use serde::{de::DeserializeOwned, de::Error, Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::{serde_as, DurationSeconds as DurateSeconds, TimestampMilliSeconds};
 use surrealdb::sql::{thing, Id, Thing};
-use tide::log::error;
+use tide::log::{error, warn};
 
-use crate::{pubsub::Relay, storage::Storage};
+use crate::{config::CONFIG, pubsub::Relay, service::Services, storage::Storage};
+
+pub mod fetch;
+pub mod query;
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -72,14 +76,109 @@ impl RecordId {
         self.0.id.to_raw()
     }
 
+    /// How clients see it: `table:id`, never escaped.
+    pub fn to_gql(&self) -> String {
+        format!("{}:{}", self.0.tb, self.id())
+    }
+
+    /// Reads what a client sent, `table:id`. Ids of another table than `table` are refused.
+    /// While [legacy ids](crate::config::Config::legacy_ids) are on just the id is taken too
+    /// where `table` is known, and counted in [LEGACY_IDS].
+    pub fn from_gql(value: &str, table: Option<&str>) -> anyhow::Result<Self> {
+        match (value.split_once(':'), table) {
+            (Some((tb, _)), Some(table)) if tb != table => Err(anyhow::anyhow!(
+                "expected the id of a {table}, got one of a {tb}"
+            )),
+            (Some((tb, id)), _) if !tb.is_empty() && !id.is_empty() => Ok(Self::new(tb, id)),
+            (None, Some(table)) if !value.is_empty() && CONFIG.legacy_ids => {
+                legacy_id_used(value);
+                Ok(Self::new(table, value))
+            }
+            _ => Err(anyhow::anyhow!(
+                "expected an id like table:id, got {value:?}"
+            )),
+        }
+    }
+
     pub async fn fetch<T: DeserializeOwned + Send + Sync>(
         &self,
         surreal: &crate::Surreal,
     ) -> surrealdb::Result<T> {
+        query::count_query();
         surreal.select(self.0.clone()).await
     }
 }
 
+/// Legacy ids taken since startup, so operators can tell when turning them off won't break
+/// anyone. On `/metrics` as `netherite_legacy_ids_total`.
+pub static LEGACY_IDS: AtomicU64 = AtomicU64::new(0);
+
+/// How often at most a legacy id is logged, however many come in.
+const LEGACY_ID_WARN_SECONDS: u64 = 60;
+
+fn legacy_id_used(value: &str) {
+    static LAST_WARNED: AtomicU64 = AtomicU64::new(0);
+
+    let used = LEGACY_IDS.fetch_add(1, Ordering::Relaxed) + 1;
+    let now = Utc::now().timestamp() as u64;
+    let last = LAST_WARNED.load(Ordering::Relaxed);
+    if now >= last + LEGACY_ID_WARN_SECONDS
+        && LAST_WARNED
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        warn!("got the legacy id {value:?}, {used} since startup, clients should send table:id");
+    }
+}
+
+/// Ids of any table, always `table:id` when sent out. See [RecordId::from_gql] for what's
+/// taken in.
+#[Scalar(name = "RecordId")]
+impl ScalarType for RecordId {
+    fn parse(value: async_graphql::Value) -> InputValueResult<Self> {
+        match value {
+            async_graphql::Value::String(ref s) => {
+                Self::from_gql(s, None).map_err(InputValueError::custom)
+            }
+            value => Err(InputValueError::expected_type(value)),
+        }
+    }
+
+    fn to_value(&self) -> async_graphql::Value {
+        async_graphql::Value::String(self.to_gql())
+    }
+}
+
+/// A point in time, RFC 3339 with the timezone on the way out and required on the way in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+#[Scalar(name = "DateTime")]
+impl ScalarType for Timestamp {
+    fn parse(value: async_graphql::Value) -> InputValueResult<Self> {
+        match value {
+            async_graphql::Value::String(ref s) => DateTime::parse_from_rfc3339(s)
+                .map(|at| Self(at.with_timezone(&Utc)))
+                .map_err(|e| {
+                    InputValueError::custom(format!(
+                        "{e}, expected RFC 3339 with a timezone like 2023-05-01T12:00:00Z"
+                    ))
+                }),
+            value => Err(InputValueError::expected_type(value)),
+        }
+    }
+
+    fn to_value(&self) -> async_graphql::Value {
+        async_graphql::Value::String(self.0.to_rfc3339())
+    }
+}
+
+impl From<Timestamp> for surrealdb::sql::Datetime {
+    fn from(value: Timestamp) -> Self {
+        Self(value.0)
+    }
+}
+
 impl TryFrom<ID> for RecordId {
     type Error = <Self as FromStr>::Err;
 
@@ -142,14 +241,12 @@ pub trait ReferrableExt: ReferrableWithId {
     fn refer(&self) -> Ref<Self>;
     fn record_id(&self) -> RecordId;
 
-    async fn save(&self, surreal: &crate::Surreal) -> surrealdb::Result<Self>
+    async fn save(&self, surreal: &crate::Surreal) -> tide::Result<Self>
     where
         Self: Serialize + Sized + DeserializeOwned + Send + Sync,
         Self::Id: AsRef<str>;
 
-    fn gql_id_just(&self) -> ID;
-
-    fn gql_id(&self) -> ID;
+    fn gql_id(&self) -> RecordId;
 }
 
 impl<R: ReferrableWithId<Id: AsRef<str> + for<'s> From<&'s str>>> ReferrableExt for R {
@@ -161,22 +258,95 @@ impl<R: ReferrableWithId<Id: AsRef<str> + for<'s> From<&'s str>>> ReferrableExt
         RecordId((Self::TABLE.to_owned(), self.id().as_ref().to_owned()).into())
     }
 
-    async fn save(&self, surreal: &crate::Surreal) -> surrealdb::Result<Self>
+    /// Replaces the whole record, so anything not in `Self` is dropped.
+    default async fn save(&self, surreal: &crate::Surreal) -> tide::Result<Self>
     where
         Self: Serialize + Sized + DeserializeOwned + Send + Sync,
     {
-        surreal.update(self.record_id().0).content(self).await
+        query::count_query();
+        Ok(surreal.update(self.record_id().0).content(self).await?)
     }
 
-    // difference from gql_id: gives only the ID, without the table
-    default fn gql_id_just(&self) -> ID {
-        ID::from(self.id().as_ref())
+    default fn gql_id(&self) -> RecordId {
+        self.record_id()
     }
+}
 
-    // difference from just: includes the table
-    default fn gql_id(&self) -> ID {
-        ID(format!("{}:{}", Self::TABLE, self.id().as_ref()))
-    }
+/// Records that count how often they were saved, so saving can refuse to overwrite changes
+/// made since they were read. See [save_versioned].
+pub trait Versioned {
+    fn version(&self) -> u64;
+    fn set_version(&mut self, version: u64);
+}
+
+/// What [ReferrableExt::save] does for [Versioned] records: writes `record` with its version
+/// bumped, unless someone else saved it first, which fails with `409 Conflict`. `merge` keeps
+/// fields of the record that `T` doesn't have.
+pub async fn save_versioned<T>(surreal: &crate::Surreal, record: &T, merge: bool) -> tide::Result<T>
+where
+    T: ReferrableExt + Versioned + Clone + Serialize + DeserializeOwned,
+{
+    let mut next = record.clone();
+    next.set_version(record.version() + 1);
+    let sql = if merge {
+        "UPDATE $record MERGE $content WHERE (version OR 0) = $version"
+    } else {
+        "UPDATE $record CONTENT $content WHERE (version OR 0) = $version"
+    };
+    query::count_query();
+    let saved: Option<T> = surreal
+        .query(sql)
+        .bind(("record", record.record_id()))
+        .bind(("content", next))
+        .bind(("version", record.version()))
+        .await?
+        .take(0)?;
+    saved.ok_or_else(|| {
+        tide::Error::new(
+            tide::StatusCode::Conflict,
+            anyhow::anyhow!(
+                "{} was changed while this was being saved, try again",
+                record.record_id()
+            ),
+        )
+    })
+}
+
+/// Writes only the top-level fields `after` changed from `before`, so fields a projection left
+/// at their defaults aren't wiped. Records that keep a `version` get it bumped.
+pub async fn update_fields<T>(surreal: &crate::Surreal, before: &T, after: T) -> tide::Result<T>
+where
+    T: ReferrableExt + Serialize + DeserializeOwned,
+{
+    let (Value::Object(old), Value::Object(new)) =
+        (serde_json::to_value(before)?, serde_json::to_value(&after)?)
+    else {
+        return Err(anyhow::anyhow!("{} isn't a struct", type_name::<T>()).into());
+    };
+    let mut sets: Vec<_> = new
+        .iter()
+        .filter(|(field, value)| *field != "version" && old.get(*field) != Some(value))
+        .map(|(field, _)| format!("{field} = $content.{field}"))
+        .chain(
+            old.keys()
+                .filter(|field| !new.contains_key(*field))
+                .map(|field| format!("{field} = NONE")),
+        )
+        .collect();
+    if sets.is_empty() {
+        return Ok(after);
+    }
+    if new.contains_key("version") {
+        sets.push("version = (version OR 0) + 1".to_owned());
+    }
+    query::count_query();
+    let updated: Option<T> = surreal
+        .query(format!("UPDATE $record SET {}", sets.join(", ")))
+        .bind(("record", after.record_id()))
+        .bind(("content", &after))
+        .await?
+        .take(0)?;
+    Ok(updated.ok_or_else(|| anyhow::anyhow!("{} is gone", after.record_id()))?)
 }
 
 #[derive(Debug, Clone)]
@@ -257,11 +427,11 @@ impl<T: ReferrableWithId + ?Sized> Ref<T> {
         &self.id.as_ref()
     }
 
-    pub fn gql_id(&self) -> async_graphql::ID
+    pub fn gql_id(&self) -> RecordId
     where
-        T::Id: Display,
+        T: ReferrableWithId<Id: Into<Id>>,
     {
-        async_graphql::ID::from(format!("{}:{}", T::TABLE, self.id))
+        self.record_id()
     }
 }
 
@@ -278,6 +448,7 @@ impl<T: ReferrableWithId<Id = String> + ?Sized> TryFrom<RecordId> for Ref<T> {
 
 impl<T: ReferrableWithId<Id: Into<Id>> + DeserializeOwned + Sync + Send> Ref<T> {
     pub async fn fetch(&self, surreal: &crate::Surreal) -> surrealdb::Result<T> {
+        query::count_query();
         surreal.select(self.record_id().0).await
     }
 }
@@ -304,25 +475,29 @@ impl<'de, T: ReferrableWithId<Id = String>> Deserialize<'de> for Ref<T> {
     }
 }
 
+/// A [RecordId] to clients, of `T`'s table, see [RecordId::from_gql].
 impl<T: ReferrableWithId<Id = String> + ?Sized + Any + Send + Sync> InputType for Ref<T> {
-    type RawValueType = ID;
+    type RawValueType = RecordId;
     fn as_raw_value(&self) -> Option<&Self::RawValueType> {
         None
     }
     fn parse(value: Option<async_graphql::Value>) -> async_graphql::InputValueResult<Self> {
-        let id =
-            <ID as ScalarType>::parse(value.ok_or_else(|| InputValueError::custom("no value"))?)
-                .map_err(InputValueError::propagate)?;
-        Ok(Ref::new(id.as_str()))
+        match value {
+            Some(async_graphql::Value::String(ref s)) => RecordId::from_gql(s, Some(T::TABLE))
+                .map(|id| Ref::new_owned(id.id()))
+                .map_err(InputValueError::custom),
+            Some(value) => Err(InputValueError::expected_type(value)),
+            None => Err(InputValueError::custom("no value")),
+        }
     }
     fn to_value(&self) -> async_graphql::Value {
-        async_graphql::Value::String(self.id().to_owned())
+        async_graphql::Value::String(self.record_id().to_gql())
     }
     fn type_name() -> std::borrow::Cow<'static, str> {
-        Cow::Borrowed("ID")
+        <RecordId as InputType>::type_name()
     }
     fn create_type_info(registry: &mut async_graphql::registry::Registry) -> String {
-        Self::qualified_type_name()
+        <RecordId as InputType>::create_type_info(registry)
     }
     fn federation_fields() -> Option<String> {
         None
@@ -372,6 +547,22 @@ pub(crate) macro referrable {
     }
 }
 
+/// A random alphanumeric string, for codes and secrets handed out to clients.
+pub fn random_string(len: usize) -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Compares secrets, or hashes of them, in time that doesn't depend on where they differ.
+pub fn secure_eq(a: &str, b: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
 pub fn unwrap_id_str(id: &Id) -> Option<&String> {
     match id {
         surrealdb::sql::Id::String(ref s) => Some(s),
@@ -383,6 +574,7 @@ pub trait Cx<'a> {
     fn cx(&self) -> &'a crate::http::State;
     fn relay(&self) -> &'a Relay;
     fn storage(&self) -> &'a RwLock<Storage>;
+    fn services(&self) -> &'a Services;
 }
 
 impl<'a> Cx<'a> for async_graphql::Context<'a> {
@@ -395,4 +587,7 @@ impl<'a> Cx<'a> for async_graphql::Context<'a> {
     fn storage(&self) -> &'a RwLock<Storage> {
         self.data_unchecked::<std::sync::Arc<RwLock<Storage>>>()
     }
+    fn services(&self) -> &'a Services {
+        self.data_unchecked()
+    }
 }
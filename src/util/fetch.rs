@@ -0,0 +1,83 @@
+//! Fetching URLs users handed us, without letting them point the server at its own network.
+
+use std::net::SocketAddr;
+
+use anyhow::anyhow;
+use async_std::net::{TcpStream, ToSocketAddrs};
+use async_tls::TlsConnector;
+use async_trait::async_trait;
+use futures_util::AsyncReadExt;
+use surf::http::{Request, Response};
+use tide::{http::Url, StatusCode};
+
+/// Connects to the address that was checked, whatever the host resolves to by the time the
+/// request is sent, so a short-lived DNS record can't swap in a private one.
+#[derive(Debug)]
+struct Pinned(SocketAddr);
+
+#[async_trait]
+impl surf::HttpClient for Pinned {
+    async fn send(&self, req: Request) -> Result<Response, surf::Error> {
+        let stream = TcpStream::connect(self.0).await?;
+        if req.url().scheme() == "https" {
+            let host = req.url().host_str().unwrap_or_default().to_owned();
+            let stream = TlsConnector::default().connect(host, stream).await?;
+            async_h1::connect(stream, req).await
+        } else {
+            async_h1::connect(stream, req).await
+        }
+    }
+}
+
+/// GETs `url` if it is http(s) and resolves to public addresses only, from the first of
+/// them. Errors on anything but a success status.
+pub async fn get_public(url: &str) -> tide::Result<surf::Response> {
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(tide::Error::new(
+            StatusCode::BadRequest,
+            anyhow!("only http(s) urls can be fetched"),
+        ));
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| tide::Error::new(StatusCode::BadRequest, anyhow!("url has no host")))?
+        .to_owned();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<_> = (host.as_str(), port).to_socket_addrs().await?.collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| addr.ip().is_global()) {
+        return Err(tide::Error::new(
+            StatusCode::Forbidden,
+            anyhow!("{host} is not a public host"),
+        ));
+    }
+
+    // surf doesn't follow redirects on its own, which would skip the check above
+    let response = surf::Client::with_http_client(Pinned(addrs[0]))
+        .get(parsed)
+        .await?;
+    if !response.status().is_success() {
+        return Err(tide::Error::new(
+            StatusCode::BadGateway,
+            anyhow!("{host} responded with {}", response.status()),
+        ));
+    }
+    Ok(response)
+}
+
+/// Reads the body, giving up once it's larger than `limit` bytes.
+pub async fn read_limited(response: &mut surf::Response, limit: u64) -> tide::Result<Vec<u8>> {
+    let mut body = vec![];
+    response
+        .take_body()
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() as u64 > limit {
+        return Err(tide::Error::new(
+            StatusCode::PayloadTooLarge,
+            anyhow!("response is larger than {limit} bytes"),
+        ));
+    }
+    Ok(body)
+}
@@ -0,0 +1,592 @@
+//! A small typed SurrealQL builder, so queries are composed from parts instead of templated
+//! strings. Values never end up in the query text, they're always bound as parameters.
+//!
+//! ```ignore
+//! let latest: Vec<Message> = Q::select::<Message>()
+//!     .filter(field("author").eq(user.record_id()))
+//!     .order_desc("created_at")
+//!     .limit(50)
+//!     .all(surreal)
+//!     .await?;
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize, Serializer};
+use surrealdb::sql::Datetime;
+
+use super::{RecordId, Ref, Referrable, ReferrableWithId};
+
+thread_local! {
+    static COUNTER: RefCell<Option<Arc<AtomicU64>>> = RefCell::new(None);
+}
+
+/// Counts a query towards the [Counted] future being polled, if any. Called by the builder
+/// and by [Ref::fetch](super::Ref::fetch) and friends; raw `surreal.query`s go uncounted.
+pub fn count_query() {
+    COUNTER.with(|counter| {
+        if let Some(ref counter) = *counter.borrow() {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Counts the queries a future makes while it is polled. Nested [Counted] futures count
+/// their own queries, not their parent's, so concurrent resolvers don't mix up.
+pub struct Counted<'a, T> {
+    future: Pin<Box<dyn Future<Output = T> + Send + 'a>>,
+    counter: Arc<AtomicU64>,
+}
+
+impl<'a, T> Counted<'a, T> {
+    pub fn new(future: impl Future<Output = T> + Send + 'a, counter: Arc<AtomicU64>) -> Self {
+        Self {
+            future: Box::pin(future),
+            counter,
+        }
+    }
+}
+
+impl<T> Future for Counted<'_, T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let outer = COUNTER.with(|counter| counter.replace(Some(self.counter.clone())));
+        let polled = self.future.as_mut().poll(cx);
+        COUNTER.with(|counter| counter.replace(outer));
+        polled
+    }
+}
+
+/// A value bound to a query parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bound {
+    Record(RecordId),
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Datetime(Datetime),
+    List(Vec<Bound>),
+}
+
+impl Serialize for Bound {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Record(record) => record.serialize(serializer),
+            Self::String(string) => string.serialize(serializer),
+            Self::Int(int) => int.serialize(serializer),
+            Self::Bool(bool) => bool.serialize(serializer),
+            Self::Datetime(datetime) => datetime.serialize(serializer),
+            Self::List(list) => list.serialize(serializer),
+        }
+    }
+}
+
+impl From<RecordId> for Bound {
+    fn from(record: RecordId) -> Self {
+        Self::Record(record)
+    }
+}
+
+impl<T: ReferrableWithId<Id: Into<surrealdb::sql::Id>>> From<&Ref<T>> for Bound {
+    fn from(record: &Ref<T>) -> Self {
+        Self::Record(record.record_id())
+    }
+}
+
+impl From<&str> for Bound {
+    fn from(string: &str) -> Self {
+        Self::String(string.to_owned())
+    }
+}
+
+impl From<String> for Bound {
+    fn from(string: String) -> Self {
+        Self::String(string)
+    }
+}
+
+impl From<i64> for Bound {
+    fn from(int: i64) -> Self {
+        Self::Int(int)
+    }
+}
+
+impl From<i32> for Bound {
+    fn from(int: i32) -> Self {
+        Self::Int(int.into())
+    }
+}
+
+impl From<bool> for Bound {
+    fn from(bool: bool) -> Self {
+        Self::Bool(bool)
+    }
+}
+
+impl From<Datetime> for Bound {
+    fn from(datetime: Datetime) -> Self {
+        Self::Datetime(datetime)
+    }
+}
+
+impl<T: Into<Bound>> From<Vec<T>> for Bound {
+    fn from(list: Vec<T>) -> Self {
+        Self::List(list.into_iter().map(Into::into).collect())
+    }
+}
+
+/// A field path like `author` or `recipient.id`. Only ever built from literals.
+#[derive(Debug, Clone, Copy)]
+pub struct Field(&'static str);
+
+pub fn field(name: &'static str) -> Field {
+    debug_assert!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '*')),
+        "not a field path: {name}"
+    );
+    Field(name)
+}
+
+macro_rules! ops {
+    ($($fn:ident => $op:literal),* $(,)?) => {
+        impl Field {
+            $(
+                pub fn $fn(self, value: impl Into<Bound>) -> Cond {
+                    Cond::Cmp(self.0, $op, value.into())
+                }
+            )*
+        }
+    };
+}
+
+ops! {
+    eq => "=",
+    ne => "!=",
+    lt => "<",
+    le => "<=",
+    gt => ">",
+    ge => ">=",
+    contains => "CONTAINS",
+    contains_any => "CONTAINSANY",
+    inside => "INSIDE",
+}
+
+impl Field {
+    pub fn is_none(self) -> Cond {
+        Cond::IsNone(self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Cond {
+    Cmp(&'static str, &'static str, Bound),
+    IsNone(&'static str),
+    All(Vec<Cond>),
+    Any(Vec<Cond>),
+}
+
+impl Cond {
+    pub fn and(self, other: Cond) -> Cond {
+        match self {
+            Self::All(mut all) => {
+                all.push(other);
+                Self::All(all)
+            }
+            cond => Self::All(vec![cond, other]),
+        }
+    }
+
+    pub fn or(self, other: Cond) -> Cond {
+        match self {
+            Self::Any(mut any) => {
+                any.push(other);
+                Self::Any(any)
+            }
+            cond => Self::Any(vec![cond, other]),
+        }
+    }
+
+    fn render(self, sql: &mut String, bindings: &mut Bindings) {
+        match self {
+            Self::Cmp(field, op, value) => {
+                let param = bindings.add(value);
+                write!(sql, "{field} {op} ${param}").unwrap();
+            }
+            Self::IsNone(field) => write!(sql, "{field} = NONE").unwrap(),
+            Self::All(conds) | Self::Any(conds) if conds.is_empty() => sql.push_str("true"),
+            Self::All(conds) => Self::join(conds, " AND ", sql, bindings),
+            Self::Any(conds) => Self::join(conds, " OR ", sql, bindings),
+        }
+    }
+
+    fn join(conds: Vec<Cond>, with: &str, sql: &mut String, bindings: &mut Bindings) {
+        sql.push('(');
+        for (i, cond) in conds.into_iter().enumerate() {
+            if i > 0 {
+                sql.push_str(with);
+            }
+            cond.render(sql, bindings);
+        }
+        sql.push(')');
+    }
+}
+
+/// The parameters of a rendered query, `$p0`, `$p1`...
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Bindings(BTreeMap<String, Bound>);
+
+impl Bindings {
+    fn add(&mut self, value: Bound) -> String {
+        let param = format!("p{}", self.0.len());
+        self.0.insert(param.clone(), value);
+        param
+    }
+
+    pub fn get(&self, param: &str) -> Option<&Bound> {
+        self.0.get(param)
+    }
+}
+
+/// Records with fields worth leaving out of a [Select] when nobody is going to look at them.
+/// Those fields need `#[serde(default)]`, since a [Select::project] won't return them.
+pub trait Projection: Referrable {
+    /// Selected whatever is wanted.
+    const ALWAYS: &'static [&'static str];
+    /// The other fields, with the GraphQL fields that need them.
+    const OPTIONAL: &'static [(&'static str, &'static [&'static str])];
+}
+
+/// Which GraphQL fields a client asked for of the records a query returns.
+#[derive(Debug, Clone, Default)]
+pub enum Wanted {
+    /// Everything, like outside GraphQL.
+    #[default]
+    All,
+    Fields(Vec<&'static str>),
+}
+
+impl Wanted {
+    /// The fields of `T` asked for on the nodes of the connection being resolved, either under
+    /// `edges { node }` or `nodes`.
+    pub fn in_connection<T: Projection>(cx: &async_graphql::Context<'_>) -> Self {
+        let look_ahead = cx.look_ahead();
+        let (edges, nodes) = (
+            look_ahead.field("edges").field("node"),
+            look_ahead.field("nodes"),
+        );
+        Self::Fields(
+            T::OPTIONAL
+                .iter()
+                .flat_map(|(_, needed_by)| needed_by.iter().copied())
+                .filter(|name| edges.field(name).exists() || nodes.field(name).exists())
+                .collect(),
+        )
+    }
+
+    fn wants(&self, needed_by: &[&str]) -> bool {
+        match self {
+            Self::All => true,
+            Self::Fields(fields) => needed_by.iter().any(|name| fields.contains(name)),
+        }
+    }
+}
+
+/// Where queries start.
+pub struct Q;
+
+impl Q {
+    pub fn select<T: Referrable>() -> Select<T> {
+        Select {
+            fields: vec![],
+            filter: None,
+            order: vec![],
+            limit: None,
+            start: None,
+            fetch: vec![],
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// A `SELECT` over the table of `T`.
+#[derive(Debug, Clone)]
+pub struct Select<T> {
+    /// Empty for `*`.
+    fields: Vec<&'static str>,
+    filter: Option<Cond>,
+    /// Later fields break ties of earlier ones.
+    order: Vec<(&'static str, bool)>,
+    limit: Option<i64>,
+    start: Option<i64>,
+    fetch: Vec<&'static str>,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Projection> Select<T> {
+    /// Selects only what `wanted` needs. Records come back with defaults for the rest, so
+    /// don't save them.
+    pub fn project(mut self, wanted: &Wanted) -> Self {
+        if let Wanted::Fields(_) = wanted {
+            self.fields = T::ALWAYS.to_vec();
+            self.fields.extend(
+                T::OPTIONAL
+                    .iter()
+                    .filter(|(_, needed_by)| wanted.wants(needed_by))
+                    .map(|(field, _)| *field),
+            );
+        }
+        self
+    }
+}
+
+impl<T: Referrable> Select<T> {
+    /// Filters are ANDed together.
+    pub fn filter(mut self, cond: Cond) -> Self {
+        self.filter = Some(match self.filter {
+            Some(filter) => filter.and(cond),
+            None => cond,
+        });
+        self
+    }
+
+    /// Called again, orders records that tie on the fields before.
+    pub fn order(mut self, field: &'static str) -> Self {
+        self.order.push((field, false));
+        self
+    }
+
+    pub fn order_desc(mut self, field: &'static str) -> Self {
+        self.order.push((field, true));
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn start(mut self, start: i64) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn fetch(mut self, field: &'static str) -> Self {
+        self.fetch.push(field);
+        self
+    }
+
+    /// The SurrealQL and its parameters, without running anything.
+    pub fn to_sql(&self) -> (String, Bindings) {
+        if self.fields.is_empty() {
+            self.render("*")
+        } else {
+            self.render(&self.fields.join(", "))
+        }
+    }
+
+    fn render(&self, fields: &str) -> (String, Bindings) {
+        let mut sql = format!("SELECT {fields} FROM {}", T::TABLE);
+        let mut bindings = Bindings::default();
+        if let Some(filter) = self.filter.clone() {
+            sql.push_str(" WHERE ");
+            filter.render(&mut sql, &mut bindings);
+        }
+        for (i, (field, desc)) in self.order.iter().enumerate() {
+            sql.push_str(if i == 0 { " ORDER BY " } else { ", " });
+            write!(sql, "{field}{}", if *desc { " DESC" } else { "" }).unwrap();
+        }
+        if let Some(limit) = self.limit {
+            write!(sql, " LIMIT {limit}").unwrap();
+        }
+        if let Some(start) = self.start {
+            write!(sql, " START AT {start}").unwrap();
+        }
+        if !self.fetch.is_empty() {
+            write!(sql, " FETCH {}", self.fetch.join(", ")).unwrap();
+        }
+        (sql, bindings)
+    }
+
+    pub async fn all(self, surreal: &crate::Surreal) -> surrealdb::Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let (sql, bindings) = self.to_sql();
+        count_query();
+        surreal.query(sql).bind(bindings).await?.take(0)
+    }
+
+    pub async fn one(self, surreal: &crate::Surreal) -> surrealdb::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let (sql, bindings) = self.limit(1).to_sql();
+        count_query();
+        surreal.query(sql).bind(bindings).await?.take(0)
+    }
+
+    /// How many records match, ignoring order, limit and start.
+    pub async fn count(self, surreal: &crate::Surreal) -> surrealdb::Result<i64> {
+        #[derive(Deserialize)]
+        struct Counted {
+            counted: i64,
+        }
+
+        let (sql, bindings) = Self {
+            fields: vec![],
+            order: vec![],
+            limit: None,
+            start: None,
+            fetch: vec![],
+            ..self
+        }
+        .render("count() AS counted");
+        count_query();
+        let counted: Option<Counted> = surreal
+            .query(format!("{sql} GROUP BY counted"))
+            .bind(bindings)
+            .await?
+            .take(0)?;
+        Ok(counted.map_or(0, |counted| counted.counted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::Thing;
+
+    use super::*;
+    use crate::model::message::Message;
+
+    fn alice() -> RecordId {
+        RecordId(Thing::from(("user", "alice")))
+    }
+
+    #[test]
+    fn selects_everything_by_default() {
+        let (sql, _) = Q::select::<Message>().to_sql();
+        assert_eq!(sql, "SELECT * FROM message");
+    }
+
+    #[test]
+    fn filters_are_anded_and_bound() {
+        let (sql, bindings) = Q::select::<Message>()
+            .filter(field("author").eq(alice()))
+            .filter(field("deleted_at").is_none())
+            .filter(field("pinned").eq(true))
+            .order_desc("created_at")
+            .limit(50)
+            .start(100)
+            .fetch("author")
+            .to_sql();
+        assert_eq!(
+            sql,
+            "SELECT * FROM message WHERE (author = $p0 AND deleted_at = NONE AND pinned = $p1) \
+             ORDER BY created_at DESC LIMIT 50 START AT 100 FETCH author"
+        );
+        assert_eq!(bindings.get("p0"), Some(&Bound::Record(alice())));
+        assert_eq!(bindings.get("p1"), Some(&Bound::Bool(true)));
+        assert_eq!(bindings.get("p2"), None);
+    }
+
+    #[test]
+    fn conditions_nest_in_parentheses() {
+        let (sql, bindings) = Q::select::<Message>()
+            .filter(
+                field("author")
+                    .eq(alice())
+                    .or(field("recipient").eq(alice())),
+            )
+            .filter(field("created_at").lt(10))
+            .order("created_at")
+            .order_desc("id")
+            .to_sql();
+        assert_eq!(
+            sql,
+            "SELECT * FROM message WHERE ((author = $p0 OR recipient = $p1) AND created_at < $p2) \
+             ORDER BY created_at, id DESC"
+        );
+        assert_eq!(bindings.get("p2"), Some(&Bound::Int(10)));
+    }
+
+    #[test]
+    fn empty_conditions_match_everything() {
+        let (sql, bindings) = Q::select::<Message>().filter(Cond::Any(vec![])).to_sql();
+        assert_eq!(sql, "SELECT * FROM message WHERE true");
+        assert_eq!(bindings.get("p0"), None);
+    }
+
+    #[test]
+    fn values_never_reach_the_query_text() {
+        let (sql, bindings) = Q::select::<Message>()
+            .filter(field("content").contains("'; DELETE message; --"))
+            .to_sql();
+        assert_eq!(sql, "SELECT * FROM message WHERE content CONTAINS $p0");
+        assert_eq!(
+            bindings.get("p0"),
+            Some(&Bound::String("'; DELETE message; --".to_owned()))
+        );
+    }
+
+    #[test]
+    fn projections_keep_what_is_always_needed() {
+        let (sql, _) = Q::select::<Message>()
+            .project(&Wanted::Fields(vec!["embeds"]))
+            .to_sql();
+        let fields = sql
+            .strip_prefix("SELECT ")
+            .and_then(|sql| sql.strip_suffix(" FROM message"))
+            .unwrap()
+            .split(", ")
+            .collect::<Vec<_>>();
+        assert!(Message::ALWAYS.iter().all(|field| fields.contains(field)));
+        assert!(fields.contains(&"embeds"));
+        assert!(!fields.contains(&"content"));
+
+        let (sql, _) = Q::select::<Message>().project(&Wanted::All).to_sql();
+        assert_eq!(sql, "SELECT * FROM message");
+    }
+
+    #[test]
+    fn counted_futures_count_only_their_own_queries() {
+        let (outer, inner) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        async_std::task::block_on(Counted::new(
+            {
+                let inner = inner.clone();
+                async move {
+                    count_query();
+                    Counted::new(
+                        async {
+                            count_query();
+                            count_query();
+                        },
+                        inner,
+                    )
+                    .await;
+                    count_query();
+                }
+            },
+            outer.clone(),
+        ));
+        assert_eq!(outer.load(Ordering::Relaxed), 2);
+        assert_eq!(inner.load(Ordering::Relaxed), 2);
+
+        // outside any Counted, nothing is counted and nothing panics
+        count_query();
+    }
+}
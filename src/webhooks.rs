@@ -0,0 +1,59 @@
+//! Incoming webhooks: `POST /webhooks/<id>/<token>` posts the JSON body, a
+//! [WebhookPayload], to the webhook's channel. `POST /webhooks/github/<id>/<token>` takes
+//! GitHub and GitLab events instead, see [forge].
+
+use serde_json::Value;
+use tide::{Request, Response, StatusCode};
+
+use crate::{
+    http::HttpState,
+    model::webhook::{Webhook, WebhookPayload},
+    tenant,
+};
+
+mod forge;
+
+pub fn routes(tide: &mut tide::Server<HttpState>) {
+    tide.at(&format!("{}/:id/:token", Webhook::PATH))
+        .post(http_execute);
+    tide.at(&format!("{}/github/:id/:token", Webhook::PATH))
+        .post(http_forge);
+}
+
+/// Finds the webhook, or errors with `404 Not Found` so guessing tokens tells you nothing.
+pub(crate) async fn authenticate(request: &Request<HttpState>) -> tide::Result<Webhook> {
+    let id = request.param("id")?;
+    let token = request.param("token")?;
+    Webhook::authenticate(tenant::of(request)?.surreal(), id, token)
+        .await?
+        .ok_or_else(|| tide::Error::from_str(StatusCode::NotFound, "unknown webhook"))
+}
+
+async fn http_execute(mut request: Request<HttpState>) -> tide::Result {
+    let webhook = authenticate(&request).await?;
+    let payload: WebhookPayload = request.body_json().await?;
+    let state = tenant::of(&request)?;
+    webhook
+        .execute(state.surreal(), &state.relay, payload)
+        .await?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn http_forge(mut request: Request<HttpState>) -> tide::Result {
+    let webhook = authenticate(&request).await?;
+    let (forge, event) = forge::event(&request).ok_or_else(|| {
+        tide::Error::from_str(StatusCode::BadRequest, "not a GitHub or GitLab event")
+    })?;
+    let payload: Value = request.body_json().await?;
+    if let Some(embed) = forge::render(forge, &event, &payload) {
+        let state = tenant::of(&request)?;
+        let payload = WebhookPayload {
+            embeds: vec![embed],
+            ..Default::default()
+        };
+        webhook
+            .execute(state.surreal(), &state.relay, payload)
+            .await?;
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
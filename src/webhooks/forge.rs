@@ -0,0 +1,198 @@
+//! Turns GitHub and GitLab event payloads into embeds. Only pushes, pull/merge requests and
+//! issues are rendered, everything else is dropped.
+
+use serde_json::Value;
+use tide::{http::Url, Request};
+
+use crate::{http::HttpState, model::message::Embed};
+
+const COMMITS_SHOWN: usize = 5;
+const BODY_LENGTH: usize = 500;
+
+const PUSH: i32 = 0x5865f2;
+const OPENED: i32 = 0x2da44e;
+const CLOSED: i32 = 0xcf222e;
+const MERGED: i32 = 0x8250df;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    GitLab,
+}
+
+/// Which forge sent the request, and the event it's about.
+pub fn event(request: &Request<HttpState>) -> Option<(Forge, String)> {
+    if let Some(event) = request.header("x-github-event") {
+        return Some((Forge::GitHub, event.last().to_string()));
+    }
+    request
+        .header("x-gitlab-event")
+        .map(|event| (Forge::GitLab, event.last().to_string()))
+}
+
+fn str<'a>(payload: &'a Value, pointer: &str) -> Option<&'a str> {
+    payload.pointer(pointer).and_then(Value::as_str)
+}
+
+/// A URL from the payload, only if it's http(s): embeds are clicked, and `javascript:` is
+/// just as valid in a payload anyone with the webhook token can send.
+fn link(payload: &Value, pointer: &str) -> Option<String> {
+    str(payload, pointer)
+        .and_then(|url| Url::parse(url).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .map(String::from)
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// `None` for events that aren't worth a message, like pings or labels being added.
+pub fn render(forge: Forge, event: &str, payload: &Value) -> Option<Embed> {
+    match (forge, event) {
+        (Forge::GitHub, "push") => push(
+            payload,
+            str(payload, "/repository/full_name")?,
+            str(payload, "/pusher/name").or(str(payload, "/sender/login")),
+            link(payload, "/compare"),
+        ),
+        (Forge::GitLab, "Push Hook") => push(
+            payload,
+            str(payload, "/project/path_with_namespace")?,
+            str(payload, "/user_name"),
+            link(payload, "/project/web_url"),
+        ),
+        (Forge::GitHub, "pull_request") => {
+            let action = match str(payload, "/action")? {
+                "closed" if payload.pointer("/pull_request/merged") == Some(&Value::Bool(true)) => {
+                    "merged"
+                }
+                action => action,
+            };
+            change(
+                payload,
+                "Pull request",
+                "/pull_request",
+                "/html_url",
+                str(payload, "/repository/full_name")?,
+                action,
+                str(payload, "/sender/login"),
+            )
+        }
+        (Forge::GitHub, "issues") => change(
+            payload,
+            "Issue",
+            "/issue",
+            "/html_url",
+            str(payload, "/repository/full_name")?,
+            str(payload, "/action")?,
+            str(payload, "/sender/login"),
+        ),
+        (Forge::GitLab, "Merge Request Hook" | "Issue Hook") => {
+            let kind = match event {
+                "Merge Request Hook" => "Merge request",
+                _ => "Issue",
+            };
+            let action = match str(payload, "/object_attributes/action")? {
+                "open" => "opened",
+                "close" => "closed",
+                "reopen" => "reopened",
+                "merge" => "merged",
+                action => action,
+            };
+            change(
+                payload,
+                kind,
+                "/object_attributes",
+                "/url",
+                str(payload, "/project/path_with_namespace")?,
+                action,
+                str(payload, "/user/name"),
+            )
+        }
+        _ => None,
+    }
+}
+
+fn push(payload: &Value, repo: &str, pusher: Option<&str>, url: Option<String>) -> Option<Embed> {
+    let commits = payload.pointer("/commits")?.as_array()?;
+    // branch deletions and tag pushes come without commits
+    if commits.is_empty() {
+        return None;
+    }
+    let branch = str(payload, "/ref")?.trim_start_matches("refs/heads/");
+
+    let mut description: Vec<String> = commits
+        .iter()
+        .take(COMMITS_SHOWN)
+        .map(|commit| {
+            let id = str(commit, "/id").unwrap_or_default();
+            let id = format!("`{}`", id.chars().take(7).collect::<String>());
+            format!(
+                "{} {} - {}",
+                match link(commit, "/url") {
+                    Some(url) => format!("[{id}]({url})"),
+                    None => id,
+                },
+                first_line(str(commit, "/message").unwrap_or_default()),
+                str(commit, "/author/name").unwrap_or("someone"),
+            )
+        })
+        .collect();
+    if commits.len() > COMMITS_SHOWN {
+        description.push(format!("and {} more", commits.len() - COMMITS_SHOWN));
+    }
+
+    Some(Embed {
+        title: Some(format!(
+            "[{repo}:{branch}] {} new commit{}",
+            commits.len(),
+            if commits.len() == 1 { "" } else { "s" }
+        )),
+        description: Some(description.join("\n")),
+        url,
+        color: Some(PUSH),
+        author: pusher.map(ToOwned::to_owned),
+        ..Default::default()
+    })
+}
+
+/// A pull/merge request or an issue being opened, closed, reopened or merged.
+fn change(
+    payload: &Value,
+    kind: &str,
+    object: &str,
+    url: &str,
+    repo: &str,
+    action: &str,
+    actor: Option<&str>,
+) -> Option<Embed> {
+    let color = match action {
+        "opened" | "reopened" => OPENED,
+        "closed" => CLOSED,
+        "merged" => MERGED,
+        _ => return None,
+    };
+    let object = payload.pointer(object)?;
+    let number = object
+        .get("number")
+        .or_else(|| object.get("iid"))
+        .and_then(Value::as_i64)?;
+    let body = str(object, "/body")
+        .or(str(object, "/description"))
+        .filter(|_| action == "opened")
+        .map(|body| body.chars().take(BODY_LENGTH).collect::<String>())
+        .filter(|body| !body.trim().is_empty());
+
+    Some(Embed {
+        title: Some(format!(
+            "[{repo}] {kind} {action}: #{number} {}",
+            str(object, "/title").unwrap_or_default()
+        )),
+        description: body,
+        url: link(object, url),
+        color: Some(color),
+        author: actor.map(ToOwned::to_owned),
+        ..Default::default()
+    })
+}